@@ -9,9 +9,9 @@ use async_openai::{
         ChatCompletionRequestUserMessageArgs, CreateChatCompletionRequestArgs,
     },
 };
-use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
 use clap::Parser;
-use hyperlight_unikraft::{parse_memory, run_vm_capture_output, VmConfig};
+use hyperlight_unikraft::workspace::Workspace;
+use hyperlight_unikraft::{Vm, VmOutput};
 use std::path::{Path, PathBuf};
 use std::process::Command;
 use tracing::{debug, info};
@@ -57,13 +57,6 @@ const SYSTEM_PROMPT: &str = r#"Generate Python code using python-pptx to create
 Requirements:
 1. Use python-pptx to create the presentation
 2. Save to '/output.pptx'
-3. Output the file as base64 with prefix "PPTX_BASE64:"
-
-End with:
-import base64
-with open('/output.pptx', 'rb') as f:
-    data = f.read()
-print(f"PPTX_BASE64:{base64.b64encode(data).decode()}")
 
 Output only Python code.
 "#;
@@ -173,13 +166,36 @@ zipfile.ZipInfo.__init__ = _patched_ZipInfo_init
 
 "#;
 
+/// Where the generated code saves the presentation in the guest
+const PPTX_PATH: &str = "/output.pptx";
+
+/// Suffix that hands the presentation back to the host through the
+/// `output_file` tool (see `hyperlight_unikraft::hostfn::OutputFiles`),
+/// which lands it in `VmOutput::files`
+const SEND_PPTX: &str = r#"
+
+import base64 as _hl_b64, json as _hl_json
+def _hl_output_file(path, data):
+    request = _hl_json.dumps({"name": "output_file", "args": {
+        "path": path, "data": _hl_b64.b64encode(data).decode()}})
+    with open("/dev/hcall", "r+b", buffering=0) as hcall:
+        hcall.write(request.encode())
+        reply = _hl_json.loads(hcall.read())
+    if "error" in reply:
+        raise RuntimeError(reply["error"])
+with open('/output.pptx', 'rb') as _hl_f:
+    _hl_data = _hl_f.read()
+for _hl_i in range(0, max(len(_hl_data), 1), 48 * 1024):
+    _hl_output_file('/output.pptx', _hl_data[_hl_i:_hl_i + 48 * 1024])
+"#;
+
 fn execute_in_sandbox(
     python_code: &str,
     kernel: &Path,
     rootfs: &Path,
     memory: &str,
     timing: bool,
-) -> Result<String> {
+) -> Result<VmOutput> {
     if !kernel.exists() {
         anyhow::bail!("kernel not found: {:?}. Run 'make assets'.", kernel);
     }
@@ -188,16 +204,16 @@ fn execute_in_sandbox(
     }
 
     // Prepend the zipfile patch to the generated code
-    let patched_code = format!("{}{}", ZIPFILE_PATCH, python_code);
+    let patched_code = format!("{}{}{}", ZIPFILE_PATCH, python_code, SEND_PPTX);
 
-    let temp_dir = tempfile::tempdir()?;
-    let script_path = temp_dir.path().join("generate_pptx.py");
-    std::fs::write(&script_path, &patched_code)?;
+    // Owns the script and the modified rootfs; removed when this returns.
+    let workspace = Workspace::new()?;
+    let script_path = workspace.write("generate_pptx.py", &patched_code)?;
 
     debug!("script: {:?}", script_path);
 
     let cpio_start = std::time::Instant::now();
-    let modified_rootfs = inject_script_into_rootfs(rootfs, &script_path)?;
+    let modified_rootfs = inject_script_into_rootfs(&workspace, rootfs, &script_path)?;
     if timing {
         info!("  cpio inject: {:?}", cpio_start.elapsed());
     }
@@ -205,18 +221,14 @@ fn execute_in_sandbox(
     // Load rootfs into memory
     let rootfs_data = std::fs::read(&modified_rootfs)?;
 
-    // Parse memory size
-    let heap_size = parse_memory(memory)?;
-
-    let config = VmConfig::default().with_heap_size(heap_size);
-
     let vm_start = std::time::Instant::now();
-    let vm_output = run_vm_capture_output(
-        kernel,
-        Some(&rootfs_data),
-        &["/generate_pptx.py".to_string()],
-        config,
-    )?;
+    let vm_output = Vm::builder()
+        .kernel(kernel)
+        .initrd_bytes(rootfs_data)
+        .arg("/generate_pptx.py")
+        .memory(memory)
+        .capture_output(true)
+        .run()?;
     if timing {
         info!("  vm total: {:?}", vm_start.elapsed());
         info!("  sandbox setup: {:?}", vm_output.setup_time);
@@ -225,20 +237,21 @@ fn execute_in_sandbox(
 
     debug!("output: {}", vm_output.output);
 
-    Ok(vm_output.output)
+    Ok(vm_output)
 }
 
-fn inject_script_into_rootfs(original_rootfs: &Path, script_path: &Path) -> Result<PathBuf> {
-    let temp_dir = tempfile::tempdir()?;
-    let extract_dir = temp_dir.path().join("rootfs");
-    let new_cpio = temp_dir.path().join("rootfs_with_script.cpio");
+fn inject_script_into_rootfs(
+    workspace: &Workspace,
+    original_rootfs: &Path,
+    script_path: &Path,
+) -> Result<PathBuf> {
+    let extract_dir = workspace.dir("rootfs")?;
+    let new_cpio = workspace.file("rootfs_with_script.cpio")?;
 
     // Convert to absolute path before cd
     let rootfs_abs = original_rootfs.canonicalize()
         .with_context(|| format!("failed to resolve: {:?}", original_rootfs))?;
 
-    std::fs::create_dir_all(&extract_dir)?;
-
     let status = Command::new("sh")
         .arg("-c")
         .arg(format!(
@@ -270,48 +283,21 @@ fn inject_script_into_rootfs(original_rootfs: &Path, script_path: &Path) -> Resu
         anyhow::bail!("cpio create failed");
     }
 
-    // Leak tempdir so file persists
-    let path = new_cpio.clone();
-    std::mem::forget(temp_dir);
-
-    Ok(path)
+    Ok(new_cpio)
 }
 
-fn extract_pptx_from_output(output: &str) -> Result<Vec<u8>> {
-    const PREFIX: &str = "PPTX_BASE64:";
-
-    // First try line-by-line
-    for line in output.lines() {
-        if let Some(base64_data) = line.strip_prefix(PREFIX) {
-            let decoded = BASE64
-                .decode(base64_data.trim())
-                .context("base64 decode failed")?;
-            return Ok(decoded);
-        }
-    }
-
-    // Fallback: search for marker anywhere in output (handles missing newline)
-    if let Some(start) = output.find(PREFIX) {
-        let data_start = start + PREFIX.len();
-        // Find end: next newline or "Kernel" or end of string
-        let remaining = &output[data_start..];
-        let end = remaining
-            .find('\n')
-            .or_else(|| remaining.find("Kernel"))
-            .unwrap_or(remaining.len());
-        let base64_data = &remaining[..end];
-
-        let decoded = BASE64
-            .decode(base64_data.trim())
-            .context("base64 decode failed")?;
-        return Ok(decoded);
+fn extract_pptx_from_output(vm_output: &VmOutput) -> Result<Vec<u8>> {
+    if let Some(pptx) = vm_output.files.get(Path::new(PPTX_PATH)) {
+        return Ok(pptx.clone());
     }
 
     // Show what we got so the user can diagnose Python errors
-    let preview = if output.len() > 2000 {
-        format!("{}...[truncated, {} bytes total]", &output[..2000], output.len())
+    let output = &vm_output.output;
+    let preview = if output.chars().count() > 2000 {
+        let head: String = output.chars().take(2000).collect();
+        format!("{}...[truncated, {} bytes total]", head, output.len())
     } else {
         output.to_string()
     };
-    anyhow::bail!("PPTX_BASE64: marker not found in VM output:\n{}", preview)
+    anyhow::bail!("{} not returned by the VM; output:\n{}", PPTX_PATH, preview)
 }
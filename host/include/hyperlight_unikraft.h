@@ -2,6 +2,7 @@
 #ifndef HYPERLIGHT_UNIKRAFT_H
 #define HYPERLIGHT_UNIKRAFT_H
 
+#include <stddef.h>
 #include <stdint.h>
 
 #ifdef __cplusplus
@@ -14,6 +15,14 @@ extern "C" {
 #define HL_STATUS_STOPPED 2
 #define HL_STATUS_ERROR   3
 
+/* Exit reasons (hl_vm_exit_reason) */
+#define HL_EXIT_HALT           0
+#define HL_EXIT_ABORT          1
+#define HL_EXIT_STACK_OVERFLOW 2
+#define HL_EXIT_OUT_OF_MEMORY  3
+#define HL_EXIT_UNEXPECTED     4
+#define HL_EXIT_INTERRUPTED    5
+
 /* Opaque VM handle */
 typedef struct HlVm HlVm;
 
@@ -44,10 +53,20 @@ int hl_vm_status(const HlVm *vm);
 /* Block until VM finishes. Returns 0 on success, -1 on failure. */
 int hl_vm_wait(HlVm *vm);
 
-/* Get captured output. Valid after VM stops.
+/* Get captured output. Valid after VM stops. Invalid UTF-8 and NUL bytes
+ * are replaced with U+FFFD.
  * Pointer valid until next hl_vm_output or hl_vm_free call on same VM. */
 const char *hl_vm_output(const HlVm *vm);
 
+/* Get the raw captured output bytes; *len receives the byte count.
+ * Returns NULL while the VM is running. Pointer valid until hl_vm_free. */
+const uint8_t *hl_vm_output_bytes(const HlVm *vm, size_t *len);
+
+/* Get how the guest run ended (HL_EXIT_*). Returns -1 if vm is NULL or the
+ * guest hasn't finished. For HL_EXIT_ABORT, *abort_code (if non-NULL)
+ * receives the guest's abort code. */
+int hl_vm_exit_reason(const HlVm *vm, uint8_t *abort_code);
+
 /* Get error message if status is HL_STATUS_ERROR. Returns NULL otherwise. */
 const char *hl_vm_error(const HlVm *vm);
 
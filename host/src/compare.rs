@@ -0,0 +1,268 @@
+//! A/B benchmarking of two kernel images on the same workload.
+//!
+//! [`compare`] alternates runs of the two kernels (A, B, B, A, ...) so
+//! drift in host load or thermal state hits both equally, and summarizes
+//! boot time, evolve time and memory per kernel with the difference
+//! between them.
+
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::{stats, Sandbox};
+
+/// What each iteration runs: the same initrd, arguments and heap for
+/// both kernels.
+#[derive(Debug, Clone, Default)]
+pub struct Workload {
+    pub initrd: Option<PathBuf>,
+    pub args: Vec<String>,
+    /// Guest heap size in bytes (the builder default otherwise).
+    pub heap_size: Option<u64>,
+}
+
+/// One measured run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sample {
+    /// Building the sandbox: setup plus guest boot and init.
+    pub boot: Duration,
+    /// Restore plus the application call.
+    pub evolve: Duration,
+    /// Host RSS growth from before the build to the end of the call;
+    /// `None` where RSS can't be read.
+    pub memory_bytes: Option<u64>,
+}
+
+/// Summary statistics of one metric over all iterations.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Summary {
+    pub n: usize,
+    pub mean: f64,
+    pub median: f64,
+    /// Sample standard deviation.
+    pub stddev: f64,
+    pub min: f64,
+    pub max: f64,
+}
+
+impl Summary {
+    pub fn of(values: &[f64]) -> Option<Self> {
+        if values.is_empty() {
+            return None;
+        }
+        let mut sorted = values.to_vec();
+        sorted.sort_by(f64::total_cmp);
+        let n = sorted.len();
+        let mean = sorted.iter().sum::<f64>() / n as f64;
+        let median = if n % 2 == 1 {
+            sorted[n / 2]
+        } else {
+            (sorted[n / 2 - 1] + sorted[n / 2]) / 2.0
+        };
+        let var = if n > 1 {
+            sorted.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (n - 1) as f64
+        } else {
+            0.0
+        };
+        Some(Self {
+            n,
+            mean,
+            median,
+            stddev: var.sqrt(),
+            min: sorted[0],
+            max: sorted[n - 1],
+        })
+    }
+}
+
+/// One metric for both kernels.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Delta {
+    pub a: Summary,
+    pub b: Summary,
+}
+
+impl Delta {
+    /// Change of B's mean relative to A's, in percent (negative: B is
+    /// smaller/faster).
+    pub fn change_percent(&self) -> f64 {
+        if self.a.mean == 0.0 {
+            return 0.0;
+        }
+        (self.b.mean - self.a.mean) / self.a.mean * 100.0
+    }
+
+    /// Welch's t statistic for the difference of the means.
+    pub fn t_statistic(&self) -> f64 {
+        let se = (self.a.stddev.powi(2) / self.a.n as f64
+            + self.b.stddev.powi(2) / self.b.n as f64)
+            .sqrt();
+        if se == 0.0 {
+            return if self.a.mean == self.b.mean {
+                0.0
+            } else {
+                f64::INFINITY
+            };
+        }
+        (self.b.mean - self.a.mean) / se
+    }
+
+    /// Whether the difference is unlikely to be noise (|t| > 2, roughly
+    /// 95% confidence for the sample sizes benchmarks use).
+    pub fn is_significant(&self) -> bool {
+        self.t_statistic().abs() > 2.0
+    }
+}
+
+/// Result of [`compare`]. Times are in microseconds, memory in bytes.
+#[derive(Debug, Clone)]
+pub struct Comparison {
+    pub kernel_a: PathBuf,
+    pub kernel_b: PathBuf,
+    pub samples_a: Vec<Sample>,
+    pub samples_b: Vec<Sample>,
+    pub boot: Delta,
+    pub evolve: Delta,
+    /// `None` where RSS can't be read.
+    pub memory: Option<Delta>,
+}
+
+impl std::fmt::Display for Comparison {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "A: {}", self.kernel_a.display())?;
+        writeln!(f, "B: {}", self.kernel_b.display())?;
+        writeln!(
+            f,
+            "{:<8} {:>14} {:>14} {:>9}",
+            "metric", "A (median)", "B (median)", "change"
+        )?;
+        let ms = |us: f64| format!("{:.2}ms", us / 1000.0);
+        let mib = |b: f64| format!("{:.1}MiB", b / (1024.0 * 1024.0));
+        let mut row = |name: &str, d: &Delta, fmt: &dyn Fn(f64) -> String| {
+            writeln!(
+                f,
+                "{:<8} {:>14} {:>14} {:>+8.1}%{}",
+                name,
+                fmt(d.a.median),
+                fmt(d.b.median),
+                d.change_percent(),
+                if d.is_significant() { "" } else { " (noise)" }
+            )
+        };
+        row("boot", &self.boot, &ms)?;
+        row("evolve", &self.evolve, &ms)?;
+        if let Some(ref memory) = self.memory {
+            row("memory", memory, &mib)?;
+        }
+        Ok(())
+    }
+}
+
+/// Run `workload` `iterations` times on each kernel, after one discarded
+/// warm-up run each, and summarize the differences. Any failed run
+/// aborts the comparison, since its timings would skew the result.
+pub fn compare(
+    kernel_a: &Path,
+    kernel_b: &Path,
+    workload: &Workload,
+    iterations: usize,
+) -> Result<Comparison> {
+    let iterations = iterations.max(2);
+    measure(kernel_a, workload).context("warm-up run of kernel A")?;
+    measure(kernel_b, workload).context("warm-up run of kernel B")?;
+
+    let mut samples_a = Vec::with_capacity(iterations);
+    let mut samples_b = Vec::with_capacity(iterations);
+    for i in 0..iterations {
+        // ABBA ordering cancels linear drift between the two.
+        let order = if i % 2 == 0 {
+            [(kernel_a, &mut samples_a), (kernel_b, &mut samples_b)]
+        } else {
+            [(kernel_b, &mut samples_b), (kernel_a, &mut samples_a)]
+        };
+        for (kernel, samples) in order {
+            let sample = measure(kernel, workload)
+                .with_context(|| format!("iteration {} of {}", i + 1, kernel.display()))?;
+            samples.push(sample);
+        }
+    }
+
+    let delta = |metric: fn(&Sample) -> Option<f64>| -> Option<Delta> {
+        let a: Option<Vec<f64>> = samples_a.iter().map(metric).collect();
+        let b: Option<Vec<f64>> = samples_b.iter().map(metric).collect();
+        Some(Delta {
+            a: Summary::of(&a?)?,
+            b: Summary::of(&b?)?,
+        })
+    };
+    fn micros(d: Duration) -> Option<f64> {
+        Some(d.as_secs_f64() * 1e6)
+    }
+    Ok(Comparison {
+        kernel_a: kernel_a.to_path_buf(),
+        kernel_b: kernel_b.to_path_buf(),
+        boot: delta(|s| micros(s.boot)).expect("at least two samples"),
+        evolve: delta(|s| micros(s.evolve)).expect("at least two samples"),
+        memory: delta(|s| s.memory_bytes.map(|b| b as f64)),
+        samples_a,
+        samples_b,
+    })
+}
+
+fn measure(kernel: &Path, workload: &Workload) -> Result<Sample> {
+    let mut builder = Sandbox::builder(kernel).args(workload.args.iter().cloned());
+    if let Some(ref initrd) = workload.initrd {
+        builder = builder.initrd_file(initrd);
+    }
+    if let Some(heap) = workload.heap_size {
+        builder = builder.heap_size(heap);
+    }
+
+    // Keep the guest's console off the terminal, without taking turns
+    // with other threads once the kernel is known to print through
+    // HostPrint.
+    let rss_before = stats::process_rss();
+    let sample = builder.build_silenced(|sandbox| {
+        let start = Instant::now();
+        sandbox.restore()?;
+        sandbox.call_run()?;
+        let evolve = start.elapsed();
+        let timings = sandbox.boot_timings();
+        Ok(Sample {
+            boot: timings.setup + timings.evolve,
+            evolve,
+            memory_bytes: rss_before
+                .zip(stats::process_rss())
+                .map(|(before, after)| after.saturating_sub(before)),
+        })
+    })?;
+    Ok(sample)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summary_statistics() {
+        let s = Summary::of(&[4.0, 1.0, 3.0, 2.0]).unwrap();
+        assert_eq!(
+            (s.n, s.mean, s.median, s.min, s.max),
+            (4, 2.5, 2.5, 1.0, 4.0)
+        );
+        assert!((s.stddev - 1.290_994).abs() < 1e-6);
+        assert!(Summary::of(&[]).is_none());
+    }
+
+    #[test]
+    fn deltas_report_change_and_significance() {
+        let a = Summary::of(&[100.0, 101.0, 99.0, 100.0]).unwrap();
+        let slower = Summary::of(&[120.0, 121.0, 119.0, 120.0]).unwrap();
+        let d = Delta { a, b: slower };
+        assert_eq!(d.change_percent(), 20.0);
+        assert!(d.is_significant());
+
+        let noisy = Summary::of(&[80.0, 130.0, 90.0, 110.0]).unwrap();
+        assert!(!Delta { a, b: noisy }.is_significant());
+    }
+}
@@ -0,0 +1,538 @@
+//! Running several cooperating VMs from one file, `hyperlight-compose.toml`.
+//!
+//! ```toml
+//! [vm.producer]
+//! project = "producer"          # a Kraftfile directory, or:
+//! # kernel = "build/producer"   # plus an optional initrd
+//! memory = "256Mi"
+//! args = ["/app/produce.py"]
+//! channels = ["jobs"]
+//!
+//! [vm.consumer]
+//! kernel = "consumer/.unikraft/build/consumer_hyperlight-x86_64"
+//! initrd = "consumer/rootfs.cpio"
+//! channels = ["jobs"]
+//! mounts = ["./results:/results"]
+//!
+//! [channel.jobs]                # optional; dir = "..." to keep the files
+//! ```
+//!
+//! A channel is a host directory mounted writable at
+//! `/channels/<name>` in every VM that lists it, so the guests need
+//! `lib/hostfs`. Unless given a `dir`, it is a scratch directory that
+//! [`Compose::up`] creates and removes when the VMs are gone. Relative
+//! paths are relative to the compose file.
+//!
+//! All VMs boot together, each on its own thread, and write to the shared
+//! console. [`Running::down`] asks them all to stop.
+
+use anyhow::{anyhow, bail, Context, Result};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::kraft::{BuildOptions, KraftProject};
+use crate::placement::Placer;
+use crate::workspace::Workspace;
+use crate::{parse_memory, Preopen, Sandbox, VmExit, VmHandle};
+
+/// Default compose file name.
+pub const COMPOSE_FILE: &str = "hyperlight-compose.toml";
+
+/// Guest directory channels are mounted under.
+pub const CHANNEL_DIR: &str = "/channels";
+
+/// A parsed compose file.
+///
+/// Parsed from a plain TOML table so compose works without the `serde`
+/// feature; unknown keys are errors.
+#[derive(Debug, Clone, Default)]
+pub struct ComposeFile {
+    pub vm: BTreeMap<String, VmSpec>,
+    pub channel: BTreeMap<String, ChannelSpec>,
+}
+
+/// One `[vm.<name>]` table.
+#[derive(Debug, Clone, Default)]
+pub struct VmSpec {
+    pub kernel: Option<PathBuf>,
+    /// A Kraftfile project, built as needed; its `cmd` and `memory` are
+    /// the defaults for `args` and `memory`.
+    pub project: Option<PathBuf>,
+    pub initrd: Option<PathBuf>,
+    /// Heap size, e.g. `512Mi`.
+    pub memory: Option<String>,
+    pub stack: Option<String>,
+    pub args: Vec<String>,
+    /// `HOST[:GUEST]` directories, as for `--mount`.
+    pub mounts: Vec<String>,
+    pub channels: Vec<String>,
+}
+
+/// One `[channel.<name>]` table.
+#[derive(Debug, Clone, Default)]
+pub struct ChannelSpec {
+    /// Host directory backing the channel; kept after `down`.
+    pub dir: Option<PathBuf>,
+}
+
+impl ComposeFile {
+    pub fn parse(text: &str) -> Result<Self> {
+        let root: toml::Table = text.parse()?;
+        let mut file = Self::default();
+        for (key, value) in &root {
+            match key.as_str() {
+                "vm" => {
+                    for (name, spec) in table(value, "vm")? {
+                        let spec =
+                            VmSpec::from_toml(spec).with_context(|| format!("vm {name:?}"))?;
+                        file.vm.insert(name.clone(), spec);
+                    }
+                }
+                "channel" => {
+                    for (name, spec) in table(value, "channel")? {
+                        let spec = ChannelSpec::from_toml(spec)
+                            .with_context(|| format!("channel {name:?}"))?;
+                        file.channel.insert(name.clone(), spec);
+                    }
+                }
+                other => bail!("unknown key {other:?}"),
+            }
+        }
+        file.validate()?;
+        Ok(file)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.vm.is_empty() {
+            bail!("compose file defines no [vm.<name>] tables");
+        }
+        for name in self.channel.keys() {
+            if !is_valid_channel(name) {
+                bail!("invalid channel name {name:?}");
+            }
+        }
+        for (name, vm) in &self.vm {
+            if vm.kernel.is_some() == vm.project.is_some() {
+                bail!("vm {name:?}: set exactly one of `kernel` and `project`");
+            }
+            for channel in &vm.channels {
+                if !is_valid_channel(channel) {
+                    bail!("vm {name:?}: invalid channel name {channel:?}");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Every channel: declared ones plus those only named by a VM.
+    fn channels(&self) -> BTreeMap<&str, Option<&Path>> {
+        let mut all: BTreeMap<&str, Option<&Path>> = self
+            .vm
+            .values()
+            .flat_map(|vm| vm.channels.iter().map(|c| (c.as_str(), None)))
+            .collect();
+        for (name, spec) in &self.channel {
+            all.insert(name, spec.dir.as_deref());
+        }
+        all
+    }
+}
+
+impl VmSpec {
+    fn from_toml(value: &toml::Value) -> Result<Self> {
+        let mut spec = Self::default();
+        for (key, value) in table(value, "vm")? {
+            match key.as_str() {
+                "kernel" => spec.kernel = Some(string(value, key)?.into()),
+                "project" => spec.project = Some(string(value, key)?.into()),
+                "initrd" => spec.initrd = Some(string(value, key)?.into()),
+                "memory" => spec.memory = Some(string(value, key)?),
+                "stack" => spec.stack = Some(string(value, key)?),
+                "args" => spec.args = strings(value, key)?,
+                "mounts" => spec.mounts = strings(value, key)?,
+                "channels" => spec.channels = strings(value, key)?,
+                other => bail!("unknown key {other:?}"),
+            }
+        }
+        Ok(spec)
+    }
+}
+
+impl ChannelSpec {
+    fn from_toml(value: &toml::Value) -> Result<Self> {
+        let mut spec = Self::default();
+        for (key, value) in table(value, "channel")? {
+            match key.as_str() {
+                "dir" => spec.dir = Some(string(value, key)?.into()),
+                other => bail!("unknown key {other:?}"),
+            }
+        }
+        Ok(spec)
+    }
+}
+
+fn table<'a>(value: &'a toml::Value, key: &str) -> Result<&'a toml::Table> {
+    value
+        .as_table()
+        .ok_or_else(|| anyhow!("`{key}` must be a table"))
+}
+
+fn string(value: &toml::Value, key: &str) -> Result<String> {
+    value
+        .as_str()
+        .map(str::to_string)
+        .ok_or_else(|| anyhow!("`{key}` must be a string"))
+}
+
+fn strings(value: &toml::Value, key: &str) -> Result<Vec<String>> {
+    let items = value
+        .as_array()
+        .ok_or_else(|| anyhow!("`{key}` must be an array of strings"))?;
+    items.iter().map(|item| string(item, key)).collect()
+}
+
+fn is_valid_channel(name: &str) -> bool {
+    !name.is_empty() && !name.contains(['/', '\\']) && !name.starts_with('.')
+}
+
+/// A compose file and the directory its paths are relative to.
+#[derive(Debug, Clone)]
+pub struct Compose {
+    dir: PathBuf,
+    file: ComposeFile,
+}
+
+/// How a VM ended.
+#[derive(Debug)]
+pub struct VmResult {
+    pub name: String,
+    pub exit: VmExit,
+    pub error: Option<anyhow::Error>,
+}
+
+/// VMs started by [`Compose::up`].
+pub struct Running {
+    handles: Arc<Mutex<BTreeMap<String, VmHandle>>>,
+    threads: Vec<(String, JoinHandle<Result<()>>)>,
+    _scratch: Workspace,
+}
+
+/// A VM ready to boot: everything resolved on the calling thread so
+/// configuration errors surface before anything starts.
+struct Plan {
+    name: String,
+    kernel: PathBuf,
+    initrd: Option<PathBuf>,
+    heap_size: Option<u64>,
+    stack_size: Option<u64>,
+    args: Vec<String>,
+    preopens: Vec<Preopen>,
+}
+
+impl Compose {
+    /// Load and validate a compose file.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let text =
+            std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+        let file =
+            ComposeFile::parse(&text).with_context(|| format!("parsing {}", path.display()))?;
+        let dir = match path.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        Ok(Self { dir, file })
+    }
+
+    pub fn from_file(dir: impl Into<PathBuf>, file: ComposeFile) -> Self {
+        Self {
+            dir: dir.into(),
+            file,
+        }
+    }
+
+    pub fn file(&self) -> &ComposeFile {
+        &self.file
+    }
+
+    /// Build what's missing, create the channels and boot every VM.
+    pub fn up(&self) -> Result<Running> {
+        let scratch = Workspace::new()?;
+        let mut channel_dirs = BTreeMap::new();
+        for (name, dir) in self.file.channels() {
+            let host = match dir {
+                Some(d) => {
+                    let d = self.resolve(d);
+                    std::fs::create_dir_all(&d)
+                        .with_context(|| format!("creating channel dir {}", d.display()))?;
+                    d
+                }
+                None => scratch.dir(&format!("channels/{name}"))?,
+            };
+            channel_dirs.insert(name, host);
+        }
+        let plans = self
+            .file
+            .vm
+            .iter()
+            .map(|(name, spec)| {
+                self.plan(name, spec, &channel_dirs)
+                    .with_context(|| format!("vm {name:?}"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let handles = Arc::new(Mutex::new(BTreeMap::new()));
+        let (ready_tx, ready_rx) = mpsc::channel();
+        let threads = plans
+            .into_iter()
+            .map(|plan| {
+                let name = plan.name.clone();
+                let handles = handles.clone();
+                let ready = ready_tx.clone();
+                let thread = std::thread::Builder::new()
+                    .name(format!("compose-{name}"))
+                    .spawn(move || boot(plan, &handles, ready))?;
+                Ok((name, thread))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        drop(ready_tx);
+        // Wait until every VM has booted (or failed to), so `down` right
+        // after `up` reaches all of them.
+        for _ in ready_rx {}
+        Ok(Running {
+            handles,
+            threads,
+            _scratch: scratch,
+        })
+    }
+
+    fn resolve(&self, path: &Path) -> PathBuf {
+        if path.is_absolute() {
+            path.to_path_buf()
+        } else {
+            self.dir.join(path)
+        }
+    }
+
+    fn plan(
+        &self,
+        name: &str,
+        spec: &VmSpec,
+        channel_dirs: &BTreeMap<&str, PathBuf>,
+    ) -> Result<Plan> {
+        let mut args = spec.args.clone();
+        let mut memory = spec.memory.clone();
+        let (kernel, mut initrd) = match (&spec.kernel, &spec.project) {
+            (Some(kernel), _) => (self.resolve(kernel), None),
+            (None, Some(dir)) => {
+                let project = KraftProject::open(self.resolve(dir))?;
+                let assets = project.resolve(&BuildOptions::default())?;
+                if args.is_empty() {
+                    args = project.cmd().to_vec();
+                }
+                memory = memory.or_else(|| project.memory().map(Into::into));
+                (assets.kernel, assets.initrd)
+            }
+            (None, None) => unreachable!("validated"),
+        };
+        if let Some(ref p) = spec.initrd {
+            initrd = Some(self.resolve(p));
+        }
+        let mut preopens = Vec::new();
+        for mount in &spec.mounts {
+            preopens.push(Preopen::parse_cli(
+                &self.resolve(Path::new(mount)).to_string_lossy(),
+            )?);
+        }
+        for channel in &spec.channels {
+            preopens.push(Preopen::new(
+                &channel_dirs[channel.as_str()],
+                format!("{CHANNEL_DIR}/{channel}"),
+            )?);
+        }
+        Ok(Plan {
+            name: name.to_string(),
+            kernel,
+            initrd,
+            heap_size: memory.as_deref().map(parse_memory).transpose()?,
+            stack_size: spec.stack.as_deref().map(parse_memory).transpose()?,
+            args,
+            preopens,
+        })
+    }
+}
+
+/// Runs on the VM's thread: build, publish the handle, run.
+fn boot(
+    plan: Plan,
+    handles: &Mutex<BTreeMap<String, VmHandle>>,
+    ready: mpsc::Sender<()>,
+) -> Result<()> {
+    let mut builder = Sandbox::builder(&plan.kernel)
+        .args(plan.args)
+        .shutdown_signal()
+        .placement(Placer::global());
+    if let Some(initrd) = plan.initrd {
+        builder = builder.initrd_file(initrd);
+    }
+    if let Some(heap) = plan.heap_size {
+        builder = builder.heap_size(heap);
+    }
+    if let Some(stack) = plan.stack_size {
+        builder = builder.stack_size(stack);
+    }
+    for p in plan.preopens {
+        builder = builder.preopen(p);
+    }
+    let built = builder
+        .build()
+        .and_then(|mut sandbox| {
+            sandbox.restore()?;
+            Ok(sandbox)
+        })
+        .map_err(anyhow::Error::from);
+    let mut sandbox = match built {
+        Ok(sandbox) => sandbox,
+        Err(e) => {
+            drop(ready);
+            return Err(e.context("booting"));
+        }
+    };
+    lock(handles).insert(plan.name, sandbox.handle());
+    drop(ready);
+    Ok(sandbox.call_run()?)
+}
+
+fn lock<T>(m: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    m.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+impl Running {
+    /// Names of the VMs that booted.
+    pub fn names(&self) -> Vec<String> {
+        lock(&self.handles).keys().cloned().collect()
+    }
+
+    /// Ask every VM to stop, interrupting those still running after
+    /// `grace`. Returns without waiting for them; see
+    /// [`wait`](Self::wait).
+    pub fn down(&self, grace: Duration) {
+        self.stopper().down(grace);
+    }
+
+    /// A clone of the handles, for stopping the VMs from another thread
+    /// while this one waits.
+    pub fn stopper(&self) -> Stopper {
+        Stopper(self.handles.clone())
+    }
+
+    /// Wait for every VM to finish. Sorted by name; the channels are
+    /// removed afterwards.
+    pub fn wait(self) -> Vec<VmResult> {
+        self.threads
+            .into_iter()
+            .map(|(name, thread)| {
+                let result = thread
+                    .join()
+                    .unwrap_or_else(|_| Err(anyhow!("vm thread panicked")));
+                VmResult {
+                    exit: VmExit::from_result(&result),
+                    error: result.err(),
+                    name,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Stops the VMs of a [`Running`] from any thread.
+#[derive(Clone)]
+pub struct Stopper(Arc<Mutex<BTreeMap<String, VmHandle>>>);
+
+impl Stopper {
+    /// [`Running::down`] from another thread.
+    pub fn down(&self, grace: Duration) {
+        let handles: Vec<VmHandle> = lock(&self.0).values().cloned().collect();
+        std::thread::scope(|s| {
+            for handle in &handles {
+                s.spawn(move || handle.shutdown(grace));
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compose_files_validate() {
+        let file = ComposeFile::parse(
+            r#"
+            [vm.a]
+            kernel = "a.elf"
+            memory = "128Mi"
+            args = ["/x.py"]
+            channels = ["jobs", "logs"]
+
+            [vm.b]
+            project = "b"
+            channels = ["jobs"]
+
+            [channel.jobs]
+            dir = "jobs"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(file.vm["a"].args, ["/x.py"]);
+        let channels = file.channels();
+        assert_eq!(
+            channels.keys().copied().collect::<Vec<_>>(),
+            ["jobs", "logs"]
+        );
+        assert_eq!(channels["jobs"], Some(Path::new("jobs")));
+
+        assert!(ComposeFile::parse("").is_err());
+        assert!(ComposeFile::parse("[vm.a]\nkernel = \"k\"\nproject = \"p\"").is_err());
+        assert!(ComposeFile::parse("[vm.a]\nkernel = \"k\"\nheap = \"1Gi\"").is_err());
+        assert!(ComposeFile::parse("[vm.a]\nkernel = \"k\"\nchannels = [\"../x\"]").is_err());
+        assert!(ComposeFile::parse("[vm.a]\nkernel = \"k\"\nargs = \"x\"").is_err());
+        assert!(ComposeFile::parse("[vm.a]\nkernel = \"k\"\n[channel.c]\npath = \"d\"").is_err());
+        assert!(ComposeFile::parse("volumes = 1\n[vm.a]\nkernel = \"k\"").is_err());
+    }
+
+    #[test]
+    fn plans_resolve_paths_and_mount_channels() {
+        let ws = Workspace::new().unwrap();
+        ws.dir("data").unwrap();
+        let compose = Compose::from_file(
+            ws.path(),
+            ComposeFile::parse(
+                "[vm.a]\nkernel = \"a.elf\"\nmounts = [\"data:/data\"]\nchannels = [\"jobs\"]",
+            )
+            .unwrap(),
+        );
+        let jobs = ws.dir("jobs").unwrap();
+        let dirs = BTreeMap::from([("jobs", jobs.clone())]);
+        let plan = compose.plan("a", &compose.file.vm["a"], &dirs).unwrap();
+        assert_eq!(plan.kernel, ws.path().join("a.elf"));
+        let mounts: Vec<_> = plan
+            .preopens
+            .iter()
+            .map(|p| (p.host_dir.clone(), p.guest_path.as_str()))
+            .collect();
+        assert_eq!(
+            mounts,
+            [
+                (
+                    std::fs::canonicalize(ws.path().join("data")).unwrap(),
+                    "/data"
+                ),
+                (std::fs::canonicalize(jobs).unwrap(), "/channels/jobs"),
+            ]
+        );
+    }
+}
@@ -0,0 +1,271 @@
+//! [`VmConfig`] as data, for config files, HTTP APIs and job queues.
+//!
+//! With the `serde` feature, `VmConfig` serializes to and deserializes
+//! from its plain settings:
+//!
+//! ```json
+//! { "heap_size": "256Mi", "stack_size": "8Mi", "timeout": "30s",
+//!   "env": { "GREETING": "hello" }, "timezone": "UTC0" }
+//! ```
+//!
+//! Every field is optional and defaults as in [`VmConfig::default`];
+//! unknown fields are rejected, so a typo doesn't go unnoticed. Sizes
+//! take a byte count or a [`parse_memory`] string, durations seconds or
+//! a [`parse_duration`] string; they are written back as
+//! [`format_memory`] and `"<secs>s"` strings. What isn't data — hooks, sinks, tools, stdin,
+//! caches, the initrd pipeline — is left out and has to be attached in
+//! code after deserializing.
+//!
+//! [`memory`] and [`duration`] are the field codecs, for run descriptors
+//! of your own: `#[serde(with = "hyperlight_unikraft::config_serde::memory")]`.
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use crate::{
+    format_memory, parse_duration, parse_memory, Console, Error, Hypervisor, OutputOverflow,
+    VmConfig,
+};
+
+/// A size as a byte count or a [`parse_memory`] string like `"512Mi"`;
+/// written with [`format_memory`].
+pub mod memory {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(bytes: &u64, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&format_memory(*bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<u64, D::Error> {
+        match NumberOrText::deserialize(d)? {
+            NumberOrText::Number(bytes) => Ok(bytes),
+            NumberOrText::Text(text) => parse_memory(&text).map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+/// An optional duration as seconds or a [`parse_duration`] string like
+/// `"250ms"`; written as `"<secs>s"`.
+pub mod duration {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(d: &Option<Duration>, s: S) -> Result<S::Ok, S::Error> {
+        match d {
+            Some(d) => s.serialize_str(&format!("{}s", d.as_secs_f64())),
+            None => s.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Duration>, D::Error> {
+        match Option::<NumberOrText>::deserialize(d)? {
+            None => Ok(None),
+            Some(NumberOrText::Number(secs)) => Ok(Some(Duration::from_secs(secs))),
+            Some(NumberOrText::Text(text)) => parse_duration(&text)
+                .map(Some)
+                .map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum NumberOrText {
+    Number(u64),
+    Text(String),
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct VmConfigRecord {
+    #[serde(with = "memory")]
+    heap_size: u64,
+    #[serde(with = "memory")]
+    stack_size: u64,
+    #[serde(with = "duration", skip_serializing_if = "Option::is_none")]
+    timeout: Option<Duration>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    env: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timezone: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    locale: Option<String>,
+    /// Seconds since the Unix epoch.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    wall_clock: Option<u64>,
+    capture_output: bool,
+    tee_output: bool,
+    strip_ansi: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_output_bytes: Option<u64>,
+    output_overflow: OutputOverflow,
+    forward_to_tracing: bool,
+    guest_trace: bool,
+    call_stats: bool,
+    scrub_inputs: bool,
+    #[serde(with = "duration", skip_serializing_if = "Option::is_none")]
+    interrupt_retry_delay: Option<Duration>,
+    core_dump: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gdb_port: Option<u16>,
+    hypervisor: Hypervisor,
+    console: Console,
+}
+
+impl Default for VmConfigRecord {
+    fn default() -> Self {
+        Self::from(&VmConfig::default())
+    }
+}
+
+impl From<&VmConfig> for VmConfigRecord {
+    fn from(c: &VmConfig) -> Self {
+        Self {
+            heap_size: c.heap_size,
+            stack_size: c.stack_size,
+            timeout: c.timeout,
+            env: c.env.iter().cloned().collect(),
+            timezone: c.timezone.clone(),
+            locale: c.locale.clone(),
+            wall_clock: c.wall_clock.map(|t| {
+                t.duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs()
+            }),
+            capture_output: c.capture_output,
+            tee_output: c.tee_output,
+            strip_ansi: c.strip_ansi,
+            max_output_bytes: c.max_output_bytes,
+            output_overflow: c.output_overflow,
+            forward_to_tracing: c.forward_to_tracing,
+            guest_trace: c.guest_trace,
+            call_stats: c.call_stats,
+            scrub_inputs: c.scrub_inputs,
+            interrupt_retry_delay: c.interrupt_retry_delay,
+            core_dump: c.core_dump,
+            gdb_port: c.gdb_port,
+            hypervisor: c.hypervisor,
+            console: c.console,
+        }
+    }
+}
+
+impl From<VmConfigRecord> for VmConfig {
+    fn from(r: VmConfigRecord) -> Self {
+        VmConfig {
+            heap_size: r.heap_size,
+            stack_size: r.stack_size,
+            timeout: r.timeout,
+            env: r.env.into_iter().collect(),
+            timezone: r.timezone,
+            locale: r.locale,
+            wall_clock: r
+                .wall_clock
+                .map(|secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs)),
+            capture_output: r.capture_output,
+            tee_output: r.tee_output,
+            strip_ansi: r.strip_ansi,
+            max_output_bytes: r.max_output_bytes,
+            output_overflow: r.output_overflow,
+            forward_to_tracing: r.forward_to_tracing,
+            guest_trace: r.guest_trace,
+            call_stats: r.call_stats,
+            scrub_inputs: r.scrub_inputs,
+            interrupt_retry_delay: r.interrupt_retry_delay,
+            core_dump: r.core_dump,
+            gdb_port: r.gdb_port,
+            hypervisor: r.hypervisor,
+            console: r.console,
+            ..VmConfig::default()
+        }
+    }
+}
+
+impl Serialize for VmConfig {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        VmConfigRecord::from(self).serialize(s)
+    }
+}
+
+impl<'de> Deserialize<'de> for VmConfig {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        VmConfigRecord::deserialize(d).map(Into::into)
+    }
+}
+
+impl VmConfig {
+    /// Read a config file: TOML if the name ends in `.toml`, JSON
+    /// otherwise. Settings it leaves out keep their defaults. Layer
+    /// [`with_env_overrides`](Self::with_env_overrides) and explicit
+    /// setters on top for flags > env > file > defaults.
+    pub fn from_file(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| Error::config(format!("reading config file {path:?}: {e}")))?;
+        let config = if path.extension().is_some_and(|ext| ext == "toml") {
+            toml::from_str(&text).map_err(|e| e.to_string())
+        } else {
+            serde_json::from_str(&text).map_err(|e| e.to_string())
+        };
+        config.map_err(|e| Error::config(format!("invalid config file {path:?}: {e}")).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn config_reads_human_sizes_and_round_trips() {
+        let config: VmConfig = serde_json::from_value(json!({
+            "heap_size": "256Mi",
+            "stack_size": 1048576,
+            "timeout": "1.5s",
+            "env": { "GREETING": "hello" },
+            "tee_output": true,
+        }))
+        .unwrap();
+        assert_eq!(config.heap_size, 256 * 1024 * 1024);
+        assert_eq!(config.stack_size, 1024 * 1024);
+        assert_eq!(config.timeout, Some(Duration::from_millis(1500)));
+        assert_eq!(config.guest_env().unwrap(), ["GREETING=hello"]);
+        assert!(config.tee_output && config.capture_output);
+
+        let text = serde_json::to_value(&config).unwrap();
+        assert_eq!(text["heap_size"], "256Mi");
+        assert_eq!(text["timeout"], "1.5s");
+        let again: VmConfig = serde_json::from_value(text.clone()).unwrap();
+        assert_eq!(serde_json::to_value(&again).unwrap(), text);
+
+        let bad = serde_json::from_value::<VmConfig>(json!({ "heap_size": "lots" }));
+        assert!(bad.is_err());
+        let typo = serde_json::from_value::<VmConfig>(json!({ "heapsize": 1 }));
+        assert!(typo.err().unwrap().to_string().contains("unknown field"));
+        // Guest RAM can't be zeroed, so no config may ask for it.
+        let scrub = serde_json::from_value::<VmConfig>(json!({ "scrub_memory": true }));
+        assert!(scrub.err().unwrap().to_string().contains("unknown field"));
+    }
+
+    #[test]
+    fn env_overrides_a_config_file() {
+        let ws = crate::workspace::Workspace::new().unwrap();
+        let file = ws
+            .write("run.toml", b"heap_size = \"256Mi\"\ntimeout = \"30s\"\n")
+            .unwrap();
+        let env = |key: &str| (key == crate::ENV_TIMEOUT).then(|| "5s".to_string());
+        let config = VmConfig::from_file(&file)
+            .unwrap()
+            .apply_env_from(env)
+            .unwrap();
+        assert_eq!(config.heap_size, 256 * 1024 * 1024);
+        assert_eq!(config.timeout, Some(Duration::from_secs(5)));
+
+        let bad = ws.write("bad.json", b"{\"heapsize\": 1}").unwrap();
+        let Err(err) = VmConfig::from_file(&bad) else {
+            panic!("a misspelled field should be rejected");
+        };
+        assert!(err.to_string().contains("unknown field"), "{err}");
+    }
+}
@@ -0,0 +1,365 @@
+//! Multi-step workflows as a graph of VM runs.
+//!
+//! Each node is one sandbox run. Whatever it writes under [`OUT_DIR`]
+//! (a host directory preopened there, so kernels need `lib/hostfs`) is
+//! its result; a node's builder closure sees the results of the nodes it
+//! depends on and typically packs them into its initrd with
+//! [`NodeInputs::layer`]. Nodes whose dependencies are done run in
+//! parallel, up to [`Dag::max_parallel`] at a time.
+//!
+//! ```no_run
+//! use hyperlight_unikraft::dag::Dag;
+//! use hyperlight_unikraft::Sandbox;
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! let base = std::fs::read("python.cpio")?;
+//! let results = Dag::new()
+//!     .node("generate", &[], |_| {
+//!         Ok(Sandbox::builder("python-kernel").initrd_file("python.cpio").arg("/gen.py"))
+//!     })
+//!     .node("render", &["generate"], move |inputs| {
+//!         Ok(Sandbox::builder("python-kernel")
+//!             .initrd_bytes(inputs.layer(&base)?)
+//!             .arg("/render.py"))
+//!     })
+//!     .run()?;
+//! let report = results["render"].output().unwrap().file("/report.pdf");
+//! # Ok(())
+//! # }
+//! ```
+
+use anyhow::{anyhow, bail, Result};
+use std::collections::BTreeMap;
+use std::sync::{mpsc, Arc};
+
+use crate::placement::Placer;
+use crate::workspace::{collect_files, Workspace};
+use crate::{pipeline, rootfs, Preopen, SandboxBuilder};
+
+/// Guest directory a node writes its results to.
+pub const OUT_DIR: &str = "/out";
+
+/// Guest directory [`NodeInputs::layer`] puts dependencies' results in,
+/// one subdirectory per dependency.
+pub const IN_DIR: &str = "/in";
+
+type BuildFn = Box<dyn Fn(&NodeInputs) -> Result<SandboxBuilder> + Send + Sync>;
+
+struct Node {
+    deps: Vec<String>,
+    build: BuildFn,
+}
+
+/// A graph of VM runs; see the [module docs](self).
+pub struct Dag {
+    nodes: BTreeMap<String, Node>,
+    max_parallel: usize,
+}
+
+/// Files a node wrote under [`OUT_DIR`], keyed by path below it (with a
+/// leading `/`).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NodeOutput {
+    pub files: BTreeMap<String, Vec<u8>>,
+}
+
+impl NodeOutput {
+    pub fn file(&self, path: &str) -> Option<&[u8]> {
+        self.files.get(path).map(Vec::as_slice)
+    }
+}
+
+/// How a node ended.
+#[derive(Debug)]
+pub enum NodeResult {
+    Done(Arc<NodeOutput>),
+    Failed(anyhow::Error),
+    /// Not run because a dependency failed or was skipped.
+    Skipped,
+}
+
+impl NodeResult {
+    pub fn output(&self) -> Option<&NodeOutput> {
+        match self {
+            Self::Done(out) => Some(out),
+            _ => None,
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        matches!(self, Self::Done(_))
+    }
+}
+
+/// The results of a node's dependencies, handed to its builder.
+#[derive(Debug, Default)]
+pub struct NodeInputs {
+    outputs: BTreeMap<String, Arc<NodeOutput>>,
+}
+
+impl NodeInputs {
+    /// The result of dependency `name`.
+    pub fn get(&self, name: &str) -> Option<&NodeOutput> {
+        self.outputs.get(name).map(|o| &**o)
+    }
+
+    /// `base` (a plain, gzip or zstd CPIO, or empty) with every
+    /// dependency's files added under `/in/<dependency>/`.
+    pub fn layer(&self, base: &[u8]) -> Result<Vec<u8>> {
+        let base = pipeline::decompress(base.to_vec())?;
+        let paths: Vec<(String, &[u8])> = self
+            .outputs
+            .iter()
+            .flat_map(|(dep, out)| {
+                out.files
+                    .iter()
+                    .map(move |(path, data)| (format!("{IN_DIR}/{dep}{path}"), data.as_slice()))
+            })
+            .collect();
+        let files: Vec<(&str, &[u8])> = paths.iter().map(|(p, d)| (p.as_str(), *d)).collect();
+        rootfs::append_files(&base, &files)
+    }
+}
+
+impl Default for Dag {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Dag {
+    pub fn new() -> Self {
+        Self {
+            nodes: BTreeMap::new(),
+            max_parallel: std::thread::available_parallelism().map_or(4, |n| n.get()),
+        }
+    }
+
+    /// Add node `name`, run after every node in `deps`. `build` makes its
+    /// sandbox from the dependencies' results; the runner adds the
+    /// [`OUT_DIR`] preopen.
+    pub fn node<F>(mut self, name: &str, deps: &[&str], build: F) -> Self
+    where
+        F: Fn(&NodeInputs) -> Result<SandboxBuilder> + Send + Sync + 'static,
+    {
+        self.nodes.insert(
+            name.to_string(),
+            Node {
+                deps: deps.iter().map(|d| d.to_string()).collect(),
+                build: Box::new(build),
+            },
+        );
+        self
+    }
+
+    /// Run at most `n` nodes at once (default: the host's parallelism).
+    pub fn max_parallel(mut self, n: usize) -> Self {
+        self.max_parallel = n.max(1);
+        self
+    }
+
+    /// Run every node. Fails only for an invalid graph (a cycle or an
+    /// unknown dependency); node failures are in the results, and
+    /// everything downstream of a failure is [`NodeResult::Skipped`].
+    pub fn run(&self) -> Result<BTreeMap<String, NodeResult>> {
+        self.schedule(|name, inputs| {
+            let ws = Workspace::new()?;
+            let out = ws.dir("out")?;
+            let builder = (self.nodes[name].build)(inputs)?
+                .preopen(Preopen::new(&out, OUT_DIR)?)
+                .placement(Placer::global());
+            let mut sandbox = builder.build()?;
+            sandbox.restore()?;
+            sandbox.call_run()?;
+            drop(sandbox);
+            Ok(NodeOutput {
+                files: collect_files(&out)?.into_iter().collect(),
+            })
+        })
+    }
+
+    /// Node names in an order where every node follows its dependencies.
+    fn topo_order(&self) -> Result<Vec<&str>> {
+        for (name, node) in &self.nodes {
+            if let Some(dep) = node.deps.iter().find(|d| !self.nodes.contains_key(*d)) {
+                bail!("node {name:?} depends on unknown node {dep:?}");
+            }
+        }
+        let mut remaining: BTreeMap<&str, usize> = self
+            .nodes
+            .iter()
+            .map(|(name, node)| (name.as_str(), node.deps.len()))
+            .collect();
+        let mut order = Vec::with_capacity(self.nodes.len());
+        while let Some(next) = remaining.iter().find(|(_, &n)| n == 0).map(|(k, _)| *k) {
+            remaining.remove(next);
+            order.push(next);
+            for (name, node) in &self.nodes {
+                if node.deps.iter().any(|d| d == next) {
+                    if let Some(n) = remaining.get_mut(name.as_str()) {
+                        *n -= node.deps.iter().filter(|d| *d == next).count();
+                    }
+                }
+            }
+        }
+        if !remaining.is_empty() {
+            let cycle: Vec<&str> = remaining.keys().copied().collect();
+            bail!("dependency cycle among {}", cycle.join(", "));
+        }
+        Ok(order)
+    }
+
+    /// Run nodes with `exec` as their dependencies complete, keeping up to
+    /// `max_parallel` in flight.
+    fn schedule<F>(&self, exec: F) -> Result<BTreeMap<String, NodeResult>>
+    where
+        F: Fn(&str, &NodeInputs) -> Result<NodeOutput> + Sync,
+    {
+        let order = self.topo_order()?;
+        let mut results: BTreeMap<String, NodeResult> = BTreeMap::new();
+        let mut running = 0;
+        let (tx, rx) = mpsc::channel::<(String, Result<NodeOutput>)>();
+        std::thread::scope(|s| {
+            let mut pending: Vec<&str> = order;
+            while !pending.is_empty() || running > 0 {
+                // Settle what can be settled: skip nodes downstream of a
+                // failure, start those whose dependencies are all done.
+                let mut i = 0;
+                while i < pending.len() {
+                    let name = pending[i];
+                    let deps = &self.nodes[name].deps;
+                    if deps
+                        .iter()
+                        .any(|d| results.get(d).is_some_and(|r| !r.is_done()))
+                    {
+                        results.insert(name.to_string(), NodeResult::Skipped);
+                        pending.remove(i);
+                    } else if running < self.max_parallel
+                        && deps.iter().all(|d| results.contains_key(d))
+                    {
+                        let inputs = NodeInputs {
+                            outputs: deps
+                                .iter()
+                                .filter_map(|d| match &results[d] {
+                                    NodeResult::Done(out) => Some((d.clone(), out.clone())),
+                                    _ => None,
+                                })
+                                .collect(),
+                        };
+                        let tx = tx.clone();
+                        let exec = &exec;
+                        s.spawn(move || {
+                            let result =
+                                std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                                    exec(name, &inputs)
+                                }))
+                                .unwrap_or_else(|_| Err(anyhow!("node panicked")));
+                            let _ = tx.send((name.to_string(), result));
+                        });
+                        running += 1;
+                        pending.remove(i);
+                    } else {
+                        i += 1;
+                    }
+                }
+                if running == 0 {
+                    break;
+                }
+                let (name, result) = rx.recv().expect("a node is running");
+                running -= 1;
+                let result = match result {
+                    Ok(out) => NodeResult::Done(Arc::new(out)),
+                    Err(e) => NodeResult::Failed(e.context(format!("node {name:?}"))),
+                };
+                results.insert(name, result);
+            }
+        });
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Sandbox;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    fn dag(edges: &[(&str, &[&str])]) -> Dag {
+        edges.iter().fold(Dag::new(), |dag, (name, deps)| {
+            dag.node(name, deps, |_| Ok(Sandbox::builder("kernel")))
+        })
+    }
+
+    #[test]
+    fn invalid_graphs_are_rejected() {
+        assert!(dag(&[("a", &["b"]), ("b", &["a"])]).topo_order().is_err());
+        assert!(dag(&[("a", &["missing"])]).topo_order().is_err());
+        let diamond = dag(&[("c", &["a", "b"]), ("b", &["a"]), ("a", &[])]);
+        assert_eq!(diamond.topo_order().unwrap(), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn branches_run_in_parallel_and_failures_skip_downstream() {
+        let graph = dag(&[
+            ("gen", &[]),
+            ("left", &["gen"]),
+            ("right", &["gen"]),
+            ("join", &["left", "right"]),
+            ("bad", &[]),
+            ("after_bad", &["bad"]),
+            ("after_after", &["after_bad"]),
+        ])
+        .max_parallel(4);
+        let in_flight = AtomicUsize::new(0);
+        let peak = AtomicUsize::new(0);
+        let seen = Mutex::new(BTreeMap::new());
+        let results = graph
+            .schedule(|name, inputs| {
+                let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(now, Ordering::SeqCst);
+                std::thread::sleep(Duration::from_millis(20));
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+                let deps: Vec<String> = inputs.outputs.keys().cloned().collect();
+                seen.lock().unwrap().insert(name.to_string(), deps);
+                if name == "bad" {
+                    bail!("boom");
+                }
+                Ok(NodeOutput {
+                    files: BTreeMap::from([(format!("/{name}.txt"), name.as_bytes().to_vec())]),
+                })
+            })
+            .unwrap();
+        assert!(peak.load(Ordering::SeqCst) >= 2);
+        assert!(results["join"].is_done());
+        assert_eq!(seen.lock().unwrap()["join"], ["left", "right"]);
+        assert!(matches!(results["bad"], NodeResult::Failed(_)));
+        assert!(matches!(results["after_bad"], NodeResult::Skipped));
+        assert!(matches!(results["after_after"], NodeResult::Skipped));
+        assert!(!seen.lock().unwrap().contains_key("after_bad"));
+    }
+
+    #[test]
+    fn inputs_layer_under_in_dir() {
+        let mut cpio = rootfs::CpioWriter::new(Vec::new());
+        cpio.file("app.py", 0o644, 0, b"print(1)").unwrap();
+        let base = cpio.finish().unwrap();
+        let inputs = NodeInputs {
+            outputs: BTreeMap::from([(
+                "gen".to_string(),
+                Arc::new(NodeOutput {
+                    files: BTreeMap::from([("/data/x.json".to_string(), b"{}".to_vec())]),
+                }),
+            )]),
+        };
+        let layered = inputs.layer(&base).unwrap();
+        let (entries, _) = rootfs::scan(&layered).unwrap();
+        let x = entries
+            .iter()
+            .find(|e| e.name.trim_start_matches('/') == "in/gen/data/x.json")
+            .unwrap();
+        assert_eq!(x.data, b"{}");
+        assert!(entries.iter().any(|e| e.name == "app.py"));
+    }
+}
@@ -0,0 +1,109 @@
+//! Kernels and rootfs archives compiled into the binary.
+//!
+//! A tool that ships as one executable can carry its guest with it:
+//! [`embed_unikraft_assets!`](crate::embed_unikraft_assets) bakes the
+//! kernel and rootfs in with `include_bytes!` (paths are relative to the
+//! file the macro is used in, as for `include_bytes!`), and
+//! [`EmbeddedAssets::vm`] starts a [`Vm`] from them without reading
+//! either from disk:
+//!
+//! ```ignore
+//! use hyperlight_unikraft::EmbeddedAssets;
+//! use hyperlight_unikraft::embed_unikraft_assets;
+//!
+//! static ASSETS: EmbeddedAssets =
+//!     embed_unikraft_assets!("../assets/kernel", "../assets/rootfs.cpio");
+//!
+//! fn main() -> anyhow::Result<()> {
+//!     let out = ASSETS.vm().arg("/app.py").run()?;
+//!     print!("{}", out.stdout);
+//!     Ok(())
+//! }
+//! ```
+//!
+//! The guest maps its rootfs from a file, so an in-memory rootfs is
+//! written to a temporary file at boot; the assets keep an
+//! [`InitrdCache`] so that happens once per process, not once per run.
+
+use std::sync::{Arc, OnceLock};
+
+use crate::initrd_cache::InitrdCache;
+use crate::{Vm, VmBuilder};
+
+/// A kernel and optional rootfs held in the binary; see the
+/// [module docs](self).
+pub struct EmbeddedAssets {
+    pub kernel: &'static [u8],
+    pub rootfs: Option<&'static [u8]>,
+    spilled: OnceLock<Arc<InitrdCache>>,
+}
+
+impl EmbeddedAssets {
+    pub const fn new(kernel: &'static [u8], rootfs: Option<&'static [u8]>) -> Self {
+        Self {
+            kernel,
+            rootfs,
+            spilled: OnceLock::new(),
+        }
+    }
+
+    /// A [`Vm`] builder with the embedded kernel and rootfs already set;
+    /// add arguments and options, then run it.
+    pub fn vm(&self) -> VmBuilder {
+        let builder = Vm::builder().kernel_static(self.kernel);
+        let Some(rootfs) = self.rootfs else {
+            return builder;
+        };
+        let cache = self
+            .spilled
+            .get_or_init(|| Arc::new(InitrdCache::with_max_entries(1)))
+            .clone();
+        builder
+            .initrd_static(rootfs)
+            .config(|c| c.with_initrd_cache(cache))
+    }
+}
+
+impl std::fmt::Debug for EmbeddedAssets {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EmbeddedAssets")
+            .field("kernel", &self.kernel.len())
+            .field("rootfs", &self.rootfs.map(<[u8]>::len))
+            .finish()
+    }
+}
+
+/// Compile a kernel, and optionally a rootfs, into the binary as
+/// [`EmbeddedAssets`]: `embed_unikraft_assets!("kernel", "rootfs.cpio")`.
+/// Usable in a `static`.
+#[macro_export]
+macro_rules! embed_unikraft_assets {
+    ($kernel:expr $(,)?) => {
+        $crate::embed::EmbeddedAssets::new(::core::include_bytes!($kernel), None)
+    };
+    ($kernel:expr, $rootfs:expr $(,)?) => {
+        $crate::embed::EmbeddedAssets::new(
+            ::core::include_bytes!($kernel),
+            Some(::core::include_bytes!($rootfs) as &[u8]),
+        )
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn embedded_assets_build_a_vm_without_files() {
+        static ASSETS: EmbeddedAssets = embed_unikraft_assets!("embed.rs", "lib.rs");
+        assert_eq!(ASSETS.kernel, include_bytes!("embed.rs"));
+        assert!(ASSETS.vm().arg("/app.py").build().is_ok());
+        // Runs share one spill of the rootfs.
+        let first = ASSETS.spilled.get().cloned().unwrap();
+        ASSETS.vm().build().unwrap();
+        assert!(Arc::ptr_eq(&first, ASSETS.spilled.get().unwrap()));
+
+        let bare = EmbeddedAssets::new(b"\x7fELF", None);
+        assert!(bare.vm().build().is_ok() && bare.spilled.get().is_none());
+    }
+}
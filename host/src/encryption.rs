@@ -0,0 +1,277 @@
+//! Encrypted rootfs archives (`encrypted-initrd` feature).
+//!
+//! An encrypted initrd is the (optionally compressed) cpio sealed with
+//! AES-256-GCM, so application code can sit on shared storage without
+//! being readable there. The host decrypts it in memory as an initrd
+//! pipeline layer, just before the boot header is added:
+//!
+//! ```no_run
+//! use hyperlight_unikraft::encryption::{decrypt_layer, KeySource};
+//! use hyperlight_unikraft::pipeline::{decompress, InitrdPipeline};
+//! use hyperlight_unikraft::{run_vm_capture_output, VmConfig};
+//! use std::path::Path;
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! let pipeline = InitrdPipeline::new()
+//!     .layer(decrypt_layer(KeySource::parse("env:APP_ROOTFS_KEY")?))
+//!     .layer(decompress);
+//! let config = VmConfig::default().with_initrd_pipeline(pipeline);
+//! let sealed = std::fs::read("app.cpio.enc")?;
+//! run_vm_capture_output(Path::new("kernel"), Some(&sealed), &[], config)?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Layout: [`ENCRYPTED_MAGIC`], a little-endian `u16` key id length, the
+//! key id (UTF-8), a 12-byte nonce, then the ciphertext with its 16-byte
+//! tag. The magic and key id are authenticated as associated data. The
+//! key id is whatever names the key to its [`KeySource`] — a KMS key ARN,
+//! a version label — and may be empty.
+
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use anyhow::{anyhow, bail, Context, Result};
+use base64::Engine;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::pipeline::InitrdLayer;
+
+/// First bytes of an encrypted initrd.
+pub const ENCRYPTED_MAGIC: &[u8; 8] = b"HLENC01\0";
+
+/// AES-256 key length in bytes.
+pub const KEY_LEN: usize = 32;
+
+const NONCE_LEN: usize = 12;
+
+/// Callback behind [`KeySource::Hook`].
+pub type KeyHook = Arc<dyn Fn(&str) -> Result<Vec<u8>> + Send + Sync>;
+
+/// Where the decryption key comes from.
+///
+/// Key material from the environment or a file is either the 32 raw key
+/// bytes or their base64 encoding (surrounding whitespace ignored).
+#[derive(Clone)]
+pub enum KeySource {
+    /// An environment variable, read when the key is needed.
+    Env(String),
+    /// A file, read when the key is needed.
+    File(PathBuf),
+    /// A callback given the archive's key id, e.g. to unwrap a data key
+    /// through a KMS. Returns the raw 32-byte key.
+    Hook(KeyHook),
+}
+
+impl KeySource {
+    /// Parse `env:VAR` or `file:PATH`.
+    pub fn parse(spec: &str) -> Result<Self> {
+        match spec.split_once(':') {
+            Some(("env", var)) if !var.is_empty() => Ok(Self::Env(var.into())),
+            Some(("file", path)) if !path.is_empty() => Ok(Self::File(path.into())),
+            _ => bail!("invalid key source {spec:?} (expected env:VAR or file:PATH)"),
+        }
+    }
+
+    /// A [`KeySource::Hook`] calling `f`.
+    pub fn hook<F>(f: F) -> Self
+    where
+        F: Fn(&str) -> Result<Vec<u8>> + Send + Sync + 'static,
+    {
+        Self::Hook(Arc::new(f))
+    }
+
+    /// The key for an archive sealed under `key_id`.
+    pub fn key(&self, key_id: &str) -> Result<[u8; KEY_LEN]> {
+        let material = match self {
+            Self::Env(var) => std::env::var(var)
+                .with_context(|| format!("reading initrd key from ${var}"))?
+                .into_bytes(),
+            Self::File(path) => std::fs::read(path)
+                .with_context(|| format!("reading initrd key from {}", path.display()))?,
+            Self::Hook(f) => {
+                f(key_id).with_context(|| format!("fetching initrd key {key_id:?}"))?
+            }
+        };
+        parse_key(&material)
+    }
+}
+
+impl std::fmt::Debug for KeySource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Env(var) => f.debug_tuple("Env").field(var).finish(),
+            Self::File(path) => f.debug_tuple("File").field(path).finish(),
+            Self::Hook(_) => f.write_str("Hook(..)"),
+        }
+    }
+}
+
+fn parse_key(material: &[u8]) -> Result<[u8; KEY_LEN]> {
+    if let Ok(key) = <[u8; KEY_LEN]>::try_from(material) {
+        return Ok(key);
+    }
+    let text = std::str::from_utf8(material)
+        .map_err(|_| anyhow!("initrd key must be {KEY_LEN} bytes or base64"))?;
+    let decoded = base64::engine::general_purpose::STANDARD
+        .decode(text.trim())
+        .map_err(|_| anyhow!("initrd key must be {KEY_LEN} bytes or base64"))?;
+    <[u8; KEY_LEN]>::try_from(decoded.as_slice())
+        .map_err(|_| anyhow!("initrd key is {} bytes, expected {KEY_LEN}", decoded.len()))
+}
+
+/// True if `data` starts like an encrypted initrd.
+pub fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(ENCRYPTED_MAGIC)
+}
+
+/// Seal `plaintext` under `key`, recording `key_id` in the header.
+pub fn encrypt(plaintext: &[u8], key: &[u8; KEY_LEN], key_id: &str) -> Result<Vec<u8>> {
+    let id_len = u16::try_from(key_id.len()).map_err(|_| anyhow!("key id too long"))?;
+    let mut out = Vec::with_capacity(ENCRYPTED_MAGIC.len() + 2 + key_id.len() + NONCE_LEN + 16);
+    out.extend_from_slice(ENCRYPTED_MAGIC);
+    out.extend_from_slice(&id_len.to_le_bytes());
+    out.extend_from_slice(key_id.as_bytes());
+    let aad_len = out.len();
+    let mut nonce = [0u8; NONCE_LEN];
+    getrandom::fill(&mut nonce).map_err(|e| anyhow!("getrandom: {}", e))?;
+    out.extend_from_slice(&nonce);
+    let sealed = Aes256Gcm::new(key.into())
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: plaintext,
+                aad: &out[..aad_len],
+            },
+        )
+        .map_err(|_| anyhow!("encrypting initrd"))?;
+    out.extend_from_slice(&sealed);
+    Ok(out)
+}
+
+/// The key id an encrypted initrd was sealed under.
+pub fn key_id(data: &[u8]) -> Result<&str> {
+    Ok(Sealed::parse(data)?.key_id)
+}
+
+/// Open an encrypted initrd with the key from `source`. Fails if `data`
+/// isn't encrypted, was sealed under another key, or was tampered with.
+pub fn decrypt(data: &[u8], source: &KeySource) -> Result<Vec<u8>> {
+    let sealed = Sealed::parse(data)?;
+    let key = source.key(sealed.key_id)?;
+    let payload = Payload {
+        msg: sealed.ciphertext,
+        aad: sealed.aad,
+    };
+    Aes256Gcm::new(&key.into())
+        .decrypt(Nonce::from_slice(sealed.nonce), payload)
+        .map_err(|_| anyhow!("decrypting initrd: wrong key or corrupted archive"))
+}
+
+/// The parts of an encrypted initrd.
+struct Sealed<'a> {
+    /// Magic, key id length and key id.
+    aad: &'a [u8],
+    key_id: &'a str,
+    nonce: &'a [u8],
+    ciphertext: &'a [u8],
+}
+
+impl<'a> Sealed<'a> {
+    fn parse(data: &'a [u8]) -> Result<Self> {
+        if !is_encrypted(data) {
+            bail!("initrd is not encrypted");
+        }
+        let rest = &data[ENCRYPTED_MAGIC.len()..];
+        let truncated = || anyhow!("truncated encrypted initrd");
+        let id_len = u16::from_le_bytes(rest.get(..2).ok_or_else(truncated)?.try_into()?) as usize;
+        let aad_len = ENCRYPTED_MAGIC.len() + 2 + id_len;
+        if data.len() < aad_len + NONCE_LEN {
+            return Err(truncated());
+        }
+        let key_id = std::str::from_utf8(&data[aad_len - id_len..aad_len])
+            .context("encrypted initrd key id is not UTF-8")?;
+        let (nonce, ciphertext) = data[aad_len..].split_at(NONCE_LEN);
+        Ok(Self {
+            aad: &data[..aad_len],
+            key_id,
+            nonce,
+            ciphertext,
+        })
+    }
+}
+
+/// Layer: decrypt an encrypted initrd with the key from `source`;
+/// anything else passes through. Runs before
+/// [`decompress`](crate::pipeline::decompress), since archives are
+/// compressed before they are sealed.
+pub fn decrypt_layer(source: KeySource) -> impl InitrdLayer {
+    move |initrd: Vec<u8>| -> Result<Vec<u8>> {
+        if is_encrypted(&initrd) {
+            decrypt(&initrd, &source)
+        } else {
+            Ok(initrd)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::{decompress, InitrdPipeline};
+
+    const KEY: [u8; KEY_LEN] = [7; KEY_LEN];
+
+    #[test]
+    fn sealed_rootfs_decrypts_only_with_its_key() {
+        let sealed = encrypt(b"070701rootfs", &KEY, "kms/app-v2").unwrap();
+        assert!(is_encrypted(&sealed));
+        assert_eq!(key_id(&sealed).unwrap(), "kms/app-v2");
+
+        let asked = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = asked.clone();
+        let source = KeySource::hook(move |id| {
+            seen.lock().unwrap().push(id.to_string());
+            Ok(KEY.to_vec())
+        });
+        assert_eq!(decrypt(&sealed, &source).unwrap(), b"070701rootfs");
+        assert_eq!(*asked.lock().unwrap(), ["kms/app-v2"]);
+
+        let wrong = KeySource::hook(|_| Ok(vec![8; KEY_LEN]));
+        assert!(decrypt(&sealed, &wrong).is_err());
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(decrypt(&tampered, &source).is_err());
+        // The key id is authenticated too.
+        let mut relabeled = sealed;
+        relabeled[ENCRYPTED_MAGIC.len() + 2] = b'K';
+        assert!(decrypt(&relabeled, &source).is_err());
+    }
+
+    #[test]
+    fn key_material_is_raw_or_base64() {
+        let b64 = base64::engine::general_purpose::STANDARD.encode(KEY);
+        assert_eq!(parse_key(&KEY).unwrap(), KEY);
+        assert_eq!(parse_key(format!("{b64}\n").as_bytes()).unwrap(), KEY);
+        assert!(parse_key(b"c2hvcnQ=").is_err());
+        assert!(KeySource::parse("vault:x").is_err());
+        assert!(matches!(
+            KeySource::parse("file:/etc/key").unwrap(),
+            KeySource::File(p) if p == std::path::Path::new("/etc/key")
+        ));
+    }
+
+    #[test]
+    fn decrypt_layer_runs_before_decompress() {
+        let mut cpio = crate::rootfs::CpioWriter::new(Vec::new());
+        cpio.file("app.py", 0o644, 0, b"print(1)").unwrap();
+        let plain = cpio.finish().unwrap();
+        let sealed = encrypt(&zstd::encode_all(&plain[..], 0).unwrap(), &KEY, "").unwrap();
+
+        let pipeline = InitrdPipeline::new()
+            .layer(decrypt_layer(KeySource::hook(|_| Ok(KEY.to_vec()))))
+            .layer(decompress);
+        assert_eq!(pipeline.run(sealed).unwrap(), plain);
+        assert_eq!(pipeline.run(plain.clone()).unwrap(), plain);
+    }
+}
@@ -0,0 +1,332 @@
+//! Typed errors for failures callers may want to handle specially.
+//!
+//! The run entry points — the `run_vm*` functions, [`Sandbox`](crate::Sandbox)
+//! and its builder, the [`sweep`](crate::sweep) functions and
+//! [`VmTask`](crate::VmTask) — return `Result<T, Error>`, so a missing
+//! kernel, a rejected option, a malformed rootfs, a sandbox Hyperlight
+//! wouldn't create and a guest that crashed can be told apart with a
+//! `match`. Errors from the caller's own hooks, pipeline layers and sinks
+//! come back as [`Error::Caller`] with the original as its source, and
+//! anything else unclassified as [`Error::Other`], so nothing is
+//! flattened into a string.
+//!
+//! ```no_run
+//! use hyperlight_unikraft::{run_vm_capture_output, Error, VmConfig};
+//! use std::path::Path;
+//!
+//! match run_vm_capture_output(Path::new("kernel"), None, &[], VmConfig::default()) {
+//!     Ok(out) => print!("{}", out.output),
+//!     Err(Error::Kernel { path, .. }) => eprintln!("build {path:?} first"),
+//!     Err(Error::GuestExecution { exit, output }) => eprintln!("app failed: {exit}\n{output}"),
+//!     Err(e) => eprintln!("{:#}", anyhow::Error::from(e)),
+//! }
+//! ```
+//!
+//! The APIs layered on top, such as [`Vm`](crate::Vm) and
+//! [`VmPool`](crate::VmPool), return `anyhow::Result` with the [`Error`]
+//! attached, where `err.downcast_ref::<Error>()` finds it.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::exit::{output_shows_oom, VmExit};
+
+const MIB: u64 = 1024 * 1024;
+
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum Error {
+    /// The kernel image is missing or can't be read.
+    #[error("{}", io_message("kernel", .path, *.kind))]
+    Kernel { path: PathBuf, kind: io::ErrorKind },
+    /// The initrd file is missing or can't be read.
+    #[error("{}", io_message("initrd", .path, *.kind))]
+    InitrdIo { path: PathBuf, kind: io::ErrorKind },
+    /// The initrd isn't a usable archive: a malformed cpio or a failed
+    /// decompression.
+    #[error("{reason}")]
+    InvalidInitrd { reason: String },
+    /// An option was rejected before anything booted: a bad memory size,
+    /// duration, mount point or environment variable, or a rootfs too
+    /// big for the heap.
+    #[error("{reason}")]
+    InvalidConfig { reason: String },
+    /// Hyperlight couldn't create the sandbox, map its memory or register
+    /// the host functions. `source` is the Hyperlight error.
+    #[error("creating the sandbox failed")]
+    SandboxCreation {
+        #[source]
+        source: anyhow::Error,
+    },
+    /// The run is pinned to a hypervisor this host can't provide; see
+    /// [`hypervisor`](crate::hypervisor).
+    #[error("{hypervisor} is unavailable: {reason}")]
+    HypervisorUnavailable {
+        hypervisor: crate::Hypervisor,
+        reason: String,
+    },
+    /// The guest failed while booting or running the application.
+    /// `output` is what it printed, if the run captured its console.
+    #[error("guest failed: {exit}")]
+    GuestExecution { exit: VmExit, output: String },
+    /// The guest's allocator ran out of heap. `configured` is the heap
+    /// size the guest had and `suggestion` a larger one to retry with,
+    /// both in bytes; `output` as for `GuestExecution`.
+    #[error(
+        "guest ran out of memory with a {} heap; try --memory {}",
+        crate::format_mebibytes(*.configured),
+        crate::format_mebibytes(*.suggestion)
+    )]
+    OutOfGuestMemory {
+        configured: u64,
+        suggestion: u64,
+        output: String,
+    },
+    /// The application ran past its
+    /// [`VmConfig::with_timeout`](crate::VmConfig::with_timeout) limit
+    /// and was interrupted. `output` is what it printed until then.
+    #[error("timed out after {limit:?}")]
+    TimedOut { limit: Duration, output: String },
+    /// The application printed more than
+    /// [`VmConfig::with_max_output_bytes`](crate::VmConfig::with_max_output_bytes)
+    /// allows under [`OutputOverflow::Abort`](crate::OutputOverflow::Abort)
+    /// and was stopped. `output` is the part that was kept.
+    #[error("printed more than {} of output", crate::format_memory(*.limit))]
+    OutputLimitExceeded { limit: u64, output: String },
+    /// The run was stopped from outside with
+    /// [`VmHandle::kill`](crate::VmHandle::kill).
+    #[error("killed by the host")]
+    Killed,
+    /// A pre-run hook, initrd pipeline layer or output sink the caller
+    /// supplied failed; its error is passed through unchanged.
+    #[error(transparent)]
+    Caller(anyhow::Error),
+    /// A failure this crate doesn't classify, e.g. a snapshot Hyperlight
+    /// couldn't take or a thread that couldn't be spawned, with any
+    /// context added on the way out.
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+/// The [`Error`] classified somewhere in `err`'s chain, or `err` itself
+/// as [`Error::Other`].
+impl From<anyhow::Error> for Error {
+    fn from(err: anyhow::Error) -> Self {
+        err.downcast().unwrap_or_else(Self::Other)
+    }
+}
+
+fn io_message(what: &str, path: &Path, kind: io::ErrorKind) -> String {
+    match kind {
+        io::ErrorKind::NotFound => format!("{what} not found: {path:?}"),
+        kind => format!("can't read {what} {path:?}: {kind}"),
+    }
+}
+
+impl Error {
+    pub(crate) fn config(reason: impl Into<String>) -> Self {
+        Self::InvalidConfig {
+            reason: reason.into(),
+        }
+    }
+
+    pub(crate) fn invalid_initrd(reason: impl Into<String>) -> Self {
+        Self::InvalidInitrd {
+            reason: reason.into(),
+        }
+    }
+
+    fn out_of_memory(configured: u64, output: &str) -> Self {
+        Self::OutOfGuestMemory {
+            configured,
+            suggestion: (configured * 2).next_multiple_of(MIB).max(64 * MIB),
+            output: output.to_string(),
+        }
+    }
+}
+
+/// Fail with [`Error::Kernel`] unless `path` exists.
+pub(crate) fn check_kernel(path: &Path) -> anyhow::Result<()> {
+    if path.exists() {
+        return Ok(());
+    }
+    Err(Error::Kernel {
+        path: path.to_path_buf(),
+        kind: io::ErrorKind::NotFound,
+    }
+    .into())
+}
+
+/// `err`, from opening or mapping the kernel at `path`, as
+/// [`Error::Kernel`].
+pub(crate) fn kernel_io(path: &Path, err: io::Error) -> anyhow::Error {
+    let kind = err.kind();
+    anyhow::Error::new(err).context(Error::Kernel {
+        path: path.to_path_buf(),
+        kind,
+    })
+}
+
+/// `err`, from opening or reading the initrd at `path`, as
+/// [`Error::InitrdIo`].
+pub(crate) fn initrd_io(path: &Path, err: io::Error) -> anyhow::Error {
+    let kind = err.kind();
+    anyhow::Error::new(err).context(Error::InitrdIo {
+        path: path.to_path_buf(),
+        kind,
+    })
+}
+
+/// `err`, from setting up a Hyperlight sandbox, as
+/// [`Error::SandboxCreation`].
+pub(crate) fn sandbox_creation(err: impl Into<anyhow::Error>) -> anyhow::Error {
+    Error::SandboxCreation { source: err.into() }.into()
+}
+
+/// `err`, from a hook, pipeline layer or sink the caller supplied, as
+/// [`Error::Caller`], unless it already carries an [`Error`] (a built-in
+/// layer's, say).
+pub(crate) fn caller(err: anyhow::Error) -> anyhow::Error {
+    if err.downcast_ref::<Error>().is_some() {
+        return err;
+    }
+    Error::Caller(err).into()
+}
+
+/// `err`, from anything this crate doesn't classify, as [`Error::Other`].
+pub(crate) fn other(err: impl Into<anyhow::Error>) -> Error {
+    Error::Other(err.into())
+}
+
+/// Classify a failed boot or guest call: [`Error::OutOfGuestMemory`] if
+/// `err`, or the last lines of the console `output` of the failed run,
+/// show the guest's allocator giving up, otherwise
+/// [`Error::GuestExecution`]. Without a
+/// known `heap_size` there is no size to suggest, so an OOM is reported
+/// as a plain guest failure. An `err` already classified as either,
+/// before the output was known, is classified again with it.
+pub(crate) fn guest_failed(
+    err: anyhow::Error,
+    heap_size: Option<u64>,
+    output: &str,
+) -> anyhow::Error {
+    let exit = match err.downcast_ref::<Error>() {
+        // Classified where the output wasn't known; classify again with it.
+        Some(Error::GuestExecution { exit, .. }) => exit.clone(),
+        Some(Error::OutOfGuestMemory { .. }) => VmExit::OutOfMemory,
+        Some(_) => return err,
+        None => match err.downcast_ref::<hyperlight_host::HyperlightError>() {
+            Some(hl) => VmExit::classify(hl),
+            None => VmExit::UnexpectedVmExit(err.to_string()),
+        },
+    };
+    match heap_size {
+        Some(heap) if exit == VmExit::OutOfMemory || output_shows_oom(output) => {
+            err.context(Error::out_of_memory(heap, output))
+        }
+        _ => err.context(Error::GuestExecution {
+            exit,
+            output: output.to_string(),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyperlight_host::HyperlightError;
+
+    #[test]
+    fn allocator_abort_becomes_out_of_guest_memory() {
+        let err = anyhow::Error::from(HyperlightError::GuestAborted(13, String::new()));
+        let err = guest_failed(err, Some(256 * MIB), "");
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(&Error::OutOfGuestMemory {
+                configured,
+                suggestion,
+                ..
+            }) if configured == 256 * MIB && suggestion == 512 * MIB
+        ));
+        assert_eq!(
+            err.to_string(),
+            "guest ran out of memory with a 256Mi heap; try --memory 512Mi"
+        );
+        // The Hyperlight error is still reachable.
+        assert!(err.downcast_ref::<HyperlightError>().is_some());
+    }
+
+    #[test]
+    fn io_failures_name_the_file() {
+        let err = check_kernel(Path::new("/nonexistent/kernel")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"kernel not found: "/nonexistent/kernel""#
+        );
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::Kernel {
+                kind: io::ErrorKind::NotFound,
+                ..
+            })
+        ));
+
+        let io = io::Error::from(io::ErrorKind::PermissionDenied);
+        let err = initrd_io(Path::new("rootfs.cpio"), io);
+        assert!(err
+            .to_string()
+            .starts_with(r#"can't read initrd "rootfs.cpio": "#));
+        assert!(err.downcast_ref::<io::Error>().is_some());
+    }
+
+    #[test]
+    fn anyhow_errors_convert_to_their_classification() {
+        let err = check_kernel(Path::new("/nonexistent/kernel"))
+            .unwrap_err()
+            .context("booting");
+        assert!(matches!(Error::from(err), Error::Kernel { .. }));
+
+        // A caller's error keeps its own chain; one that already carries
+        // an `Error` keeps that instead.
+        let hook = caller(anyhow::anyhow!("no such user").context("pre-run hook"));
+        let Error::Caller(inner) = Error::from(hook) else {
+            panic!("expected Error::Caller");
+        };
+        assert_eq!(format!("{inner:#}"), "pre-run hook: no such user");
+        let typed = caller(Error::config("bad mount").into());
+        assert!(matches!(Error::from(typed), Error::InvalidConfig { .. }));
+
+        let err = Error::from(anyhow::anyhow!("disk full").context("saving snapshot"));
+        assert!(matches!(err, Error::Other(_)));
+        assert_eq!(err.to_string(), "saving snapshot");
+
+        let err = Error::from(sandbox_creation(anyhow::anyhow!("no /dev/kvm")));
+        let source = std::error::Error::source(&err).unwrap();
+        assert_eq!(source.to_string(), "no /dev/kvm");
+    }
+
+    #[test]
+    fn output_patterns_are_recognized() {
+        let err = guest_failed(
+            anyhow::anyhow!("guest aborted"),
+            Some(8 * MIB),
+            "[    0.1] CRIT: [libukalloc] Cannot allocate memory\n",
+        );
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::OutOfGuestMemory {
+                suggestion, ..
+            }) if *suggestion == 64 * MIB
+        ));
+
+        let other = guest_failed(anyhow::anyhow!("page fault"), Some(8 * MIB), "hello\n");
+        assert!(matches!(
+            other.downcast_ref::<Error>(),
+            Some(Error::GuestExecution {
+                exit: VmExit::UnexpectedVmExit(_),
+                ..
+            })
+        ));
+    }
+}
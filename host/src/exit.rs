@@ -0,0 +1,470 @@
+//! Semantic classification of how a guest run ended.
+//!
+//! Hyperlight reports the end of a unikernel run as either `Ok` (the
+//! guest halted) or one of many `HyperlightError` variants. Callers care
+//! about a handful of categories, not the variant zoo, so [`VmExit`]
+//! folds them down.
+
+use hyperlight_host::HyperlightError;
+use std::time::Duration;
+
+use crate::phase::{Phase, PhaseTimings};
+
+/// Guest error codes (hyperlight `ErrorCode`) that carry meaning for us.
+const ERROR_CODE_STACK_OVERFLOW: u8 = 9;
+const ERROR_CODE_DLMALLOC_FAILURE: u8 = 12;
+const ERROR_CODE_MALLOC_FAILED: u8 = 13;
+
+/// Why a guest run ended.
+///
+/// [`VmExit::to_json`] (and, with the `serde` feature, `Serialize`)
+/// gives `{"kind": "abort", "detail": {"code": 1, "message": ".."}}`;
+/// unit variants carry no `detail`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(tag = "kind", content = "detail", rename_all = "snake_case")
+)]
+pub enum VmExit {
+    /// The guest halted normally.
+    Halt,
+    /// The guest aborted with a code and message (Unikraft panics,
+    /// `abort()` in the app, explicit `hl_abort`).
+    Abort { code: u8, message: String },
+    /// The guest overran its stack.
+    StackOverflow,
+    /// The guest ran out of heap.
+    OutOfMemory,
+    /// The host stopped the guest via [`crate::VmHandle::interrupt`].
+    Interrupted,
+    /// Anything else: a VM exit the host didn't expect.
+    UnexpectedVmExit(String),
+}
+
+impl VmExit {
+    /// Classify a Hyperlight error returned by evolve or a guest call.
+    pub fn classify(err: &HyperlightError) -> Self {
+        match err {
+            HyperlightError::StackOverflow() => Self::StackOverflow,
+            HyperlightError::ExecutionCanceledByHost() => Self::Interrupted,
+            HyperlightError::GuestAborted(code, message) => match *code {
+                ERROR_CODE_STACK_OVERFLOW => Self::StackOverflow,
+                ERROR_CODE_DLMALLOC_FAILURE | ERROR_CODE_MALLOC_FAILED => Self::OutOfMemory,
+                _ if mentions_oom(message) => Self::OutOfMemory,
+                code => Self::Abort {
+                    code,
+                    message: message.clone(),
+                },
+            },
+            other => {
+                let message = other.to_string();
+                if mentions_oom(&message) {
+                    Self::OutOfMemory
+                } else {
+                    Self::UnexpectedVmExit(message)
+                }
+            }
+        }
+    }
+
+    /// Classify the outcome of an evolve or call. `Ok` is a halt; errors
+    /// that neither wrap a `HyperlightError` nor carry the guest's
+    /// [`ExitStatus`] (host-side setup failures) are reported as
+    /// unexpected exits.
+    pub fn from_result<T>(result: &anyhow::Result<T>) -> Self {
+        let Err(e) = result else {
+            return Self::Halt;
+        };
+        if let Some(hl) = e.downcast_ref::<HyperlightError>() {
+            return Self::classify(hl);
+        }
+        match e
+            .downcast_ref::<crate::Error>()
+            .and_then(ExitStatus::from_error)
+        {
+            Some(status) => status.0,
+            None => Self::UnexpectedVmExit(e.to_string()),
+        }
+    }
+
+    /// True for a normal halt.
+    pub fn is_halt(&self) -> bool {
+        matches!(self, Self::Halt)
+    }
+
+    /// The tagged JSON form, available without the `serde` feature.
+    pub fn to_json(&self) -> serde_json::Value {
+        use serde_json::json;
+        match self {
+            Self::Halt => json!({"kind": "halt"}),
+            Self::Abort { code, message } => {
+                json!({"kind": "abort", "detail": {"code": code, "message": message}})
+            }
+            Self::StackOverflow => json!({"kind": "stack_overflow"}),
+            Self::OutOfMemory => json!({"kind": "out_of_memory"}),
+            Self::Interrupted => json!({"kind": "interrupted"}),
+            Self::UnexpectedVmExit(msg) => json!({"kind": "unexpected_vm_exit", "detail": msg}),
+        }
+    }
+}
+
+impl std::fmt::Display for VmExit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Halt => write!(f, "halted"),
+            Self::Abort { code, message } => write!(f, "aborted (code {code}): {message}"),
+            Self::StackOverflow => write!(f, "stack overflow"),
+            Self::OutOfMemory => write!(f, "out of memory"),
+            Self::Interrupted => write!(f, "interrupted by host"),
+            Self::UnexpectedVmExit(msg) => write!(f, "unexpected VM exit: {msg}"),
+        }
+    }
+}
+
+/// How the application ended, as [`RunReport::exit`] reports it:
+/// the guest's [`VmExit`] read through the exit-code convention the
+/// Unikraft platform uses. A normal halt is exit code 0; a nonzero
+/// `exit()` in the app, a Unikraft panic or an explicit `hl_abort`
+/// aborts the guest with that code.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(transparent)
+)]
+pub struct ExitStatus(VmExit);
+
+impl ExitStatus {
+    /// The guest halted normally.
+    pub const HALTED: Self = Self(VmExit::Halt);
+
+    /// The status carried by a run's error, if the guest got as far as
+    /// running and the error is how it ended rather than a host-side
+    /// failure (a missing kernel, a rejected option).
+    pub fn from_error(err: &crate::Error) -> Option<Self> {
+        match err {
+            crate::Error::GuestExecution { exit, .. } => Some(Self(exit.clone())),
+            crate::Error::OutOfGuestMemory { .. } => Some(Self(VmExit::OutOfMemory)),
+            crate::Error::TimedOut { .. }
+            | crate::Error::OutputLimitExceeded { .. }
+            | crate::Error::Killed => Some(Self(VmExit::Interrupted)),
+            _ => None,
+        }
+    }
+
+    /// The exit code: 0 for a halt, the abort code otherwise. `None`
+    /// when the guest didn't exit by itself (a crash or a kill).
+    pub fn code(&self) -> Option<u8> {
+        match self.0 {
+            VmExit::Halt => Some(0),
+            VmExit::Abort { code, .. } => Some(code),
+            _ => None,
+        }
+    }
+
+    /// True for exit code 0.
+    pub fn success(&self) -> bool {
+        self.0.is_halt()
+    }
+
+    /// True if the guest halted normally.
+    pub fn halted(&self) -> bool {
+        self.0.is_halt()
+    }
+
+    /// True if the guest died on its own: an abort, a stack overflow,
+    /// running out of memory or an unexpected VM exit.
+    pub fn crashed(&self) -> bool {
+        !self.halted() && !self.killed()
+    }
+
+    /// True if the host stopped the guest, e.g. via
+    /// [`VmHandle::interrupt`](crate::VmHandle::interrupt) or a timeout.
+    pub fn killed(&self) -> bool {
+        self.0 == VmExit::Interrupted
+    }
+
+    /// The underlying classification.
+    pub fn exit(&self) -> &VmExit {
+        &self.0
+    }
+}
+
+impl From<VmExit> for ExitStatus {
+    fn from(exit: VmExit) -> Self {
+        Self(exit)
+    }
+}
+
+impl std::fmt::Display for ExitStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.code() {
+            Some(code) => write!(f, "exit code {code}"),
+            None => self.0.fmt(f),
+        }
+    }
+}
+
+/// What a [`run_vm`](crate::run_vm) run did and where its time went,
+/// for the caller to log or print. The phase times are zero for a run
+/// whose guest crashed during boot, which leaves nothing to time but the
+/// whole.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunReport {
+    /// Running the initrd pipeline and prepending the boot header.
+    pub prepend: Duration,
+    /// Loading the assets and creating the sandbox.
+    pub setup: Duration,
+    /// The guest's boot and run.
+    pub evolve: Duration,
+    /// The whole call, hooks included.
+    pub total: Duration,
+    pub exit: ExitStatus,
+}
+
+impl RunReport {
+    pub(crate) fn new(phases: &PhaseTimings, total: Duration, exit: ExitStatus) -> Self {
+        Self {
+            prepend: phases.get(Phase::InitrdPrepare),
+            setup: phases.get(Phase::AssetLoad) + phases.get(Phase::SandboxCreate),
+            evolve: phases.get(Phase::Evolve),
+            total,
+            exit,
+        }
+    }
+}
+
+/// `prepend=0.4ms setup=2.1ms evolve=80.3ms total=83.0ms exit code 0`.
+impl std::fmt::Display for RunReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        write!(
+            f,
+            "prepend={:.1}ms setup={:.1}ms evolve={:.1}ms total={:.1}ms {}",
+            ms(self.prepend),
+            ms(self.setup),
+            ms(self.evolve),
+            ms(self.total),
+            self.exit
+        )
+    }
+}
+
+/// How an allocator failure starts its line, once any Unikraft log
+/// prefix is stripped: Unikraft's `ukalloc`, C `malloc`, and the Go and
+/// Python runtimes. Rust's `memory allocation of N bytes failed` is
+/// matched separately.
+const OOM_LINE_STARTS: &[&str] = &[
+    "out of memory",
+    "cannot allocate memory",
+    "malloc failed",
+    "fatal error: out of memory",
+    "runtime: out of memory",
+    "memoryerror",
+];
+
+/// How many of the last console lines before the exit
+/// [`output_shows_oom`] looks at.
+const OOM_TAIL_LINES: usize = 3;
+
+/// Whether a line of an abort or VM-exit message is an allocator
+/// failure.
+pub(crate) fn mentions_oom(message: &str) -> bool {
+    message.lines().any(is_oom_line)
+}
+
+/// Whether the guest's console output ends with an allocator failure.
+/// Only the last few lines count, so an app that printed the words
+/// earlier and went on to fail for another reason isn't an OOM.
+pub(crate) fn output_shows_oom(output: &str) -> bool {
+    output
+        .lines()
+        .rev()
+        .filter(|l| !l.trim().is_empty())
+        .take(OOM_TAIL_LINES)
+        .any(is_oom_line)
+}
+
+fn is_oom_line(line: &str) -> bool {
+    let line = strip_log_prefix(line).to_ascii_lowercase();
+    OOM_LINE_STARTS.iter().any(|p| line.starts_with(p))
+        || (line.starts_with("memory allocation of ") && line.ends_with(" failed"))
+}
+
+/// `line` without a Unikraft log prefix such as
+/// `[    0.1] CRIT: [libukalloc] `.
+fn strip_log_prefix(line: &str) -> &str {
+    let mut rest = line.trim();
+    loop {
+        if let Some((_, after)) = rest.strip_prefix('[').and_then(|r| r.split_once(']')) {
+            rest = after.trim_start();
+            continue;
+        }
+        match rest.split_once(':') {
+            Some((level, after))
+                if ["crit", "err", "warn", "info", "dbg"]
+                    .iter()
+                    .any(|l| level.eq_ignore_ascii_case(l)) =>
+            {
+                rest = after.trim_start();
+            }
+            _ => return rest,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn guest_abort_codes_map_to_categories() {
+        let overflow = HyperlightError::GuestAborted(ERROR_CODE_STACK_OVERFLOW, String::new());
+        assert_eq!(VmExit::classify(&overflow), VmExit::StackOverflow);
+
+        let oom = HyperlightError::GuestAborted(ERROR_CODE_MALLOC_FAILED, String::new());
+        assert_eq!(VmExit::classify(&oom), VmExit::OutOfMemory);
+
+        let panic = HyperlightError::GuestAborted(1, "uk_panic".into());
+        assert_eq!(
+            VmExit::classify(&panic),
+            VmExit::Abort {
+                code: 1,
+                message: "uk_panic".into()
+            }
+        );
+    }
+
+    #[test]
+    fn oom_wording_is_recognized_in_abort_messages() {
+        let err = HyperlightError::GuestAborted(1, "Out of memory in ukalloc".into());
+        assert_eq!(VmExit::classify(&err), VmExit::OutOfMemory);
+
+        let err = HyperlightError::GuestAborted(1, "config: out of memory limits".into());
+        assert!(matches!(VmExit::classify(&err), VmExit::Abort { .. }));
+    }
+
+    #[test]
+    fn only_allocator_failures_at_the_end_of_the_output_count() {
+        assert!(output_shows_oom(
+            "[    0.1] CRIT: [libukalloc] Cannot allocate memory\n\n"
+        ));
+        assert!(output_shows_oom(
+            "Traceback (most recent call last):\n  File \"a.py\"\nMemoryError\n"
+        ));
+        assert!(output_shows_oom(
+            "memory allocation of 1048576 bytes failed\n"
+        ));
+
+        // Mentioning the words isn't failing to allocate.
+        assert!(!output_shows_oom("retrying: worker was out of memory\n"));
+        assert!(!output_shows_oom("see `malloc failed` in the FAQ\n"));
+        // Nor is an allocator failure the app recovered from.
+        assert!(!output_shows_oom(
+            "Cannot allocate memory\nfalling back\nstep 2\nstep 3\n"
+        ));
+    }
+
+    #[test]
+    fn host_cancellation_is_interrupted() {
+        let err = anyhow::Error::from(HyperlightError::ExecutionCanceledByHost());
+        assert_eq!(VmExit::from_result::<()>(&Err(err)), VmExit::Interrupted);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serializes_with_a_kind_tag() {
+        let abort = VmExit::Abort {
+            code: 1,
+            message: "uk_panic".into(),
+        };
+        let json = serde_json::to_value(&abort).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({"kind": "abort", "detail": {"code": 1, "message": "uk_panic"}})
+        );
+        assert_eq!(serde_json::from_value::<VmExit>(json).unwrap(), abort);
+        assert_eq!(
+            serde_json::to_value(VmExit::OutOfMemory).unwrap(),
+            serde_json::json!({"kind": "out_of_memory"})
+        );
+        for exit in [
+            VmExit::Halt,
+            abort,
+            VmExit::StackOverflow,
+            VmExit::OutOfMemory,
+            VmExit::Interrupted,
+            VmExit::UnexpectedVmExit("io".into()),
+        ] {
+            assert_eq!(exit.to_json(), serde_json::to_value(&exit).unwrap());
+        }
+    }
+
+    #[test]
+    fn exit_status_follows_the_exit_code_convention() {
+        assert_eq!(ExitStatus::HALTED.code(), Some(0));
+        assert!(ExitStatus::HALTED.success());
+
+        let abort = ExitStatus::from(VmExit::Abort {
+            code: 3,
+            message: String::new(),
+        });
+        assert_eq!(abort.code(), Some(3));
+        assert!(abort.crashed() && !abort.success());
+        assert_eq!(abort.to_string(), "exit code 3");
+
+        let killed = ExitStatus::from(VmExit::Interrupted);
+        assert!(killed.killed() && !killed.crashed());
+        assert_eq!(killed.code(), None);
+
+        let err =
+            crate::error::guest_failed(HyperlightError::StackOverflow().into(), Some(64 << 20), "");
+        let status = ExitStatus::from_error(&err.into()).unwrap();
+        assert_eq!(status.exit(), &VmExit::StackOverflow);
+        let host = crate::Error::from(anyhow::anyhow!("Kernel not found"));
+        assert!(ExitStatus::from_error(&host).is_none());
+
+        let timed_out = anyhow::Error::new(crate::Error::TimedOut {
+            limit: std::time::Duration::from_secs(1),
+            output: "partial\n".into(),
+        })
+        .context("VM call failed");
+        assert!(ExitStatus::from_error(&timed_out.into()).unwrap().killed());
+        assert!(ExitStatus::from_error(&crate::Error::Killed)
+            .unwrap()
+            .killed());
+        // A status passed back through anyhow is still recognized.
+        let killed = anyhow::Error::new(crate::Error::Killed);
+        assert_eq!(VmExit::from_result::<()>(&Err(killed)), VmExit::Interrupted);
+    }
+
+    #[test]
+    fn ok_is_halt_and_host_errors_are_unexpected() {
+        assert!(VmExit::from_result(&Ok(())).is_halt());
+        let host: anyhow::Result<()> = Err(anyhow::anyhow!("Kernel not found"));
+        assert!(matches!(
+            VmExit::from_result(&host),
+            VmExit::UnexpectedVmExit(_)
+        ));
+    }
+
+    #[test]
+    fn run_report_groups_phases_into_steps() {
+        let mut phases = PhaseTimings::default();
+        let ms = Duration::from_millis;
+        phases.add(Phase::AssetLoad, ms(1));
+        phases.add(Phase::InitrdPrepare, ms(2));
+        phases.add(Phase::SandboxCreate, ms(3));
+        phases.add(Phase::Evolve, ms(40));
+        let report = RunReport::new(&phases, ms(50), ExitStatus::HALTED);
+        assert_eq!(
+            (report.prepend, report.setup, report.evolve),
+            (ms(2), ms(4), ms(40))
+        );
+        assert_eq!(
+            report.to_string(),
+            "prepend=2.0ms setup=4.0ms evolve=40.0ms total=50.0ms exit code 0"
+        );
+    }
+}
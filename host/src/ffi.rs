@@ -17,7 +17,8 @@ use std::sync::atomic::{AtomicI32, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread::JoinHandle;
 
-use crate::prepend_cmdline_to_initrd;
+use crate::workspace::Workspace;
+use crate::{prepend_cmdline_to_initrd, VmExit};
 use hyperlight_host::sandbox::uninitialized::GuestEnvironment;
 use hyperlight_host::sandbox::SandboxConfiguration;
 use hyperlight_host::{GuestBinary, UninitializedSandbox};
@@ -28,11 +29,20 @@ pub const HL_STATUS_RUNNING: i32 = 1;
 pub const HL_STATUS_STOPPED: i32 = 2;
 pub const HL_STATUS_ERROR: i32 = 3;
 
+/// Exit reasons exposed to C (see [`VmExit`]).
+pub const HL_EXIT_HALT: i32 = 0;
+pub const HL_EXIT_ABORT: i32 = 1;
+pub const HL_EXIT_STACK_OVERFLOW: i32 = 2;
+pub const HL_EXIT_OUT_OF_MEMORY: i32 = 3;
+pub const HL_EXIT_UNEXPECTED: i32 = 4;
+pub const HL_EXIT_INTERRUPTED: i32 = 5;
+
 /// Opaque VM handle. All fields are thread-safe.
 pub struct HlVm {
     status: AtomicI32,
-    output: Arc<Mutex<String>>,
+    output: Arc<Mutex<Vec<u8>>>,
     error: Mutex<Option<CString>>,
+    exit: Mutex<Option<VmExit>>,
     output_cstr: Mutex<Option<CString>>,
     thread: Mutex<Option<JoinHandle<()>>>,
     // Config stored for deferred sandbox creation (sandbox is not Send)
@@ -150,8 +160,9 @@ pub extern "C" fn hl_vm_create(config: *const HlConfig) -> *mut HlVm {
 
     let vm = Box::new(HlVm {
         status: AtomicI32::new(HL_STATUS_CREATED),
-        output: Arc::new(Mutex::new(String::new())),
+        output: Arc::new(Mutex::new(Vec::new())),
         error: Mutex::new(None),
+        exit: Mutex::new(None),
         output_cstr: Mutex::new(None),
         thread: Mutex::new(None),
         kernel_path,
@@ -212,7 +223,10 @@ pub extern "C" fn hl_vm_start(vm: *mut HlVm) -> c_int {
 
         let vm = unsafe { &*(vm_ptr as *const HlVm) };
         match result {
-            Ok(()) => {
+            Ok(exit) => {
+                if let Ok(mut e) = vm.exit.lock() {
+                    *e = Some(exit);
+                }
                 vm.status.store(HL_STATUS_STOPPED, Ordering::SeqCst);
             }
             Err(e) => {
@@ -236,14 +250,17 @@ fn run_vm_on_thread(
     initrd_data: Option<&[u8]>,
     heap_size: u64,
     stack_size: u64,
-    output: &Arc<Mutex<String>>,
-) -> anyhow::Result<()> {
+    output: &Arc<Mutex<Vec<u8>>>,
+) -> anyhow::Result<VmExit> {
     use std::io::Write as _;
 
     let path = Path::new(kernel_path);
     if !path.exists() {
         return Err(anyhow::anyhow!("kernel not found: {}", kernel_path));
     }
+    if let Some(initrd) = initrd_data {
+        crate::check_initrd_fits(initrd.len() as u64, heap_size)?;
+    }
 
     let mut sandbox_config = SandboxConfiguration::default();
     // In v0.13.1+, stack is part of the guest heap memory region
@@ -256,30 +273,29 @@ fn run_vm_on_thread(
     // Capture stderr to a temp file while the VM runs. Unikraft console output
     // goes through Hyperlight's `eprint!` → process stderr. See
     // stderr_capture module for the platform-specific redirect.
-    let capture_file = std::env::temp_dir().join(format!(
-        "hl-ffi-capture-{}-{:p}",
-        std::process::id(),
-        output
-    ));
+    // The workspace removes the capture file on every exit path.
+    let workspace = Workspace::new()?;
+    let capture_file = workspace.file("console")?;
     let capture = crate::stderr_capture::Capture::redirect_to_file(&capture_file)?;
 
-    // Evolve runs the unikernel to completion (blocks until HLT)
-    match sandbox.evolve() {
-        Ok(_) | Err(_) => {} // HLT is expected for unikernels
-    }
+    // Evolve runs the unikernel to completion (blocks until HLT). Any
+    // guest-side ending still leaves the VM STOPPED with its output; the
+    // classified reason tells the caller how it ended.
+    let exit = match sandbox.evolve() {
+        Ok(_) => VmExit::Halt,
+        Err(e) => VmExit::classify(&e),
+    };
 
     std::io::stderr().flush().ok();
     capture.restore()?;
 
     let captured = std::fs::read(&capture_file).unwrap_or_default();
-    let _ = std::fs::remove_file(&capture_file);
-    let captured = String::from_utf8_lossy(&captured).into_owned();
 
     if let Ok(mut buf) = output.lock() {
         *buf = captured;
     }
 
-    Ok(())
+    Ok(exit)
 }
 
 /// Get the current VM status.
@@ -331,8 +347,11 @@ pub extern "C" fn hl_vm_wait(vm: *mut HlVm) -> c_int {
 
 /// Get captured output from the VM. Valid after VM stops.
 ///
-/// Returns a pointer to a null-terminated UTF-8 string. The pointer is valid
-/// until the next call to `hl_vm_output` or `hl_vm_free` on the same VM.
+/// Returns a pointer to a null-terminated UTF-8 string. Invalid UTF-8 and
+/// embedded NUL bytes are replaced with U+FFFD so a stray byte from the
+/// guest never hides the rest of the output; use `hl_vm_output_bytes`
+/// for the lossless view. The pointer is valid until the next call to
+/// `hl_vm_output` or `hl_vm_free` on the same VM.
 /// Returns NULL if vm is null.
 #[unsafe(no_mangle)]
 pub extern "C" fn hl_vm_output(vm: *const HlVm) -> *const c_char {
@@ -344,7 +363,7 @@ pub extern "C" fn hl_vm_output(vm: *const HlVm) -> *const c_char {
     };
 
     let output = match vm.output.lock() {
-        Ok(o) => o.clone(),
+        Ok(o) => String::from_utf8_lossy(&o).replace('\0', "\u{FFFD}"),
         Err(_) => return std::ptr::null(),
     };
 
@@ -360,6 +379,71 @@ pub extern "C" fn hl_vm_output(vm: *const HlVm) -> *const c_char {
     ptr
 }
 
+/// Get the raw captured output bytes from the VM. Valid after VM stops.
+///
+/// Writes the byte count to `len` (if non-null) and returns a pointer to
+/// the bytes exactly as the guest produced them. The pointer is valid
+/// until `hl_vm_free`. Returns NULL if vm is null or the VM is still
+/// running.
+#[unsafe(no_mangle)]
+pub extern "C" fn hl_vm_output_bytes(vm: *const HlVm, len: *mut usize) -> *const u8 {
+    let vm = unsafe {
+        if vm.is_null() {
+            return std::ptr::null();
+        }
+        &*vm
+    };
+    if vm.status.load(Ordering::SeqCst) == HL_STATUS_RUNNING {
+        return std::ptr::null();
+    }
+
+    let output = match vm.output.lock() {
+        Ok(o) => o,
+        Err(_) => return std::ptr::null(),
+    };
+    if !len.is_null() {
+        unsafe { *len = output.len() };
+    }
+    // The buffer is written once by the VM thread before the status
+    // leaves RUNNING and never reallocated afterwards, so the pointer
+    // stays valid after the guard drops.
+    output.as_ptr()
+}
+
+/// Get how the guest run ended. Valid once the VM is STOPPED.
+///
+/// Returns one of `HL_EXIT_*`, or -1 if vm is null or the guest hasn't
+/// finished (still running, or never booted because setup failed). For
+/// `HL_EXIT_ABORT`, `abort_code` (if non-null) receives the guest's code.
+#[unsafe(no_mangle)]
+pub extern "C" fn hl_vm_exit_reason(vm: *const HlVm, abort_code: *mut u8) -> c_int {
+    let vm = unsafe {
+        if vm.is_null() {
+            return -1;
+        }
+        &*vm
+    };
+
+    let exit = match vm.exit.lock() {
+        Ok(e) => e,
+        Err(_) => return -1,
+    };
+    match exit.as_ref() {
+        None => -1,
+        Some(VmExit::Halt) => HL_EXIT_HALT,
+        Some(VmExit::Abort { code, .. }) => {
+            if !abort_code.is_null() {
+                unsafe { *abort_code = *code };
+            }
+            HL_EXIT_ABORT
+        }
+        Some(VmExit::StackOverflow) => HL_EXIT_STACK_OVERFLOW,
+        Some(VmExit::OutOfMemory) => HL_EXIT_OUT_OF_MEMORY,
+        Some(VmExit::Interrupted) => HL_EXIT_INTERRUPTED,
+        Some(VmExit::UnexpectedVmExit(_)) => HL_EXIT_UNEXPECTED,
+    }
+}
+
 /// Get the error message if VM status is ERROR.
 /// Returns NULL if no error or vm is null.
 #[unsafe(no_mangle)]
@@ -0,0 +1,364 @@
+//! Persistent run history in SQLite (`sqlite` feature).
+//!
+//! A [`RunHistory`] appends one row per run — what was run (kernel,
+//! arguments, a hash of the inputs, memory sizes), how it ended and how
+//! long it took — so past executions can be queried after the fact with
+//! [`RunHistory::query`] or `hyperlight-unikraft history`.
+//!
+//! [`RunHistory::attach`] records every run of a [`VmConfig`] through its
+//! pre- and post-run hooks:
+//!
+//! ```no_run
+//! use hyperlight_unikraft::history::RunHistory;
+//! use hyperlight_unikraft::{run_vm_capture_output, VmConfig};
+//! use std::{path::Path, sync::Arc};
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! let history = Arc::new(RunHistory::open("runs.sqlite")?);
+//! let config = history.attach(VmConfig::default());
+//! run_vm_capture_output(Path::new("kernel"), None, &["/app.py".into()], config)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use anyhow::{Context, Result};
+use rusqlite::{params, Connection};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread::ThreadId;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::{RunAssets, VmConfig, VmExit};
+
+/// Environment variable naming the history database the CLI writes to
+/// and `history` reads from.
+pub const ENV_HISTORY: &str = "HYPERLIGHT_UNIKRAFT_HISTORY";
+
+const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS runs (
+    id            INTEGER PRIMARY KEY,
+    started_ms    INTEGER NOT NULL,
+    kernel        TEXT NOT NULL,
+    args          TEXT NOT NULL,
+    inputs_sha256 TEXT NOT NULL,
+    heap_size     INTEGER NOT NULL,
+    stack_size    INTEGER NOT NULL,
+    ok            INTEGER NOT NULL,
+    exit          TEXT NOT NULL,
+    total_us      INTEGER NOT NULL,
+    setup_us      INTEGER,
+    evolve_us     INTEGER,
+    output_bytes  INTEGER
+);
+CREATE INDEX IF NOT EXISTS runs_started ON runs (started_ms);";
+
+/// One run as stored in the history.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistoryEntry {
+    pub started: SystemTime,
+    pub kernel: PathBuf,
+    pub args: Vec<String>,
+    /// See [`inputs_hash`].
+    pub inputs_sha256: String,
+    pub heap_size: u64,
+    pub stack_size: u64,
+    pub exit: VmExit,
+    /// From the start of the run to its end, boot included.
+    pub total_time: Duration,
+    pub setup_time: Option<Duration>,
+    pub evolve_time: Option<Duration>,
+    /// Bytes of console output captured (0 for runs that don't capture);
+    /// `None` when the run reported no output, e.g. because it failed.
+    pub output_bytes: Option<u64>,
+}
+
+/// Which runs [`RunHistory::query`] returns, newest first.
+#[derive(Debug, Clone, Default)]
+pub struct HistoryFilter {
+    /// Only runs started at or after this time.
+    pub since: Option<SystemTime>,
+    /// Only runs that didn't halt normally.
+    pub failed_only: bool,
+    /// At most this many runs.
+    pub limit: Option<usize>,
+}
+
+/// A SQLite-backed run history, safe to share between threads.
+pub struct RunHistory {
+    conn: Mutex<Connection>,
+    /// Runs whose pre-run hook has fired, by the thread running them.
+    pending: Mutex<HashMap<ThreadId, Pending>>,
+}
+
+struct Pending {
+    started: SystemTime,
+    clock: Instant,
+    kernel: PathBuf,
+    args: Vec<String>,
+    inputs_sha256: String,
+}
+
+impl RunHistory {
+    /// Open (creating if needed) the history database at `path`.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)?;
+        }
+        let conn = Connection::open(path)
+            .with_context(|| format!("opening run history {}", path.display()))?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+            pending: Mutex::default(),
+        })
+    }
+
+    /// Append `entry`, returning its row id.
+    pub fn record(&self, entry: &HistoryEntry) -> Result<i64> {
+        let conn = self.lock();
+        conn.execute(
+            "INSERT INTO runs (started_ms, kernel, args, inputs_sha256, heap_size, stack_size,
+                               ok, exit, total_us, setup_us, evolve_us, output_bytes)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+            params![
+                unix_ms(entry.started),
+                entry.kernel.to_string_lossy(),
+                serde_json::to_string(&entry.args)?,
+                entry.inputs_sha256,
+                entry.heap_size as i64,
+                entry.stack_size as i64,
+                entry.exit.is_halt(),
+                serde_json::to_string(&entry.exit)?,
+                entry.total_time.as_micros() as i64,
+                entry.setup_time.map(|d| d.as_micros() as i64),
+                entry.evolve_time.map(|d| d.as_micros() as i64),
+                entry.output_bytes.map(|n| n as i64),
+            ],
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    /// Runs matching `filter` with their row ids, newest first.
+    pub fn query(&self, filter: &HistoryFilter) -> Result<Vec<(i64, HistoryEntry)>> {
+        let conn = self.lock();
+        let mut stmt = conn.prepare(
+            "SELECT id, started_ms, kernel, args, inputs_sha256, heap_size, stack_size, exit,
+                    total_us, setup_us, evolve_us, output_bytes
+             FROM runs
+             WHERE started_ms >= ?1 AND (?2 = 0 OR ok = 0)
+             ORDER BY started_ms DESC, id DESC
+             LIMIT ?3",
+        )?;
+        let since = filter.since.map_or(i64::MIN, unix_ms);
+        let limit = filter.limit.map_or(-1, |n| n as i64);
+        let rows = stmt.query_map(params![since, filter.failed_only, limit], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, i64>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, String>(3)?,
+                row.get::<_, String>(4)?,
+                row.get::<_, i64>(5)?,
+                row.get::<_, i64>(6)?,
+                row.get::<_, String>(7)?,
+                row.get::<_, i64>(8)?,
+                row.get::<_, Option<i64>>(9)?,
+                row.get::<_, Option<i64>>(10)?,
+                row.get::<_, Option<i64>>(11)?,
+            ))
+        })?;
+        let micros = |us: i64| Duration::from_micros(us as u64);
+        rows.map(|row| {
+            let (id, started, kernel, args, hash, heap, stack, exit, total, setup, evolve, out) =
+                row?;
+            Ok((
+                id,
+                HistoryEntry {
+                    started: UNIX_EPOCH + Duration::from_millis(started as u64),
+                    kernel: kernel.into(),
+                    args: serde_json::from_str(&args).context("malformed args in history")?,
+                    inputs_sha256: hash,
+                    heap_size: heap as u64,
+                    stack_size: stack as u64,
+                    exit: serde_json::from_str(&exit).context("malformed exit in history")?,
+                    total_time: micros(total),
+                    setup_time: setup.map(micros),
+                    evolve_time: evolve.map(micros),
+                    output_bytes: out.map(|n| n as u64),
+                },
+            ))
+        })
+        .collect()
+    }
+
+    /// Record every run of `config` via a pre-run hook (inputs) and a
+    /// post-run hook (outcome). Runs that fail before the pre-run hooks
+    /// (e.g. in the initrd pipeline) aren't recorded. Write errors are
+    /// logged rather than failing the run.
+    pub fn attach(self: &Arc<Self>, config: VmConfig) -> VmConfig {
+        let (heap_size, stack_size) = (config.heap_size, config.stack_size);
+        let pre = self.clone();
+        let post = self.clone();
+        config
+            .with_pre_run_hook(move |assets: &RunAssets<'_>| {
+                let pending = Pending {
+                    started: SystemTime::now(),
+                    clock: Instant::now(),
+                    kernel: assets.kernel_path.to_path_buf(),
+                    args: assets.app_args.to_vec(),
+                    inputs_sha256: inputs_hash(
+                        assets.kernel_path,
+                        assets.app_args,
+                        assets.initrd.and_then(|i| i.body()),
+                    ),
+                };
+                pre.pending_lock()
+                    .insert(std::thread::current().id(), pending);
+                Ok(())
+            })
+            .with_post_run_hook(move |result| {
+                let Some(p) = post.pending_lock().remove(&std::thread::current().id()) else {
+                    return;
+                };
+                let output = result.ok();
+                let exit = match result {
+                    Ok(_) => VmExit::Halt,
+                    Err(e) => match e.downcast_ref::<hyperlight_host::HyperlightError>() {
+                        Some(hl) => VmExit::classify(hl),
+                        None => VmExit::UnexpectedVmExit(e.to_string()),
+                    },
+                };
+                let entry = HistoryEntry {
+                    started: p.started,
+                    kernel: p.kernel,
+                    args: p.args,
+                    inputs_sha256: p.inputs_sha256,
+                    heap_size,
+                    stack_size,
+                    exit,
+                    total_time: p.clock.elapsed(),
+                    setup_time: output.map(|o| o.setup_time),
+                    evolve_time: output.map(|o| o.evolve_time),
+                    output_bytes: output.map(|o| o.raw.len() as u64),
+                };
+                if let Err(e) = post.record(&entry) {
+                    tracing::warn!("recording run history: {e:#}");
+                }
+            })
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn pending_lock(&self) -> std::sync::MutexGuard<'_, HashMap<ThreadId, Pending>> {
+        self.pending.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// SHA-256 over the kernel path, the arguments and the rootfs, so runs
+/// of the same inputs can be grouped. The boot header is left out: it
+/// carries the wall clock and differs on every run.
+pub fn inputs_hash(kernel: &Path, args: &[String], rootfs: Option<&[u8]>) -> String {
+    let mut h = Sha256::new();
+    h.update(kernel.to_string_lossy().as_bytes());
+    h.update([0]);
+    for arg in args {
+        h.update(arg.as_bytes());
+        h.update([0]);
+    }
+    if let Some(rootfs) = rootfs {
+        h.update(rootfs);
+    }
+    h.finalize().iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn unix_ms(t: SystemTime) -> i64 {
+    t.duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(started_s: u64, exit: VmExit) -> HistoryEntry {
+        HistoryEntry {
+            started: UNIX_EPOCH + Duration::from_secs(started_s),
+            kernel: "kernel".into(),
+            args: vec!["/app.py".into(), "--n".into()],
+            inputs_sha256: inputs_hash(Path::new("kernel"), &[], None),
+            heap_size: 512 << 20,
+            stack_size: 8 << 20,
+            exit,
+            total_time: Duration::from_millis(120),
+            setup_time: Some(Duration::from_millis(40)),
+            evolve_time: None,
+            output_bytes: Some(6),
+        }
+    }
+
+    #[test]
+    fn entries_roundtrip_and_filter_by_time_and_failure() {
+        let dir = std::env::temp_dir().join(format!("hl-history-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let history = RunHistory::open(dir.join("runs.sqlite")).unwrap();
+        let ok = entry(100, VmExit::Halt);
+        let oom = entry(200, VmExit::OutOfMemory);
+        history.record(&ok).unwrap();
+        let oom_id = history.record(&oom).unwrap();
+        history.record(&entry(300, VmExit::Halt)).unwrap();
+
+        let all = history.query(&HistoryFilter::default()).unwrap();
+        assert_eq!(all.len(), 3);
+        assert_eq!(all[2].1, ok);
+
+        let failed = history
+            .query(&HistoryFilter {
+                failed_only: true,
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(failed, [(oom_id, oom)]);
+
+        let recent = history
+            .query(&HistoryFilter {
+                since: Some(UNIX_EPOCH + Duration::from_secs(150)),
+                limit: Some(1),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(recent.len(), 1);
+        assert_eq!(recent[0].1.started, UNIX_EPOCH + Duration::from_secs(300));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn attached_hooks_record_runs_that_reach_them() {
+        let dir = std::env::temp_dir().join(format!("hl-history-hook-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let history = Arc::new(RunHistory::open(dir.join("runs.sqlite")).unwrap());
+        let config = history.attach(VmConfig::default().with_heap_size(64 << 20));
+        let err = crate::run_vm(
+            Path::new("/nonexistent/kernel"),
+            Some(b"070701"),
+            &["/app.py".to_string()],
+            config,
+        )
+        .unwrap_err();
+
+        let runs = history.query(&HistoryFilter::default()).unwrap();
+        assert_eq!(runs.len(), 1, "{err}");
+        let run = &runs[0].1;
+        assert_eq!(run.kernel, Path::new("/nonexistent/kernel"));
+        assert_eq!(run.heap_size, 64 << 20);
+        assert!(!run.exit.is_halt());
+        assert_eq!(
+            run.inputs_sha256,
+            inputs_hash(run.kernel.as_path(), &run.args, Some(b"070701"))
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
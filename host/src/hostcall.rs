@@ -0,0 +1,314 @@
+//! Watching the guest's calls across the host boundary.
+//!
+//! Every host function the guest reaches through `__dispatch` can be
+//! reported to [`CallObserver`]s attached with
+//! [`SandboxBuilder::observe_calls`](crate::SandboxBuilder::observe_calls)
+//! or [`VmConfig::with_call_observer`](crate::VmConfig::with_call_observer).
+//! [`CallTrace`] is the strace-like observer behind the CLI's
+//! `--trace-calls FILE`: one line per call with its start time, tool,
+//! arguments, response and duration.
+//!
+//! ```text
+//!   0.003112 fs_stat({"path":"/host/data.csv"}) = {"result":{"is_dir":false,"size":812}} <0.000041>
+//!   0.003190 fs_read_bytes({"path":"/host/data.csv","offset":0,"len":812}) = {"result":...} <0.000052>
+//! ```
+//!
+//! Console output doesn't appear: the guest writes it through
+//! Hyperlight's own port I/O handler, which never reaches this crate.
+//!
+//! [`CallStats`] aggregates instead: call counts, errors and a latency
+//! histogram per tool, so chatty guests (a `print` per line, a read per
+//! byte) stand out as candidates for batching. Every call is also
+//! counted in the process-wide [`metrics::host_calls`], and
+//! [`VmConfig::with_call_stats`](crate::VmConfig::with_call_stats) puts
+//! a run's own summary in [`VmOutput::host_calls`](crate::VmOutput::host_calls).
+
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::metrics::{self, Histogram};
+
+/// Longest argument or response shown in a [`CallTrace`] line; the rest
+/// is elided with its length.
+pub const TRACE_PREVIEW_LEN: usize = 200;
+
+/// One completed host call.
+#[derive(Debug, Clone, Copy)]
+pub struct HostCall<'a> {
+    /// The Hyperlight host function the guest called.
+    pub function: &'a str,
+    /// The tool named in the request, if it parsed.
+    pub tool: Option<&'a str>,
+    pub request: &'a [u8],
+    pub response: &'a [u8],
+    pub started: Instant,
+    pub duration: Duration,
+    /// Whether the handler succeeded rather than returning an error.
+    pub ok: bool,
+}
+
+/// Told about every host call of the sandboxes it is attached to, on
+/// the vCPU thread, after the handler has returned.
+pub trait CallObserver: Send + Sync {
+    fn observe(&self, call: &HostCall<'_>);
+}
+
+/// Writes an strace-like line per host call. Times are seconds since
+/// the trace was created; each line is flushed as it is written, so a
+/// guest that crashes leaves everything up to its last call.
+pub struct CallTrace {
+    out: Mutex<Box<dyn Write + Send>>,
+    start: Instant,
+}
+
+impl CallTrace {
+    /// Trace into a new file at `path`, truncating any existing one.
+    pub fn create(path: &Path) -> Result<Self> {
+        let file =
+            std::fs::File::create(path).with_context(|| format!("creating call trace {path:?}"))?;
+        Ok(Self::to_writer(BufWriter::new(file)))
+    }
+
+    pub fn to_writer(out: impl Write + Send + 'static) -> Self {
+        Self {
+            out: Mutex::new(Box::new(out)),
+            start: Instant::now(),
+        }
+    }
+}
+
+impl CallObserver for CallTrace {
+    fn observe(&self, call: &HostCall<'_>) {
+        let at = call.started.saturating_duration_since(self.start);
+        let args = call
+            .tool
+            .and_then(|_| serde_json::from_slice::<serde_json::Value>(call.request).ok())
+            .map(|req| {
+                req.get("args")
+                    .unwrap_or(&serde_json::Value::Null)
+                    .to_string()
+            });
+        let line = format!(
+            "{:>10.6} {}({}) = {} <{:.6}>\n",
+            at.as_secs_f64(),
+            call.tool.unwrap_or(call.function),
+            preview(args.as_deref().map_or(call.request, str::as_bytes)),
+            preview(call.response),
+            call.duration.as_secs_f64(),
+        );
+        let mut out = self.out.lock().unwrap_or_else(|e| e.into_inner());
+        // A full disk shouldn't take the guest down with it.
+        let _ = out.write_all(line.as_bytes()).and_then(|()| out.flush());
+    }
+}
+
+/// Call counts and latencies per tool. Calls whose request didn't parse
+/// are counted under the host function's name.
+#[derive(Debug, Default)]
+pub struct CallStats {
+    tools: Mutex<BTreeMap<String, ToolCalls>>,
+}
+
+#[derive(Debug, Default)]
+struct ToolCalls {
+    latency: Histogram,
+    errors: u64,
+}
+
+/// One tool's calls, as summarized by [`CallStats::summary`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CallSummary {
+    pub tool: String,
+    pub count: u64,
+    /// Calls whose handler returned an error (unknown tools included).
+    pub errors: u64,
+    /// Time spent in the host across all calls.
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "total_us", with = "crate::trace::micros")
+    )]
+    pub total: Duration,
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "p50_us", with = "crate::trace::micros")
+    )]
+    pub p50: Duration,
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "p99_us", with = "crate::trace::micros")
+    )]
+    pub p99: Duration,
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "max_us", with = "crate::trace::micros")
+    )]
+    pub max: Duration,
+}
+
+impl CallStats {
+    pub fn record(&self, tool: &str, duration: Duration, ok: bool) {
+        let mut tools = self.lock();
+        let calls = match tools.get_mut(tool) {
+            Some(calls) => calls,
+            None => tools.entry(tool.to_string()).or_default(),
+        };
+        calls.latency.record(duration);
+        calls.errors += u64::from(!ok);
+    }
+
+    /// Every tool called so far, most total host time first.
+    pub fn summary(&self) -> Vec<CallSummary> {
+        let mut out: Vec<CallSummary> = self
+            .lock()
+            .iter()
+            .map(|(tool, calls)| CallSummary {
+                tool: tool.clone(),
+                count: calls.latency.count(),
+                errors: calls.errors,
+                total: calls.latency.sum(),
+                p50: calls.latency.percentile(0.5).unwrap_or_default(),
+                p99: calls.latency.percentile(0.99).unwrap_or_default(),
+                max: calls.latency.max().unwrap_or_default(),
+            })
+            .collect();
+        out.sort_by(|a, b| b.total.cmp(&a.total).then_with(|| a.tool.cmp(&b.tool)));
+        out
+    }
+
+    /// Forget every call.
+    pub fn reset(&self) {
+        self.lock().clear();
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, BTreeMap<String, ToolCalls>> {
+        self.tools.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl CallObserver for CallStats {
+    fn observe(&self, call: &HostCall<'_>) {
+        self.record(call.tool.unwrap_or(call.function), call.duration, call.ok);
+    }
+}
+
+/// Count `call` in [`metrics::host_calls`]. Only registered tools get
+/// their own entry; anything else a guest asks for lands under
+/// `unknown`, so a misbehaving guest can't grow the set without bound.
+pub(crate) fn record_global(call: &HostCall<'_>, known: bool) {
+    let tool = match call.tool {
+        Some(tool) if known => tool,
+        _ => "unknown",
+    };
+    metrics::host_calls().record(tool, call.duration, call.ok);
+}
+
+/// `bytes` as text, cut at [`TRACE_PREVIEW_LEN`].
+fn preview(bytes: &[u8]) -> String {
+    let text = String::from_utf8_lossy(bytes);
+    let mut end = text.len().min(TRACE_PREVIEW_LEN);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    let mut out = text[..end].to_string();
+    if end < text.len() {
+        let _ = write!(out, "...(+{} bytes)", text.len() - end);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ToolRegistry;
+    use serde_json::json;
+    use std::sync::Arc;
+
+    /// A writer the test can read back.
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn trace_logs_each_call_with_args_result_and_timing() {
+        let buf = Shared::default();
+        let mut registry = ToolRegistry::new();
+        registry.register("echo", Ok);
+        registry.register("fail", |_| anyhow::bail!("nope"));
+        registry.observe(Arc::new(CallTrace::to_writer(buf.clone())));
+
+        registry.dispatch(
+            json!({ "name": "echo", "args": { "n": 1 } })
+                .to_string()
+                .as_bytes(),
+        );
+        registry.dispatch(json!({ "name": "fail" }).to_string().as_bytes());
+        registry.dispatch(b"not json");
+        let big = "x".repeat(500);
+        registry.dispatch(
+            json!({ "name": "echo", "args": big })
+                .to_string()
+                .as_bytes(),
+        );
+
+        let text = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 4);
+        let call = |line: &str| line.trim_start().split_once(' ').unwrap().1.to_string();
+        assert!(call(lines[0]).starts_with(r#"echo({"n":1}) = {"result":{"n":1}} <0."#));
+        assert!(call(lines[1]).starts_with(r#"fail(null) = {"error":"nope"} <"#));
+        assert!(call(lines[2]).starts_with(r#"__dispatch(not json) = {"error":"#));
+        assert!(lines[3].contains("...(+302 bytes)) = "));
+        // Start times are monotonic seconds since the trace began.
+        let at = |line: &str| {
+            line.split_whitespace()
+                .next()
+                .unwrap()
+                .parse::<f64>()
+                .unwrap()
+        };
+        assert!(at(lines[0]) <= at(lines[3]));
+    }
+
+    #[test]
+    fn stats_count_calls_and_errors_per_tool() {
+        let stats = Arc::new(CallStats::default());
+        let mut registry = ToolRegistry::new();
+        registry.register("echo", Ok);
+        registry.observe(stats.clone());
+        for _ in 0..3 {
+            registry.dispatch(json!({ "name": "echo", "args": 1 }).to_string().as_bytes());
+        }
+        registry.dispatch(json!({ "name": "nope" }).to_string().as_bytes());
+        registry.dispatch(b"{");
+
+        let summary = stats.summary();
+        let get = |tool: &str| summary.iter().find(|s| s.tool == tool).unwrap();
+        assert_eq!(summary.len(), 3);
+        assert_eq!((get("echo").count, get("echo").errors), (3, 0));
+        assert_eq!((get("nope").count, get("nope").errors), (1, 1));
+        assert_eq!((get("__dispatch").count, get("__dispatch").errors), (1, 1));
+        assert!(get("echo").p50 <= get("echo").max);
+        // Unknown names share one process-wide entry.
+        let global = metrics::host_calls().summary();
+        assert!(global.iter().any(|s| s.tool == "unknown"));
+        assert!(!global.iter().any(|s| s.tool == "nope"));
+
+        stats.reset();
+        assert!(stats.summary().is_empty());
+    }
+}
@@ -0,0 +1,730 @@
+//! Built-in host functions served to the guest over `__dispatch`.
+//!
+//! Each `register_*` function adds one or more handlers to a
+//! [`ToolRegistry`]; [`SandboxBuilder`](crate::SandboxBuilder) has a
+//! matching method for each, and the CLI's `--enable-tools` turns on the
+//! ones that need no configuration.
+
+use anyhow::{anyhow, Result};
+use base64::Engine;
+use serde_json::json;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::ToolRegistry;
+
+/// Largest `getrandom` request served in one call. Guests needing more
+/// loop, as they would on `getrandom(2)`.
+pub const GETRANDOM_MAX_LEN: u64 = 64 * 1024;
+
+/// Register `getrandom`: `{ len }` → `{ data: "<base64>" }`, `len` bytes
+/// from the host OS's CSPRNG. Gives guests without a virtio-rng
+/// equivalent a non-blocking source of real entropy.
+pub fn register_getrandom(registry: &mut ToolRegistry) {
+    registry.register("getrandom", |args| {
+        let len = args["len"]
+            .as_u64()
+            .ok_or_else(|| anyhow!("getrandom: missing 'len'"))?;
+        Ok(json!({ "data": base64::engine::general_purpose::STANDARD.encode(random_bytes(len)?) }))
+    });
+}
+
+/// Register `clock_realtime`: `{}` → `{ ns }`, the current wall-clock
+/// time in nanoseconds since the Unix epoch — the unit of the boot
+/// header's one-shot `HLWALL0` value. Long-running guests call it to
+/// resync instead of drifting from that boot-time reading.
+///
+/// With `pinned` (a replayed run's fixed boot time), the clock starts at
+/// that time and advances with the host's monotonic clock, so it stays
+/// consistent with what the guest saw at boot.
+pub fn register_clock(registry: &mut ToolRegistry, pinned: Option<SystemTime>) {
+    let origin = Instant::now();
+    registry.register("clock_realtime", move |_| {
+        let now = match pinned {
+            Some(t) => t + origin.elapsed(),
+            None => SystemTime::now(),
+        };
+        let ns = now
+            .duration_since(UNIX_EPOCH)
+            .map_err(|_| anyhow!("clock_realtime: host clock is before 1970"))?
+            .as_nanos() as u64;
+        Ok(json!({ "ns": ns }))
+    });
+}
+
+/// The host's request that the guest wind down, polled by the guest
+/// through the `shutdown_requested` tool. Set by
+/// [`VmHandle::shutdown`](crate::VmHandle::shutdown) ahead of its grace
+/// period; guests that poll it (e.g. from their main loop) can flush
+/// output and files and exit cleanly before being interrupted.
+#[derive(Debug, Default)]
+pub struct ShutdownSignal {
+    requested: AtomicBool,
+}
+
+impl ShutdownSignal {
+    pub fn request(&self) {
+        self.requested.store(true, Ordering::Release);
+    }
+
+    /// Withdraw the request, e.g. before the next run of a restored
+    /// sandbox.
+    pub fn clear(&self) {
+        self.requested.store(false, Ordering::Release);
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::Acquire)
+    }
+
+    /// Register `shutdown_requested`: `{}` → `{ requested }`.
+    pub fn register(self: &Arc<Self>, registry: &mut ToolRegistry) {
+        let signal = self.clone();
+        registry.register("shutdown_requested", move |_| {
+            Ok(json!({ "requested": signal.is_requested() }))
+        });
+    }
+}
+
+/// Largest `stdin_read` chunk served in one call.
+pub const STDIN_READ_MAX_LEN: u64 = 1024 * 1024;
+
+/// Input for the guest to read as its standard input, through the
+/// `stdin_read` tool; set with
+/// [`VmConfig::with_stdin`](crate::VmConfig::with_stdin). Lets a host
+/// feed a script its data (a CSV, a JSON request) without rebuilding
+/// the rootfs. Input is consumed as it is read: a restored sandbox
+/// called again continues where the last call stopped.
+pub struct Stdin {
+    source: Mutex<Box<dyn Read + Send>>,
+    interactive: bool,
+}
+
+impl Stdin {
+    pub fn from_bytes(data: impl Into<Vec<u8>>) -> Self {
+        Self::from_reader(std::io::Cursor::new(data.into()))
+    }
+
+    /// Stream from `reader`, read only as the guest asks for more.
+    pub fn from_reader(reader: impl Read + Send + 'static) -> Self {
+        Self {
+            source: Mutex::new(Box::new(reader)),
+            interactive: false,
+        }
+    }
+
+    /// Like [`from_reader`](Self::from_reader) for a terminal or other
+    /// live source: a read returns whatever one `read` of `reader` gives
+    /// instead of waiting to fill the request, so a REPL sees each line
+    /// as it is typed. Only an empty read is the end of the input.
+    pub fn interactive(reader: impl Read + Send + 'static) -> Self {
+        Self {
+            interactive: true,
+            ..Self::from_reader(reader)
+        }
+    }
+
+    /// Read up to `max` bytes; fewer only at the end of the input, or
+    /// whenever an [`interactive`](Self::interactive) source has no more
+    /// yet.
+    pub fn read(&self, max: usize) -> std::io::Result<Vec<u8>> {
+        let mut source = self.source.lock().unwrap_or_else(|e| e.into_inner());
+        let mut buf = Vec::with_capacity(max);
+        if self.interactive {
+            buf.resize(max, 0);
+            let n = loop {
+                match source.read(&mut buf) {
+                    Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                    result => break result?,
+                }
+            };
+            buf.truncate(n);
+            return Ok(buf);
+        }
+        (&mut **source).take(max as u64).read_to_end(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// Register `stdin_read`: `{ max }` → `{ data: "<base64>", eof }`.
+    /// `eof` is set once the input is exhausted, so the guest can stop
+    /// without another round trip.
+    pub fn register(self: &Arc<Self>, registry: &mut ToolRegistry) {
+        let stdin = self.clone();
+        registry.register("stdin_read", move |args| {
+            let max = args["max"].as_u64().unwrap_or(STDIN_READ_MAX_LEN);
+            if max == 0 || max > STDIN_READ_MAX_LEN {
+                return Err(anyhow!("stdin_read: 'max' must be 1..={STDIN_READ_MAX_LEN}"));
+            }
+            let data = stdin
+                .read(max as usize)
+                .map_err(|e| anyhow!("stdin_read: {e}"))?;
+            let eof = if stdin.interactive {
+                data.is_empty()
+            } else {
+                (data.len() as u64) < max
+            };
+            Ok(json!({ "data": base64::engine::general_purpose::STANDARD.encode(&data), "eof": eof }))
+        });
+    }
+}
+
+impl std::fmt::Debug for Stdin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Stdin").finish_non_exhaustive()
+    }
+}
+
+/// Most bytes of output files one run may send back.
+pub const OUTPUT_FILES_MAX_BYTES: u64 = 256 * 1024 * 1024;
+
+/// Files the guest hands back through the `output_file` tool, for
+/// [`VmOutput::files`](crate::VmOutput::files). They travel over
+/// `__dispatch`, not the console, so output limits, tees and sinks never
+/// see them. Capturing runs register one per sandbox.
+#[derive(Debug, Default)]
+pub struct OutputFiles {
+    files: Mutex<(HashMap<PathBuf, Vec<u8>>, u64)>,
+}
+
+impl OutputFiles {
+    /// Append `data` to the file at `path`, failing once the run's files
+    /// would exceed [`OUTPUT_FILES_MAX_BYTES`].
+    pub fn append(&self, path: &str, data: &[u8]) -> Result<u64> {
+        if path.is_empty() {
+            return Err(anyhow!("output_file: empty 'path'"));
+        }
+        let mut guard = self.files.lock().unwrap_or_else(|e| e.into_inner());
+        let (files, total) = &mut *guard;
+        let grown = *total + data.len() as u64;
+        if grown > OUTPUT_FILES_MAX_BYTES {
+            return Err(anyhow!(
+                "output_file: output files exceed {OUTPUT_FILES_MAX_BYTES} bytes"
+            ));
+        }
+        *total = grown;
+        let file = files.entry(PathBuf::from(path)).or_default();
+        file.extend_from_slice(data);
+        Ok(file.len() as u64)
+    }
+
+    /// The files sent so far, leaving none, e.g. at the end of a run.
+    pub fn take(&self) -> HashMap<PathBuf, Vec<u8>> {
+        let mut guard = self.files.lock().unwrap_or_else(|e| e.into_inner());
+        std::mem::take(&mut *guard).0
+    }
+
+    /// Register `output_file`: `{ path, data: "<base64>" }` → `{ size }`.
+    /// Each call appends `data` to `path`, so a large file can be sent in
+    /// chunks; a call without `data` creates an empty file. `size` is the
+    /// file's length so far.
+    pub fn register(self: &Arc<Self>, registry: &mut ToolRegistry) {
+        let files = self.clone();
+        registry.register("output_file", move |args| {
+            let path = args["path"]
+                .as_str()
+                .ok_or_else(|| anyhow!("output_file: missing 'path'"))?;
+            let data = base64::engine::general_purpose::STANDARD
+                .decode(args["data"].as_str().unwrap_or_default())
+                .map_err(|e| anyhow!("output_file: 'data': {e}"))?;
+            Ok(json!({ "size": files.append(path, &data)? }))
+        });
+    }
+}
+
+/// Most files a guest may hold open through `host_open` at once.
+pub const HOST_READ_MAX_OPEN: usize = 64;
+
+/// Largest `host_read` chunk served in one call.
+pub const HOST_READ_MAX_LEN: u64 = 1024 * 1024;
+
+/// Read-only access to an operator-chosen set of host files and
+/// directories, by host path. Lets guests pull large reference data
+/// (models, datasets) lazily instead of packing it into the initrd.
+///
+/// Unlike [`FsSandbox`](crate::FsSandbox) there is no guest-side mount:
+/// the guest names absolute host paths directly. A path is served only
+/// if it is an allowlisted entry or lies under an allowlisted directory
+/// as written: `..` components are rejected, and on Unix the file is
+/// opened one component at a time from a handle on the entry taken at
+/// construction, refusing symlinks, so swapping a path component for a
+/// link after the check can't redirect the read. Missing and disallowed
+/// paths get the same error, so guests can't probe the host.
+#[derive(Debug, Clone)]
+pub struct ReadAllowlist {
+    roots: Vec<ReadRoot>,
+}
+
+/// One allowlist entry.
+#[derive(Debug, Clone)]
+struct ReadRoot {
+    /// The entry's canonical path.
+    path: PathBuf,
+    /// The entry if it is a directory, else its parent.
+    #[cfg(unix)]
+    dir: Arc<File>,
+    /// For a file entry, its name in `dir`.
+    #[cfg(unix)]
+    file: Option<std::ffi::OsString>,
+}
+
+impl ReadAllowlist {
+    /// Allow reading each of `paths` (files, or directories and
+    /// everything under them). Every entry must exist.
+    pub fn new<I, P>(paths: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<Path>,
+    {
+        let roots = paths
+            .into_iter()
+            .map(|p| {
+                let p = p.as_ref();
+                ReadRoot::new(p).map_err(|e| anyhow!("open read allowlist entry {:?}: {}", p, e))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { roots })
+    }
+
+    /// Open `path` for reading if the allowlist covers it. Any failure,
+    /// including a missing file, is `host path not readable`.
+    pub fn open(&self, path: &str) -> Result<File> {
+        let denied = || anyhow!("host path not readable: {:?}", path);
+        let requested = Path::new(path);
+        if !requested.is_absolute() {
+            return Err(denied());
+        }
+        let mut lexical = PathBuf::from("/");
+        for component in requested.components() {
+            match component {
+                std::path::Component::Normal(part) => lexical.push(part),
+                std::path::Component::RootDir | std::path::Component::Prefix(_) => {}
+                _ => return Err(denied()),
+            }
+        }
+        self.roots
+            .iter()
+            .find_map(|root| root.open(&lexical))
+            .and_then(|opened| opened.ok())
+            .ok_or_else(denied)
+    }
+
+    /// Register the read proxy handlers on `registry`:
+    ///
+    /// - `host_stat` — `{ path }` → `{ size, is_dir, is_file }`.
+    /// - `host_open` — `{ path }` → `{ fd, size }`; regular files only.
+    /// - `host_read` — `{ fd, offset?, len? }` → `{ data: "<base64>",
+    ///   eof, bytes_read }`. Reads are positional, so one handle can
+    ///   serve out-of-order chunks.
+    /// - `host_close` — `{ fd }` → `{}`.
+    ///
+    /// Handles are per registry and capped at [`HOST_READ_MAX_OPEN`].
+    pub fn register(self, registry: &mut ToolRegistry) {
+        let files: Arc<Mutex<OpenFiles>> = Arc::default();
+
+        let a = self.clone();
+        registry.register("host_stat", move |args| {
+            let path = args["path"]
+                .as_str()
+                .ok_or_else(|| anyhow!("host_stat: missing 'path'"))?;
+            let meta = a
+                .open(path)?
+                .metadata()
+                .map_err(|e| anyhow!("host_stat {:?}: {}", path, e))?;
+            Ok(json!({ "size": meta.len(), "is_dir": meta.is_dir(), "is_file": meta.is_file() }))
+        });
+
+        let f = files.clone();
+        registry.register("host_open", move |args| {
+            let path = args["path"]
+                .as_str()
+                .ok_or_else(|| anyhow!("host_open: missing 'path'"))?;
+            // The checks below are on the handle that will be read.
+            let file = self.open(path)?;
+            let meta = file
+                .metadata()
+                .map_err(|e| anyhow!("host_open {:?}: {}", path, e))?;
+            if !meta.is_file() {
+                return Err(anyhow!("host_open {:?}: not a regular file", path));
+            }
+            let fd = lock(&f).insert(file)?;
+            Ok(json!({ "fd": fd, "size": meta.len() }))
+        });
+
+        let f = files.clone();
+        registry.register("host_read", move |args| {
+            let fd = args["fd"]
+                .as_u64()
+                .ok_or_else(|| anyhow!("host_read: missing 'fd'"))?;
+            let offset = args["offset"].as_u64().unwrap_or(0);
+            let want = args["len"].as_u64().unwrap_or(65536).min(HOST_READ_MAX_LEN);
+            let files = lock(&f);
+            let file = files.get(fd)?;
+            let mut buf = vec![0u8; want as usize];
+            let n = read_at(file, &mut buf, offset)
+                .map_err(|e| anyhow!("host_read fd {}: {}", fd, e))?;
+            buf.truncate(n);
+            let encoded = base64::engine::general_purpose::STANDARD.encode(&buf);
+            Ok(json!({ "data": encoded, "eof": n < want as usize, "bytes_read": n }))
+        });
+
+        registry.register("host_close", move |args| {
+            let fd = args["fd"]
+                .as_u64()
+                .ok_or_else(|| anyhow!("host_close: missing 'fd'"))?;
+            lock(&files).remove(fd)?;
+            Ok(json!({}))
+        });
+    }
+}
+
+impl ReadRoot {
+    #[cfg(unix)]
+    fn new(entry: &Path) -> std::io::Result<Self> {
+        let path = std::fs::canonicalize(entry)?;
+        let (dir, file) = if path.is_dir() {
+            (File::open(&path)?, None)
+        } else {
+            let parent = path.parent().unwrap_or(Path::new("/"));
+            (File::open(parent)?, path.file_name().map(Into::into))
+        };
+        Ok(Self {
+            path,
+            dir: Arc::new(dir),
+            file,
+        })
+    }
+
+    #[cfg(not(unix))]
+    fn new(entry: &Path) -> std::io::Result<Self> {
+        Ok(Self {
+            path: std::fs::canonicalize(entry)?,
+        })
+    }
+
+    /// Open `requested`, an absolute path free of `..`, if it is this
+    /// entry or under it; `None` if it isn't.
+    #[cfg(unix)]
+    fn open(&self, requested: &Path) -> Option<std::io::Result<File>> {
+        use std::ffi::OsStr;
+        let parts: Vec<&OsStr> = match self.file {
+            Some(ref name) => (requested == self.path).then(|| vec![name.as_os_str()])?,
+            None => requested.strip_prefix(&self.path).ok()?.iter().collect(),
+        };
+        Some(open_beneath(&self.dir, &parts))
+    }
+
+    /// Windows has no `openat`: the check and the open are separate
+    /// steps there.
+    #[cfg(not(unix))]
+    fn open(&self, requested: &Path) -> Option<std::io::Result<File>> {
+        requested.starts_with(&self.path).then(|| {
+            let resolved = std::fs::canonicalize(requested)?;
+            if !resolved.starts_with(&self.path) {
+                return Err(std::io::ErrorKind::PermissionDenied.into());
+            }
+            File::open(resolved)
+        })
+    }
+}
+
+/// Open `dir/parts...` one component at a time, refusing symlinks, so
+/// the file opened is the one under `dir` whatever the rest of the
+/// filesystem does meanwhile. No parts opens `dir` itself again.
+#[cfg(unix)]
+fn open_beneath(dir: &File, parts: &[&std::ffi::OsStr]) -> std::io::Result<File> {
+    use nix::fcntl::{openat, OFlag};
+    use nix::sys::stat::Mode;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+    let flags = OFlag::O_RDONLY | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC | OFlag::O_NONBLOCK;
+    let mut current: Option<OwnedFd> = None;
+    for (i, part) in parts.iter().enumerate() {
+        let at = current.as_ref().map_or(dir.as_raw_fd(), AsRawFd::as_raw_fd);
+        let flags = if i + 1 == parts.len() {
+            flags
+        } else {
+            flags | OFlag::O_DIRECTORY
+        };
+        let fd = openat(Some(at), *part, flags, Mode::empty())?;
+        // SAFETY: `openat` just returned this descriptor and nothing
+        // else owns it.
+        current = Some(unsafe { OwnedFd::from_raw_fd(fd) });
+    }
+    match current {
+        Some(fd) => Ok(File::from(fd)),
+        None => dir.try_clone(),
+    }
+}
+
+#[derive(Default)]
+struct OpenFiles {
+    next: u64,
+    files: HashMap<u64, File>,
+}
+
+impl OpenFiles {
+    fn insert(&mut self, file: File) -> Result<u64> {
+        if self.files.len() >= HOST_READ_MAX_OPEN {
+            return Err(anyhow!(
+                "host_open: too many open files (limit {})",
+                HOST_READ_MAX_OPEN
+            ));
+        }
+        self.next += 1;
+        self.files.insert(self.next, file);
+        Ok(self.next)
+    }
+
+    fn get(&self, fd: u64) -> Result<&File> {
+        self.files
+            .get(&fd)
+            .ok_or_else(|| anyhow!("bad host fd: {}", fd))
+    }
+
+    fn remove(&mut self, fd: u64) -> Result<()> {
+        self.files
+            .remove(&fd)
+            .map(drop)
+            .ok_or_else(|| anyhow!("bad host fd: {}", fd))
+    }
+}
+
+fn lock(files: &Mutex<OpenFiles>) -> std::sync::MutexGuard<'_, OpenFiles> {
+    files.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Fill as much of `buf` as the file has from `offset` on.
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+    let mut done = 0;
+    while done < buf.len() {
+        #[cfg(unix)]
+        let n = std::os::unix::fs::FileExt::read_at(file, &mut buf[done..], offset + done as u64);
+        #[cfg(windows)]
+        let n =
+            std::os::windows::fs::FileExt::seek_read(file, &mut buf[done..], offset + done as u64);
+        match n {
+            Ok(0) => break,
+            Ok(n) => done += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(done)
+}
+
+fn random_bytes(len: u64) -> Result<Vec<u8>> {
+    if len > GETRANDOM_MAX_LEN {
+        return Err(anyhow!(
+            "getrandom: len {} exceeds the {} byte limit",
+            len,
+            GETRANDOM_MAX_LEN
+        ));
+    }
+    let mut buf = vec![0u8; len as usize];
+    getrandom::fill(&mut buf).map_err(|e| anyhow!("getrandom: {}", e))?;
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shutdown_requested_follows_the_signal() {
+        let signal = Arc::new(ShutdownSignal::default());
+        let mut reg = ToolRegistry::new();
+        signal.register(&mut reg);
+        assert_eq!(
+            call(&reg, "shutdown_requested", json!({}))["result"]["requested"],
+            false
+        );
+        signal.request();
+        assert_eq!(
+            call(&reg, "shutdown_requested", json!({}))["result"]["requested"],
+            true
+        );
+        signal.clear();
+        assert!(!signal.is_requested());
+    }
+
+    fn call(registry: &ToolRegistry, name: &str, args: serde_json::Value) -> serde_json::Value {
+        let req = json!({ "name": name, "args": args });
+        serde_json::from_slice(&registry.dispatch(req.to_string().as_bytes())).unwrap()
+    }
+
+    #[test]
+    fn getrandom_serves_the_requested_length() {
+        let mut registry = ToolRegistry::new();
+        register_getrandom(&mut registry);
+
+        let decode = |v: &serde_json::Value| {
+            base64::engine::general_purpose::STANDARD
+                .decode(v["result"]["data"].as_str().unwrap())
+                .unwrap()
+        };
+        let a = decode(&call(&registry, "getrandom", json!({ "len": 32 })));
+        let b = decode(&call(&registry, "getrandom", json!({ "len": 32 })));
+        assert_eq!(a.len(), 32);
+        assert_ne!(a, b);
+
+        let too_big = call(
+            &registry,
+            "getrandom",
+            json!({ "len": GETRANDOM_MAX_LEN + 1 }),
+        );
+        assert!(too_big["error"].as_str().unwrap().contains("limit"));
+        assert!(call(&registry, "getrandom", json!({}))["error"].is_string());
+    }
+
+    #[test]
+    fn clock_reports_now_or_advances_from_the_pinned_time() {
+        let ns = |registry: &ToolRegistry| {
+            call(registry, "clock_realtime", json!({}))["result"]["ns"]
+                .as_u64()
+                .unwrap()
+        };
+        let mut live = ToolRegistry::new();
+        register_clock(&mut live, None);
+        let before = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos() as u64;
+        assert!(ns(&live) >= before);
+
+        let mut pinned = ToolRegistry::new();
+        register_clock(
+            &mut pinned,
+            Some(UNIX_EPOCH + std::time::Duration::from_secs(1_000)),
+        );
+        let first = ns(&pinned);
+        assert!((1_000_000_000_000..1_010_000_000_000).contains(&first));
+        assert!(ns(&pinned) >= first);
+    }
+
+    #[test]
+    fn output_files_are_appended_in_chunks_and_taken() {
+        let files = Arc::new(OutputFiles::default());
+        let mut registry = ToolRegistry::new();
+        files.register(&mut registry);
+        let send = |args| call(&registry, "output_file", args);
+
+        let path = "/out/my report.bin";
+        assert_eq!(
+            send(json!({ "path": path, "data": "AAEC" }))["result"]["size"],
+            3
+        );
+        assert_eq!(
+            send(json!({ "path": path, "data": "/w==" }))["result"]["size"],
+            4
+        );
+        send(json!({ "path": "/empty" }));
+        assert!(send(json!({ "path": "/bad", "data": "!!" }))["error"].is_string());
+        assert!(send(json!({ "data": "AAEC" }))["error"].is_string());
+
+        let taken = files.take();
+        assert_eq!(taken[Path::new(path)], [0, 1, 2, 0xff]);
+        assert!(taken[Path::new("/empty")].is_empty());
+        assert_eq!(taken.len(), 2);
+        assert!(files.take().is_empty());
+    }
+
+    #[test]
+    fn output_files_are_capped_per_run() {
+        let files = OutputFiles::default();
+        let chunk = vec![0; (OUTPUT_FILES_MAX_BYTES / 2) as usize];
+        files.append("/a", &chunk).unwrap();
+        files.append("/b", &chunk).unwrap();
+        let err = files.append("/c", &[0]).unwrap_err();
+        assert!(err.to_string().contains("exceed"), "{err:#}");
+        // Taking the files starts the count again.
+        files.take();
+        files.append("/c", &[0]).unwrap();
+    }
+
+    #[test]
+    fn stdin_is_served_in_chunks_until_eof() {
+        let stdin = Arc::new(Stdin::from_bytes("a,b\n1,2\n"));
+        let mut registry = ToolRegistry::new();
+        stdin.register(&mut registry);
+        let read = |max: u64| call(&registry, "stdin_read", json!({ "max": max }));
+
+        let first = read(4);
+        assert_eq!(first["result"]["data"], "YSxiCg==");
+        assert_eq!(first["result"]["eof"], false);
+        let rest = read(64);
+        assert_eq!(rest["result"]["data"], "MSwyCg==");
+        assert_eq!(rest["result"]["eof"], true);
+        assert_eq!(read(64)["result"]["data"], "");
+        assert!(read(0)["error"].is_string());
+    }
+
+    #[test]
+    fn interactive_stdin_returns_each_read_as_it_comes() {
+        // Each `Cursor` is one burst of typing; `chain` reads one at a time.
+        let keys = std::io::Cursor::new("1+1\n").chain(std::io::Cursor::new("exit()\n"));
+        let stdin = Arc::new(Stdin::interactive(keys));
+        let mut registry = ToolRegistry::new();
+        stdin.register(&mut registry);
+        let read = || call(&registry, "stdin_read", json!({ "max": 64 }));
+
+        let first = read();
+        assert_eq!(first["result"]["data"], "MSsxCg==");
+        assert_eq!(first["result"]["eof"], false);
+        assert_eq!(read()["result"]["data"], "ZXhpdCgpCg==");
+        assert_eq!(read()["result"]["eof"], true);
+    }
+
+    #[test]
+    fn read_proxy_serves_allowlisted_files_only() {
+        let ws = crate::workspace::Workspace::new().unwrap();
+        let model = ws.write("models/weights.bin", b"0123456789").unwrap();
+        let secret = ws.write("secret.txt", b"nope").unwrap();
+        let mut registry = ToolRegistry::new();
+        ReadAllowlist::new([ws.path().join("models")])
+            .unwrap()
+            .register(&mut registry);
+        let path = |p: &Path| json!({ "path": p.to_str().unwrap() });
+
+        let stat = call(&registry, "host_stat", path(&model));
+        assert_eq!(stat["result"]["size"], 10);
+        let denied = call(&registry, "host_open", path(&secret));
+        assert_eq!(
+            denied["error"],
+            format!("host path not readable: {:?}", secret.to_str().unwrap())
+        );
+        // A missing file looks the same as a forbidden one.
+        let missing = ws.path().join("models/missing.bin");
+        assert_eq!(
+            call(&registry, "host_open", path(&missing))["error"],
+            format!("host path not readable: {:?}", missing.to_str().unwrap())
+        );
+        let sneaky = ws.path().join("models/../secret.txt");
+        assert!(call(&registry, "host_open", path(&sneaky))["error"].is_string());
+        #[cfg(unix)]
+        {
+            let link = ws.path().join("models/link.txt");
+            std::os::unix::fs::symlink(&secret, &link).unwrap();
+            assert!(call(&registry, "host_open", path(&link))["error"].is_string());
+        }
+
+        let fd = call(&registry, "host_open", path(&model))["result"]["fd"].clone();
+        let chunk = call(
+            &registry,
+            "host_read",
+            json!({ "fd": fd, "offset": 6, "len": 8 }),
+        );
+        let data = base64::engine::general_purpose::STANDARD
+            .decode(chunk["result"]["data"].as_str().unwrap())
+            .unwrap();
+        assert_eq!(data, b"6789");
+        assert_eq!(chunk["result"]["eof"], true);
+
+        assert!(call(&registry, "host_close", json!({ "fd": fd }))["result"].is_object());
+        assert!(call(&registry, "host_read", json!({ "fd": fd }))["error"].is_string());
+    }
+}
@@ -0,0 +1,223 @@
+//! Which hypervisor a run uses, and which ones this host offers.
+//!
+//! Hyperlight picks its backend itself: KVM when `/dev/kvm` is usable,
+//! otherwise MSHV (`/dev/mshv`), and the Windows Hypervisor Platform on
+//! Windows. [`VmConfig::with_hypervisor`](crate::VmConfig::with_hypervisor)
+//! pins a run to one of them, so a host where the expected backend is
+//! missing fails up front with the reason rather than running elsewhere
+//! or failing deep inside sandbox creation. [`probe`] reports what is
+//! usable here and why the rest isn't:
+//!
+//! ```no_run
+//! for backend in hyperlight_unikraft::probe().backends {
+//!     match backend.problem {
+//!         None => println!("{}: available", backend.hypervisor),
+//!         Some(why) => println!("{}: {why}", backend.hypervisor),
+//!     }
+//! }
+//! ```
+
+use anyhow::Result;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::error::Error;
+
+/// A hypervisor backend to run on.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum Hypervisor {
+    /// Whatever Hyperlight finds.
+    #[default]
+    Auto,
+    /// Linux KVM, through `/dev/kvm`.
+    Kvm,
+    /// Microsoft Hypervisor on Linux, through `/dev/mshv`.
+    Mshv,
+}
+
+impl Hypervisor {
+    /// The device the backend is driven through.
+    pub fn device(self) -> Option<&'static str> {
+        match self {
+            Self::Auto => None,
+            Self::Kvm => Some("/dev/kvm"),
+            Self::Mshv => Some("/dev/mshv"),
+        }
+    }
+}
+
+impl fmt::Display for Hypervisor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Auto => "auto",
+            Self::Kvm => "kvm",
+            Self::Mshv => "mshv",
+        })
+    }
+}
+
+impl FromStr for Hypervisor {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s.to_ascii_lowercase().as_str() {
+            "auto" => Ok(Self::Auto),
+            "kvm" => Ok(Self::Kvm),
+            "mshv" => Ok(Self::Mshv),
+            _ => Err(Error::config(format!(
+                "unknown hypervisor {s:?}; expected auto, kvm or mshv"
+            ))),
+        }
+    }
+}
+
+/// What [`probe`] found out about one backend.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Backend {
+    pub hypervisor: Hypervisor,
+    /// Why it can't be used, or `None` if it can.
+    pub problem: Option<String>,
+}
+
+impl Backend {
+    pub fn is_available(&self) -> bool {
+        self.problem.is_none()
+    }
+}
+
+/// The backends of this host, in the order Hyperlight prefers them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Probe {
+    pub backends: Vec<Backend>,
+}
+
+impl Probe {
+    /// The backends that can be used.
+    pub fn available(&self) -> impl Iterator<Item = Hypervisor> + '_ {
+        self.backends
+            .iter()
+            .filter(|b| b.is_available())
+            .map(|b| b.hypervisor)
+    }
+
+    /// The backend a run asking for `wanted` would get, or why it can't
+    /// have it. [`Hypervisor::Auto`] is Hyperlight's first choice; on
+    /// hosts without a device-backed backend it stays `Auto` and the
+    /// choice is left to Hyperlight.
+    pub fn resolve(&self, wanted: Hypervisor) -> Result<Hypervisor, Error> {
+        let chosen = self.available().next();
+        if wanted == Hypervisor::Auto {
+            return Ok(chosen.unwrap_or(Hypervisor::Auto));
+        }
+        let problem = match self.backends.iter().find(|b| b.hypervisor == wanted) {
+            None => Some("not supported on this platform".to_string()),
+            Some(backend) => backend.problem.clone(),
+        };
+        let reason = match (problem, chosen) {
+            (Some(problem), _) => problem,
+            (None, Some(chosen)) if chosen != wanted => format!(
+                "Hyperlight prefers {chosen} when both are usable; \
+                 make {} inaccessible to use {wanted}",
+                chosen.device().unwrap_or_default()
+            ),
+            (None, _) => return Ok(wanted),
+        };
+        Err(Error::HypervisorUnavailable {
+            hypervisor: wanted,
+            reason,
+        })
+    }
+}
+
+/// Check which hypervisor backends this host can run guests on.
+pub fn probe() -> Probe {
+    let backends = [Hypervisor::Kvm, Hypervisor::Mshv]
+        .into_iter()
+        .map(|hypervisor| Backend {
+            hypervisor,
+            problem: problem(hypervisor),
+        })
+        .collect();
+    Probe { backends }
+}
+
+/// Fail unless a run pinned to `wanted` would get it. `Auto` always
+/// passes; Hyperlight reports a host without any hypervisor itself.
+pub(crate) fn check(wanted: Hypervisor) -> Result<()> {
+    if wanted != Hypervisor::Auto {
+        probe().resolve(wanted)?;
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn problem(hypervisor: Hypervisor) -> Option<String> {
+    use std::io::ErrorKind;
+    let device = hypervisor.device()?;
+    let err = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(device)
+        .err()?;
+    Some(match err.kind() {
+        ErrorKind::NotFound => match hypervisor {
+            Hypervisor::Kvm => {
+                format!("{device} not found; enable virtualization and load the kvm module")
+            }
+            _ => format!("{device} not found; not running under Microsoft Hypervisor"),
+        },
+        ErrorKind::PermissionDenied => {
+            format!("no read/write access to {device}; add your user to the group owning it")
+        }
+        _ => format!("can't open {device}: {err}"),
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn problem(hypervisor: Hypervisor) -> Option<String> {
+    Some(format!("{hypervisor} is only available on Linux"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn host(kvm: Option<&str>, mshv: Option<&str>) -> Probe {
+        let backend = |hypervisor, problem: Option<&str>| Backend {
+            hypervisor,
+            problem: problem.map(str::to_string),
+        };
+        Probe {
+            backends: vec![
+                backend(Hypervisor::Kvm, kvm),
+                backend(Hypervisor::Mshv, mshv),
+            ],
+        }
+    }
+
+    #[test]
+    fn pinned_backends_resolve_or_say_why_not() {
+        let kvm_only = host(None, Some("/dev/mshv not found"));
+        assert_eq!(kvm_only.resolve(Hypervisor::Auto).unwrap(), Hypervisor::Kvm);
+        assert_eq!(kvm_only.resolve(Hypervisor::Kvm).unwrap(), Hypervisor::Kvm);
+        let err = kvm_only.resolve(Hypervisor::Mshv).unwrap_err();
+        assert_eq!(err.to_string(), "mshv is unavailable: /dev/mshv not found");
+
+        // Both usable: Hyperlight takes KVM, so MSHV can't be promised.
+        let both = host(None, None);
+        assert!(both.resolve(Hypervisor::Mshv).is_err());
+        let none = host(Some("no access"), Some("not found"));
+        assert_eq!(none.resolve(Hypervisor::Auto).unwrap(), Hypervisor::Auto);
+        assert_eq!(none.available().count(), 0);
+
+        assert_eq!("KVM".parse::<Hypervisor>().unwrap(), Hypervisor::Kvm);
+        assert!("xen".parse::<Hypervisor>().is_err());
+        // The real host answers for every backend it knows.
+        assert_eq!(probe().backends.len(), 2);
+    }
+}
@@ -0,0 +1,172 @@
+//! Prepared initrds shared across runs.
+//!
+//! Booting an in-memory rootfs spills it to a file the guest maps behind
+//! the boot header (see [`ExtendedInitrd`](crate::ExtendedInitrd)). An
+//! [`InitrdCache`] keeps those files keyed by the rootfs's SHA-256, so a
+//! rootfs run over and over — with the same arguments or different ones
+//! — is written once and later runs only hash it. The boot header itself
+//! is rebuilt every run; it is a single page.
+//!
+//! ```no_run
+//! # use hyperlight_unikraft::{initrd_cache::InitrdCache, run_vm_capture_output, VmConfig};
+//! # use std::{path::Path, sync::Arc};
+//! # fn main() -> anyhow::Result<()> {
+//! let cache = Arc::new(InitrdCache::new());
+//! let rootfs = std::fs::read("app.cpio")?;
+//! for arg in ["a", "b", "c"] {
+//!     let config = VmConfig::default().with_initrd_cache(cache.clone());
+//!     run_vm_capture_output(Path::new("kernel"), Some(&rootfs), &[arg.into()], config)?;
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use anyhow::Result;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use crate::workspace::Workspace;
+
+/// Content-addressed store of spilled rootfs files, evicting the least
+/// recently used entry beyond its capacity. Evicted files stay on disk
+/// until the last sandbox mapping them is dropped.
+pub struct InitrdCache {
+    max_entries: usize,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    entries: HashMap<[u8; 32], Entry>,
+    clock: u64,
+    hits: u64,
+    misses: u64,
+}
+
+struct Entry {
+    path: PathBuf,
+    workspace: Arc<Workspace>,
+    last_used: u64,
+}
+
+impl InitrdCache {
+    pub const DEFAULT_MAX_ENTRIES: usize = 8;
+
+    pub fn new() -> Self {
+        Self::with_max_entries(Self::DEFAULT_MAX_ENTRIES)
+    }
+
+    /// A cache holding at most `max` rootfs files (at least one).
+    pub fn with_max_entries(max: usize) -> Self {
+        Self {
+            max_entries: max.max(1),
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// `(hits, misses)` since the cache was created.
+    pub fn stats(&self) -> (u64, u64) {
+        let state = self.lock();
+        (state.hits, state.misses)
+    }
+
+    /// The spilled file holding `body`, written on a miss. The workspace
+    /// must outlive every mapping of the path.
+    pub(crate) fn spill(&self, body: &[u8]) -> Result<(PathBuf, Arc<Workspace>)> {
+        let key: [u8; 32] = Sha256::digest(body).into();
+        {
+            let mut state = self.lock();
+            state.clock += 1;
+            let now = state.clock;
+            if let Some(entry) = state.entries.get_mut(&key) {
+                entry.last_used = now;
+                let found = (entry.path.clone(), entry.workspace.clone());
+                state.hits += 1;
+                return Ok(found);
+            }
+            state.misses += 1;
+        }
+
+        // Write outside the lock; if another run raced us to the same
+        // rootfs, keep whichever entry landed first.
+        let workspace = Arc::new(Workspace::new()?);
+        let path = workspace.write("initrd", body)?;
+        let mut state = self.lock();
+        state.clock += 1;
+        let now = state.clock;
+        let entry = state.entries.entry(key).or_insert(Entry {
+            path,
+            workspace,
+            last_used: now,
+        });
+        let found = (entry.path.clone(), entry.workspace.clone());
+        while state.entries.len() > self.max_entries {
+            let oldest = state
+                .entries
+                .iter()
+                .min_by_key(|(_, e)| e.last_used)
+                .map(|(k, _)| *k);
+            if let Some(k) = oldest {
+                state.entries.remove(&k);
+            }
+        }
+        Ok(found)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Default for InitrdCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeated_rootfs_is_spilled_once() {
+        let cache = InitrdCache::new();
+        let (a, _ws) = cache.spill(b"rootfs one").unwrap();
+        let (b, _) = cache.spill(b"rootfs one").unwrap();
+        assert_eq!(a, b);
+        assert_eq!(std::fs::read(&a).unwrap(), b"rootfs one");
+        let (c, _) = cache.spill(b"rootfs two").unwrap();
+        assert_ne!(a, c);
+        assert_eq!(cache.stats(), (1, 2));
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn least_recently_used_entry_is_evicted_but_stays_mapped() {
+        let cache = InitrdCache::with_max_entries(2);
+        let (first, held) = cache.spill(b"1").unwrap();
+        cache.spill(b"2").unwrap();
+        cache.spill(b"1").unwrap();
+        let (third, third_ws) = cache.spill(b"3").unwrap();
+        assert_eq!(cache.len(), 2);
+        // "2" was least recently used; "1" is still a hit.
+        cache.spill(b"1").unwrap();
+        assert_eq!(cache.stats(), (2, 3));
+
+        drop(cache);
+        // Callers' handles keep the files alive past eviction and drop.
+        assert!(first.exists() && third.exists());
+        drop((held, third_ws));
+        assert!(!first.exists() && !third.exists());
+    }
+}
@@ -0,0 +1,212 @@
+//! Kernel images shared across runs.
+//!
+//! Every run hands Hyperlight the kernel's ELF image. The process-wide
+//! cache returned by [`global`] maps each kernel file once and gives the
+//! same mapping to every later run of it, so running one kernel hundreds
+//! of times doesn't read it hundreds of times, and concurrent sandboxes
+//! share its pages. Entries are keyed by path and checked against the
+//! file's size and modification time on each lookup, so a rebuilt kernel
+//! is picked up by the next run.
+//!
+//! As with [`MappedInitrd`](crate::rootfs::MappedInitrd), replace a
+//! kernel by renaming the new file over it rather than rewriting it in
+//! place. [`KernelCache::evict`] drops a kernel that won't be run again.
+//!
+//! ```no_run
+//! # use std::path::Path;
+//! use hyperlight_unikraft::kernel_cache;
+//!
+//! let cache = kernel_cache::global();
+//! let (hits, misses) = cache.stats();
+//! cache.evict(Path::new("old-kernel"));
+//! ```
+
+use anyhow::Result;
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::SystemTime;
+
+use crate::error;
+
+/// Mapped kernel files by path.
+#[derive(Default)]
+pub struct KernelCache {
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    entries: HashMap<PathBuf, Entry>,
+    hits: u64,
+    misses: u64,
+}
+
+struct Entry {
+    len: u64,
+    modified: Option<SystemTime>,
+    image: Arc<KernelImage>,
+}
+
+/// A kernel file mapped read-only. Derefs to the ELF image; the mapping
+/// lasts as long as the last run holding it, evicted or not.
+pub struct KernelImage {
+    map: memmap2::Mmap,
+    host_print: AtomicBool,
+}
+
+impl KernelImage {
+    /// True once a boot of this image printed through Hyperlight's
+    /// `HostPrint`, so later runs of it under
+    /// [`Console::Auto`](crate::Console::Auto) capture per sandbox. A
+    /// rebuilt kernel is mapped afresh and starts out unknown.
+    pub fn prints_to_host(&self) -> bool {
+        self.host_print.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn note_prints_to_host(&self) {
+        self.host_print.store(true, Ordering::Relaxed);
+    }
+}
+
+impl std::ops::Deref for KernelImage {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.map
+    }
+}
+
+/// The cache every run in this process looks kernels up in.
+pub fn global() -> &'static KernelCache {
+    static CACHE: OnceLock<KernelCache> = OnceLock::new();
+    CACHE.get_or_init(KernelCache::default)
+}
+
+impl KernelCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The image of the kernel at `path`, mapped on the first lookup and
+    /// again whenever the file has changed since.
+    pub fn get(&self, path: &Path) -> Result<Arc<KernelImage>> {
+        let key = key(path);
+        let meta = std::fs::metadata(&key).map_err(|e| error::kernel_io(path, e))?;
+        let (len, modified) = (meta.len(), meta.modified().ok());
+        {
+            let mut state = self.lock();
+            if let Some(entry) = state.entries.get(&key) {
+                if entry.len == len && entry.modified == modified {
+                    let image = entry.image.clone();
+                    state.hits += 1;
+                    return Ok(image);
+                }
+            }
+            state.misses += 1;
+        }
+
+        // Map outside the lock; a concurrent miss on the same kernel
+        // just maps it twice and the later entry wins.
+        let file = File::open(&key).map_err(|e| error::kernel_io(path, e))?;
+        // SAFETY: the mapping is read-only; the documented contract is
+        // that the file isn't modified in place while mapped.
+        let map = unsafe { memmap2::Mmap::map(&file) }.map_err(|e| error::kernel_io(path, e))?;
+        // Start reading it in while the caller prepares the rest of the
+        // run.
+        #[cfg(unix)]
+        let _ = map.advise(memmap2::Advice::WillNeed);
+        let image = Arc::new(KernelImage {
+            map,
+            host_print: AtomicBool::new(false),
+        });
+        self.lock().entries.insert(
+            key,
+            Entry {
+                len,
+                modified,
+                image: image.clone(),
+            },
+        );
+        Ok(image)
+    }
+
+    /// The image of the kernel at `path` if one is cached and the file
+    /// hasn't changed since, without mapping it or counting a lookup.
+    pub fn cached(&self, path: &Path) -> Option<Arc<KernelImage>> {
+        let key = key(path);
+        let meta = std::fs::metadata(&key).ok()?;
+        let state = self.lock();
+        let entry = state.entries.get(&key)?;
+        (entry.len == meta.len() && entry.modified == meta.modified().ok())
+            .then(|| entry.image.clone())
+    }
+
+    /// Forget the kernel at `path`. Returns whether it was cached.
+    pub fn evict(&self, path: &Path) -> bool {
+        self.lock().entries.remove(&key(path)).is_some()
+    }
+
+    /// Forget every kernel.
+    pub fn clear(&self) {
+        self.lock().entries.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// `(hits, misses)` since the cache was created.
+    pub fn stats(&self) -> (u64, u64) {
+        let state = self.lock();
+        (state.hits, state.misses)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// One entry per file, however the path to it is spelled.
+fn key(path: &Path) -> PathBuf {
+    std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::workspace::Workspace;
+
+    #[test]
+    fn kernel_is_mapped_once_until_it_changes() {
+        let ws = Workspace::new().unwrap();
+        let kernel = ws.write("kernel", b"\x7fELF one").unwrap();
+        let cache = KernelCache::new();
+
+        let first = cache.get(&kernel).unwrap();
+        let again = cache.get(&ws.path().join(".").join("kernel")).unwrap();
+        assert!(Arc::ptr_eq(&first, &again));
+        assert_eq!(cache.stats(), (1, 1));
+
+        // A rebuild renamed into place is mapped afresh.
+        let rebuilt = ws.write("kernel.new", b"\x7fELF two!").unwrap();
+        std::fs::rename(&rebuilt, &kernel).unwrap();
+        assert_eq!(&cache.get(&kernel).unwrap()[..], b"\x7fELF two!");
+        assert_eq!(&first[..], b"\x7fELF one");
+        assert_eq!((cache.stats(), cache.len()), ((1, 2), 1));
+        first.note_prints_to_host();
+        assert!(!cache.cached(&kernel).unwrap().prints_to_host());
+        assert!(cache.cached(&ws.path().join("nope")).is_none());
+
+        assert!(cache.evict(&kernel));
+        assert!(!cache.evict(&kernel) && cache.is_empty());
+        let err = cache.get(&ws.path().join("nope")).err().unwrap();
+        assert!(err.to_string().contains("not found"), "{err}");
+    }
+}
@@ -0,0 +1,464 @@
+//! Building a Unikraft project's kernel and rootfs.
+//!
+//! A project directory holds a `Kraftfile` (or the older `kraft.yaml`)
+//! and usually a `Dockerfile` for the rootfs — the layout of the
+//! `examples/`. [`KraftProject::build`] drives `kraft-hyperlight` for the
+//! kernel and Docker (or [`rootfs::convert`]) for the rootfs, leaving both
+//! under `.unikraft/build/`:
+//!
+//! ```no_run
+//! use hyperlight_unikraft::kraft::{BuildOptions, KraftProject};
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! let project = KraftProject::open("examples/python")?;
+//! let assets = project.build(&BuildOptions::default())?;
+//! println!("kernel {:?}, rootfs {:?}", assets.kernel, assets.initrd);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The rootfs comes from the manifest's `rootfs:` entry when present (a
+//! directory, tarball, CPIO archive or Dockerfile), otherwise from a
+//! `Dockerfile` next to the manifest. A Dockerfile with a `cpio` stage
+//! that writes `/output.cpio` is built to that stage, as in the examples;
+//! any other is built and its filesystem exported and converted.
+//!
+//! Besides kraft's own keys, a top-level `memory:` (e.g. `1Gi`) gives the
+//! guest heap a project needs; kraft ignores it.
+
+use anyhow::{anyhow, bail, Context, Result};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::rootfs;
+use crate::workspace::Workspace;
+
+/// Environment variable naming the kraft binary (default
+/// `kraft-hyperlight`).
+pub const ENV_KRAFT: &str = "HYPERLIGHT_UNIKRAFT_KRAFT";
+
+/// Manifest names looked up in a project directory, in order.
+pub const MANIFEST_NAMES: &[&str] = &["Kraftfile", "kraft.yaml", "kraft.yml"];
+
+const PLATFORM: &str = "hyperlight";
+const ARCH: &str = "x86_64";
+
+/// A Unikraft project directory and the manifest fields this crate uses.
+#[derive(Debug, Clone)]
+pub struct KraftProject {
+    dir: PathBuf,
+    manifest: PathBuf,
+    name: String,
+    rootfs: Option<String>,
+    cmd: Vec<String>,
+    memory: Option<String>,
+}
+
+/// Tools and steps for [`KraftProject::build`].
+#[derive(Debug, Clone)]
+pub struct BuildOptions {
+    /// kraft binary; defaults to [`ENV_KRAFT`] or `kraft-hyperlight`.
+    pub kraft: PathBuf,
+    /// Docker-compatible CLI for Dockerfile rootfs builds.
+    pub docker: PathBuf,
+    pub kernel: bool,
+    pub rootfs: bool,
+}
+
+impl Default for BuildOptions {
+    fn default() -> Self {
+        Self {
+            kraft: std::env::var_os(ENV_KRAFT)
+                .map(PathBuf::from)
+                .unwrap_or_else(|| "kraft-hyperlight".into()),
+            docker: "docker".into(),
+            kernel: true,
+            rootfs: true,
+        }
+    }
+}
+
+/// What [`KraftProject::build`] produced.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BuiltAssets {
+    /// The kernel, whether built now or already there.
+    pub kernel: PathBuf,
+    /// The rootfs, if the project has one and it was built (or already
+    /// there).
+    pub initrd: Option<PathBuf>,
+}
+
+impl KraftProject {
+    /// Open the project in `dir`, reading its manifest.
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        let dir = dir.as_ref();
+        let manifest = MANIFEST_NAMES
+            .iter()
+            .map(|n| dir.join(n))
+            .find(|p| p.is_file())
+            .ok_or_else(|| anyhow!("no Kraftfile or kraft.yaml in {}", dir.display()))?;
+        let text = std::fs::read_to_string(&manifest)
+            .with_context(|| format!("reading {}", manifest.display()))?;
+        let fields = parse_manifest(&text);
+        let name = fields
+            .name
+            .ok_or_else(|| anyhow!("{} has no `name`", manifest.display()))?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            manifest,
+            name,
+            rootfs: fields.rootfs,
+            cmd: fields.cmd,
+            memory: fields.memory,
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn manifest(&self) -> &Path {
+        &self.manifest
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The manifest's `cmd`: the application arguments to boot with.
+    pub fn cmd(&self) -> &[String] {
+        &self.cmd
+    }
+
+    /// The manifest's `memory`, unparsed (see
+    /// [`parse_memory`](crate::parse_memory)).
+    pub fn memory(&self) -> Option<&str> {
+        self.memory.as_deref()
+    }
+
+    /// Where kraft leaves the Hyperlight kernel.
+    pub fn kernel_path(&self) -> PathBuf {
+        self.build_dir()
+            .join(format!("{}_{PLATFORM}-{ARCH}", self.name))
+    }
+
+    /// Where [`build_rootfs`](Self::build_rootfs) writes the rootfs,
+    /// unless the manifest names a CPIO archive to use as is.
+    pub fn initrd_path(&self) -> PathBuf {
+        match self.rootfs_source() {
+            Some(RootfsSource::Cpio(path)) => path,
+            _ => self.build_dir().join(format!("{}.cpio", self.name)),
+        }
+    }
+
+    fn build_dir(&self) -> PathBuf {
+        self.dir.join(".unikraft").join("build")
+    }
+
+    /// Build the kernel and/or rootfs as `options` asks. Steps that are
+    /// skipped still report existing outputs.
+    pub fn build(&self, options: &BuildOptions) -> Result<BuiltAssets> {
+        let kernel = if options.kernel {
+            self.build_kernel(&options.kraft)?
+        } else {
+            self.kernel_path()
+        };
+        let initrd = if options.rootfs {
+            self.build_rootfs(&options.docker)?
+        } else {
+            Some(self.initrd_path()).filter(|p| p.is_file())
+        };
+        Ok(BuiltAssets { kernel, initrd })
+    }
+
+    /// Like [`build`](Self::build), but only for outputs that don't exist
+    /// yet — what running a project directory needs.
+    pub fn resolve(&self, options: &BuildOptions) -> Result<BuiltAssets> {
+        let kernel_missing = !self.kernel_path().is_file();
+        let initrd_missing = self.rootfs_source().is_some() && !self.initrd_path().is_file();
+        self.build(&BuildOptions {
+            kernel: options.kernel && kernel_missing,
+            rootfs: options.rootfs && initrd_missing,
+            ..options.clone()
+        })
+    }
+
+    /// Run `kraft build` for the Hyperlight platform.
+    pub fn build_kernel(&self, kraft: &Path) -> Result<PathBuf> {
+        run(Command::new(kraft)
+            .args(["build", "--plat", PLATFORM, "--arch", ARCH])
+            .current_dir(&self.dir))
+        .with_context(|| format!("building kernel with {}", kraft.display()))?;
+        let kernel = self.kernel_path();
+        if !kernel.is_file() {
+            bail!("kraft finished but {} was not produced", kernel.display());
+        }
+        Ok(kernel)
+    }
+
+    /// Build the rootfs into [`initrd_path`](Self::initrd_path). `None`
+    /// if the project has no rootfs.
+    pub fn build_rootfs(&self, docker: &Path) -> Result<Option<PathBuf>> {
+        let out = self.initrd_path();
+        match self.rootfs_source() {
+            None => return Ok(None),
+            Some(RootfsSource::Cpio(path)) => {
+                if !path.is_file() {
+                    bail!("rootfs {} does not exist", path.display());
+                }
+                return Ok(Some(path));
+            }
+            Some(RootfsSource::Tree(src)) => {
+                std::fs::create_dir_all(self.build_dir())?;
+                rootfs::convert(&src, &out, None)?;
+            }
+            Some(RootfsSource::Dockerfile(file)) => {
+                std::fs::create_dir_all(self.build_dir())?;
+                self.docker_rootfs(docker, &file, &out)?;
+            }
+        }
+        Ok(Some(out))
+    }
+
+    fn rootfs_source(&self) -> Option<RootfsSource> {
+        let path = match self.rootfs {
+            Some(ref rel) => self.dir.join(rel),
+            None => Some(self.dir.join("Dockerfile")).filter(|p| p.is_file())?,
+        };
+        let is_dockerfile = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .is_some_and(|n| n == "Dockerfile" || n.ends_with(".Dockerfile"));
+        Some(if is_dockerfile {
+            RootfsSource::Dockerfile(path)
+        } else if path.extension().is_some_and(|e| e == "cpio") {
+            RootfsSource::Cpio(path)
+        } else {
+            RootfsSource::Tree(path)
+        })
+    }
+
+    fn docker_rootfs(&self, docker: &Path, dockerfile: &Path, out: &Path) -> Result<()> {
+        let text = std::fs::read_to_string(dockerfile)
+            .with_context(|| format!("reading {}", dockerfile.display()))?;
+        let has_cpio_stage = text.lines().any(|l| {
+            let words: Vec<&str> = l.split_whitespace().collect();
+            words
+                .first()
+                .is_some_and(|w| w.eq_ignore_ascii_case("FROM"))
+                && words
+                    .windows(2)
+                    .any(|w| w[0].eq_ignore_ascii_case("AS") && w[1] == "cpio")
+        });
+        let tag = format!("{}-rootfs", self.name.to_ascii_lowercase());
+        let mut build = Command::new(docker);
+        build
+            .args(["build", "--platform", "linux/amd64", "-f"])
+            .arg(dockerfile)
+            .args(["-t", &tag])
+            .current_dir(&self.dir)
+            .arg(".");
+        if has_cpio_stage {
+            build.args(["--target", "cpio"]);
+        }
+        run(&mut build).context("building rootfs image")?;
+
+        let container = format!("{tag}-{}", std::process::id());
+        run(Command::new(docker).args(["create", "--name", &container, &tag, "/bin/true"]))
+            .context("creating rootfs container")?;
+        let copied = if has_cpio_stage {
+            run(Command::new(docker)
+                .args(["cp", &format!("{container}:/output.cpio")])
+                .arg(out))
+        } else {
+            Workspace::new().and_then(|ws| {
+                let tar = ws.file("rootfs.tar")?;
+                run(Command::new(docker)
+                    .args(["export", "-o"])
+                    .arg(&tar)
+                    .arg(&container))?;
+                rootfs::convert(&tar, out, None).map(drop)
+            })
+        };
+        let _ = Command::new(docker).args(["rm", "-f", &container]).output();
+        copied.context("extracting rootfs")
+    }
+}
+
+enum RootfsSource {
+    Cpio(PathBuf),
+    Dockerfile(PathBuf),
+    /// A directory or tarball for [`rootfs::convert`].
+    Tree(PathBuf),
+}
+
+/// Run `cmd` with inherited stdio, failing on a non-zero exit.
+fn run(cmd: &mut Command) -> Result<()> {
+    let program = cmd.get_program().to_string_lossy().into_owned();
+    let status = cmd.status().with_context(|| format!("running {program}"))?;
+    if !status.success() {
+        bail!("{program} exited with {status}");
+    }
+    Ok(())
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+struct ManifestFields {
+    name: Option<String>,
+    rootfs: Option<String>,
+    cmd: Vec<String>,
+    memory: Option<String>,
+}
+
+/// The top-level `name`, `rootfs`, `cmd` and `memory` keys of a Kraftfile. Only
+/// the YAML forms kraft manifests use are understood: plain or quoted
+/// scalars, and `cmd` as a flow list, a block list or a single string.
+fn parse_manifest(text: &str) -> ManifestFields {
+    let mut fields = ManifestFields::default();
+    let mut lines = text.lines().peekable();
+    while let Some(line) = lines.next() {
+        if line.starts_with([' ', '\t', '#', '-']) {
+            continue;
+        }
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = strip_comment(value).trim();
+        match key.trim() {
+            "name" => fields.name = Some(unquote(value).to_string()),
+            "rootfs" => fields.rootfs = Some(unquote(value).to_string()),
+            "memory" => fields.memory = Some(unquote(value).to_string()),
+            "cmd" if value.starts_with('[') => {
+                let inner = value.trim_start_matches('[').trim_end_matches(']');
+                fields.cmd = inner
+                    .split(',')
+                    .map(|s| unquote(s.trim()).to_string())
+                    .filter(|s| !s.is_empty())
+                    .collect();
+            }
+            "cmd" if value.is_empty() => {
+                while let Some(item) = lines.peek().and_then(|l| l.trim_start().strip_prefix("- "))
+                {
+                    fields
+                        .cmd
+                        .push(unquote(strip_comment(item).trim()).to_string());
+                    lines.next();
+                }
+            }
+            "cmd" => {
+                fields.cmd = value
+                    .split_whitespace()
+                    .map(unquote)
+                    .map(Into::into)
+                    .collect()
+            }
+            _ => {}
+        }
+    }
+    fields
+}
+
+fn strip_comment(s: &str) -> &str {
+    match s.find(" #") {
+        Some(i) => &s[..i],
+        None => s,
+    }
+}
+
+fn unquote(s: &str) -> &str {
+    for q in ['"', '\''] {
+        if let Some(inner) = s.strip_prefix(q).and_then(|s| s.strip_suffix(q)) {
+            return inner;
+        }
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manifest_fields_parse_in_kraft_forms() {
+        let text = "specification: '0.6'\nname: python-hyperlight # app\n\
+                    unikraft:\n  source: https://example.com/unikraft.git\n  kconfig:\n    name: nested\n\
+                    rootfs: ./Dockerfile\ncmd: [\"/hello.py\", '--n', 3]\n";
+        assert_eq!(
+            parse_manifest(text),
+            ManifestFields {
+                name: Some("python-hyperlight".into()),
+                rootfs: Some("./Dockerfile".into()),
+                cmd: vec!["/hello.py".into(), "--n".into(), "3".into()],
+                memory: None,
+            }
+        );
+        let block = parse_manifest(
+            "name: \"app\"\ncmd:\n  - /app.py\n  - \"--verbose\"\ntargets:\n  - x\n",
+        );
+        assert_eq!(block.cmd, ["/app.py", "--verbose"]);
+        assert_eq!(
+            parse_manifest("cmd: /bin/sh -c true").cmd,
+            ["/bin/sh", "-c", "true"]
+        );
+        assert_eq!(
+            parse_manifest("memory: '1Gi'").memory.as_deref(),
+            Some("1Gi")
+        );
+    }
+
+    #[test]
+    fn project_paths_follow_kraft_layout() {
+        let ws = Workspace::new().unwrap();
+        assert!(KraftProject::open(ws.path()).is_err());
+        ws.write("kraft.yaml", "name: hello\n").unwrap();
+        let project = KraftProject::open(ws.path()).unwrap();
+        assert_eq!(
+            project.kernel_path(),
+            ws.path().join(".unikraft/build/hello_hyperlight-x86_64")
+        );
+        // No Dockerfile and no rootfs entry: nothing to build.
+        assert!(project.build_rootfs(Path::new("docker")).unwrap().is_none());
+
+        ws.write("Kraftfile", "name: hello\nrootfs: ./initrd.cpio\n")
+            .unwrap();
+        let project = KraftProject::open(ws.path()).unwrap();
+        assert_eq!(project.manifest(), ws.path().join("Kraftfile"));
+        assert_eq!(project.initrd_path(), ws.path().join("./initrd.cpio"));
+
+        ws.write("Kraftfile", "name: hello\nrootfs: ./root\n")
+            .unwrap();
+        ws.write("root/hello.txt", "hi").unwrap();
+        let built = KraftProject::open(ws.path())
+            .unwrap()
+            .build_rootfs(Path::new("docker"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(built, ws.path().join(".unikraft/build/hello.cpio"));
+        let archive = std::fs::read(built).unwrap();
+        let (entries, _) = rootfs::scan(&archive).unwrap();
+        assert!(entries.iter().any(|e| e.name.ends_with("hello.txt")));
+    }
+
+    #[test]
+    fn resolve_builds_only_missing_outputs() {
+        let ws = Workspace::new().unwrap();
+        ws.write("Kraftfile", "name: app\nrootfs: ./root\n")
+            .unwrap();
+        ws.write("root/app.py", "print(1)").unwrap();
+        ws.write(".unikraft/build/app_hyperlight-x86_64", b"elf")
+            .unwrap();
+        let project = KraftProject::open(ws.path()).unwrap();
+        // A kraft that can't run: the existing kernel must not be rebuilt.
+        let options = BuildOptions {
+            kraft: ws.path().join("no-such-kraft"),
+            ..BuildOptions::default()
+        };
+        let assets = project.resolve(&options).unwrap();
+        assert_eq!(assets.kernel, project.kernel_path());
+        assert_eq!(assets.initrd, Some(project.initrd_path()));
+        assert!(project.initrd_path().is_file());
+
+        std::fs::remove_file(project.kernel_path()).unwrap();
+        assert!(project.resolve(&options).is_err());
+    }
+}
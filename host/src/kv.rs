@@ -0,0 +1,422 @@
+//! Small persistent key-value state shared by successive runs of one
+//! tenant.
+//!
+//! A [`KvStore`] is a host directory with one subdirectory per
+//! namespace and one file per key. The operator picks the namespace
+//! (typically the tenant) with [`KvStore::namespace`]; the guest only
+//! ever sees keys inside it, through the `kv_get` / `kv_put` /
+//! `kv_delete` / `kv_list` tools registered by
+//! [`KvNamespace::register`]. Each namespace is held to a [`KvQuota`].
+//!
+//! ```no_run
+//! use hyperlight_unikraft::kv::KvStore;
+//! use hyperlight_unikraft::Sandbox;
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! let store = KvStore::open("/var/lib/tenants")?;
+//! let sandbox = Sandbox::builder("kernel")
+//!     .kv(store.namespace("tenant-42")?)
+//!     .build()?;
+//! # Ok(())
+//! # }
+//! ```
+
+use anyhow::{anyhow, Context, Result};
+use base64::Engine;
+use serde_json::json;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::ToolRegistry;
+
+/// Longest key any quota allows, in bytes. Keys are stored hex-encoded
+/// as file names, which most filesystems cap at 255 bytes.
+pub const MAX_KEY_LEN: usize = 127;
+
+/// Per-namespace limits, checked on every `kv_put`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KvQuota {
+    /// Longest key, in bytes; values over [`MAX_KEY_LEN`] act as it.
+    pub max_key_len: usize,
+    /// Largest single value, in bytes.
+    pub max_value_len: usize,
+    /// Most keys in the namespace.
+    pub max_keys: usize,
+    /// Most bytes of values in the namespace, summed.
+    pub max_total_bytes: u64,
+}
+
+impl Default for KvQuota {
+    fn default() -> Self {
+        Self {
+            max_key_len: MAX_KEY_LEN,
+            max_value_len: 64 * 1024,
+            max_keys: 1024,
+            max_total_bytes: 1024 * 1024,
+        }
+    }
+}
+
+/// A directory of namespaces. Cheap to clone; clones share a lock, so
+/// sandboxes built from one store in the same process never interleave
+/// writes.
+#[derive(Debug, Clone)]
+pub struct KvStore {
+    root: PathBuf,
+    quota: KvQuota,
+    lock: Arc<Mutex<()>>,
+}
+
+impl KvStore {
+    /// Open (creating if needed) the store rooted at `dir`, with the
+    /// default [`KvQuota`].
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        let root = dir.as_ref();
+        std::fs::create_dir_all(root)
+            .with_context(|| format!("creating kv store {}", root.display()))?;
+        Ok(Self {
+            root: root.to_path_buf(),
+            quota: KvQuota::default(),
+            lock: Arc::default(),
+        })
+    }
+
+    /// Apply `quota` to namespaces handed out from now on.
+    pub fn with_quota(mut self, quota: KvQuota) -> Self {
+        self.quota = quota;
+        self
+    }
+
+    /// The namespace called `name`: ASCII letters, digits, `-`, `_` and
+    /// `.`, not starting with `.`.
+    pub fn namespace(&self, name: &str) -> Result<KvNamespace> {
+        check_tenant_name("kv namespace", name)?;
+        Ok(KvNamespace {
+            dir: self.root.join(name),
+            quota: self.quota,
+            lock: self.lock.clone(),
+        })
+    }
+}
+
+/// One namespace of a [`KvStore`].
+#[derive(Debug, Clone)]
+pub struct KvNamespace {
+    dir: PathBuf,
+    quota: KvQuota,
+    lock: Arc<Mutex<()>>,
+}
+
+impl KvNamespace {
+    pub fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let _guard = self.lock();
+        match std::fs::read(self.key_path(key)?) {
+            Ok(v) => Ok(Some(v)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(anyhow!("kv_get {:?}: {}", key, e)),
+        }
+    }
+
+    /// Store `value` under `key`, replacing any previous value, unless
+    /// that would break the quota.
+    pub fn put(&self, key: &str, value: &[u8]) -> Result<()> {
+        let _guard = self.lock();
+        let path = self.key_path(key)?;
+        if value.len() > self.quota.max_value_len {
+            return Err(anyhow!(
+                "kv_put {:?}: value of {} bytes exceeds the {} byte limit",
+                key,
+                value.len(),
+                self.quota.max_value_len
+            ));
+        }
+        let (keys, total) = self.usage()?;
+        let old = std::fs::metadata(&path).ok().map(|m| m.len());
+        let keys_after = keys + usize::from(old.is_none());
+        if keys_after > self.quota.max_keys {
+            return Err(anyhow!(
+                "kv_put {:?}: namespace is full ({} keys)",
+                key,
+                self.quota.max_keys
+            ));
+        }
+        let total_after = total - old.unwrap_or(0) + value.len() as u64;
+        if total_after > self.quota.max_total_bytes {
+            return Err(anyhow!(
+                "kv_put {:?}: namespace would hold {} bytes, over its {} byte quota",
+                key,
+                total_after,
+                self.quota.max_total_bytes
+            ));
+        }
+        std::fs::create_dir_all(&self.dir)
+            .with_context(|| format!("creating kv namespace {}", self.dir.display()))?;
+        // Write-then-rename so a crash never leaves a torn value. The
+        // temp name is unique, so other processes sharing the store
+        // never write into the same file.
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        let tmp = self.dir.join(format!(
+            ".put-{}-{}.tmp",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let written = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&tmp)
+            .and_then(|mut file| file.write_all(value))
+            .and_then(|()| std::fs::rename(&tmp, &path));
+        if let Err(e) = written {
+            let _ = std::fs::remove_file(&tmp);
+            return Err(anyhow!("kv_put {:?}: {}", key, e));
+        }
+        Ok(())
+    }
+
+    /// Remove `key`; `false` if it wasn't there.
+    pub fn delete(&self, key: &str) -> Result<bool> {
+        let _guard = self.lock();
+        match std::fs::remove_file(self.key_path(key)?) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(anyhow!("kv_delete {:?}: {}", key, e)),
+        }
+    }
+
+    /// Every key in the namespace, sorted.
+    pub fn keys(&self) -> Result<Vec<String>> {
+        let _guard = self.lock();
+        let mut keys: Vec<String> = self
+            .entries()?
+            .into_iter()
+            .filter_map(|(name, _)| decode_key(&name))
+            .collect();
+        keys.sort();
+        Ok(keys)
+    }
+
+    /// Register the guest-facing tools on `registry`:
+    ///
+    /// - `kv_get` — `{ key }` → `{ value: "<base64>" | null }`.
+    /// - `kv_put` — `{ key, value: "<base64>" }` → `{}`.
+    /// - `kv_delete` — `{ key }` → `{ deleted }`.
+    /// - `kv_list` — `{}` → `{ keys: [...] }`.
+    pub fn register(self, registry: &mut ToolRegistry) {
+        use base64::engine::general_purpose::STANDARD;
+
+        let ns = self.clone();
+        registry.register("kv_get", move |args| {
+            let key = key_arg(&args, "kv_get")?;
+            Ok(json!({ "value": ns.get(key)?.map(|v| STANDARD.encode(v)) }))
+        });
+
+        let ns = self.clone();
+        registry.register("kv_put", move |args| {
+            let key = key_arg(&args, "kv_put")?;
+            let value = args["value"]
+                .as_str()
+                .ok_or_else(|| anyhow!("kv_put: missing 'value'"))?;
+            let value = STANDARD
+                .decode(value)
+                .map_err(|e| anyhow!("kv_put: bad base64 value: {}", e))?;
+            ns.put(key, &value)?;
+            Ok(json!({}))
+        });
+
+        let ns = self.clone();
+        registry.register("kv_delete", move |args| {
+            let key = key_arg(&args, "kv_delete")?;
+            Ok(json!({ "deleted": ns.delete(key)? }))
+        });
+
+        registry.register("kv_list", move |_| Ok(json!({ "keys": self.keys()? })));
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, ()> {
+        self.lock.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Keys are stored hex-encoded, so any UTF-8 key is a safe file name.
+    fn key_path(&self, key: &str) -> Result<PathBuf> {
+        let max = self.quota.max_key_len.min(MAX_KEY_LEN);
+        if key.is_empty() || key.len() > max {
+            return Err(anyhow!(
+                "kv key must be 1 to {} bytes, got {}",
+                max,
+                key.len()
+            ));
+        }
+        Ok(self.dir.join(encode_key(key)))
+    }
+
+    /// `(file name, size)` of every stored value.
+    fn entries(&self) -> Result<Vec<(String, u64)>> {
+        let read = match std::fs::read_dir(&self.dir) {
+            Ok(read) => read,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(anyhow!("reading kv namespace {:?}: {}", self.dir, e)),
+        };
+        let mut out = Vec::new();
+        for entry in read {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !name.starts_with('.') {
+                out.push((name, entry.metadata()?.len()));
+            }
+        }
+        Ok(out)
+    }
+
+    fn usage(&self) -> Result<(usize, u64)> {
+        let entries = self.entries()?;
+        Ok((entries.len(), entries.iter().map(|(_, n)| n).sum()))
+    }
+}
+
+/// Tenant names become file names: ASCII letters, digits, `-`, `_` and
+/// `.`, not starting with `.`.
+pub(crate) fn check_tenant_name(what: &str, name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'));
+    if valid {
+        Ok(())
+    } else {
+        Err(anyhow!("invalid {}: {:?}", what, name))
+    }
+}
+
+fn key_arg<'a>(args: &'a serde_json::Value, tool: &str) -> Result<&'a str> {
+    args["key"]
+        .as_str()
+        .ok_or_else(|| anyhow!("{}: missing 'key'", tool))
+}
+
+fn encode_key(key: &str) -> String {
+    key.bytes().map(|b| format!("{b:02x}")).collect()
+}
+
+fn decode_key(name: &str) -> Option<String> {
+    if !name.len().is_multiple_of(2) {
+        return None;
+    }
+    let bytes = (0..name.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&name[i..i + 2], 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    String::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::workspace::Workspace;
+
+    #[test]
+    fn namespaces_persist_and_stay_apart() {
+        let ws = Workspace::new().unwrap();
+        let store = KvStore::open(ws.path()).unwrap();
+        let a = store.namespace("tenant-a").unwrap();
+        a.put("last/run", b"ok").unwrap();
+        assert_eq!(
+            store
+                .namespace("tenant-b")
+                .unwrap()
+                .get("last/run")
+                .unwrap(),
+            None
+        );
+
+        let reopened = KvStore::open(ws.path())
+            .unwrap()
+            .namespace("tenant-a")
+            .unwrap();
+        assert_eq!(
+            reopened.get("last/run").unwrap().as_deref(),
+            Some(&b"ok"[..])
+        );
+        assert_eq!(reopened.keys().unwrap(), ["last/run"]);
+        assert!(reopened.delete("last/run").unwrap());
+        assert!(!reopened.delete("last/run").unwrap());
+
+        assert!(store.namespace("../etc").is_err());
+        assert!(store.namespace("").is_err());
+    }
+
+    #[test]
+    fn quotas_are_enforced_on_put() {
+        let ws = Workspace::new().unwrap();
+        let ns = KvStore::open(ws.path())
+            .unwrap()
+            .with_quota(KvQuota {
+                max_key_len: 8,
+                max_value_len: 4,
+                max_keys: 2,
+                max_total_bytes: 6,
+            })
+            .namespace("t")
+            .unwrap();
+        assert!(ns.put("toolongkey", b"x").is_err());
+        assert!(ns.put("k", b"12345").is_err());
+        ns.put("a", b"1234").unwrap();
+        assert!(ns.put("b", b"123").is_err(), "total over 6 bytes");
+        ns.put("a", b"12").unwrap();
+        ns.put("b", b"1234").unwrap();
+        assert!(ns.put("c", b"").is_err(), "third key");
+    }
+
+    #[test]
+    fn keys_up_to_the_file_name_limit_are_stored() {
+        let ws = Workspace::new().unwrap();
+        let ns = KvStore::open(ws.path())
+            .unwrap()
+            .with_quota(KvQuota {
+                max_key_len: 300,
+                ..KvQuota::default()
+            })
+            .namespace("t")
+            .unwrap();
+        let longest = "k".repeat(MAX_KEY_LEN);
+        ns.put(&longest, b"v").unwrap();
+        assert_eq!(ns.get(&longest).unwrap().as_deref(), Some(&b"v"[..]));
+        assert_eq!(ns.keys().unwrap(), [longest.as_str()]);
+
+        let err = ns.put(&format!("{longest}k"), b"v").unwrap_err();
+        assert_eq!(err.to_string(), "kv key must be 1 to 127 bytes, got 128");
+        // No temp files are left behind.
+        assert_eq!(std::fs::read_dir(ws.path().join("t")).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn guest_tools_round_trip_base64_values() {
+        let ws = Workspace::new().unwrap();
+        let mut registry = ToolRegistry::new();
+        KvStore::open(ws.path())
+            .unwrap()
+            .namespace("t")
+            .unwrap()
+            .register(&mut registry);
+        let call = |name: &str, args: serde_json::Value| -> serde_json::Value {
+            let req = json!({ "name": name, "args": args });
+            serde_json::from_slice(&registry.dispatch(req.to_string().as_bytes())).unwrap()
+        };
+        assert_eq!(
+            call("kv_get", json!({ "key": "k" }))["result"]["value"],
+            json!(null)
+        );
+        call("kv_put", json!({ "key": "k", "value": "aGk=" }));
+        assert_eq!(
+            call("kv_get", json!({ "key": "k" }))["result"]["value"],
+            "aGk="
+        );
+        assert_eq!(call("kv_list", json!({}))["result"]["keys"], json!(["k"]));
+        assert_eq!(
+            call("kv_delete", json!({ "key": "k" }))["result"]["deleted"],
+            true
+        );
+    }
+}
@@ -19,6 +19,9 @@
 //! # }
 //! ```
 //!
+//! For a single run with its output collected, [`Vm::builder`] takes
+//! the whole configuration in one chain and checks it before booting.
+//!
 //! # Snapshot lifecycle
 //!
 //! The sandbox keeps a live snapshot and lets you rewind to it. This
@@ -55,20 +58,82 @@
 //! `normalize_fs_error` rewrites host-OS-specific error wording so
 //! the cross-platform Unikraft guest classifies errors uniformly.
 
+pub mod compare;
+pub mod compose;
+#[cfg(feature = "serde")]
+pub mod config_serde;
+pub mod dag;
+pub mod embed;
+#[cfg(feature = "encrypted-initrd")]
+pub mod encryption;
+pub mod error;
+pub mod exit;
 pub mod ffi;
+#[cfg(feature = "sqlite")]
+pub mod history;
+pub mod hostcall;
+pub mod hostfn;
+pub mod hypervisor;
+pub mod initrd_cache;
+pub mod kernel_cache;
+pub mod kraft;
+pub mod kv;
+pub mod loadtest;
+pub mod metrics;
+pub mod output;
+pub mod phase;
+pub mod pipe;
+pub mod pipeline;
+pub mod placement;
+pub mod pool;
+pub mod profile;
+pub mod progress;
 pub mod pyhl;
+pub mod reload;
+pub mod replay;
+pub mod rootfs;
+pub mod runs;
+pub mod runs_server;
+#[cfg(feature = "s3")]
+pub mod s3;
+pub mod sink;
+#[cfg(feature = "sqlite")]
+pub mod sql;
+pub mod stats;
 pub mod stderr_capture;
-
-use anyhow::{anyhow, Result};
+pub mod sweep;
+pub mod task;
+pub mod testing;
+pub mod trace;
+pub mod vm;
+pub mod warm;
+pub mod workspace;
+
+use anyhow::{anyhow, Context, Result};
 use hyperlight_host::func::Registerable;
 use hyperlight_host::sandbox::snapshot::Snapshot;
 use hyperlight_host::sandbox::uninitialized::GuestEnvironment;
-use hyperlight_host::sandbox::SandboxConfiguration;
 use hyperlight_host::{GuestBinary, MultiUseSandbox, UninitializedSandbox};
 use std::collections::HashMap;
 use std::path::Path;
 use std::sync::Arc;
 use std::time::Duration;
+use zeroize::Zeroize;
+
+pub use compare::compare;
+pub use embed::EmbeddedAssets;
+pub use error::Error;
+pub use exit::{ExitStatus, RunReport, VmExit};
+/// Re-exported for [`VmConfig::customize`].
+pub use hyperlight_host::sandbox::SandboxConfiguration;
+pub use hypervisor::{probe, Hypervisor};
+pub use output::OutputOverflow;
+pub use phase::{Phase, PhaseTimings};
+pub use pool::VmPool;
+pub use stats::VmStats;
+pub use sweep::{run_many, sweep, sweep_runs, SweepRun};
+pub use task::VmTask;
+pub use vm::{Capture, Vm, VmBuilder};
 
 /// Magic header for cmdline embedded in initrd: "HLCMDLN\0"
 const CMDLINE_MAGIC: &[u8; 8] = b"HLCMDLN\0";
@@ -83,6 +148,23 @@ const MOUNT_MAGIC: &[u8; 8] = b"HLHSMNT\0";
 /// a sensible wall time without any host round-trip per call.
 const WALLTIME_MAGIC: &[u8; 8] = b"HLWALL0\0";
 
+/// Magic header for the optional extra-host-regions TLV: one entry per
+/// [`HostRegion`] giving its guest-physical base, size and name.
+const REGION_MAGIC: &[u8; 8] = b"HLMMAP0\0";
+
+/// Magic header for the optional guest-environment TLV: `KEY=VALUE`
+/// strings (`TZ`, `LANG`, `LC_ALL`) the guest exports before starting the
+/// application.
+const ENV_MAGIC: &[u8; 8] = b"HLENV00\0";
+
+/// Where the mapped initrd lives in guest-physical memory. 3 GiB is high
+/// enough to not overlap any reasonable primary shared memory region,
+/// within the 4 GiB identity map. Extra host regions follow it.
+const INITRD_MAP_BASE: u64 = 0xC000_0000;
+
+/// End of the guest's identity-mapped window; mappings must fit below it.
+const MAP_LIMIT: u64 = 0x1_0000_0000;
+
 const PAGE_SIZE: usize = 4096;
 
 /// Guest paths that would shadow the kernel's own ramfs and break the VM.
@@ -108,17 +190,19 @@ impl Preopen {
     pub fn new<P: AsRef<Path>>(host_dir: P, guest_path: impl Into<String>) -> Result<Self> {
         let guest_path = guest_path.into();
         if !guest_path.starts_with('/') {
-            return Err(anyhow!(
+            return Err(Error::config(format!(
                 "guest mount path {:?} must be absolute",
                 guest_path
-            ));
+            ))
+            .into());
         }
         for reserved in RESERVED_GUEST_MOUNTPOINTS {
             if guest_path == *reserved || guest_path.starts_with(&format!("{}/", reserved)) {
-                return Err(anyhow!(
+                return Err(Error::config(format!(
                     "refusing to mount at guest path {:?}: shadows reserved kernel dir",
                     guest_path
-                ));
+                ))
+                .into());
             }
         }
         let host_dir = std::fs::canonicalize(host_dir.as_ref()).map_err(|e| {
@@ -161,17 +245,183 @@ impl Preopen {
 // Configuration
 // ---------------------------------------------------------------------------
 
+/// How the guest's console reaches the host, which decides how a
+/// capturing run collects it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum Console {
+    /// [`HostPrint`](Self::HostPrint) for a kernel known to print
+    /// through it, [`Stderr`](Self::Stderr) otherwise. A kernel becomes
+    /// known once one of its boots in this process printed that way (see
+    /// [`KernelImage::prints_to_host`](kernel_cache::KernelImage::prints_to_host)),
+    /// so only the first boot of each kernel file takes turns on fd 2;
+    /// later boots, runs and post-run hook captures of it proceed side by
+    /// side.
+    #[default]
+    Auto,
+    /// Writes to port 0xE9, which Hyperlight prints to the host's stderr.
+    /// Capturing redirects fd 2, so capturing runs take turns and
+    /// anything else the process writes to stderr meanwhile is captured
+    /// too.
+    Stderr,
+    /// Calls to Hyperlight's `HostPrint` function, which each sandbox
+    /// answers itself. Captures are per sandbox: they run concurrently
+    /// and see only their own guest's output. Needs a kernel whose
+    /// console prints that way.
+    HostPrint,
+}
+
+impl Console {
+    /// Whether runs of `kernel` can collect their console per sandbox,
+    /// with no need for the process-wide console lock.
+    pub(crate) fn per_sandbox(self, kernel: &Path) -> bool {
+        match self {
+            Self::HostPrint => true,
+            Self::Stderr => false,
+            Self::Auto => kernel_prints_to_host(kernel),
+        }
+    }
+}
+
+/// Whether `kernel`'s cached image is known to print through `HostPrint`.
+/// Only looks at images already cached, so an unknown kernel isn't
+/// mapped just to find out it's unknown.
+fn kernel_prints_to_host(kernel: &Path) -> bool {
+    kernel_cache::global()
+        .cached(kernel)
+        .is_some_and(|image| image.prints_to_host())
+}
+
 /// Configuration for a Unikraft VM.
 pub struct VmConfig {
     pub heap_size: u64,
     pub stack_size: u64,
+    /// When capturing output, also forward it live to the host's stderr.
+    pub tee_output: bool,
+    /// Strip ANSI escape sequences from the captured output. The tee
+    /// stream is unaffected.
+    pub strip_ansi: bool,
+    /// Most console bytes a capturing run keeps; `None` keeps all.
+    pub max_output_bytes: Option<u64>,
+    /// What happens past `max_output_bytes`.
+    pub output_overflow: OutputOverflow,
+    /// Inject this wall-clock time at boot instead of reading the host
+    /// clock, so guest `time()` calls see a reproducible value.
+    pub wall_clock: Option<std::time::SystemTime>,
+    /// Also emit each captured line as a `tracing` event (see
+    /// [`output::forward_to_tracing`]).
+    pub forward_to_tracing: bool,
+    /// Collect guest trace frames into [`VmOutput::trace`] (see
+    /// [`trace`]).
+    pub guest_trace: bool,
+    /// Summarize the run's host calls into [`VmOutput::host_calls`] (see
+    /// [`hostcall`]).
+    pub call_stats: bool,
+    /// `TZ` for the guest, e.g. `Europe/Berlin` or a POSIX rule like
+    /// `CET-1CEST,M3.5.0,M10.5.0/3`. Named zones need tzdata in the
+    /// rootfs.
+    pub timezone: Option<String>,
+    /// Locale for the guest (`LANG` and `LC_ALL`), e.g. `de_DE.UTF-8`.
+    pub locale: Option<String>,
+    /// Zero the host-side copies of the run's inputs once it is done:
+    /// rootfs buffers this crate allocated and spilled initrd or region
+    /// files. See [`VmConfig::with_scrub_inputs`].
+    pub scrub_inputs: bool,
+    /// How long Hyperlight waits before re-signalling a vCPU that
+    /// hasn't stopped for a [timeout](Self::with_timeout) or kill yet;
+    /// `None` keeps Hyperlight's default. Linux only.
+    pub interrupt_retry_delay: Option<Duration>,
+    /// Have Hyperlight write a core dump of the guest when it crashes —
+    /// a panic, abort or fault — for inspection with gdb. Needs the
+    /// `crashdump` feature.
+    pub core_dump: bool,
+    /// Stop the guest at boot and wait for gdb on this port. Needs the
+    /// `gdb` feature and a debug build.
+    pub gdb_port: Option<u16>,
+    /// The backend the run must get; see [`hypervisor`].
+    pub hypervisor: Hypervisor,
+    /// How the guest prints; see [`Console`].
+    pub console: Console,
+    sinks: Vec<Arc<SharedSink>>,
+    tee_writer: Option<Arc<stderr_capture::SharedWriter>>,
+    initrd_pipeline: Option<pipeline::InitrdPipeline>,
+    initrd_cache: Option<Arc<initrd_cache::InitrdCache>>,
+    customizers: Vec<ConfigCustomizer>,
+    call_observers: Vec<Arc<dyn hostcall::CallObserver>>,
+    stdin: Option<Arc<hostfn::Stdin>>,
+    env: Vec<(String, String)>,
+    /// Interrupt the application if it runs longer than this; see
+    /// [`VmConfig::with_timeout`].
+    pub timeout: Option<Duration>,
+    /// Whether capturing runs take the console at all; with `false`
+    /// output stays on the host's stderr (see [`Capture::None`]).
+    pub(crate) capture_output: bool,
+    /// Set for runs behind a [`VmTask`], which interrupts the sandbox
+    /// when the task is dropped.
+    cancel: Option<Arc<task::Cancel>>,
+    pre_run_hooks: Vec<PreRunHook>,
+    post_run_hooks: Vec<PostRunHook>,
+    /// Set by [`VmConfig::with_heap_fraction`]; checked by `validate`.
+    heap_fraction: Option<f64>,
+}
+
+/// The assets a run is about to boot, as seen by a pre-run hook.
+pub struct RunAssets<'a> {
+    pub kernel_path: &'a Path,
+    /// The extended initrd (cmdline header + rootfs) exactly as it will
+    /// be handed to the guest; [`ExtendedInitrd::to_vec`] joins it.
+    pub initrd: Option<&'a ExtendedInitrd<'a>>,
+    pub app_args: &'a [String],
 }
 
+/// Called before the sandbox is created. Returning an error aborts the run.
+pub type PreRunHook = Box<dyn Fn(&RunAssets<'_>) -> Result<()> + Send + Sync>;
+
+/// Adjusts the Hyperlight [`SandboxConfiguration`] this crate built.
+pub type ConfigCustomizer = Box<dyn Fn(&mut SandboxConfiguration) + Send + Sync>;
+
+/// Called once the run has finished, successfully or not.
+pub type PostRunHook = Box<dyn Fn(Result<&VmOutput, &anyhow::Error>) + Send + Sync>;
+
 impl Default for VmConfig {
     fn default() -> Self {
         Self {
             heap_size: 512 * 1024 * 1024,
             stack_size: 8 * 1024 * 1024,
+            tee_output: false,
+            strip_ansi: false,
+            max_output_bytes: None,
+            output_overflow: OutputOverflow::KeepHead,
+            wall_clock: None,
+            forward_to_tracing: false,
+            guest_trace: false,
+            call_stats: false,
+            timezone: None,
+            locale: None,
+            scrub_inputs: false,
+            interrupt_retry_delay: None,
+            core_dump: false,
+            gdb_port: None,
+            hypervisor: Hypervisor::Auto,
+            console: Console::Auto,
+            sinks: Vec::new(),
+            tee_writer: None,
+            initrd_pipeline: None,
+            initrd_cache: None,
+            customizers: Vec::new(),
+            call_observers: Vec::new(),
+            stdin: None,
+            env: Vec::new(),
+            timeout: None,
+            capture_output: true,
+            cancel: None,
+            pre_run_hooks: Vec::new(),
+            post_run_hooks: Vec::new(),
+            heap_fraction: None,
         }
     }
 }
@@ -181,6 +431,21 @@ impl VmConfig {
     /// for building a `VmConfig` inline.
     pub fn with_heap_size(mut self, size: u64) -> Self {
         self.heap_size = size;
+        self.heap_fraction = None;
+        self
+    }
+
+    /// Set the guest heap to `fraction` (0.0–1.0] of the memory
+    /// available to this process (see [`host_memory`]), resolved now.
+    /// Useful when one config runs on machines of different sizes. A
+    /// fraction out of range, or a host whose memory can't be read,
+    /// leaves the heap size alone and fails [`validate`](Self::validate),
+    /// so the run reports it.
+    pub fn with_heap_fraction(mut self, fraction: f64) -> Self {
+        if let Ok(bytes) = host_memory().and_then(|total| fraction_of(total, fraction)) {
+            self.heap_size = bytes;
+        }
+        self.heap_fraction = Some(fraction);
         self
     }
 
@@ -190,6 +455,381 @@ impl VmConfig {
         self
     }
 
+    /// Pass captured guest output through to the host's stderr as it is
+    /// produced, while [`run_vm_capture_output`] still returns the full
+    /// text. Chainable setter.
+    pub fn with_tee_output(mut self, tee: bool) -> Self {
+        self.tee_output = tee;
+        self
+    }
+
+    /// Like [`with_tee_output`](Self::with_tee_output), but the live copy
+    /// goes to `writer` (a log file, a socket, a progress pane) instead
+    /// of stderr. The writer is shared by all runs that use this config,
+    /// each chunk written in one piece. With the default
+    /// [`Console::Stderr`], `writer` must not itself write to the
+    /// process's stderr: that is where the capture reads from. Chainable
+    /// setter.
+    pub fn with_tee_to<W: std::io::Write + Send + 'static>(mut self, writer: W) -> Self {
+        self.tee_output = true;
+        self.tee_writer = Some(Arc::new(std::sync::Mutex::new(writer)));
+        self
+    }
+
+    /// Remove ANSI color/cursor sequences from the text returned by
+    /// [`run_vm_capture_output`], so it can be parsed or embedded in JSON.
+    /// Live tee output keeps its colors. Chainable setter.
+    pub fn with_strip_ansi(mut self, strip: bool) -> Self {
+        self.strip_ansi = strip;
+        self
+    }
+
+    /// Keep at most `bytes` of the guest's console, so a runaway guest
+    /// can't exhaust the host's memory. By default the start is kept and
+    /// the rest dropped; [`with_output_overflow`](Self::with_output_overflow)
+    /// picks another policy. [`VmOutput::dropped_bytes`] reports the cut.
+    /// Chainable setter.
+    pub fn with_max_output_bytes(mut self, bytes: u64) -> Self {
+        self.max_output_bytes = Some(bytes);
+        self
+    }
+
+    /// What to do once the output passes
+    /// [`with_max_output_bytes`](Self::with_max_output_bytes). Chainable
+    /// setter.
+    pub fn with_output_overflow(mut self, overflow: OutputOverflow) -> Self {
+        self.output_overflow = overflow;
+        self
+    }
+
+    /// Pin the wall-clock time injected at boot (see [`wall_clock`](Self::wall_clock)).
+    pub fn with_wall_clock(mut self, time: std::time::SystemTime) -> Self {
+        self.wall_clock = Some(time);
+        self
+    }
+
+    /// Set the guest's timezone (see [`timezone`](Self::timezone)).
+    pub fn with_timezone(mut self, tz: impl Into<String>) -> Self {
+        self.timezone = Some(tz.into());
+        self
+    }
+
+    /// Set the guest's locale (see [`locale`](Self::locale)).
+    pub fn with_locale(mut self, locale: impl Into<String>) -> Self {
+        self.locale = Some(locale.into());
+        self
+    }
+
+    /// Set an environment variable for the guest application.
+    /// Repeatable; a later value for the same key wins. Names must be
+    /// shell identifiers and values free of NUL bytes, which the run
+    /// checks before booting.
+    pub fn with_env(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        let key = key.into();
+        self.env.retain(|(k, _)| *k != key);
+        self.env.push((key, value.into()));
+        self
+    }
+
+    /// Interrupt the application if it is still running after `limit`
+    /// of wall-clock time; boot doesn't count. A capturing run then
+    /// fails with [`Error::TimedOut`], which carries the output printed
+    /// up to that point. Chainable setter.
+    pub fn with_timeout(mut self, limit: Duration) -> Self {
+        self.timeout = Some(limit);
+        self
+    }
+
+    /// Re-emit captured guest lines through the `tracing` facade with
+    /// their parsed level, so they reach the embedding service's
+    /// subscriber. Chainable setter.
+    pub fn with_tracing(mut self, forward: bool) -> Self {
+        self.forward_to_tracing = forward;
+        self
+    }
+
+    /// Attach the trace frames Hyperlight reports for the guest to the
+    /// run's [`VmOutput`]. Frames are only produced when the crate is
+    /// built with the `guest-trace` feature. Chainable setter.
+    pub fn with_guest_trace(mut self, collect: bool) -> Self {
+        self.guest_trace = collect;
+        self
+    }
+
+    /// Count the run's host calls per tool, with latency percentiles,
+    /// into [`VmOutput::host_calls`]. Chainable setter.
+    pub fn with_call_stats(mut self, collect: bool) -> Self {
+        self.call_stats = collect;
+        self
+    }
+
+    /// Send every captured line to `sink` as well, e.g. a
+    /// [`sink::JournalSink`]. Repeatable; the sink is shared by all runs
+    /// that use this config.
+    pub fn with_sink<S: sink::OutputSink + 'static>(mut self, sink: S) -> Self {
+        self.sinks.push(Arc::new(std::sync::Mutex::new(sink)));
+        self
+    }
+
+    /// Transform the initrd with `pipeline` before the boot header is
+    /// prepended. Pre-run hooks see the transformed archive.
+    pub fn with_initrd_pipeline(mut self, pipeline: pipeline::InitrdPipeline) -> Self {
+        self.initrd_pipeline = Some(pipeline);
+        self
+    }
+
+    /// Reuse prepared initrds from `cache` (see [`initrd_cache`]), so
+    /// repeated runs of the same in-memory rootfs skip writing it out
+    /// again. Share one cache between the configs of those runs.
+    pub fn with_initrd_cache(mut self, cache: Arc<initrd_cache::InitrdCache>) -> Self {
+        self.initrd_cache = Some(cache);
+        self
+    }
+
+    /// Scrub the host copies of the run's inputs when the sandbox is torn
+    /// down, so secrets in an (e.g. decrypted) rootfs or host region
+    /// don't linger in freed heap pages or the page cache: buffers this
+    /// crate allocated are zeroed before they are freed, and spilled
+    /// files are overwritten before they are deleted. Scrubbed runs
+    /// bypass the [initrd cache](Self::with_initrd_cache), whose files
+    /// outlive the run. Caller-owned buffers and files stay the caller's
+    /// to clear.
+    ///
+    /// This does not touch guest RAM, which Hyperlight gives the host no
+    /// way to write; there is deliberately no option claiming to zero it.
+    pub fn with_scrub_inputs(mut self, scrub: bool) -> Self {
+        self.scrub_inputs = scrub;
+        self
+    }
+
+    /// Report every host call the guest makes to `observer`, e.g. a
+    /// [`hostcall::CallTrace`]. Repeatable.
+    pub fn with_call_observer(mut self, observer: Arc<dyn hostcall::CallObserver>) -> Self {
+        self.call_observers.push(observer);
+        self
+    }
+
+    /// Give the guest `data` as its standard input, served through the
+    /// `stdin_read` tool; see [`hostfn::Stdin`].
+    pub fn with_stdin(mut self, data: impl Into<Vec<u8>>) -> Self {
+        self.stdin = Some(Arc::new(hostfn::Stdin::from_bytes(data)));
+        self
+    }
+
+    /// Like [`with_stdin`](Self::with_stdin), streaming from `reader` as
+    /// the guest reads instead of buffering it all up front.
+    pub fn with_stdin_reader(mut self, reader: impl std::io::Read + Send + 'static) -> Self {
+        self.stdin = Some(Arc::new(hostfn::Stdin::from_reader(reader)));
+        self
+    }
+
+    /// Set how long Hyperlight waits between attempts to interrupt a
+    /// vCPU. Shorter delays stop timed-out guests sooner at the cost of
+    /// more signals. Chainable setter.
+    pub fn with_interrupt_retry_delay(mut self, delay: Duration) -> Self {
+        self.interrupt_retry_delay = Some(delay);
+        self
+    }
+
+    /// Dump the guest's memory and registers when it crashes; see
+    /// [`core_dump`](Self::core_dump). Chainable setter.
+    pub fn with_core_dump(mut self, enabled: bool) -> Self {
+        self.core_dump = enabled;
+        self
+    }
+
+    /// Serve the guest to gdb on `port`, waiting for it to attach before
+    /// the kernel runs; see [`gdb_port`](Self::gdb_port). Chainable
+    /// setter.
+    pub fn with_gdb(mut self, port: u16) -> Self {
+        self.gdb_port = Some(port);
+        self
+    }
+
+    /// Only run on `hypervisor`, failing before boot with
+    /// [`Error::HypervisorUnavailable`] if this host can't provide it.
+    /// Chainable setter.
+    pub fn with_hypervisor(mut self, hypervisor: Hypervisor) -> Self {
+        self.hypervisor = hypervisor;
+        self
+    }
+
+    /// Capture the guest's console the way its kernel prints it. The
+    /// default, [`Console::Auto`], captures per sandbox once the kernel
+    /// is known to print through `HostPrint`; [`Console::HostPrint`]
+    /// does from its first boot, and [`Console::Stderr`] always
+    /// redirects fd 2. Chainable setter.
+    pub fn with_console(mut self, console: Console) -> Self {
+        self.console = console;
+        self
+    }
+
+    /// Escape hatch for Hyperlight options this crate doesn't wrap:
+    /// `f` gets the [`SandboxConfiguration`] after heap and scratch sizes
+    /// are set, so anything it changes wins. Repeatable; runs in order.
+    ///
+    /// ```no_run
+    /// # use hyperlight_unikraft::VmConfig;
+    /// let config = VmConfig::default().customize(|cfg| {
+    ///     cfg.set_input_data_size(1024 * 1024);
+    /// });
+    /// ```
+    pub fn customize<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&mut SandboxConfiguration) + Send + Sync + 'static,
+    {
+        self.customizers.push(Box::new(f));
+        self
+    }
+
+    /// Register a hook that inspects the prepared assets before boot —
+    /// custom validation, logging, policy checks. An `Err` aborts the run
+    /// before any sandbox is created. Repeatable; hooks run in order.
+    pub fn with_pre_run_hook<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&RunAssets<'_>) -> Result<()> + Send + Sync + 'static,
+    {
+        self.pre_run_hooks.push(Box::new(hook));
+        self
+    }
+
+    /// Register a hook that receives the run's result — artifact upload,
+    /// metrics, audit logs. Runs for failures too. Repeatable.
+    pub fn with_post_run_hook<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(Result<&VmOutput, &anyhow::Error>) + Send + Sync + 'static,
+    {
+        self.post_run_hooks.push(Box::new(hook));
+        self
+    }
+
+    /// Apply `HYPERLIGHT_UNIKRAFT_*` environment overrides on top of
+    /// this config: [`ENV_MEMORY`] and [`ENV_STACK`] take the same
+    /// formats as [`parse_memory`], [`ENV_TIMEOUT`] those of
+    /// [`parse_duration`]. Unset variables leave the current value
+    /// alone, so explicit values set *after* this call still win — the
+    /// CLI's precedence is flags > env > config file > defaults.
+    pub fn with_env_overrides(self) -> Result<Self> {
+        self.apply_env_from(|key| std::env::var(key).ok())
+    }
+
+    fn apply_env_from(mut self, get: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        if let Some(v) = get(ENV_MEMORY) {
+            let size = parse_memory(&v).with_context(|| format!("{ENV_MEMORY}={v:?}"))?;
+            self = self.with_heap_size(size);
+        }
+        if let Some(v) = get(ENV_STACK) {
+            self.stack_size = parse_memory(&v).with_context(|| format!("{ENV_STACK}={v:?}"))?;
+        }
+        if let Some(v) = get(ENV_TIMEOUT) {
+            self.timeout =
+                Some(parse_duration(&v).with_context(|| format!("{ENV_TIMEOUT}={v:?}"))?);
+        }
+        Ok(self)
+    }
+
+    /// Check the settings a run would otherwise only trip over inside
+    /// Hyperlight or the guest: heap and stack at least [`MIN_HEAP_SIZE`]
+    /// and [`MIN_STACK_SIZE`] and whole pages, a well-formed guest
+    /// environment, and debug options this build can honour. Every run
+    /// calls this before creating the sandbox.
+    pub fn validate(&self) -> Result<()> {
+        if let Some(fraction) = self.heap_fraction {
+            fraction_of(host_memory()?, fraction)
+                .map_err(|e| Error::config(format!("heap fraction: {e}")))?;
+        }
+        check_size("heap", self.heap_size, MIN_HEAP_SIZE)?;
+        check_size("stack", self.stack_size, MIN_STACK_SIZE)?;
+        if self.core_dump && !cfg!(feature = "crashdump") {
+            return Err(Error::config("core dumps need the `crashdump` feature").into());
+        }
+        if self.gdb_port.is_some() && !cfg!(all(feature = "gdb", debug_assertions)) {
+            return Err(Error::config(
+                "debugging with gdb needs the `gdb` feature and a debug build",
+            )
+            .into());
+        }
+        self.guest_env().map(drop)
+    }
+
+    /// [`validate`](Self::validate), and check that a rootfs of
+    /// `initrd_len` bytes fits in the heap once extracted.
+    pub fn validate_for_initrd(&self, initrd_len: u64) -> Result<()> {
+        self.validate()?;
+        check_initrd_fits(initrd_len, self.heap_size)
+    }
+
+    /// The environment announced in the boot header.
+    fn guest_env(&self) -> Result<Vec<String>> {
+        self.guest_env_with(&[])
+    }
+
+    /// [`guest_env`](Self::guest_env) with `extra` set on top; a key in
+    /// `extra` replaces the config's value.
+    fn guest_env_with(&self, extra: &[(String, String)]) -> Result<Vec<String>> {
+        let mut env = guest_env(self.timezone.as_deref(), self.locale.as_deref())?;
+        let overridden = |key: &String| extra.iter().any(|(k, _)| k == key);
+        let vars = self.env.iter().filter(|(k, _)| !overridden(k));
+        for (key, value) in vars.chain(extra) {
+            let mut chars = key.chars();
+            let identifier = chars
+                .next()
+                .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+                && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+            if !identifier {
+                return Err(
+                    Error::config(format!("invalid environment variable name {key:?}")).into(),
+                );
+            }
+            if value.contains('\0') {
+                return Err(Error::config(format!(
+                    "environment variable {key} contains a NUL byte"
+                ))
+                .into());
+            }
+            env.push(format!("{key}={value}"));
+        }
+        Ok(env)
+    }
+
+    /// Run the initrd through the configured pipeline, if any. A zstd
+    /// archive is decompressed first, as it is for a file initrd.
+    fn apply_initrd_pipeline<'a>(&self, initrd: Option<&'a [u8]>) -> Result<PreparedInitrd<'a>> {
+        let scrub = self.scrub_inputs;
+        let initrd = match initrd {
+            Some(data) => Some(match rootfs::decode_if_zstd(data)? {
+                Some(plain) => std::borrow::Cow::Owned(plain),
+                None => std::borrow::Cow::Borrowed(data),
+            }),
+            None => None,
+        };
+        let Some(ref pipeline) = self.initrd_pipeline else {
+            return Ok(PreparedInitrd {
+                data: initrd,
+                scrub,
+            });
+        };
+        let out = pipeline
+            .run(initrd.map(std::borrow::Cow::into_owned).unwrap_or_default())
+            .map_err(error::caller)?;
+        Ok(PreparedInitrd {
+            data: (!out.is_empty()).then_some(std::borrow::Cow::Owned(out)),
+            scrub,
+        })
+    }
+    fn run_pre_hooks(&self, assets: &RunAssets<'_>) -> Result<()> {
+        for hook in &self.pre_run_hooks {
+            hook(assets).map_err(error::caller)?;
+        }
+        Ok(())
+    }
+
+    fn run_post_hooks(&self, result: &Result<VmOutput>) {
+        for hook in &self.post_run_hooks {
+            hook(result.as_ref());
+        }
+    }
+
     fn sandbox_config(&self) -> SandboxConfiguration {
         let mut cfg = SandboxConfiguration::default();
         cfg.set_heap_size(self.heap_size);
@@ -203,146 +843,808 @@ impl VmConfig {
         let base = std::cmp::max(self.heap_size as usize / 4, 64 * 1024 * 1024);
         let scratch = (pt_estimate + base).next_multiple_of(PAGE_SIZE);
         cfg.set_scratch_size(scratch);
+        #[cfg(target_os = "linux")]
+        if let Some(delay) = self.interrupt_retry_delay {
+            cfg.set_interrupt_retry_delay(delay);
+        }
+        #[cfg(feature = "crashdump")]
+        cfg.set_guest_core_dump(self.core_dump);
+        #[cfg(all(feature = "gdb", debug_assertions))]
+        if let Some(port) = self.gdb_port {
+            cfg.set_guest_debug_info(hyperlight_host::sandbox::config::DebugInfo { port });
+        }
+        for customize in &self.customizers {
+            customize(&mut cfg);
+        }
         cfg
     }
 }
 
-/// Parse memory size string (e.g., "512Mi", "1Gi") into bytes.
+/// Environment variable overriding the guest heap size (e.g. `1Gi`).
+pub const ENV_MEMORY: &str = "HYPERLIGHT_UNIKRAFT_MEMORY";
+/// Environment variable overriding the guest stack size (e.g. `8Mi`).
+pub const ENV_STACK: &str = "HYPERLIGHT_UNIKRAFT_STACK";
+/// Environment variable setting the run time limit (e.g. `30s`); see
+/// [`VmConfig::with_timeout`].
+pub const ENV_TIMEOUT: &str = "HYPERLIGHT_UNIKRAFT_TIMEOUT";
+/// Environment variable naming the kernel binary for the CLI.
+pub const ENV_KERNEL: &str = "HYPERLIGHT_UNIKRAFT_KERNEL";
+/// Environment variable naming the initrd CPIO for the CLI.
+pub const ENV_INITRD: &str = "HYPERLIGHT_UNIKRAFT_INITRD";
+
+/// Parse memory size string (e.g., "512Mi", "1.5Gi", "64MiB") into
+/// bytes. Binary units run `Ki` to `Pi` (optionally with a `B`),
+/// decimal ones `K`/`KB` to `P`/`PB`; units are case-insensitive and a
+/// bare number is bytes. A percentage ("25%") is resolved against
+/// [`host_memory`]. [`format_memory`] writes sizes back the same way.
 pub fn parse_memory(mem_str: &str) -> Result<u64> {
+    memory_bytes(mem_str)
+        .map_err(|e| Error::config(format!("invalid memory size {mem_str:?}: {e}")).into())
+}
+
+/// Binary units, largest first, as [`format_memory`] picks them.
+const MEMORY_UNITS: [(&str, u64); 5] = [
+    ("Pi", 1 << 50),
+    ("Ti", 1 << 40),
+    ("Gi", 1 << 30),
+    ("Mi", 1 << 20),
+    ("Ki", 1 << 10),
+];
+
+fn memory_bytes(mem_str: &str) -> Result<u64> {
     let s = mem_str.trim();
-    if let Some(v) = s.strip_suffix("Gi") {
-        Ok(v.parse::<u64>()? * 1024 * 1024 * 1024)
-    } else if let Some(v) = s.strip_suffix("Mi") {
-        Ok(v.parse::<u64>()? * 1024 * 1024)
-    } else if let Some(v) = s.strip_suffix("Ki") {
-        Ok(v.parse::<u64>()? * 1024)
-    } else if let Some(v) = s.strip_suffix("G") {
-        Ok(v.parse::<u64>()? * 1_000_000_000)
-    } else if let Some(v) = s.strip_suffix("M") {
-        Ok(v.parse::<u64>()? * 1_000_000)
-    } else if let Some(v) = s.strip_suffix("K") {
-        Ok(v.parse::<u64>()? * 1000)
+    if let Some(v) = s.strip_suffix('%') {
+        let pct: f64 = v
+            .trim()
+            .parse()
+            .map_err(|e| anyhow!("Invalid memory format: {}", e))?;
+        return fraction_of(host_memory()?, pct / 100.0);
+    }
+    let split = s.find(|c: char| c.is_ascii_alphabetic()).unwrap_or(s.len());
+    let (number, unit) = (s[..split].trim(), s[split..].to_ascii_lowercase());
+    let unit = unit.strip_suffix('b').unwrap_or(&unit);
+    let scale: u64 = match unit {
+        "" => 1,
+        "k" => 1000,
+        "m" => 1000_u64.pow(2),
+        "g" => 1000_u64.pow(3),
+        "t" => 1000_u64.pow(4),
+        "p" => 1000_u64.pow(5),
+        binary => MEMORY_UNITS
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(binary))
+            .map(|&(_, scale)| scale)
+            .ok_or_else(|| anyhow!("unknown unit {:?}", &s[split..]))?,
+    };
+    let too_large = || anyhow!("more than {} bytes", u64::MAX);
+    if let Ok(whole) = number.parse::<u64>() {
+        return whole.checked_mul(scale).ok_or_else(too_large);
+    }
+    let n: f64 = number
+        .parse()
+        .map_err(|e| anyhow!("Invalid memory format: {}", e))?;
+    if !n.is_finite() || n < 0.0 {
+        return Err(anyhow!("Invalid memory format: {number:?}"));
+    }
+    let bytes = (n * scale as f64).round();
+    if bytes >= u64::MAX as f64 {
+        return Err(too_large());
+    }
+    Ok(bytes as u64)
+}
+
+/// Render a byte count in the largest binary unit that holds it exactly
+/// ("512Mi", "1536Mi", "4097"), so [`parse_memory`] reads back the same
+/// number.
+pub fn format_memory(bytes: u64) -> String {
+    MEMORY_UNITS
+        .iter()
+        .find(|&&(_, scale)| bytes != 0 && bytes.is_multiple_of(scale))
+        .map_or_else(
+            || bytes.to_string(),
+            |(name, scale)| format!("{}{name}", bytes / scale),
+        )
+}
+
+/// Memory available to this process in bytes: its cgroup's limit
+/// (`memory.max`, or `memory.limit_in_bytes` on cgroup v1) when one is
+/// set below the host's physical memory, otherwise the physical memory.
+/// In a container, that is the container's share rather than the
+/// machine's.
+#[cfg(target_os = "linux")]
+pub fn host_memory() -> Result<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo")?;
+    let total = meminfo
+        .lines()
+        .find_map(|l| l.strip_prefix("MemTotal:"))
+        .and_then(|v| v.trim().strip_suffix("kB"))
+        .and_then(|kb| kb.trim().parse::<u64>().ok())
+        .map(|kb| kb * 1024)
+        .ok_or_else(|| anyhow!("MemTotal not found in /proc/meminfo"))?;
+    let limit = std::fs::read_to_string("/proc/self/cgroup")
+        .ok()
+        .and_then(|cgroups| cgroup_memory_limit(&cgroups, Path::new("/sys/fs/cgroup")));
+    Ok(limit.map_or(total, |limit| limit.min(total)))
+}
+
+/// The memory limit of the cgroup named in `/proc/self/cgroup` text,
+/// read under the cgroup mount `root`. `None` when unlimited or unknown.
+#[cfg(target_os = "linux")]
+fn cgroup_memory_limit(cgroups: &str, root: &Path) -> Option<u64> {
+    let read = |file: std::path::PathBuf| {
+        let text = std::fs::read_to_string(file).ok()?;
+        text.trim().parse::<u64>().ok()
+    };
+    cgroups.lines().find_map(|line| {
+        let mut fields = line.splitn(3, ':');
+        let (_, controllers, path) = (fields.next()?, fields.next()?, fields.next()?);
+        let path = path.trim_start_matches('/');
+        if controllers.is_empty() {
+            // v2; "max" doesn't parse, and means no limit.
+            read(root.join(path).join("memory.max"))
+        } else if controllers.split(',').any(|c| c == "memory") {
+            read(root.join("memory").join(path).join("memory.limit_in_bytes"))
+        } else {
+            None
+        }
+    })
+}
+
+/// Total physical memory of the host in bytes.
+#[cfg(not(target_os = "linux"))]
+pub fn host_memory() -> Result<u64> {
+    Err(anyhow!(
+        "host memory detection is only supported on Linux; give an absolute size"
+    ))
+}
+
+/// `fraction` of `total`, rounded down to a whole page.
+fn fraction_of(total: u64, fraction: f64) -> Result<u64> {
+    if !(fraction > 0.0 && fraction <= 1.0) {
+        return Err(anyhow!(
+            "memory fraction must be in (0%, 100%], got {}%",
+            fraction * 100.0
+        ));
+    }
+    let bytes = (total as f64 * fraction) as u64 & !(PAGE_SIZE as u64 - 1);
+    if bytes == 0 {
+        return Err(anyhow!(
+            "{}% of host memory is less than a page",
+            fraction * 100.0
+        ));
+    }
+    Ok(bytes)
+}
+
+/// Parse a duration string (e.g., "250ms", "30s", "5m", "1h") into a
+/// [`Duration`]. A bare number is seconds; fractions are allowed ("1.5s").
+pub fn parse_duration(dur_str: &str) -> Result<Duration> {
+    let s = dur_str.trim();
+    let (v, scale) = if let Some(v) = s.strip_suffix("ms") {
+        (v, 0.001)
+    } else if let Some(v) = s.strip_suffix('s') {
+        (v, 1.0)
+    } else if let Some(v) = s.strip_suffix('m') {
+        (v, 60.0)
+    } else if let Some(v) = s.strip_suffix('h') {
+        (v, 3600.0)
     } else {
-        s.parse()
-            .map_err(|e| anyhow!("Invalid memory format: {}", e))
+        (s, 1.0)
+    };
+    let invalid = |e: &dyn std::fmt::Display| {
+        Error::config(format!("Invalid duration format {:?}: {}", dur_str, e)).into()
+    };
+    let n: f64 = v.trim().parse().map_err(|e| invalid(&e))?;
+    Duration::try_from_secs_f64(n * scale).map_err(|e| invalid(&e))
+}
+
+/// Heap the guest needs beyond the extracted rootfs: kernel allocator
+/// metadata, ramfs bookkeeping, and the app's own startup.
+const INITRD_HEAP_HEADROOM: u64 = 16 * 1024 * 1024;
+
+/// Smallest heap [`VmConfig::validate`] accepts; the kernel alone needs
+/// a few MiB before any application runs.
+pub const MIN_HEAP_SIZE: u64 = 8 * 1024 * 1024;
+/// Smallest stack [`VmConfig::validate`] accepts.
+pub const MIN_STACK_SIZE: u64 = 64 * 1024;
+
+/// Reject a heap or stack size Hyperlight would choke on: below `min`,
+/// or not a whole number of pages (decimal units like `100M` aren't).
+fn check_size(what: &str, bytes: u64, min: u64) -> Result<()> {
+    let page = PAGE_SIZE as u64;
+    if !bytes.is_multiple_of(page) {
+        return Err(Error::config(format!(
+            "{what} size {bytes} is not a multiple of the {}Ki page size; \
+             use {} or a Ki/Mi/Gi size",
+            page / 1024,
+            bytes.next_multiple_of(page)
+        ))
+        .into());
+    }
+    if bytes < min {
+        return Err(Error::config(format!(
+            "{what} {} too small; need at least {}",
+            format_memory(bytes),
+            format_memory(min)
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+/// Fail fast when the rootfs can't possibly fit. Unikraft extracts the
+/// cpio into a heap-backed ramfs, so an initrd close to the heap size
+/// otherwise dies mid-extraction with an unhelpful guest crash. The
+/// suggestion rounds the minimum up to a power of two.
+pub(crate) fn check_initrd_fits(initrd_len: u64, heap_size: u64) -> Result<()> {
+    let needed = initrd_len.saturating_add(INITRD_HEAP_HEADROOM);
+    if needed > heap_size {
+        return Err(Error::config(format!(
+            "heap {} too small for {} rootfs; need at least {}",
+            format_mebibytes(heap_size),
+            format_mebibytes(initrd_len),
+            format_mebibytes(needed.next_power_of_two())
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+/// `TZ`/`LANG`/`LC_ALL` assignments for the boot header's env TLV.
+/// Values must be non-empty printable ASCII without spaces, which covers
+/// zone names, POSIX TZ rules and locale names.
+pub(crate) fn guest_env(timezone: Option<&str>, locale: Option<&str>) -> Result<Vec<String>> {
+    let check = |what: &str, v: &str| -> Result<()> {
+        if v.is_empty() || !v.bytes().all(|b| b.is_ascii_graphic()) {
+            return Err(Error::config(format!("invalid {what} {v:?}")).into());
+        }
+        Ok(())
+    };
+    let mut env = Vec::new();
+    if let Some(tz) = timezone {
+        check("timezone", tz)?;
+        env.push(format!("TZ={tz}"));
+    }
+    if let Some(locale) = locale {
+        check("locale", locale)?;
+        env.push(format!("LANG={locale}"));
+        env.push(format!("LC_ALL={locale}"));
     }
+    Ok(env)
+}
+
+/// Render a byte count as whole mebibytes, rounding up ("480Mi").
+pub(crate) fn format_mebibytes(bytes: u64) -> String {
+    format!("{}Mi", bytes.div_ceil(1024 * 1024))
 }
 
 // ---------------------------------------------------------------------------
 // Initrd cmdline prepend
 // ---------------------------------------------------------------------------
 
-/// Serialize the shared "cmdline + preopens + wall clock" TLV block into `buf`.
-///
-/// Layout:
-///   [HLCMDLN\0][cmdline_len u32][cmdline…][\0]
-///   [HLHSMNT\0][count u32]([path_len u32][path…][\0])*count  (optional block)
-///   [HLWALL0\0][8 u32][wall_ns_le u64]
-///
-/// Callers are responsible for any trailing padding / metadata (e.g. the
-/// mapped-initrd-size footer used by `build_cmdline_initdata`).
-fn write_cmdline_mount_tlv(buf: &mut Vec<u8>, cmdline_bytes: &[u8], preopens: &[Preopen]) {
-    let cmdline_len = cmdline_bytes.len() as u32;
-    buf.extend_from_slice(CMDLINE_MAGIC);
-    buf.extend_from_slice(&cmdline_len.to_le_bytes());
-    buf.extend_from_slice(cmdline_bytes);
-    buf.push(0);
+/// Everything the boot header (the TLV block ahead of the initrd, or the
+/// whole init_data in mapped mode) announces to the guest.
+#[derive(Default)]
+struct BootHeader<'a> {
+    app_args: &'a [String],
+    preopens: &'a [Preopen],
+    regions: &'a [FileMapping],
+    /// `KEY=VALUE` pairs from [`guest_env`].
+    env: &'a [String],
+    /// Wall clock to inject; `None` reads the host clock at build time.
+    wall_clock: Option<std::time::SystemTime>,
+}
 
-    if !preopens.is_empty() {
-        buf.extend_from_slice(MOUNT_MAGIC);
-        buf.extend_from_slice(&(preopens.len() as u32).to_le_bytes());
-        for p in preopens {
-            let b = p.guest_path.as_bytes();
-            buf.extend_from_slice(&(b.len() as u32).to_le_bytes());
-            buf.extend_from_slice(b);
-            buf.push(0);
-        }
+impl BootHeader<'_> {
+    /// True when there is nothing the guest needs to be told.
+    fn is_empty(&self) -> bool {
+        self.app_args.join(" ").is_empty()
+            && self.preopens.is_empty()
+            && self.regions.is_empty()
+            && self.env.is_empty()
+            && self.wall_clock.is_none()
     }
 
-    // Wall clock: read the host's time once at VM build time and embed
-    // as ns since epoch. The guest will add its own monotonic delta.
-    let wall_ns = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .map(|d| d.as_nanos() as u64)
-        .unwrap_or(0);
-    buf.extend_from_slice(WALLTIME_MAGIC);
-    buf.extend_from_slice(&8u32.to_le_bytes());
-    buf.extend_from_slice(&wall_ns.to_le_bytes());
+    /// Serialize the shared "cmdline + preopens + regions + env + wall
+    /// clock" TLV block into `buf`.
+    ///
+    /// Layout:
+    ///   [HLCMDLN\0][cmdline_len u32][cmdline…][\0]
+    ///   [HLHSMNT\0][count u32]([path_len u32][path…][\0])*count  (optional block)
+    ///   [HLWALL0\0][8 u32][wall_ns_le u64]
+    ///   [HLMMAP0\0][count u32]([base u64][size u64][name_len u32][name…][\0])*count
+    ///                                                              (optional block)
+    ///   [HLENV00\0][count u32]([len u32][KEY=VALUE…][\0])*count     (optional block)
+    ///
+    /// Existing guests stop reading at `HLWALL0`, so new blocks are
+    /// appended after it, and only a guest that knows a block's magic
+    /// reads it; the zero padding that follows ends the header.
+    ///
+    /// Callers are responsible for any trailing padding / metadata (e.g. the
+    /// mapped-initrd-size footer used by `build_cmdline_initdata`).
+    fn write(&self, buf: &mut Vec<u8>) {
+        let cmdline = self.app_args.join(" ");
+        let cmdline_bytes = cmdline.as_bytes();
+        let cmdline_len = cmdline_bytes.len() as u32;
+        buf.extend_from_slice(CMDLINE_MAGIC);
+        buf.extend_from_slice(&cmdline_len.to_le_bytes());
+        buf.extend_from_slice(cmdline_bytes);
+        buf.push(0);
+
+        if !self.preopens.is_empty() {
+            buf.extend_from_slice(MOUNT_MAGIC);
+            buf.extend_from_slice(&(self.preopens.len() as u32).to_le_bytes());
+            for p in self.preopens {
+                let b = p.guest_path.as_bytes();
+                buf.extend_from_slice(&(b.len() as u32).to_le_bytes());
+                buf.extend_from_slice(b);
+                buf.push(0);
+            }
+        }
+
+        // Wall clock: read the host's time once at VM build time (unless
+        // pinned) and embed as ns since epoch. The guest will add its own
+        // monotonic delta.
+        let wall_ns = self
+            .wall_clock
+            .unwrap_or_else(std::time::SystemTime::now)
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        buf.extend_from_slice(WALLTIME_MAGIC);
+        buf.extend_from_slice(&8u32.to_le_bytes());
+        buf.extend_from_slice(&wall_ns.to_le_bytes());
+
+        // Blocks added since HLWALL0 go after it: a guest that doesn't
+        // know their magic stops at HLWALL0 and never sees them.
+        if !self.regions.is_empty() {
+            buf.extend_from_slice(REGION_MAGIC);
+            buf.extend_from_slice(&(self.regions.len() as u32).to_le_bytes());
+            for r in self.regions {
+                let name = r.label.as_bytes();
+                buf.extend_from_slice(&r.base.to_le_bytes());
+                buf.extend_from_slice(&r.size.to_le_bytes());
+                buf.extend_from_slice(&(name.len() as u32).to_le_bytes());
+                buf.extend_from_slice(name);
+                buf.push(0);
+            }
+        }
+
+        if !self.env.is_empty() {
+            buf.extend_from_slice(ENV_MAGIC);
+            buf.extend_from_slice(&(self.env.len() as u32).to_le_bytes());
+            for var in self.env {
+                buf.extend_from_slice(&(var.len() as u32).to_le_bytes());
+                buf.extend_from_slice(var.as_bytes());
+                buf.push(0);
+            }
+        }
+    }
 }
 
-/// Build init_data with cmdline + preopens + mapped initrd size (for
+/// Build init_data with the boot header + mapped initrd size (for
 /// map_file_cow mode). The mapped file size is stored in the last 8
 /// bytes of the page-aligned header.
-fn build_cmdline_initdata(
-    app_args: &[String],
-    mapped_initrd_size: u64,
-    preopens: &[Preopen],
-) -> Option<Vec<u8>> {
-    let cmdline = app_args.join(" ");
-    if cmdline.is_empty() && mapped_initrd_size == 0 && preopens.is_empty() {
+fn build_cmdline_initdata(header: &BootHeader<'_>, mapped_initrd_size: u64) -> Option<Vec<u8>> {
+    if header.is_empty() && mapped_initrd_size == 0 {
         return None;
     }
 
-    let cmdline_bytes = cmdline.as_bytes();
     let mut buf = Vec::new();
-    write_cmdline_mount_tlv(&mut buf, cmdline_bytes, preopens);
+    header.write(&mut buf);
 
-    let padded = (buf.len() + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+    // Room for the footer even when the header ends near a page boundary.
+    let padded = (buf.len() + 8).next_multiple_of(PAGE_SIZE);
     buf.resize(padded - 8, 0);
     buf.extend_from_slice(&mapped_initrd_size.to_le_bytes());
     Some(buf)
 }
 
 /// Prepend application arguments + preopens as a header in the initrd.
+///
+/// This assembles one contiguous buffer for callers that need the bytes
+/// (the C API, pipeline stages); the boot path keeps the two parts
+/// separate as an [`ExtendedInitrd`].
 pub fn prepend_cmdline_to_initrd(
     initrd: Option<&[u8]>,
     app_args: &[String],
     preopens: &[Preopen],
 ) -> Option<Vec<u8>> {
-    let cmdline = app_args.join(" ");
+    let header = BootHeader {
+        app_args,
+        preopens,
+        ..BootHeader::default()
+    };
+    prepend_boot_header(initrd, &header).map(|e| e.to_vec())
+}
 
-    if cmdline.is_empty() && initrd.is_none() && preopens.is_empty() {
-        return None;
-    }
-    if cmdline.is_empty() && preopens.is_empty() {
-        return initrd.map(|d| d.to_vec());
-    }
+/// [`prepend_cmdline_to_initrd`] for an arbitrary [`BootHeader`],
+/// without copying the initrd.
+fn prepend_boot_header<'a>(
+    initrd: Option<&'a [u8]>,
+    header: &BootHeader<'_>,
+) -> Option<ExtendedInitrd<'a>> {
+    let header = if header.is_empty() {
+        Vec::new()
+    } else {
+        header_page(header)
+    };
+    (initrd.is_some() || !header.is_empty()).then_some(ExtendedInitrd {
+        header,
+        body: initrd,
+        file: None,
+    })
+}
 
-    let cmdline_bytes = cmdline.as_bytes();
+/// `header` serialized and zero-padded to a page boundary.
+fn header_page(header: &BootHeader<'_>) -> Vec<u8> {
     let mut buf = Vec::new();
-    write_cmdline_mount_tlv(&mut buf, cmdline_bytes, preopens);
-
-    let padded = (buf.len() + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
-    buf.resize(padded, 0);
-    if let Some(data) = initrd {
-        buf.extend_from_slice(data);
-    }
-    Some(buf)
+    header.write(&mut buf);
+    buf.resize(buf.len().next_multiple_of(PAGE_SIZE), 0);
+    buf
 }
 
-// ---------------------------------------------------------------------------
-// Tool dispatch (host functions callable from guest)
-// ---------------------------------------------------------------------------
+/// What a boot header announced, as decoded by [`parse_extended_initrd`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BootInfo {
+    /// The application arguments, joined with spaces.
+    pub cmdline: String,
+    /// Guest mount points of the preopened host directories.
+    pub mounts: Vec<String>,
+    /// Extra host regions mapped after the initrd.
+    pub regions: Vec<BootRegion>,
+    /// `KEY=VALUE` assignments exported before the application starts.
+    pub env: Vec<String>,
+    /// The host's wall clock when the header was built.
+    pub wall_clock: Option<std::time::SystemTime>,
+}
 
-/// Registry of tool handlers callable from guest user-space via `/dev/hcall`.
-pub struct ToolRegistry {
-    tools:
-        HashMap<String, Box<dyn Fn(serde_json::Value) -> Result<serde_json::Value> + Send + Sync>>,
+/// One `HLMMAP0` entry: a [`HostRegion`] as placed in guest memory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BootRegion {
+    pub base: u64,
+    pub size: u64,
+    pub name: String,
 }
 
-impl ToolRegistry {
-    /// Create an empty registry. Add handlers with
-    /// [`register`](Self::register) before wiring it into a sandbox.
-    pub fn new() -> Self {
-        Self {
-            tools: HashMap::new(),
-        }
+/// Decode the boot header at the front of an extended initrd (the output
+/// of [`prepend_cmdline_to_initrd`] or [`ExtendedInitrd::to_vec`]) and
+/// return it with the rootfs that follows its page.
+///
+/// An initrd without a header, which is what the host passes when there
+/// is nothing to announce, decodes to an empty [`BootInfo`] and the
+/// whole input.
+pub fn parse_extended_initrd(data: &[u8]) -> Result<(BootInfo, &[u8])> {
+    if !data.starts_with(CMDLINE_MAGIC) {
+        return Ok((BootInfo::default(), data));
     }
-
-    /// Register a named handler. The handler receives the JSON-encoded
-    /// `args` payload the guest sent and returns a `serde_json::Value`
-    /// that becomes the `{"result": ...}` portion of the response.
-    /// Errors returned by the handler become `{"error": "..."}`.
+    let mut r = HeaderReader { data, pos: 0 };
+    let mut info = BootInfo::default();
+    let mut after_wall = false;
+    loop {
+        if after_wall && r.at_padding() {
+            break;
+        }
+        let magic = r.take(8).context("boot header ends without HLWALL0")?;
+        match <&[u8; 8]>::try_from(magic).unwrap() {
+            CMDLINE_MAGIC if r.pos == 8 => info.cmdline = r.string()?,
+            MOUNT_MAGIC => {
+                for _ in 0..r.u32()? {
+                    info.mounts.push(r.string()?);
+                }
+            }
+            REGION_MAGIC => {
+                for _ in 0..r.u32()? {
+                    let (base, size) = (r.u64()?, r.u64()?);
+                    let name = r.string()?;
+                    info.regions.push(BootRegion { base, size, name });
+                }
+            }
+            ENV_MAGIC => {
+                for _ in 0..r.u32()? {
+                    info.env.push(r.string()?);
+                }
+            }
+            WALLTIME_MAGIC => {
+                if r.u32()? != 8 {
+                    return Err(anyhow!("HLWALL0 value is not 8 bytes"));
+                }
+                let ns = r.u64()?;
+                info.wall_clock = Some(std::time::UNIX_EPOCH + Duration::from_nanos(ns));
+                after_wall = true;
+            }
+            _ => {
+                return Err(anyhow!(
+                    "unknown boot header block {:?} at offset {}",
+                    String::from_utf8_lossy(magic),
+                    r.pos - 8
+                ))
+            }
+        }
+    }
+    let end = r.pos.next_multiple_of(PAGE_SIZE).min(data.len());
+    Ok((info, &data[end..]))
+}
+
+/// A cursor over boot header bytes for [`parse_extended_initrd`].
+struct HeaderReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> HeaderReader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos.saturating_add(n))
+            .ok_or_else(|| anyhow!("boot header truncated at offset {}", self.pos))?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    /// True at the zero padding (or the mapped-initrd size footer) that
+    /// ends the header page.
+    fn at_padding(&self) -> bool {
+        let page_end = self.pos.next_multiple_of(PAGE_SIZE).min(self.data.len());
+        page_end - self.pos <= 8 || self.data[self.pos..self.pos + 8] == [0; 8]
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    /// A `[len u32][bytes…][\0]` string.
+    fn string(&mut self) -> Result<String> {
+        let len = self.u32()? as usize;
+        let bytes = self.take(len)?;
+        if self.take(1)? != [0] {
+            return Err(anyhow!(
+                "boot header string at offset {} not NUL-terminated",
+                self.pos - 1
+            ));
+        }
+        String::from_utf8(bytes.to_vec()).context("boot header string is not UTF-8")
+    }
+}
+
+/// An initrd buffer as handed to the run functions, plus the file it is
+/// a mapping of, if any (see [`rootfs::MappedInitrd`]).
+#[derive(Clone, Copy)]
+pub(crate) struct InitrdRef<'a> {
+    data: &'a [u8],
+    file: Option<&'a Path>,
+}
+
+impl<'a> From<&'a [u8]> for InitrdRef<'a> {
+    fn from(data: &'a [u8]) -> Self {
+        Self { data, file: None }
+    }
+}
+
+impl<'a> From<&'a rootfs::MappedInitrd> for InitrdRef<'a> {
+    fn from(map: &'a rootfs::MappedInitrd) -> Self {
+        Self {
+            data: map,
+            file: Some(map.path()),
+        }
+    }
+}
+
+/// What an in-memory kernel is called in errors, hooks and sinks.
+pub const IN_MEMORY_KERNEL: &str = "<memory>";
+
+/// A kernel as handed to the run functions: the path it is known by
+/// and, for one held in memory, its image.
+#[derive(Clone, Copy)]
+pub(crate) struct KernelRef<'a> {
+    path: &'a Path,
+    image: Option<&'a [u8]>,
+}
+
+impl<'a> From<&'a Path> for KernelRef<'a> {
+    fn from(path: &'a Path) -> Self {
+        Self { path, image: None }
+    }
+}
+
+impl<'a> From<&'a [u8]> for KernelRef<'a> {
+    fn from(image: &'a [u8]) -> Self {
+        Self {
+            path: Path::new(IN_MEMORY_KERNEL),
+            image: Some(image),
+        }
+    }
+}
+
+impl<'a> KernelRef<'a> {
+    /// The kernel's image alongside `prepare`'s result: the one in
+    /// memory, or the file's from [`with_kernel_prefetch`].
+    fn load<T>(self, prepare: impl FnOnce() -> T) -> (Option<KernelImage<'a>>, T) {
+        match self.image {
+            Some(image) => (Some(KernelImage::Borrowed(image)), prepare()),
+            None => {
+                let (image, prepared) = with_kernel_prefetch(self.path, prepare);
+                (image.map(KernelImage::Cached), prepared)
+            }
+        }
+    }
+}
+
+enum KernelImage<'a> {
+    Borrowed(&'a [u8]),
+    Cached(Arc<kernel_cache::KernelImage>),
+}
+
+impl std::ops::Deref for KernelImage<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            KernelImage::Borrowed(image) => image,
+            KernelImage::Cached(image) => image,
+        }
+    }
+}
+
+/// The initrd after [`VmConfig::apply_initrd_pipeline`]: the caller's
+/// buffer, or the pipeline's output, which is zeroed on drop when
+/// [`VmConfig::scrub_inputs`] is set.
+struct PreparedInitrd<'a> {
+    data: Option<std::borrow::Cow<'a, [u8]>>,
+    scrub: bool,
+}
+
+impl PreparedInitrd<'_> {
+    fn as_deref(&self) -> Option<&[u8]> {
+        self.data.as_deref()
+    }
+
+    /// True if this is still the caller's buffer, unchanged.
+    fn is_borrowed(&self) -> bool {
+        matches!(self.data, Some(std::borrow::Cow::Borrowed(_)))
+    }
+}
+
+impl Drop for PreparedInitrd<'_> {
+    fn drop(&mut self) {
+        if let (true, Some(std::borrow::Cow::Owned(buf))) = (self.scrub, &mut self.data) {
+            buf.zeroize();
+        }
+    }
+}
+
+/// An initrd with its boot header, kept as two segments: the header
+/// page and the caller's rootfs buffer, which is borrowed rather than
+/// copied behind the header.
+///
+/// The guest sees the same layout as [`prepend_cmdline_to_initrd`]'s
+/// output. At boot the header page is the sandbox's init data and the
+/// rootfs is mapped after it, so a multi-hundred-megabyte rootfs is
+/// never duplicated on the host heap.
+pub struct ExtendedInitrd<'a> {
+    header: Vec<u8>,
+    body: Option<&'a [u8]>,
+    /// The file `body` is a mapping of; boot maps it directly instead of
+    /// spilling the buffer.
+    file: Option<&'a Path>,
+}
+
+impl<'a> ExtendedInitrd<'a> {
+    /// The page-aligned boot header; empty when there was nothing to
+    /// announce and the rootfs is passed through as-is.
+    pub fn header(&self) -> &[u8] {
+        &self.header
+    }
+
+    /// The rootfs archive, exactly as the caller provided it.
+    pub fn body(&self) -> Option<&'a [u8]> {
+        self.body
+    }
+
+    /// The non-empty segments in guest order.
+    pub fn segments(&self) -> impl Iterator<Item = &[u8]> {
+        std::iter::once(self.header.as_slice())
+            .chain(self.body)
+            .filter(|s| !s.is_empty())
+    }
+
+    /// Total size of the segments.
+    pub fn len(&self) -> usize {
+        self.header.len() + self.body.map_or(0, <[u8]>::len)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Write the segments to `out` back to back.
+    pub fn write_to(&self, out: &mut impl std::io::Write) -> std::io::Result<()> {
+        self.segments().try_for_each(|s| out.write_all(s))
+    }
+
+    /// The segments joined into one buffer. Copies the rootfs.
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.len());
+        for s in self.segments() {
+            buf.extend_from_slice(s);
+        }
+        buf
+    }
+
+    /// Record that the rootfs is a mapping of `file`.
+    fn backed_by(self, file: Option<&'a Path>) -> Self {
+        Self { file, ..self }
+    }
+
+    /// The header page as init data for the mapped layout: the rootfs
+    /// size goes in the page's last 8 bytes, as in
+    /// [`build_cmdline_initdata`].
+    fn mapped_initdata(&self) -> Vec<u8> {
+        let mut page = if self.header.is_empty() {
+            header_page(&BootHeader::default())
+        } else {
+            self.header.clone()
+        };
+        let len = page.len();
+        let size = self.body.map_or(0, <[u8]>::len) as u64;
+        page[len - 8..].copy_from_slice(&size.to_le_bytes());
+        page
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Tool dispatch (host functions callable from guest)
+// ---------------------------------------------------------------------------
+
+/// Registry of tool handlers callable from guest user-space via `/dev/hcall`.
+pub struct ToolRegistry {
+    tools:
+        HashMap<String, Box<dyn Fn(serde_json::Value) -> Result<serde_json::Value> + Send + Sync>>,
+    observers: Vec<Arc<dyn hostcall::CallObserver>>,
+    run_end: Vec<Box<dyn Fn() + Send + Sync>>,
+}
+
+impl ToolRegistry {
+    /// Create an empty registry. Add handlers with
+    /// [`register`](Self::register) before wiring it into a sandbox.
+    pub fn new() -> Self {
+        Self {
+            tools: HashMap::new(),
+            observers: Vec::new(),
+            run_end: Vec::new(),
+        }
+    }
+
+    /// Call `f` after each [`Sandbox::call_run`] on a sandbox this
+    /// registry serves, so handlers can drop state a run left behind.
+    /// Repeatable.
+    pub fn on_run_end<F>(&mut self, f: F)
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        self.run_end.push(Box::new(f));
+    }
+
+    fn end_run(&self) {
+        for f in &self.run_end {
+            f();
+        }
+    }
+
+    /// Report every [`dispatch`](Self::dispatch) to `observer`; see
+    /// [`hostcall`]. Repeatable.
+    pub fn observe(&mut self, observer: Arc<dyn hostcall::CallObserver>) {
+        self.observers.push(observer);
+    }
+
+    /// Register a named handler. The handler receives the JSON-encoded
+    /// `args` payload the guest sent and returns a `serde_json::Value`
+    /// that becomes the `{"result": ...}` portion of the response.
+    /// Errors returned by the handler become `{"error": "..."}`.
     pub fn register<F>(&mut self, name: &str, handler: F)
     where
         F: Fn(serde_json::Value) -> Result<serde_json::Value> + Send + Sync + 'static,
@@ -362,6 +1664,7 @@ impl ToolRegistry {
     /// payload and result to stderr — useful when diagnosing
     /// guest/host protocol mismatches.
     pub fn dispatch(&self, payload: &[u8]) -> Vec<u8> {
+        let started = std::time::Instant::now();
         let debug = std::env::var("HL_DISPATCH_DEBUG")
             .ok()
             .map(|v| v == "1")
@@ -378,16 +1681,20 @@ impl ToolRegistry {
                 std::str::from_utf8(preview).unwrap_or("<non-utf8>")
             );
         }
+        let mut tool = None;
+        let mut known = false;
         let result = (|| -> Result<serde_json::Value> {
             let req: serde_json::Value = serde_json::from_slice(payload)?;
             let name = req["name"]
                 .as_str()
                 .ok_or_else(|| anyhow!("missing 'name'"))?;
+            tool = Some(name.to_string());
             let args = req.get("args").cloned().unwrap_or(serde_json::Value::Null);
             let handler = self
                 .tools
                 .get(name)
                 .ok_or_else(|| anyhow!("unknown tool: {}", name))?;
+            known = true;
             handler(args)
         })();
         if debug {
@@ -396,6 +1703,7 @@ impl ToolRegistry {
                 Err(e) => eprintln!("[__dispatch] ERR: {}", e),
             }
         }
+        let ok = result.is_ok();
         let json = match result {
             Ok(v) => serde_json::json!({ "result": v }),
             Err(e) => {
@@ -416,8 +1724,22 @@ impl ToolRegistry {
                 serde_json::json!({ "error": normalize_fs_error(&e.to_string()) })
             }
         };
-        serde_json::to_vec(&json)
-            .unwrap_or_else(|_| b"{\"error\":\"serialization failed\"}".to_vec())
+        let response = serde_json::to_vec(&json)
+            .unwrap_or_else(|_| b"{\"error\":\"serialization failed\"}".to_vec());
+        let call = hostcall::HostCall {
+            function: "__dispatch",
+            tool: tool.as_deref(),
+            request: payload,
+            response: &response,
+            started,
+            duration: started.elapsed(),
+            ok,
+        };
+        hostcall::record_global(&call, known);
+        for observer in &self.observers {
+            observer.observe(&call);
+        }
+        response
     }
 }
 
@@ -773,6 +2095,24 @@ impl FsSandbox {
     }
 }
 
+/// What a booting guest's host calls reach besides its mounts: the
+/// caller's tools, and the console `HostPrint` writes to. The console is
+/// made before the boot so a capture can start ahead of the banner.
+pub(crate) struct BootHost {
+    tools: Option<ToolRegistry>,
+    console: Arc<stderr_capture::SandboxConsole>,
+}
+
+impl BootHost {
+    /// `tools`, with a console that prints to stderr until captured.
+    pub(crate) fn new(tools: Option<ToolRegistry>) -> Self {
+        Self {
+            tools,
+            console: Arc::default(),
+        }
+    }
+}
+
 /// Internal helper: assemble the final tool registry from caller-supplied
 /// tools plus any preopened directories. Multiple preopens share one set
 /// of fs_* tool handlers that route by guest-path prefix: the handler
@@ -781,13 +2121,22 @@ impl FsSandbox {
 fn build_tools(
     user_tools: Option<ToolRegistry>,
     preopens: &[Preopen],
+    observers: &[Arc<dyn hostcall::CallObserver>],
+    stdin: Option<&Arc<hostfn::Stdin>>,
 ) -> Result<Option<ToolRegistry>> {
-    if preopens.is_empty() {
+    if preopens.is_empty() && observers.is_empty() && stdin.is_none() {
         return Ok(user_tools);
     }
     let mut registry = user_tools.unwrap_or_default();
-    let router = FsRouter::new(preopens)?;
-    router.register(&mut registry);
+    if !preopens.is_empty() {
+        FsRouter::new(preopens)?.register(&mut registry);
+    }
+    if let Some(stdin) = stdin {
+        stdin.register(&mut registry);
+    }
+    for observer in observers {
+        registry.observe(observer.clone());
+    }
     Ok(Some(registry))
 }
 
@@ -1037,19 +2386,206 @@ pub struct Sandbox {
     inner: MultiUseSandbox,
     /// Post-init snapshot for fast restore between calls.
     snapshot: Option<Arc<Snapshot>>,
-    /// File mapping to re-register after snapshot restore.
-    /// Snapshot restore unmaps all non-snapshot regions.
-    file_mapping_path: Option<std::path::PathBuf>,
-    file_mapping_base: u64,
+    /// File mappings (initrd, host regions) to re-register after
+    /// snapshot restore. Snapshot restore unmaps all non-snapshot regions.
+    file_mappings: Vec<FileMapping>,
+    boot_timings: BootTimings,
+    phases: PhaseTimings,
+    /// Where the guest's `HostPrint` calls go.
+    console: Arc<stderr_capture::SandboxConsole>,
+    /// Serves the guest's `__dispatch` calls; told when each run ends.
+    tools: Option<Arc<ToolRegistry>>,
+    /// Guest heap size, for out-of-memory suggestions. Unknown for
+    /// sandboxes loaded from a snapshot file.
+    heap_size: Option<u64>,
+    meter: Arc<stats::RunMeter>,
+    shutdown: Arc<hostfn::ShutdownSignal>,
+    /// Set by [`VmHandle::kill`]; read and cleared by the call it stops.
+    killed: Arc<std::sync::atomic::AtomicBool>,
+    /// This sandbox's CPU claim, if it was built with
+    /// [`SandboxBuilder::placement`].
+    placement: Option<placement::Placement>,
+    /// Told about every [`call_run`](Self::call_run); see
+    /// [`SandboxBuilder::post_run_hook`].
+    post_run_hooks: Vec<PostRunHook>,
+    /// Limit on each [`call_run`](Self::call_run); see
+    /// [`SandboxBuilder::timeout`].
+    timeout: Option<Duration>,
+}
+
+/// Where the time went while building a [`Sandbox`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BootTimings {
+    /// Host-side preparation: boot header, file mappings, sandbox
+    /// creation and host function registration.
+    pub setup: Duration,
+    /// The guest's boot and init (`evolve`).
+    pub evolve: Duration,
 }
 
 /// Where the initrd comes from — either a file (zero-copy `map_file_cow`)
-/// or an in-memory buffer (copied into snapshot memory).
+/// or an in-memory buffer (spilled to the run's workspace and mapped the
+/// same way).
 enum InitrdSource {
     File(std::path::PathBuf),
     Bytes(Vec<u8>),
 }
 
+/// A host file or buffer mapped read-only into the guest's physical
+/// address space, for inputs too large to ship inside the initrd.
+///
+/// Regions are laid out page-aligned after the mapped initrd (3 GiB up
+/// to the 4 GiB identity-map limit) and announced to the guest in the
+/// `HLMMAP0` boot-header TLV as `(base, size, name)` entries. Like the
+/// initrd they are `map_file_cow` mappings, so the host copy is never
+/// modified by the guest.
+///
+/// `HLMMAP0` follows the header's `HLWALL0` block, so a guest only finds
+/// its regions if it knows that magic and keeps reading past `HLWALL0`;
+/// older guests boot as before and ignore them.
+pub struct HostRegion {
+    name: String,
+    source: InitrdSource,
+}
+
+impl HostRegion {
+    /// Map the contents of a host file under `name`.
+    pub fn file<P: Into<std::path::PathBuf>>(name: impl Into<String>, path: P) -> Self {
+        Self {
+            name: name.into(),
+            source: InitrdSource::File(path.into()),
+        }
+    }
+
+    /// Map an in-memory buffer under `name`. The bytes are spilled to a
+    /// temp file (removed when the sandbox drops) because Hyperlight maps
+    /// files, not host allocations.
+    pub fn bytes(name: impl Into<String>, data: Vec<u8>) -> Self {
+        Self {
+            name: name.into(),
+            source: InitrdSource::Bytes(data),
+        }
+    }
+}
+
+/// A file mapped into the guest with `map_file_cow`: the initrd or a
+/// placed [`HostRegion`].
+#[derive(Debug, Clone)]
+struct FileMapping {
+    path: std::path::PathBuf,
+    base: u64,
+    size: u64,
+    label: String,
+    /// Holds the workspace a spilled backing file lives in until the
+    /// last mapping using it is dropped.
+    _spill: Option<Arc<workspace::Workspace>>,
+}
+
+/// The mapping for `initrd`'s rootfs at `INITRD_MAP_BASE`: its backing
+/// file if it still matches the buffer's size, otherwise a spill of the
+/// buffer into `cache` or a fresh workspace. `None` for an empty rootfs.
+/// With `scrub`, the spill always gets its own workspace, zeroed on drop.
+fn map_initrd_body(
+    initrd: &ExtendedInitrd<'_>,
+    cache: Option<&initrd_cache::InitrdCache>,
+    scrub: bool,
+) -> Result<Option<FileMapping>> {
+    let Some(body) = initrd.body.filter(|b| !b.is_empty()) else {
+        return Ok(None);
+    };
+    let size = body.len() as u64;
+    let file = initrd
+        .file
+        .filter(|f| std::fs::metadata(f).is_ok_and(|m| m.len() == size));
+    let (path, spill) = match (file, cache.filter(|_| !scrub)) {
+        (Some(path), _) => (path.to_path_buf(), None),
+        (None, Some(cache)) => {
+            let (path, ws) = cache.spill(body)?;
+            (path, Some(ws))
+        }
+        (None, None) => {
+            let ws = Arc::new(new_workspace(scrub)?);
+            (ws.write("initrd", body)?, Some(ws))
+        }
+    };
+    Ok(Some(FileMapping {
+        path,
+        base: INITRD_MAP_BASE,
+        size,
+        label: "initrd".to_string(),
+        _spill: spill,
+    }))
+}
+
+/// A workspace for spilled inputs, zeroed on drop if `scrub`.
+fn new_workspace(scrub: bool) -> Result<workspace::Workspace> {
+    let ws = workspace::Workspace::new()?;
+    Ok(if scrub { ws.scrub_on_drop() } else { ws })
+}
+
+/// Assign guest-physical addresses to `regions`, starting at the first
+/// page boundary at or after `start`. With `scrub`, in-memory regions
+/// are zeroed once spilled and their files on drop.
+fn place_regions(regions: Vec<HostRegion>, start: u64, scrub: bool) -> Result<Vec<FileMapping>> {
+    let page = PAGE_SIZE as u64;
+    let mut base = start.next_multiple_of(page);
+    let mut placed: Vec<FileMapping> = Vec::with_capacity(regions.len());
+    let mut spill: Option<Arc<workspace::Workspace>> = None;
+    for region in regions {
+        if region.name.is_empty() || region.name.contains('\0') {
+            return Err(anyhow!("host region name {:?} is invalid", region.name));
+        }
+        if placed.iter().any(|m| m.label == region.name) {
+            return Err(anyhow!("host region {:?} registered twice", region.name));
+        }
+        let (path, workspace) = match region.source {
+            InitrdSource::File(path) => (path, None),
+            InitrdSource::Bytes(mut data) => {
+                let ws = match spill {
+                    Some(ref ws) => ws.clone(),
+                    None => spill.insert(Arc::new(new_workspace(scrub)?)).clone(),
+                };
+                let path = ws.write(&format!("region-{}", placed.len()), &data);
+                if scrub {
+                    data.zeroize();
+                }
+                (path?, Some(ws))
+            }
+        };
+        let mut mapping = FileMapping {
+            path,
+            base,
+            size: 0,
+            label: region.name,
+            _spill: workspace,
+        };
+        mapping.size = std::fs::metadata(&mapping.path)
+            .map_err(|e| {
+                anyhow!(
+                    "host region {:?} ({:?}): {}",
+                    mapping.label,
+                    mapping.path,
+                    e
+                )
+            })?
+            .len();
+        if mapping.size == 0 {
+            return Err(anyhow!("host region {:?} is empty", mapping.label));
+        }
+        let end = base.saturating_add(mapping.size);
+        if end > MAP_LIMIT {
+            return Err(anyhow!(
+                "host region {:?} ends at {:#x}, past the guest's 4 GiB mapping window",
+                mapping.label,
+                end
+            ));
+        }
+        base = end.next_multiple_of(page);
+        placed.push(mapping);
+    }
+    Ok(placed)
+}
+
 /// Fluent builder for [`Sandbox`]. Returned by [`Sandbox::builder`].
 ///
 /// ```no_run
@@ -1071,19 +2607,41 @@ pub struct SandboxBuilder {
     heap_size: Option<u64>,
     stack_size: Option<u64>,
     preopens: Vec<Preopen>,
+    regions: Vec<HostRegion>,
+    wall_clock: Option<std::time::SystemTime>,
+    timezone: Option<String>,
+    locale: Option<String>,
+    env: Vec<(String, String)>,
+    clock: bool,
+    shutdown_signal: bool,
+    scrub_inputs: bool,
+    hypervisor: Hypervisor,
+    read_allowlist: Vec<std::path::PathBuf>,
     tools: ToolRegistry,
     has_tools: bool,
+    customizers: Vec<ConfigCustomizer>,
+    pre_run_hooks: Vec<PreRunHook>,
+    post_run_hooks: Vec<PostRunHook>,
+    timeout: Option<Duration>,
+    stdin: Option<Arc<hostfn::Stdin>>,
+    placer: Option<Arc<placement::Placer>>,
+    /// Where the guest's `HostPrint` calls go; made before boot so a
+    /// capture can start before the banner.
+    console: Arc<stderr_capture::SandboxConsole>,
 }
 
 impl SandboxBuilder {
     /// The initrd CPIO archive, mapped zero-copy into guest memory.
+    /// A zstd-compressed archive (`convert --compress zstd`) is
+    /// decompressed in host memory instead.
     pub fn initrd_file<P: Into<std::path::PathBuf>>(mut self, path: P) -> Self {
         self.initrd = Some(InitrdSource::File(path.into()));
         self
     }
 
-    /// An in-memory initrd buffer. Copied into snapshot memory.
-    /// Prefer [`initrd_file`](Self::initrd_file) for anything non-trivial.
+    /// An in-memory initrd buffer. Spilled to a workspace file and
+    /// mapped like [`initrd_file`](Self::initrd_file), which avoids the
+    /// extra write for anything non-trivial.
     pub fn initrd_bytes(mut self, bytes: Vec<u8>) -> Self {
         self.initrd = Some(InitrdSource::Bytes(bytes));
         self
@@ -1126,6 +2684,61 @@ impl SandboxBuilder {
         self
     }
 
+    /// Map an extra host file or buffer into guest memory. Repeatable;
+    /// see [`HostRegion`] for placement and how the guest finds it.
+    pub fn host_region(mut self, region: HostRegion) -> Self {
+        self.regions.push(region);
+        self
+    }
+
+    /// Inject a fixed wall-clock time at boot instead of the host's
+    /// current time. Used by replay to make runs reproducible.
+    pub fn wall_clock(mut self, time: std::time::SystemTime) -> Self {
+        self.wall_clock = Some(time);
+        self
+    }
+
+    /// Serve the `shutdown_requested` tool, so the guest can notice a
+    /// [`VmHandle::shutdown`] and exit cleanly within its grace period.
+    pub fn shutdown_signal(mut self) -> Self {
+        self.shutdown_signal = true;
+        self
+    }
+
+    /// Zero host copies of the inputs after boot and spilled files on
+    /// drop; see [`VmConfig::with_scrub_inputs`].
+    pub fn scrub_inputs(mut self) -> Self {
+        self.scrub_inputs = true;
+        self
+    }
+
+    /// Require a hypervisor backend; see [`VmConfig::with_hypervisor`].
+    pub fn hypervisor(mut self, hypervisor: Hypervisor) -> Self {
+        self.hypervisor = hypervisor;
+        self
+    }
+
+    /// The guest's `TZ`; see [`VmConfig::timezone`].
+    pub fn timezone(mut self, tz: impl Into<String>) -> Self {
+        self.timezone = Some(tz.into());
+        self
+    }
+
+    /// The guest's `LANG`/`LC_ALL`; see [`VmConfig::locale`].
+    pub fn locale(mut self, locale: impl Into<String>) -> Self {
+        self.locale = Some(locale.into());
+        self
+    }
+
+    /// An environment variable for the guest application; see
+    /// [`VmConfig::with_env`]. Repeatable.
+    pub fn env(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        let key = key.into();
+        self.env.retain(|(k, _)| *k != key);
+        self.env.push((key, value.into()));
+        self
+    }
+
     /// Register a host function callable from the guest via `__dispatch`.
     pub fn tool<F>(mut self, name: &str, handler: F) -> Self
     where
@@ -1136,43 +2749,276 @@ impl SandboxBuilder {
         self
     }
 
+    /// Serve the guest cryptographically secure random bytes via the
+    /// `getrandom` host function; see [`hostfn::register_getrandom`].
+    pub fn getrandom(mut self) -> Self {
+        hostfn::register_getrandom(&mut self.tools);
+        self.has_tools = true;
+        self
+    }
+
+    /// Let the guest read the current wall-clock time via the
+    /// `clock_realtime` host function; see [`hostfn::register_clock`].
+    /// Follows a pinned [`wall_clock`](Self::wall_clock) if one is set.
+    pub fn clock(mut self) -> Self {
+        self.clock = true;
+        self
+    }
+
+    /// Let the guest read the host file, or any file under the host
+    /// directory, at `path` through the `host_*` read proxy; see
+    /// [`hostfn::ReadAllowlist`]. Repeatable. `path` must exist when
+    /// the sandbox is built.
+    pub fn allow_read<P: Into<std::path::PathBuf>>(mut self, path: P) -> Self {
+        self.read_allowlist.push(path.into());
+        self
+    }
+
+    /// Give the guest the `kv_*` tools over `namespace`; see
+    /// [`kv::KvNamespace::register`].
+    pub fn kv(mut self, namespace: kv::KvNamespace) -> Self {
+        namespace.register(&mut self.tools);
+        self.has_tools = true;
+        self
+    }
+
+    /// Serve the guest the reading end of a pipe as `pipe_in_read`; see
+    /// [`pipe::PipeReader::register`].
+    pub fn pipe_in(mut self, reader: pipe::PipeReader) -> Self {
+        reader.register(&mut self.tools);
+        self.has_tools = true;
+        self
+    }
+
+    /// Serve the guest the writing end of a pipe as `pipe_out_*`; see
+    /// [`pipe::PipeWriter::register`].
+    pub fn pipe_out(mut self, writer: pipe::PipeWriter) -> Self {
+        writer.register(&mut self.tools);
+        self.has_tools = true;
+        self
+    }
+
+    /// Give the guest the `sql_*` tools over `tenant`'s database; see
+    /// [`sql::SqlTenant::register`].
+    #[cfg(feature = "sqlite")]
+    pub fn sql(mut self, tenant: sql::SqlTenant) -> Self {
+        tenant.register(&mut self.tools);
+        self.has_tools = true;
+        self
+    }
+
+    /// Give the guest the `s3_*` tools through `client`; see
+    /// [`s3::S3Client::register`].
+    #[cfg(feature = "s3")]
+    pub fn s3(mut self, client: s3::S3Client) -> Self {
+        client.register(&mut self.tools);
+        self.has_tools = true;
+        self
+    }
+
+    /// Report every host call the guest makes to `observer`; see
+    /// [`hostcall`]. Repeatable.
+    pub fn observe_calls(mut self, observer: Arc<dyn hostcall::CallObserver>) -> Self {
+        self.tools.observe(observer);
+        self.has_tools = true;
+        self
+    }
+
+    /// Pin the building thread to a CPU `placer` picks for this guest's
+    /// heap, so concurrent sandboxes spread across cores and NUMA nodes;
+    /// see [`placement`]. Call into the sandbox from the same thread; it
+    /// gets its previous affinity back when the sandbox is dropped.
+    pub fn placement(mut self, placer: Arc<placement::Placer>) -> Self {
+        self.placer = Some(placer);
+        self
+    }
+
+    /// Adjust the Hyperlight configuration directly; see
+    /// [`VmConfig::customize`]. Repeatable.
+    pub fn customize<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&mut SandboxConfiguration) + Send + Sync + 'static,
+    {
+        self.customizers.push(Box::new(f));
+        self
+    }
+
+    /// Serve the guest's `stdin_read` tool from `stdin`; see
+    /// [`VmConfig::with_stdin`].
+    pub fn stdin(mut self, stdin: hostfn::Stdin) -> Self {
+        self.stdin = Some(Arc::new(stdin));
+        self
+    }
+
+    /// Interrupt any [`Sandbox::call_run`] still running after `limit`,
+    /// which then fails with [`Error::TimedOut`] carrying what the guest
+    /// printed until then; boot doesn't count. The console is captured
+    /// for that, as for [`post_run_hook`](Self::post_run_hook)s.
+    pub fn timeout(mut self, limit: Duration) -> Self {
+        self.timeout = Some(limit);
+        self
+    }
+
+    /// Inspect the assets before boot; see [`VmConfig::with_pre_run_hook`].
+    /// An `Err` fails [`build`](Self::build). Repeatable.
+    pub fn pre_run_hook<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&RunAssets<'_>) -> Result<()> + Send + Sync + 'static,
+    {
+        self.pre_run_hooks.push(Box::new(hook));
+        self
+    }
+
+    /// Receive the result of every [`Sandbox::call_run`], with what the
+    /// guest printed, and a failed [`build`](Self::build); see
+    /// [`VmConfig::with_post_run_hook`]. Repeatable.
+    pub fn post_run_hook<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(Result<&VmOutput, &anyhow::Error>) + Send + Sync + 'static,
+    {
+        self.post_run_hooks.push(Box::new(hook));
+        self
+    }
+
+    /// Drop what the guest prints through `HostPrint`, boot banner
+    /// included, instead of writing it to stderr; captures for
+    /// [`post_run_hook`](Self::post_run_hook)s still see it. A kernel
+    /// printing to port 0xE9 reaches fd 2 regardless.
+    pub fn quiet(self) -> Self {
+        self.console.discard();
+        self
+    }
+
+    /// [`build`](Self::build) the sandbox and call `f` on it with the
+    /// guest's console discarded. A kernel known to print through
+    /// `HostPrint` (see [`Console::Auto`]) is made [`quiet`](Self::quiet),
+    /// so other threads keep their stderr; any other kernel is silenced
+    /// by redirecting fd 2, one such call at a time.
+    pub fn build_silenced<T>(
+        self,
+        f: impl FnOnce(&mut Sandbox) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        if kernel_prints_to_host(&self.kernel) {
+            return self.quiet().build().and_then(|mut sandbox| f(&mut sandbox));
+        }
+        let _console = stderr_capture::lock_console();
+        let capture = stderr_capture::PipeCapture::start(false)?;
+        let result = self.build().and_then(|mut sandbox| f(&mut sandbox));
+        capture.finish()?;
+        result
+    }
+
     /// Boot the VM, run init, and take a post-init snapshot.
-    pub fn build(self) -> Result<Sandbox> {
+    pub fn build(self) -> Result<Sandbox, Error> {
+        Ok(self.build_sandbox()?)
+    }
+
+    fn build_sandbox(mut self) -> Result<Sandbox> {
+        if self.clock {
+            hostfn::register_clock(&mut self.tools, self.wall_clock);
+            self.has_tools = true;
+        }
+        if !self.read_allowlist.is_empty() {
+            hostfn::ReadAllowlist::new(&self.read_allowlist)?.register(&mut self.tools);
+            self.has_tools = true;
+        }
+        let shutdown = Arc::new(hostfn::ShutdownSignal::default());
+        if self.shutdown_signal {
+            shutdown.register(&mut self.tools);
+            self.has_tools = true;
+        }
         let config = VmConfig {
             heap_size: self.heap_size.unwrap_or(512 * 1024 * 1024),
             stack_size: self.stack_size.unwrap_or(8 * 1024 * 1024),
+            wall_clock: self.wall_clock,
+            timezone: self.timezone,
+            locale: self.locale,
+            scrub_inputs: self.scrub_inputs,
+            hypervisor: self.hypervisor,
+            customizers: self.customizers,
+            env: self.env,
+            pre_run_hooks: self.pre_run_hooks,
+            post_run_hooks: self.post_run_hooks,
+            timeout: self.timeout,
+            stdin: self.stdin,
+            ..VmConfig::default()
         };
-        let tools = if self.has_tools {
-            Some(self.tools)
-        } else {
-            None
+        let host = BootHost {
+            tools: self.has_tools.then_some(self.tools),
+            console: self.console,
         };
-        match self.initrd {
-            Some(InitrdSource::File(path)) => Sandbox::evolve_mapped(
-                &self.kernel,
-                Some(&path),
-                &self.args,
-                config,
-                tools,
-                &self.preopens,
-            ),
+        // Before anything touches guest memory, so it lands on the
+        // chosen node.
+        let placement = self.placer.map(|placer| {
+            let mut placement = placer.place(config.heap_size);
+            if let Err(e) = placement.pin() {
+                tracing::warn!(cpu = placement.cpu(), "pinning VM thread failed: {e:#}");
+            }
+            placement
+        });
+        let load_start = std::time::Instant::now();
+        let mut zstd = match self.initrd {
+            Some(InitrdSource::File(ref path)) => rootfs::read_if_zstd(path)?,
+            _ => None,
+        };
+        let load = load_start.elapsed();
+        let built = match &self.initrd {
+            Some(InitrdSource::File(path)) => match &zstd {
+                Some(bytes) => Sandbox::evolve_inline(
+                    &self.kernel,
+                    Some(bytes),
+                    &self.args,
+                    &config,
+                    host,
+                    &self.preopens,
+                    self.regions,
+                ),
+                None => Sandbox::evolve_mapped(
+                    &self.kernel,
+                    Some(path),
+                    &self.args,
+                    &config,
+                    host,
+                    &self.preopens,
+                    self.regions,
+                ),
+            },
             Some(InitrdSource::Bytes(bytes)) => Sandbox::evolve_inline(
                 &self.kernel,
-                Some(&bytes),
+                Some(bytes),
                 &self.args,
-                config,
-                tools,
+                &config,
+                host,
                 &self.preopens,
+                self.regions,
             ),
             None => Sandbox::evolve_mapped(
                 &self.kernel,
                 None,
                 &self.args,
-                config,
-                tools,
+                &config,
+                host,
                 &self.preopens,
+                self.regions,
             ),
+        };
+        if self.scrub_inputs {
+            if let Some(InitrdSource::Bytes(ref mut bytes)) = self.initrd {
+                bytes.zeroize();
+            }
+            zstd.zeroize();
         }
+        let mut sandbox = built.inspect_err(|e| {
+            for hook in &config.post_run_hooks {
+                hook(Err(e));
+            }
+        })?;
+        sandbox.phases.add(Phase::AssetLoad, load);
+        sandbox.shutdown = shutdown;
+        sandbox.placement = placement;
+        sandbox.post_run_hooks = config.post_run_hooks;
+        sandbox.timeout = config.timeout;
+        Ok(sandbox)
     }
 }
 
@@ -1187,8 +3033,25 @@ impl Sandbox {
             heap_size: None,
             stack_size: None,
             preopens: Vec::new(),
+            regions: Vec::new(),
+            wall_clock: None,
+            timezone: None,
+            locale: None,
+            env: Vec::new(),
+            clock: false,
+            shutdown_signal: false,
+            scrub_inputs: false,
+            hypervisor: Hypervisor::Auto,
+            read_allowlist: Vec::new(),
             tools: ToolRegistry::new(),
             has_tools: false,
+            customizers: Vec::new(),
+            pre_run_hooks: Vec::new(),
+            post_run_hooks: Vec::new(),
+            timeout: None,
+            stdin: None,
+            placer: None,
+            console: Arc::default(),
         }
     }
 
@@ -1197,33 +3060,126 @@ impl Sandbox {
         kernel_path: &Path,
         initrd: Option<&[u8]>,
         app_args: &[String],
-        config: VmConfig,
-        tools: Option<ToolRegistry>,
+        config: &VmConfig,
+        host: BootHost,
         preopens: &[Preopen],
+        regions: Vec<HostRegion>,
     ) -> Result<Self> {
-        if !kernel_path.exists() {
-            return Err(anyhow!("Kernel not found: {:?}", kernel_path));
-        }
+        let prepare_start = std::time::Instant::now();
+        // The rootfs is mapped at INITRD_MAP_BASE; regions follow it.
+        let initrd_size = initrd.map_or(0, <[u8]>::len) as u64;
+        let mappings = place_regions(regions, INITRD_MAP_BASE + initrd_size, config.scrub_inputs)?;
+        let env = config.guest_env()?;
+        let header = BootHeader {
+            app_args,
+            preopens,
+            regions: &mappings,
+            env: &env,
+            wall_clock: config.wall_clock,
+        };
+        let (kernel_image, extended_initrd) =
+            with_kernel_prefetch(kernel_path, || prepend_boot_header(initrd, &header));
+        let prepare = prepare_start.elapsed();
+        config.run_pre_hooks(&RunAssets {
+            kernel_path,
+            initrd: extended_initrd.as_ref(),
+            app_args,
+        })?;
+        let mut sandbox = Self::evolve_prepared(
+            kernel_path,
+            kernel_image.as_deref().map(|i| &i[..]),
+            extended_initrd.as_ref(),
+            config,
+            host,
+            preopens,
+            mappings,
+        )?;
+        sandbox.phases.add(Phase::InitrdPrepare, prepare);
+        Ok(sandbox)
+    }
 
-        let extended_initrd = prepend_cmdline_to_initrd(initrd, app_args, preopens);
-        let env = GuestEnvironment::new(
-            GuestBinary::FilePath(kernel_path.to_string_lossy().to_string()),
-            extended_initrd.as_deref(),
-        );
+    /// Low-level: boot with an initrd that already carries the cmdline
+    /// header. The header page becomes the init data and the rootfs is
+    /// spilled to a workspace file mapped at `INITRD_MAP_BASE`, the same
+    /// layout as [`evolve_mapped`](Self::evolve_mapped). `regions` must
+    /// be placed after the rootfs and match the ones announced in the
+    /// header. `kernel_image` is the kernel's contents if already read
+    /// (see [`with_kernel_prefetch`]); otherwise Hyperlight loads
+    /// `kernel_path` itself.
+    fn evolve_prepared(
+        kernel_path: &Path,
+        kernel_image: Option<&[u8]>,
+        extended_initrd: Option<&ExtendedInitrd<'_>>,
+        config: &VmConfig,
+        host: BootHost,
+        preopens: &[Preopen],
+        regions: Vec<FileMapping>,
+    ) -> Result<Self> {
+        let setup_start = std::time::Instant::now();
+        let mut phases = PhaseTimings::default();
+        if kernel_image.is_none() {
+            error::check_kernel(kernel_path)?;
+        }
+        config.validate_for_initrd(extended_initrd.map_or(0, |e| e.len() as u64))?;
+        hypervisor::check(config.hypervisor)?;
+        phases.add(Phase::AssetLoad, setup_start.elapsed());
+
+        let spill_start = std::time::Instant::now();
+        let init_data = extended_initrd.map(ExtendedInitrd::mapped_initdata);
+        let mut mappings = Vec::with_capacity(regions.len() + 1);
+        if let Some(e) = extended_initrd {
+            if let Some(mapping) =
+                map_initrd_body(e, config.initrd_cache.as_deref(), config.scrub_inputs)?
+            {
+                mappings.push(mapping);
+            }
+        }
+        mappings.extend(regions);
+        phases.add(Phase::InitrdPrepare, spill_start.elapsed());
 
-        let mut usbox = UninitializedSandbox::new(env, Some(config.sandbox_config()))?;
+        let create_start = std::time::Instant::now();
+        let binary = match kernel_image {
+            Some(image) => GuestBinary::Buffer(image),
+            None => GuestBinary::FilePath(kernel_path.to_string_lossy().to_string()),
+        };
+        let env = GuestEnvironment::new(binary, init_data.as_deref());
+
+        let mut usbox = UninitializedSandbox::new(env, Some(config.sandbox_config()))
+            .map_err(error::sandbox_creation)?;
+        for m in &mappings {
+            usbox
+                .map_file_cow(&m.path, m.base, Some(&m.label))
+                .map_err(error::sandbox_creation)?;
+        }
 
-        let tools = build_tools(tools, preopens)?;
+        let tools = build_tools(
+            host.tools,
+            preopens,
+            &config.call_observers,
+            config.stdin.as_ref(),
+        )?;
 
-        if let Some(tools) = tools {
-            let tools = Arc::new(tools);
+        let tools = tools.map(Arc::new);
+        if let Some(ref tools) = tools {
             let tools_ref = tools.clone();
-            usbox.register_host_function("__dispatch", move |payload: Vec<u8>| -> Vec<u8> {
-                tools_ref.dispatch(&payload)
-            })?;
+            usbox
+                .register_host_function("__dispatch", move |payload: Vec<u8>| -> Vec<u8> {
+                    tools_ref.dispatch(&payload)
+                })
+                .map_err(error::sandbox_creation)?;
         }
-
-        Self::finish_evolve(usbox, None, 0)
+        phases.add(Phase::SandboxCreate, create_start.elapsed());
+
+        Self::finish_evolve(
+            usbox,
+            mappings,
+            tools,
+            host.console,
+            config.heap_size,
+            setup_start,
+            phases,
+        )
+        .inspect(|sandbox| learn_console(kernel_path, sandbox))
     }
 
     /// Low-level: boot with a zero-copy mapped initrd file. Prefer the builder.
@@ -1231,80 +3187,177 @@ impl Sandbox {
         kernel_path: &Path,
         initrd_path: Option<&Path>,
         app_args: &[String],
-        config: VmConfig,
-        tools: Option<ToolRegistry>,
+        config: &VmConfig,
+        host: BootHost,
         preopens: &[Preopen],
+        regions: Vec<HostRegion>,
     ) -> Result<Self> {
-        if !kernel_path.exists() {
-            return Err(anyhow!("Kernel not found: {:?}", kernel_path));
-        }
+        let setup_start = std::time::Instant::now();
+        let mut phases = PhaseTimings::default();
+        error::check_kernel(kernel_path)?;
 
         // Get file size before creating sandbox
         let mapped_size = match initrd_path {
-            Some(path) if path.exists() => std::fs::metadata(path)?.len(),
-            Some(path) => return Err(anyhow!("Initrd not found: {:?}", path)),
+            Some(path) => std::fs::metadata(path)
+                .map_err(|e| error::initrd_io(path, e))?
+                .len(),
             None => 0,
         };
+        config.validate_for_initrd(mapped_size)?;
+        hypervisor::check(config.hypervisor)?;
+        phases.add(Phase::AssetLoad, setup_start.elapsed());
+
+        let prepare_start = std::time::Instant::now();
+        // Lay out the initrd at INITRD_MAP_BASE with host regions after it
+        let mut mappings: Vec<FileMapping> = initrd_path
+            .map(|path| FileMapping {
+                path: path.to_path_buf(),
+                base: INITRD_MAP_BASE,
+                size: mapped_size,
+                label: "initrd".to_string(),
+                _spill: None,
+            })
+            .into_iter()
+            .collect();
+        let regions = place_regions(regions, INITRD_MAP_BASE + mapped_size, config.scrub_inputs)?;
+
+        // Build init_data with cmdline + preopens + regions + mapped file size
+        let env = config.guest_env()?;
+        let header = BootHeader {
+            app_args,
+            preopens,
+            regions: &regions,
+            env: &env,
+            wall_clock: config.wall_clock,
+        };
+        let cmdline_data = build_cmdline_initdata(&header, mapped_size);
+        phases.add(Phase::InitrdPrepare, prepare_start.elapsed());
+        if !config.pre_run_hooks.is_empty() {
+            // Hooks see the rootfs bytes; the guest still maps the file.
+            let map = initrd_path.map(rootfs::MappedInitrd::open).transpose()?;
+            let extended =
+                prepend_boot_header(map.as_deref(), &header).map(|e| e.backed_by(initrd_path));
+            config.run_pre_hooks(&RunAssets {
+                kernel_path,
+                initrd: extended.as_ref(),
+                app_args,
+            })?;
+        }
+        mappings.extend(regions);
 
-        // Build init_data with cmdline + preopens + mapped file size
-        let cmdline_data = build_cmdline_initdata(app_args, mapped_size, preopens);
+        let create_start = std::time::Instant::now();
         let env = GuestEnvironment::new(
             GuestBinary::FilePath(kernel_path.to_string_lossy().to_string()),
             cmdline_data.as_deref(),
         );
 
-        let mut usbox = UninitializedSandbox::new(env, Some(config.sandbox_config()))?;
+        let mut usbox = UninitializedSandbox::new(env, Some(config.sandbox_config()))
+            .map_err(error::sandbox_creation)?;
 
-        // Map the initrd file (zero-copy via mmap)
-        // Place at 3 GiB — high enough to not overlap any reasonable
-        // primary shared memory region, within the 4 GiB identity map.
-        const INITRD_MAP_BASE: u64 = 0xC000_0000; // 3 GiB
-        if let Some(path) = initrd_path {
-            usbox.map_file_cow(path, INITRD_MAP_BASE, Some("initrd"))?;
+        // Map the initrd and host regions (zero-copy via mmap)
+        for m in &mappings {
+            usbox
+                .map_file_cow(&m.path, m.base, Some(&m.label))
+                .map_err(error::sandbox_creation)?;
         }
 
-        let tools = build_tools(tools, preopens)?;
+        let tools = build_tools(
+            host.tools,
+            preopens,
+            &config.call_observers,
+            config.stdin.as_ref(),
+        )?;
 
         // Register tool dispatch if needed
-        if let Some(tools) = tools {
-            let tools = Arc::new(tools);
+        let tools = tools.map(Arc::new);
+        if let Some(ref tools) = tools {
             let tools_ref = tools.clone();
-            usbox.register_host_function("__dispatch", move |payload: Vec<u8>| -> Vec<u8> {
-                tools_ref.dispatch(&payload)
-            })?;
+            usbox
+                .register_host_function("__dispatch", move |payload: Vec<u8>| -> Vec<u8> {
+                    tools_ref.dispatch(&payload)
+                })
+                .map_err(error::sandbox_creation)?;
         }
-
-        Self::finish_evolve(usbox, initrd_path.map(|p| p.to_path_buf()), INITRD_MAP_BASE)
+        phases.add(Phase::SandboxCreate, create_start.elapsed());
+
+        Self::finish_evolve(
+            usbox,
+            mappings,
+            tools,
+            host.console,
+            config.heap_size,
+            setup_start,
+            phases,
+        )
+        .inspect(|sandbox| learn_console(kernel_path, sandbox))
     }
 
     fn finish_evolve(
-        usbox: UninitializedSandbox,
-        file_mapping_path: Option<std::path::PathBuf>,
-        file_mapping_base: u64,
+        mut usbox: UninitializedSandbox,
+        file_mappings: Vec<FileMapping>,
+        tools: Option<Arc<ToolRegistry>>,
+        console: Arc<stderr_capture::SandboxConsole>,
+        heap_size: u64,
+        setup_start: std::time::Instant,
+        mut phases: PhaseTimings,
     ) -> Result<Self> {
-        let mut inner = usbox.evolve()?;
+        register_console(&mut usbox, &console)?;
+        let setup = setup_start.elapsed();
+        let evolve_start = std::time::Instant::now();
+        let mut inner = usbox
+            .evolve()
+            .map_err(|e| error::guest_failed(e.into(), Some(heap_size), ""))?;
+        let evolve = evolve_start.elapsed();
+        phases.add(Phase::Evolve, evolve);
+        metrics::startup_latencies()
+            .sandbox_create
+            .record(phases.get(Phase::SandboxCreate));
         let snapshot = inner.snapshot().ok();
         Ok(Self {
             inner,
             snapshot,
-            file_mapping_path,
-            file_mapping_base,
+            file_mappings,
+            boot_timings: BootTimings { setup, evolve },
+            phases,
+            console,
+            tools,
+            heap_size: Some(heap_size),
+            meter: Arc::default(),
+            shutdown: Arc::default(),
+            killed: Arc::default(),
+            placement: None,
+            post_run_hooks: Vec::new(),
+            timeout: None,
         })
     }
 
+    /// How long [`SandboxBuilder::build`] spent creating the sandbox and
+    /// booting the guest. Zero for sandboxes loaded from a snapshot file.
+    pub fn boot_timings(&self) -> BootTimings {
+        self.boot_timings
+    }
+
+    /// The same build time broken down by [`Phase`]. All zero for
+    /// sandboxes loaded from a snapshot file.
+    pub fn phase_timings(&self) -> PhaseTimings {
+        self.phases
+    }
+
     /// Restore the sandbox to its post-init snapshot.
     ///
     /// This is a fast operation (host-level CoW via mmap) that resets all
     /// guest memory to the state captured after init.
-    pub fn restore(&mut self) -> Result<()> {
+    pub fn restore(&mut self) -> Result<(), Error> {
+        self.shutdown.clear();
         if let Some(ref snap) = self.snapshot {
-            self.inner.restore(snap.clone())?;
+            self.inner.restore(snap.clone()).map_err(error::other)?;
         }
-        // Re-register file mapping after restore (snapshot restore
+        // Re-register file mappings after restore (snapshot restore
         // unmaps all non-snapshot regions including file mappings)
-        if let Some(ref path) = self.file_mapping_path {
+        for m in &self.file_mappings {
             self.inner
-                .map_file_cow(path, self.file_mapping_base, Some("initrd"))?;
+                .map_file_cow(&m.path, m.base, Some(&m.label))
+                .map_err(error::other)?;
         }
         Ok(())
     }
@@ -1314,11 +3367,89 @@ impl Sandbox {
     /// Requires a prior `restore()` to reset guest state.
     /// The dispatch function pops the FunctionCall from input,
     /// runs the application, pushes a void result, and halts.
-    pub fn call_run(&mut self) -> Result<()> {
+    ///
+    /// With [`SandboxBuilder::post_run_hook`]s or a
+    /// [`SandboxBuilder::timeout`] the console is captured, and still
+    /// echoed to stderr, for the hooks and for [`Error::TimedOut`].
+    pub fn call_run(&mut self) -> Result<(), Error> {
+        if self.post_run_hooks.is_empty() && self.timeout.is_none() {
+            return self.call_app().map_err(|e| self.call_failed(e, "").into());
+        }
+        let start = std::time::Instant::now();
+        let (result, raw) = self.capture_console(Self::call_app)?;
+        let result = result.map_err(|e| self.call_failed(e, &String::from_utf8_lossy(&raw)));
+        if self.post_run_hooks.is_empty() {
+            return Ok(result?);
+        }
+        let mut phases = PhaseTimings::default();
+        phases.add(Phase::Evolve, start.elapsed());
+        let result = result.map(|()| VmOutput::from_console(raw, phases));
+        for hook in &self.post_run_hooks {
+            hook(result.as_ref());
+        }
+        Ok(result.map(drop)?)
+    }
+
+    /// Run `f` with the console captured for post-run hooks or a timeout
+    /// error, and still echoed to stderr: per sandbox for a guest that prints through
+    /// `HostPrint`, otherwise with [`capture_for_hooks`].
+    fn capture_console<T>(&mut self, f: impl FnOnce(&mut Self) -> T) -> Result<(T, Vec<u8>)> {
+        if !self.console.printed() {
+            return capture_for_hooks(|| f(self));
+        }
+        let capture = self
+            .console
+            .capture(stderr_capture::Tee::Stderr, None, None);
+        let value = f(self);
+        Ok((value, capture.finish().bytes))
+    }
+
+    /// The application call itself; a failure is classified by
+    /// [`call_failed`](Self::call_failed) once the output is known.
+    fn call_app(&mut self) -> std::result::Result<(), CallFailure> {
+        use std::sync::atomic::Ordering;
+        if self.killed.swap(false, Ordering::SeqCst) {
+            return Err(CallFailure::NotStarted(Error::Killed.into()));
+        }
+        let watchdog = self
+            .timeout
+            .map(|limit| Watchdog::start(self.handle(), limit))
+            .transpose()
+            .map_err(CallFailure::NotStarted)?;
         // call() with Void return type — the function name doesn't matter
         // to the guest (it ignores it and just runs the app).
-        let _: () = self.inner.call("run", ())?;
-        Ok(())
+        self.meter.begin(self.console.captured_bytes());
+        let result: std::result::Result<(), _> = self.inner.call("run", ());
+        self.meter.end(self.console.captured_bytes());
+        if let Some(ref tools) = self.tools {
+            tools.end_run();
+        }
+        let timed_out = watchdog.is_some_and(Watchdog::finish);
+        let killed = self.killed.swap(false, Ordering::SeqCst);
+        result.map_err(|e| {
+            let e = anyhow::Error::from(e);
+            if killed {
+                CallFailure::Killed(e)
+            } else if timed_out {
+                CallFailure::TimedOut(e)
+            } else {
+                CallFailure::Failed(e)
+            }
+        })
+    }
+
+    /// The error for a failed [`call_app`](Self::call_app), given what
+    /// the guest printed during it.
+    fn call_failed(&self, failure: CallFailure, output: &str) -> anyhow::Error {
+        match failure {
+            CallFailure::NotStarted(e) => e,
+            CallFailure::Killed(e) => e.context(Error::Killed),
+            CallFailure::TimedOut(e) => e.context(Error::TimedOut {
+                limit: self.timeout.unwrap_or_default(),
+                output: output.to_string(),
+            }),
+            CallFailure::Failed(e) => error::guest_failed(e, self.heap_size, output),
+        }
     }
 
     /// Call a named guest function with typed parameters.
@@ -1331,12 +3462,15 @@ impl Sandbox {
     ///
     /// Requires a prior `restore()` to reset guest state to the snapshot
     /// the caller wants to run against.
-    pub fn call_named<Output, Args>(&mut self, func_name: &str, args: Args) -> Result<Output>
+    pub fn call_named<Output, Args>(&mut self, func_name: &str, args: Args) -> Result<Output, Error>
     where
         Output: hyperlight_host::func::SupportedReturnType,
         Args: hyperlight_host::func::ParameterTuple,
     {
-        Ok(self.inner.call(func_name, args)?)
+        self.meter.begin(self.console.captured_bytes());
+        let result = self.inner.call(func_name, args);
+        self.meter.end(self.console.captured_bytes());
+        result.map_err(error::other)
     }
 
     /// Take a new snapshot of the current guest state.
@@ -1349,8 +3483,8 @@ impl Sandbox {
     ///
     /// After this call, future `restore()` calls rewind to the *new*
     /// snapshot rather than the post-evolve one.
-    pub fn snapshot_now(&mut self) -> Result<()> {
-        let snap = self.inner.snapshot()?;
+    pub fn snapshot_now(&mut self) -> Result<(), Error> {
+        let snap = self.inner.snapshot().map_err(error::other)?;
         self.snapshot = Some(snap);
         Ok(())
     }
@@ -1360,13 +3494,31 @@ impl Sandbox {
     /// to `call`. Uses hyperlight's `Snapshot::to_file` — the file
     /// format and cross-platform mmap load are documented in
     /// hyperlight/docs/snapshot-file-implementation-plan.md.
-    pub fn save_snapshot<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+    pub fn save_snapshot<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
         let snap = self
             .snapshot
             .as_ref()
             .ok_or_else(|| anyhow!("no snapshot present; build() or snapshot_now() first"))?;
-        snap.to_file(path.as_ref())?;
-        Ok(())
+        snap.to_file(path.as_ref()).map_err(error::other)
+    }
+
+    /// Write the guest's memory as it stands now to `path`, for
+    /// [`from_snapshot_file`](Self::from_snapshot_file) to resume from.
+    /// Unlike [`save_snapshot`](Self::save_snapshot) this captures the
+    /// state a call left behind, e.g. a job that returned early on a
+    /// [`VmHandle::shutdown`] with its progress still in memory; the
+    /// next call on the loaded sandbox re-enters the application with
+    /// that memory. Only possible between calls: an interrupted call
+    /// can't be checkpointed.
+    pub fn checkpoint<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Error> {
+        let snap = self.inner.snapshot().map_err(error::other)?;
+        snap.to_file(path.as_ref()).map_err(error::other)
+    }
+
+    /// True if a [`VmHandle::shutdown`] was requested since the last
+    /// [`restore`](Self::restore).
+    pub fn shutdown_requested(&self) -> bool {
+        self.shutdown.is_requested()
     }
 
     /// Load a previously-persisted snapshot from disk and create a
@@ -1384,7 +3536,7 @@ impl Sandbox {
     /// pyhl install dir), and the hash verify alone costs ~500ms on
     /// a 2.5 GB snapshot — enough to double the whole `pyhl run` wall
     /// time on simple scripts.
-    pub fn from_snapshot_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+    pub fn from_snapshot_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
         Self::from_snapshot_file_with(path, &[])
     }
 
@@ -1399,132 +3551,1275 @@ impl Sandbox {
     /// `host_dir` side is remapped — the guest-side mount point is
     /// fixed at setup time because it lives in the snapshot's memory
     /// image.
-    pub fn from_snapshot_file_with<P: AsRef<Path>>(path: P, preopens: &[Preopen]) -> Result<Self> {
-        let loaded = Snapshot::from_file_unchecked(path.as_ref())?;
-        let arc = Arc::new(loaded);
-        let mut inner = MultiUseSandbox::from_snapshot(arc.clone())?;
-
+    pub fn from_snapshot_file_with<P: AsRef<Path>>(
+        path: P,
+        preopens: &[Preopen],
+    ) -> Result<Self, Error> {
+        let loaded =
+            Snapshot::from_file_unchecked(path.as_ref()).map_err(error::sandbox_creation)?;
         // Wire up the fs_* tool handlers against the caller's preopens.
         // The snapshot was warmed up with hostfs already mounted, so the
         // guest will route fs_* calls through __dispatch → the FsRouter
         // we install here.
-        if !preopens.is_empty() {
-            if let Some(tools) = build_tools(None, preopens)? {
-                let tools = Arc::new(tools);
-                let tools_ref = tools.clone();
-                inner.register_host_function("__dispatch", move |payload: Vec<u8>| -> Vec<u8> {
-                    tools_ref.dispatch(&payload)
-                })?;
+        let tools = if preopens.is_empty() {
+            None
+        } else {
+            build_tools(None, preopens, &[], None)?
+        };
+        Ok(ForkSeed::default().sandbox(Arc::new(loaded), tools)?)
+    }
+
+    /// A new sandbox started from this one's current snapshot instead of
+    /// booting, with `tools` serving its host calls. It maps the same
+    /// rootfs and region files copy-on-write, so the two share those
+    /// pages; neither sees the other's writes.
+    pub fn fork(&self, tools: Option<ToolRegistry>) -> Result<Self, Error> {
+        let snapshot = self
+            .snapshot
+            .clone()
+            .ok_or_else(|| anyhow!("no snapshot present; build() or snapshot_now() first"))?;
+        Ok(self.fork_seed().sandbox(snapshot, tools)?)
+    }
+
+    pub(crate) fn fork_seed(&self) -> ForkSeed {
+        ForkSeed {
+            file_mappings: self.file_mappings.clone(),
+            heap_size: self.heap_size,
+            host_print: self.console.printed(),
+        }
+    }
+
+    /// A [`VmHandle`] that can interrupt this sandbox's guest from
+    /// another thread. Take it before starting a long-running `call_*`.
+    pub fn handle(&self) -> VmHandle {
+        VmHandle {
+            inner: self.inner.interrupt_handle(),
+            meter: self.meter.clone(),
+            console: self.console.clone(),
+            shutdown: self.shutdown.clone(),
+            killed: self.killed.clone(),
+        }
+    }
+}
+
+/// How a [`Sandbox::call_run`] failed, before the output is known.
+enum CallFailure {
+    /// The call never started: it was killed beforehand, or its
+    /// watchdog couldn't be set up.
+    NotStarted(anyhow::Error),
+    Killed(anyhow::Error),
+    TimedOut(anyhow::Error),
+    Failed(anyhow::Error),
+}
+
+/// How often [`VmHandle::shutdown`] checks whether the guest has stopped.
+const SHUTDOWN_POLL: Duration = Duration::from_millis(10);
+
+/// Cross-thread handle for interrupting a running guest.
+///
+/// Cheap to clone and `Send + Sync`. [`interrupt`](Self::interrupt) only
+/// flips atomics and signals the vCPU thread, so it is fine to call from
+/// a watchdog thread or a signal-handling thread (e.g. a Ctrl-C handler).
+/// It is the primitive timeouts and watchdogs build on.
+///
+/// The interrupted call returns an error that [`VmExit::from_result`]
+/// classifies as [`VmExit::Interrupted`]. The sandbox is poisoned
+/// afterwards; [`Sandbox::restore`] makes it usable again.
+#[derive(Clone, Debug)]
+pub struct VmHandle {
+    inner: Arc<dyn hyperlight_host::hypervisor::InterruptHandle>,
+    meter: Arc<stats::RunMeter>,
+    console: Arc<stderr_capture::SandboxConsole>,
+    shutdown: Arc<hostfn::ShutdownSignal>,
+    killed: Arc<std::sync::atomic::AtomicBool>,
+}
+
+impl VmHandle {
+    /// Stop the guest's current (or next) vCPU run. Returns `true` if a
+    /// running guest was interrupted, `false` if nothing was running.
+    pub fn interrupt(&self) -> bool {
+        self.inner.kill()
+    }
+
+    /// Interrupt the guest and mark its run as terminated from outside:
+    /// the call fails with [`Error::Killed`] rather than a crash. If no
+    /// call is running, the next one is stopped before it starts.
+    /// Returns `true` if a running guest was interrupted.
+    pub fn kill(&self) -> bool {
+        self.killed.store(true, std::sync::atomic::Ordering::SeqCst);
+        self.interrupt()
+    }
+
+    /// Ask the guest to stop, then interrupt it if it is still running
+    /// after `grace`. The request reaches guests that poll the
+    /// `shutdown_requested` tool (see
+    /// [`SandboxBuilder::shutdown_signal`]); others simply get `grace`
+    /// to finish on their own. Returns `true` if the guest stopped
+    /// without being interrupted.
+    pub fn shutdown(&self, grace: Duration) -> bool {
+        self.shutdown.request();
+        let deadline = std::time::Instant::now() + grace;
+        while self.meter.is_running() && !self.inner.dropped() {
+            if std::time::Instant::now() >= deadline {
+                return !self.interrupt();
+            }
+            std::thread::sleep(SHUTDOWN_POLL.min(grace));
+        }
+        true
+    }
+
+    /// True once the sandbox this handle belongs to has been dropped.
+    pub fn is_dropped(&self) -> bool {
+        self.inner.dropped()
+    }
+
+    /// Current resource use: host RSS, CPU time of the running call and
+    /// output captured so far. Cheap enough to poll from a watchdog loop.
+    pub fn stats(&self) -> VmStats {
+        self.meter.stats(self.console.captured_bytes())
+    }
+}
+
+/// Interrupts the guest behind a [`VmHandle`] if it is still running
+/// when the time limit elapses, unless [`finish`](Self::finish)ed first.
+struct Watchdog {
+    stop: std::sync::mpsc::Sender<()>,
+    thread: std::thread::JoinHandle<bool>,
+}
+
+impl Watchdog {
+    fn start(handle: VmHandle, limit: Duration) -> Result<Self> {
+        let (stop, stopped) = std::sync::mpsc::channel::<()>();
+        let thread = std::thread::Builder::new()
+            .name("hl-watchdog".into())
+            .spawn(move || match stopped.recv_timeout(limit) {
+                Err(std::sync::mpsc::RecvTimeoutError::Timeout) => handle.interrupt(),
+                _ => false,
+            })?;
+        Ok(Self { stop, thread })
+    }
+
+    /// Call the watch off. True if it interrupted the guest.
+    fn finish(self) -> bool {
+        drop(self.stop);
+        self.thread.join().unwrap_or(false)
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Convenience: run_vm (single-shot execution)
+// ---------------------------------------------------------------------------
+
+/// Run a Unikraft kernel to completion (single-shot). Thin shim over
+/// [`Sandbox::builder`] for callers that don't need the full fluent API.
+///
+/// Returns how the application ended, crashes included, and how long
+/// each step took; an error means the guest never got to run (a missing
+/// kernel, a rejected option, a sandbox Hyperlight couldn't create).
+/// Nothing is printed: log the [`RunReport`] if you want the timings.
+pub fn run_vm(
+    kernel_path: &Path,
+    initrd: Option<&[u8]>,
+    app_args: &[String],
+    config: VmConfig,
+) -> Result<RunReport, Error> {
+    evolve_with_hooks(kernel_path.into(), initrd, app_args, config, None, &[])
+}
+
+/// [`run_vm`] with the kernel image in memory, say `include_bytes!`-ed
+/// into the binary or fetched from an artifact store, so it never has to
+/// be written to disk. Errors and hooks name it [`IN_MEMORY_KERNEL`].
+pub fn run_vm_from_bytes(
+    kernel: &[u8],
+    initrd: Option<&[u8]>,
+    app_args: &[String],
+    config: VmConfig,
+) -> Result<RunReport, Error> {
+    evolve_with_hooks(kernel.into(), initrd, app_args, config, None, &[])
+}
+
+/// Run a Unikraft kernel with tool dispatch support.
+pub fn run_vm_with_tools(
+    kernel_path: &Path,
+    initrd: Option<&[u8]>,
+    app_args: &[String],
+    config: VmConfig,
+    tools: ToolRegistry,
+) -> Result<RunReport, Error> {
+    evolve_with_hooks(
+        kernel_path.into(),
+        initrd,
+        app_args,
+        config,
+        Some(tools),
+        &[],
+    )
+}
+
+/// Run a Unikraft kernel with preopened host directories exposed via
+/// `lib/hostfs`. Sandbox escape attempts are rejected host-side.
+pub fn run_vm_with_preopens(
+    kernel_path: &Path,
+    initrd: Option<&[u8]>,
+    app_args: &[String],
+    config: VmConfig,
+    preopens: &[Preopen],
+) -> Result<RunReport, Error> {
+    evolve_with_hooks(kernel_path.into(), initrd, app_args, config, None, preopens)
+}
+
+/// Shared body of the non-capturing `run_vm*` shims: prepare the initrd,
+/// run pre-run hooks, evolve, and report to post-run hooks. The console
+/// is only captured if there are post-run hooks to see it, and is still
+/// echoed to stderr.
+fn evolve_with_hooks(
+    kernel: KernelRef<'_>,
+    initrd: Option<&[u8]>,
+    app_args: &[String],
+    config: VmConfig,
+    tools: Option<ToolRegistry>,
+    preopens: &[Preopen],
+) -> Result<RunReport, Error> {
+    let start = std::time::Instant::now();
+    let result = evolve_once(
+        kernel,
+        initrd.map(InitrdRef::from),
+        app_args,
+        &config,
+        tools,
+        preopens,
+    );
+    config.run_post_hooks(&result);
+    let total = start.elapsed();
+    match result.map_err(Error::from) {
+        Ok(out) => Ok(RunReport::new(&out.phases, total, ExitStatus::HALTED)),
+        Err(e) => match ExitStatus::from_error(&e) {
+            Some(exit) => Ok(RunReport::new(&PhaseTimings::default(), total, exit)),
+            None => Err(e),
+        },
+    }
+}
+
+/// Look the kernel image up in the [`kernel_cache`] on a scoped thread
+/// while `prepare` (initrd decompression, layers, boot header) runs on
+/// this one, so cold starts pay for the longer of mapping the kernel and
+/// preparing rather than both. Sandbox creation itself can't start
+/// earlier: Hyperlight takes the initrd as part of constructing it.
+///
+/// The image is `None` if the lookup fails; the caller then hands
+/// Hyperlight the path, which reports the error as before.
+fn with_kernel_prefetch<T>(
+    kernel_path: &Path,
+    prepare: impl FnOnce() -> T,
+) -> (Option<Arc<kernel_cache::KernelImage>>, T) {
+    std::thread::scope(|s| {
+        let kernel = s.spawn(|| kernel_cache::global().get(kernel_path).ok());
+        let prepared = prepare();
+        (kernel.join().ok().flatten(), prepared)
+    })
+}
+
+fn evolve_once(
+    kernel: KernelRef<'_>,
+    initrd: Option<InitrdRef<'_>>,
+    app_args: &[String],
+    config: &VmConfig,
+    tools: Option<ToolRegistry>,
+    preopens: &[Preopen],
+) -> Result<VmOutput> {
+    let start = std::time::Instant::now();
+    let kernel_path = kernel.path;
+    let (kernel_image, prepared) =
+        kernel.load(|| config.apply_initrd_pipeline(initrd.map(|i| i.data)));
+    let prepared = prepared?;
+    // The backing file only describes the bytes if no pipeline stage
+    // replaced them.
+    let file = initrd
+        .and_then(|i| i.file)
+        .filter(|_| prepared.is_borrowed());
+    let env = config.guest_env()?;
+    let header = BootHeader {
+        app_args,
+        preopens,
+        env: &env,
+        wall_clock: config.wall_clock,
+        ..BootHeader::default()
+    };
+    let extended_initrd =
+        prepend_boot_header(prepared.as_deref(), &header).map(|e| e.backed_by(file));
+    let prepare = start.elapsed();
+    config.run_pre_hooks(&RunAssets {
+        kernel_path,
+        initrd: extended_initrd.as_ref(),
+        app_args,
+    })?;
+    let host = BootHost::new(tools);
+    let console = host.console.clone();
+    let boot = || {
+        Sandbox::evolve_prepared(
+            kernel_path,
+            kernel_image.as_deref(),
+            extended_initrd.as_ref(),
+            config,
+            host,
+            preopens,
+            Vec::new(),
+        )
+    };
+    let (sandbox, raw) = if config.post_run_hooks.is_empty() {
+        (boot(), Vec::new())
+    } else if config.console.per_sandbox(kernel_path) {
+        let capture = console.capture(stderr_capture::Tee::Stderr, None, None);
+        let sandbox = boot();
+        (sandbox, capture.finish().bytes)
+    } else {
+        capture_for_hooks(boot)?
+    };
+    let mut phases = sandbox?.phases;
+    phases.add(Phase::InitrdPrepare, prepare);
+    let mut out = VmOutput::from_console(raw, phases);
+    out.setup_time = start.elapsed().saturating_sub(out.evolve_time);
+    Ok(out)
+}
+
+/// Run `f` with fd 2 captured, and still echoed to stderr, so post-run
+/// hooks on a path that doesn't otherwise capture see what a guest
+/// printing to port 0xE9 printed. Guests known to print through
+/// `HostPrint` are captured per sandbox instead, without the lock.
+fn capture_for_hooks<T>(f: impl FnOnce() -> T) -> Result<(T, Vec<u8>)> {
+    let _console = stderr_capture::lock_console();
+    let capture = stderr_capture::PipeCapture::start_with(stderr_capture::Tee::Stderr, None, None)?;
+    let value = f();
+    Ok((value, capture.finish()?.bytes))
+}
+
+/// Output captured from a VM execution.
+///
+/// With the `serde` feature, serializes to a versioned JSON-friendly
+/// record (see [`VM_OUTPUT_SCHEMA_VERSION`]): durations become integer
+/// microseconds and `raw` is base64.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(into = "VmOutputRecord", try_from = "VmOutputRecord")
+)]
+pub struct VmOutput {
+    /// Captured console text. Invalid UTF-8 is replaced with U+FFFD, so
+    /// a stray byte from the guest never loses the rest of the capture.
+    pub output: String,
+    /// The application's lines of `output`, without the kernel's boot
+    /// banner and log lines (see [`output::split_channels`]).
+    pub stdout: String,
+    /// The kernel's lines of `output`. Not serialized: both channels are
+    /// split from `output` again when a record is read back.
+    pub kernel_log: String,
+    /// Files the guest sent back through the `output_file` tool, by the
+    /// path it gave (see [`hostfn::OutputFiles`]). Only capturing runs
+    /// collect them.
+    pub files: HashMap<std::path::PathBuf, Vec<u8>>,
+    /// The exact bytes the guest wrote, before any lossy decoding or
+    /// ANSI stripping.
+    pub raw: Vec<u8>,
+    /// Console bytes left out of `raw` and `output` by
+    /// [`VmConfig::with_max_output_bytes`]; 0 if nothing was cut.
+    pub dropped_bytes: u64,
+    pub setup_time: Duration,
+    pub evolve_time: Duration,
+    /// Time from the start of output capture, just before the guest is
+    /// called, until its first console byte reached the host. Setup and
+    /// boot are in `setup_time`, not here.
+    /// `None` if the guest produced no output.
+    pub time_to_first_output: Option<Duration>,
+    /// Per-[`Phase`] breakdown of the whole run. Unlike `setup_time`
+    /// and `evolve_time`, these keep their meaning across releases.
+    pub phases: PhaseTimings,
+    /// Guest trace frames, when [`VmConfig::with_guest_trace`] is on.
+    pub trace: Vec<trace::TraceFrame>,
+    /// Host calls per tool, busiest first, when
+    /// [`VmConfig::with_call_stats`] is on.
+    pub host_calls: Vec<hostcall::CallSummary>,
+}
+
+impl VmOutput {
+    /// The value the guest printed after `marker`, read from [`raw`]
+    /// (see [`output::find_marker`]), so binary artifacts survive.
+    ///
+    /// [`raw`]: Self::raw
+    pub fn marker(&self, marker: &str) -> Option<&[u8]> {
+        output::find_marker(&self.raw, marker)
+    }
+
+    /// The output of a run outside [`BootedRun::run`], from the console
+    /// bytes [`capture_for_hooks`] collected, split the same way.
+    fn from_console(raw: Vec<u8>, phases: PhaseTimings) -> Self {
+        let output = String::from_utf8_lossy(&raw).into_owned();
+        let (stdout, kernel_log) = output::split_channels(&output);
+        let evolve_time = phases.get(Phase::Evolve);
+        Self {
+            output,
+            stdout,
+            kernel_log,
+            files: HashMap::new(),
+            raw,
+            dropped_bytes: 0,
+            setup_time: phases.total().saturating_sub(evolve_time),
+            evolve_time,
+            time_to_first_output: None,
+            phases,
+            trace: Vec::new(),
+            host_calls: Vec::new(),
+        }
+    }
+}
+
+/// Schema version written by [`VmOutput`]'s `Serialize` impl. Bumped on
+/// any incompatible change; deserializing another version is an error.
+pub const VM_OUTPUT_SCHEMA_VERSION: u32 = 1;
+
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+struct VmOutputRecord {
+    schema_version: u32,
+    output: String,
+    raw_base64: String,
+    setup_time_us: u64,
+    evolve_time_us: u64,
+    time_to_first_output_us: Option<u64>,
+    /// Keyed by [`Phase::as_str`]; absent in records from before phases
+    /// were reported, and unknown keys are ignored.
+    #[serde(default)]
+    phases_us: std::collections::BTreeMap<String, u64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    trace: Vec<trace::TraceFrame>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    host_calls: Vec<hostcall::CallSummary>,
+    /// Only written when output was cut.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    dropped_bytes: Option<u64>,
+    /// Base64 contents by path.
+    #[serde(default, skip_serializing_if = "std::collections::BTreeMap::is_empty")]
+    files: std::collections::BTreeMap<String, String>,
+}
+
+#[cfg(feature = "serde")]
+impl From<VmOutput> for VmOutputRecord {
+    fn from(o: VmOutput) -> Self {
+        use base64::Engine;
+        let us = |d: Duration| d.as_micros() as u64;
+        Self {
+            schema_version: VM_OUTPUT_SCHEMA_VERSION,
+            output: o.output,
+            raw_base64: base64::engine::general_purpose::STANDARD.encode(&o.raw),
+            setup_time_us: us(o.setup_time),
+            evolve_time_us: us(o.evolve_time),
+            time_to_first_output_us: o.time_to_first_output.map(us),
+            phases_us: o
+                .phases
+                .iter()
+                .map(|(p, d)| (p.as_str().to_string(), us(d)))
+                .collect(),
+            trace: o.trace,
+            host_calls: o.host_calls,
+            dropped_bytes: (o.dropped_bytes > 0).then_some(o.dropped_bytes),
+            files: o
+                .files
+                .iter()
+                .map(|(path, data)| {
+                    let data = base64::engine::general_purpose::STANDARD.encode(data);
+                    (path.to_string_lossy().into_owned(), data)
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<VmOutputRecord> for VmOutput {
+    type Error = String;
+
+    fn try_from(r: VmOutputRecord) -> std::result::Result<Self, String> {
+        use base64::Engine;
+        if r.schema_version != VM_OUTPUT_SCHEMA_VERSION {
+            return Err(format!(
+                "unsupported VmOutput schema version {} (expected {VM_OUTPUT_SCHEMA_VERSION})",
+                r.schema_version
+            ));
+        }
+        let raw = base64::engine::general_purpose::STANDARD
+            .decode(&r.raw_base64)
+            .map_err(|e| format!("raw_base64: {e}"))?;
+        let mut phases = PhaseTimings::default();
+        for (name, us) in &r.phases_us {
+            if let Some(phase) = Phase::from_name(name) {
+                phases.add(phase, Duration::from_micros(*us));
             }
         }
+        let (stdout, kernel_log) = output::split_channels(&r.output);
+        let files = r
+            .files
+            .into_iter()
+            .map(|(path, data)| {
+                let data = base64::engine::general_purpose::STANDARD
+                    .decode(data)
+                    .map_err(|e| format!("files[{path:?}]: {e}"))?;
+                Ok((path.into(), data))
+            })
+            .collect::<std::result::Result<_, String>>()?;
+        Ok(Self {
+            output: r.output,
+            stdout,
+            kernel_log,
+            files,
+            raw,
+            dropped_bytes: r.dropped_bytes.unwrap_or(0),
+            setup_time: Duration::from_micros(r.setup_time_us),
+            evolve_time: Duration::from_micros(r.evolve_time_us),
+            time_to_first_output: r.time_to_first_output_us.map(Duration::from_micros),
+            phases,
+            trace: r.trace,
+            host_calls: r.host_calls,
+        })
+    }
+}
+
+/// Run a Unikraft kernel and capture its console output.
+///
+/// Unikraft console output goes through Hyperlight's port I/O to host stderr.
+/// This function redirects stderr into a pipe during the call phase to
+/// capture it.  The Unikraft dispatch lifecycle is:
+///   evolve (boot+init+snapshot) → restore → call_run (app output here)
+///
+/// With [`VmConfig::with_tee_output`] the captured bytes are also written
+/// to the original stderr as they arrive, so operators watching a long
+/// run see it live.
+///
+/// `Ok` means the guest halted, exit code 0. Any other ending is an
+/// error holding the captured output, and [`ExitStatus::from_error`]
+/// gives its status.
+pub fn run_vm_capture_output(
+    kernel_path: &Path,
+    initrd: Option<&[u8]>,
+    app_args: &[String],
+    config: VmConfig,
+) -> Result<VmOutput, Error> {
+    let result = capture_run(
+        kernel_path.into(),
+        initrd.map(InitrdRef::from),
+        app_args,
+        &[],
+        &config,
+        None,
+        &[],
+    );
+    config.run_post_hooks(&result);
+    Ok(result?)
+}
+
+/// [`run_vm_capture_output`] with a memory-mapped initrd: the guest maps
+/// the archive's file directly, so the host holds no heap copy of it and
+/// concurrent runs of the same file share its page cache.
+pub fn run_vm_capture_output_mapped(
+    kernel_path: &Path,
+    initrd: Option<&rootfs::MappedInitrd>,
+    app_args: &[String],
+    config: VmConfig,
+) -> Result<VmOutput, Error> {
+    let result = capture_run(
+        kernel_path.into(),
+        initrd.map(InitrdRef::from),
+        app_args,
+        &[],
+        &config,
+        None,
+        &[],
+    );
+    config.run_post_hooks(&result);
+    Ok(result?)
+}
+
+/// [`run_vm_capture_output`] that also hands each console line to
+/// `on_line` as the guest writes it, without its trailing newline, so a
+/// long job can be followed while it runs. `on_line` is called on this
+/// thread (the VM runs on another), so it can borrow local state.
+///
+/// ```no_run
+/// # use hyperlight_unikraft::{run_vm_streaming, VmConfig};
+/// # use std::path::Path;
+/// # fn main() -> anyhow::Result<()> {
+/// let mut progress = 0;
+/// let out = run_vm_streaming(
+///     Path::new("python-kernel"),
+///     None,
+///     &["/train.py".to_string()],
+///     VmConfig::default(),
+///     |line| {
+///         if line.starts_with("epoch") {
+///             progress += 1;
+///         }
+///         println!("{line}");
+///     },
+/// )?;
+/// # Ok(())
+/// # }
+/// ```
+pub fn run_vm_streaming(
+    kernel_path: &Path,
+    initrd: Option<&[u8]>,
+    app_args: &[String],
+    config: VmConfig,
+    mut on_line: impl FnMut(&str),
+) -> Result<VmOutput, Error> {
+    let (tx, lines) = std::sync::mpsc::channel::<String>();
+    // The sink, and with it the sender, goes when the run's config does,
+    // which ends the loop below.
+    let config = config.with_sink(sink::from_fn(move |line| {
+        let _ = tx.send(line.to_string());
+    }));
+    std::thread::scope(|s| {
+        let run = std::thread::Builder::new()
+            .name("hl-vm".into())
+            .spawn_scoped(s, move || {
+                run_vm_capture_output(kernel_path, initrd, app_args, config)
+            })
+            .map_err(error::other)?;
+        for line in lines {
+            on_line(&line);
+        }
+        run.join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+    })
+}
+
+/// What [`run_vm_with_channel`] reports while a run is in progress.
+#[derive(Debug)]
+pub enum OutputEvent {
+    /// A console line, without its trailing newline.
+    Line(String),
+    /// A [`Phase`] finished after the given time; see
+    /// [`OutputSink::phase`](sink::OutputSink::phase).
+    Phase(Phase, Duration),
+    /// The run is over, with what [`run_vm_capture_output`] would have
+    /// returned. Always the last event.
+    Exit(Result<Box<VmOutput>, Error>),
+}
+
+struct ChannelSink(std::sync::mpsc::Sender<OutputEvent>);
 
-        Ok(Self {
-            inner,
-            snapshot: Some(arc),
-            file_mapping_path: None,
-            file_mapping_base: 0,
-        })
+impl sink::OutputSink for ChannelSink {
+    fn line(&mut self, line: &str) {
+        let _ = self.0.send(OutputEvent::Line(line.to_string()));
+    }
+
+    fn phase(&mut self, phase: Phase, took: Duration) {
+        let _ = self.0.send(OutputEvent::Phase(phase, took));
     }
 }
 
-// ---------------------------------------------------------------------------
-// Convenience: run_vm (single-shot execution)
-// ---------------------------------------------------------------------------
+/// [`run_vm_capture_output`] on a thread of its own, reporting to the
+/// returned channel as it goes: each console line as the guest writes
+/// it, each phase as it finishes, then [`OutputEvent::Exit`]. Suits
+/// event loops and TUIs that poll for output rather than take a
+/// callback ([`run_vm_streaming`]). The run goes on if the receiver is
+/// dropped; stop it with [`VmConfig::with_timeout`] or a cancel token.
+///
+/// ```no_run
+/// # use hyperlight_unikraft::{run_vm_with_channel, OutputEvent, VmConfig};
+/// let events = run_vm_with_channel("python-kernel", None, vec!["/app.py".into()], VmConfig::default());
+/// for event in events {
+///     match event {
+///         OutputEvent::Line(line) => println!("{line}"),
+///         OutputEvent::Phase(phase, took) => eprintln!("{phase}: {took:?}"),
+///         OutputEvent::Exit(result) => eprintln!("done: {:?}", result.map(|o| o.evolve_time)),
+///     }
+/// }
+/// ```
+pub fn run_vm_with_channel(
+    kernel_path: impl Into<std::path::PathBuf>,
+    initrd: Option<Vec<u8>>,
+    app_args: Vec<String>,
+    config: VmConfig,
+) -> std::sync::mpsc::Receiver<OutputEvent> {
+    let kernel_path = kernel_path.into();
+    let (tx, events) = std::sync::mpsc::channel();
+    let config = config.with_sink(ChannelSink(tx.clone()));
+    let exit = tx.clone();
+    let spawned = std::thread::Builder::new()
+        .name("hl-vm".into())
+        .spawn(move || {
+            let result = run_vm_capture_output(&kernel_path, initrd.as_deref(), &app_args, config);
+            let _ = exit.send(OutputEvent::Exit(result.map(Box::new)));
+        });
+    if let Err(e) = spawned {
+        let _ = tx.send(OutputEvent::Exit(Err(error::other(e))));
+    }
+    events
+}
 
-/// Run a Unikraft kernel to completion (single-shot). Thin shim over
-/// [`Sandbox::builder`] for callers that don't need the full fluent API.
-pub fn run_vm(
-    kernel_path: &Path,
-    initrd: Option<&[u8]>,
-    app_args: &[String],
+/// [`run_vm_capture_output`] as a future, for async callers. The run
+/// goes to a thread of its own, so awaiting it never blocks the
+/// executor; dropping the [`VmTask`] before it resolves interrupts the
+/// guest and tears the sandbox down.
+pub fn run_vm_capture_output_async(
+    kernel_path: impl Into<std::path::PathBuf>,
+    initrd: Option<Vec<u8>>,
+    app_args: Vec<String>,
     config: VmConfig,
-) -> Result<()> {
-    let _ = Sandbox::evolve_inline(kernel_path, initrd, app_args, config, None, &[])?;
-    Ok(())
+) -> VmTask<VmOutput> {
+    let kernel_path = kernel_path.into();
+    task::spawn("hl-vm", move |cancel| {
+        let config = VmConfig {
+            cancel: Some(cancel),
+            ..config
+        };
+        run_vm_capture_output(&kernel_path, initrd.as_deref(), &app_args, config)
+    })
 }
 
-/// Run a Unikraft kernel with tool dispatch support.
-pub fn run_vm_with_tools(
-    kernel_path: &Path,
-    initrd: Option<&[u8]>,
-    app_args: &[String],
+/// [`run_vm`] as a future; see [`run_vm_capture_output_async`]. The
+/// guest's console stays on the host's stderr.
+pub fn run_vm_async(
+    kernel_path: impl Into<std::path::PathBuf>,
+    initrd: Option<Vec<u8>>,
+    app_args: Vec<String>,
     config: VmConfig,
-    tools: ToolRegistry,
-) -> Result<()> {
-    let _ = Sandbox::evolve_inline(kernel_path, initrd, app_args, config, Some(tools), &[])?;
-    Ok(())
+) -> VmTask<ExitStatus> {
+    let kernel_path = kernel_path.into();
+    task::spawn("hl-vm", move |cancel| {
+        let config = VmConfig {
+            cancel: Some(cancel),
+            capture_output: false,
+            ..config
+        };
+        match run_vm_capture_output(&kernel_path, initrd.as_deref(), &app_args, config) {
+            Ok(_) => Ok(ExitStatus::HALTED),
+            Err(e) => ExitStatus::from_error(&e).ok_or(e),
+        }
+    })
 }
 
-/// Run a Unikraft kernel with preopened host directories exposed via
-/// `lib/hostfs`. Sandbox escape attempts are rejected host-side.
-pub fn run_vm_with_preopens(
-    kernel_path: &Path,
-    initrd: Option<&[u8]>,
+type SharedSink = std::sync::Mutex<dyn sink::OutputSink>;
+
+fn lock_sink(sink: &SharedSink) -> std::sync::MutexGuard<'_, dyn sink::OutputSink + 'static> {
+    sink.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn report_phase(config: &VmConfig, phase: Phase, took: Duration) {
+    for s in &config.sinks {
+        lock_sink(s).phase(phase, took);
+    }
+}
+
+/// A run ID unique across processes in practice: start time, PID and a
+/// per-process counter.
+pub fn new_run_id() -> String {
+    static NEXT: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+    let millis = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis());
+    format!(
+        "{millis:x}-{:x}-{}",
+        std::process::id(),
+        NEXT.fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+    )
+}
+
+fn capture_run(
+    kernel: KernelRef<'_>,
+    initrd: Option<InitrdRef<'_>>,
     app_args: &[String],
-    config: VmConfig,
+    run_env: &[(String, String)],
+    config: &VmConfig,
+    tools: Option<ToolRegistry>,
     preopens: &[Preopen],
+) -> Result<VmOutput> {
+    boot_for_capture(kernel, initrd, app_args, run_env, config, tools, preopens)?.run(config)
+}
+
+/// A sandbox booted for a capturing run, waiting for its call.
+pub(crate) struct BootedRun {
+    sandbox: Sandbox,
+    /// When the run began; timings and trace frames count from here.
+    setup_start: std::time::Instant,
+    setup_time: Duration,
+    phases: PhaseTimings,
+    frames: Vec<trace::TraceFrame>,
+    call_stats: Option<Arc<hostcall::CallStats>>,
+    /// What the guest sends back through `output_file`.
+    files: Arc<hostfn::OutputFiles>,
+    kernel: std::path::PathBuf,
+    /// Whether the sinks have been told the next run is starting.
+    announced: bool,
+}
+
+/// What a sandbox started from another's snapshot takes from it, so it
+/// can be created on another thread.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ForkSeed {
+    file_mappings: Vec<FileMapping>,
+    heap_size: Option<u64>,
+    /// Whether the guest prints through `HostPrint`, for
+    /// [`Console::Auto`]; forks don't boot, so can't find out.
+    host_print: bool,
+}
+
+/// A sandbox `HostPrint` can be registered on: one being set up, or one
+/// started from a snapshot.
+trait ConsoleHost {
+    fn register_print(
+        &mut self,
+        print: impl Fn(String) -> i32 + Send + Sync + 'static,
+    ) -> hyperlight_host::Result<()>;
+}
+
+impl ConsoleHost for UninitializedSandbox {
+    fn register_print(
+        &mut self,
+        print: impl Fn(String) -> i32 + Send + Sync + 'static,
+    ) -> hyperlight_host::Result<()> {
+        self.register_host_function("HostPrint", print)
+    }
+}
+
+impl ConsoleHost for MultiUseSandbox {
+    fn register_print(
+        &mut self,
+        print: impl Fn(String) -> i32 + Send + Sync + 'static,
+    ) -> hyperlight_host::Result<()> {
+        self.register_host_function("HostPrint", print)
+    }
+}
+
+/// Answer `sandbox`'s `HostPrint` calls with `console`.
+fn register_console(
+    sandbox: &mut impl ConsoleHost,
+    console: &Arc<stderr_capture::SandboxConsole>,
 ) -> Result<()> {
-    let _ = Sandbox::evolve_inline(kernel_path, initrd, app_args, config, None, preopens)?;
-    Ok(())
+    let printer = console.clone();
+    sandbox
+        .register_print(move |message: String| printer.print(&message))
+        .map_err(error::sandbox_creation)
 }
 
-/// Output captured from a VM execution.
-pub struct VmOutput {
-    pub output: String,
-    pub setup_time: Duration,
-    pub evolve_time: Duration,
+/// Note on the cached image of `kernel` that it prints through
+/// `HostPrint`, once `sandbox` booting it has shown so; see
+/// [`Console::Auto`].
+fn learn_console(kernel: &Path, sandbox: &Sandbox) {
+    if !sandbox.console.printed() {
+        return;
+    }
+    // An in-memory kernel has no file to key the cache on.
+    if let Ok(image) = kernel_cache::global().get(kernel) {
+        image.note_prints_to_host();
+    }
 }
 
-/// Run a Unikraft kernel and capture its console output.
-///
-/// Unikraft console output goes through Hyperlight's port I/O to host stderr.
-/// This function redirects stderr to a temp file during the call phase to
-/// capture it.  The Unikraft dispatch lifecycle is:
-///   evolve (boot+init+snapshot) → restore → call_run (app output here)
-pub fn run_vm_capture_output(
-    kernel_path: &Path,
-    initrd: Option<&[u8]>,
+impl ForkSeed {
+    fn sandbox(&self, snapshot: Arc<Snapshot>, tools: Option<ToolRegistry>) -> Result<Sandbox> {
+        let mut inner =
+            MultiUseSandbox::from_snapshot(snapshot.clone()).map_err(error::sandbox_creation)?;
+        let tools = tools.map(Arc::new);
+        if let Some(ref tools) = tools {
+            let tools_ref = tools.clone();
+            inner
+                .register_host_function("__dispatch", move |payload: Vec<u8>| -> Vec<u8> {
+                    tools_ref.dispatch(&payload)
+                })
+                .map_err(error::sandbox_creation)?;
+        }
+        let console = Arc::new(stderr_capture::SandboxConsole::new(self.host_print));
+        register_console(&mut inner, &console)?;
+        // The mappings are (re)made by every restore.
+        Ok(Sandbox {
+            inner,
+            snapshot: Some(snapshot),
+            file_mappings: self.file_mappings.clone(),
+            boot_timings: BootTimings::default(),
+            phases: PhaseTimings::default(),
+            console,
+            tools,
+            heap_size: self.heap_size,
+            meter: Arc::default(),
+            shutdown: Arc::default(),
+            killed: Arc::default(),
+            placement: None,
+            post_run_hooks: Vec::new(),
+            timeout: None,
+        })
+    }
+}
+
+/// Where a capturing run's console output is being collected.
+enum RunCapture {
+    Pipe(stderr_capture::PipeCapture),
+    Sandbox(stderr_capture::SandboxCapture),
+}
+
+impl RunCapture {
+    fn finish(self) -> Result<stderr_capture::CapturedOutput> {
+        match self {
+            Self::Pipe(capture) => capture.finish(),
+            Self::Sandbox(capture) => Ok(capture.finish()),
+        }
+    }
+}
+
+/// The first half of [`capture_run`]: prepare the inputs and boot the
+/// sandbox, stopping short of the application. `run_env` is set on top
+/// of the config's environment for this run only.
+pub(crate) fn boot_for_capture(
+    kernel: KernelRef<'_>,
+    initrd: Option<InitrdRef<'_>>,
     app_args: &[String],
-    config: VmConfig,
-) -> Result<VmOutput> {
+    run_env: &[(String, String)],
+    config: &VmConfig,
+    mut tools: Option<ToolRegistry>,
+    preopens: &[Preopen],
+) -> Result<BootedRun> {
     let setup_start = std::time::Instant::now();
+    let kernel_path = kernel.path;
+    let (kernel_image, prepared) =
+        kernel.load(|| config.apply_initrd_pipeline(initrd.map(|i| i.data)));
+    let prepared = prepared?;
+    // The backing file only describes the bytes if no pipeline stage
+    // replaced them.
+    let file = initrd
+        .and_then(|i| i.file)
+        .filter(|_| prepared.is_borrowed());
+    let env = config.guest_env_with(run_env)?;
+    let header = BootHeader {
+        app_args,
+        preopens,
+        env: &env,
+        wall_clock: config.wall_clock,
+        ..BootHeader::default()
+    };
+    let extended_initrd =
+        prepend_boot_header(prepared.as_deref(), &header).map(|e| e.backed_by(file));
+    let prepare = setup_start.elapsed();
+    config.run_pre_hooks(&RunAssets {
+        kernel_path,
+        initrd: extended_initrd.as_ref(),
+        app_args,
+    })?;
+
+    let run_id = new_run_id();
+    for s in &config.sinks {
+        lock_sink(s)
+            .begin(&sink::RunInfo {
+                run_id: &run_id,
+                kernel: kernel_path,
+            })
+            .map_err(error::caller)?;
+    }
+
+    // Everything above is per-run preparation; the boot banner must not
+    // land in another run's capture. A kernel known to print through
+    // HostPrint keeps its banner in its own sandbox's console.
+    let _console = (!config.console.per_sandbox(kernel_path)).then(stderr_capture::lock_console);
 
     // Phase 1: evolve — boots the kernel and takes a post-init snapshot.
     // No application output happens here.
-    let mut sandbox = Sandbox::evolve_inline(kernel_path, initrd, app_args, config, None, &[])?;
-    let setup_time = setup_start.elapsed();
+    let call_stats = config
+        .call_stats
+        .then(|| Arc::new(hostcall::CallStats::default()));
+    if let Some(ref stats) = call_stats {
+        tools
+            .get_or_insert_with(ToolRegistry::new)
+            .observe(stats.clone());
+    }
+    let files = Arc::new(hostfn::OutputFiles::default());
+    files.register(tools.get_or_insert_with(ToolRegistry::new));
+    let (sandbox, frames) = trace::collect(config.guest_trace, setup_start, || {
+        Sandbox::evolve_prepared(
+            kernel_path,
+            kernel_image.as_deref(),
+            extended_initrd.as_ref(),
+            config,
+            BootHost::new(tools),
+            preopens,
+            Vec::new(),
+        )
+    });
+    let sandbox = sandbox?;
+    let mut phases = sandbox.phases;
+    phases.add(Phase::InitrdPrepare, prepare);
+    for (phase, took) in phases.iter().filter(|&(_, took)| !took.is_zero()) {
+        report_phase(config, phase, took);
+    }
+    Ok(BootedRun {
+        sandbox,
+        setup_start,
+        setup_time: setup_start.elapsed(),
+        phases,
+        frames,
+        call_stats,
+        files,
+        kernel: kernel_path.to_path_buf(),
+        announced: true,
+    })
+}
 
-    // Redirect stderr to a temp file before the call phase
-    let capture_file = std::env::temp_dir().join(format!("hl-capture-{}", std::process::id()));
-    let capture = stderr_capture::Capture::redirect_to_file(&capture_file)?;
+impl BootedRun {
+    pub(crate) fn handle(&self) -> VmHandle {
+        self.sandbox.handle()
+    }
 
-    // Phase 2: restore + call — application runs and produces output
-    let evolve_start = std::time::Instant::now();
-    sandbox.restore()?;
-    let call_result = sandbox.call_run();
-    let evolve_time = evolve_start.elapsed();
+    /// A run on a new sandbox started from `snapshot`, skipping the
+    /// boot; see [`Sandbox::fork`].
+    pub(crate) fn forked(
+        seed: &ForkSeed,
+        snapshot: Arc<Snapshot>,
+        kernel: &Path,
+        config: &VmConfig,
+        tools: Option<ToolRegistry>,
+        preopens: &[Preopen],
+    ) -> Result<Self> {
+        let setup_start = std::time::Instant::now();
+        let call_stats = config
+            .call_stats
+            .then(|| Arc::new(hostcall::CallStats::default()));
+        let mut tools = build_tools(
+            tools,
+            preopens,
+            &config.call_observers,
+            config.stdin.as_ref(),
+        )?;
+        if let Some(ref stats) = call_stats {
+            tools
+                .get_or_insert_with(ToolRegistry::new)
+                .observe(stats.clone());
+        }
+        let files = Arc::new(hostfn::OutputFiles::default());
+        files.register(tools.get_or_insert_with(ToolRegistry::new));
+        let sandbox = seed.sandbox(snapshot, tools)?;
+        let setup_time = setup_start.elapsed();
+        let mut phases = PhaseTimings::default();
+        phases.add(Phase::SandboxCreate, setup_time);
+        Ok(Self {
+            sandbox,
+            setup_start,
+            setup_time,
+            phases,
+            frames: Vec::new(),
+            call_stats,
+            files,
+            kernel: kernel.to_path_buf(),
+            announced: false,
+        })
+    }
 
-    // Restore stderr
-    capture.restore()?;
+    pub(crate) fn fork_seed(&self) -> ForkSeed {
+        self.sandbox.fork_seed()
+    }
 
-    // Read captured output
-    let captured = std::fs::read(&capture_file).unwrap_or_default();
-    let _ = std::fs::remove_file(&capture_file);
-    let captured = String::from_utf8_lossy(&captured).into_owned();
+    /// The snapshot the next run starts from: the one taken after boot
+    /// unless [`rewind_to`](Self::rewind_to) replaced it.
+    pub(crate) fn snapshot(&self) -> Option<Arc<Snapshot>> {
+        self.sandbox.snapshot.clone()
+    }
 
-    if let Err(e) = call_result {
-        return Err(anyhow!(
-            "VM call failed: {}\n--- captured output ---\n{}",
-            e,
-            captured
-        ));
+    /// Start the next run from `snapshot` instead.
+    pub(crate) fn rewind_to(&mut self, snapshot: Arc<Snapshot>) {
+        self.sandbox.snapshot = Some(snapshot);
     }
 
-    Ok(VmOutput {
-        output: captured,
-        setup_time,
-        evolve_time,
-    })
+    /// The second half of [`capture_run`]: run the application and
+    /// collect what it printed. Each call restores the sandbox first, so
+    /// a booted run can be run again.
+    pub(crate) fn run(&mut self, config: &VmConfig) -> Result<VmOutput> {
+        if !std::mem::replace(&mut self.announced, false) {
+            let run_id = new_run_id();
+            for s in &config.sinks {
+                lock_sink(s)
+                    .begin(&sink::RunInfo {
+                        run_id: &run_id,
+                        kernel: &self.kernel,
+                    })
+                    .map_err(error::caller)?;
+            }
+        }
+        let setup_start = self.setup_start;
+        let setup_time = std::mem::take(&mut self.setup_time);
+        let mut phases = std::mem::take(&mut self.phases);
+        let mut frames = std::mem::take(&mut self.frames);
+        let sandbox = &mut self.sandbox;
+        let per_sandbox = match config.console {
+            Console::Auto => sandbox.console.printed(),
+            Console::Stderr => false,
+            Console::HostPrint => true,
+        };
+        // From here to the end of the capture this run owns the console.
+        let _console = (!per_sandbox).then(stderr_capture::lock_console);
+
+        // Redirect stderr into the capture pipe (or start collecting the
+        // sandbox's own console) before the call phase
+        let keep = (config.forward_to_tracing || !config.sinks.is_empty()).then(|| {
+            let tee = config.tee_output;
+            let trace = config.forward_to_tracing;
+            let sinks = config.sinks.clone();
+            Box::new(move |line: &[u8]| {
+                let line = String::from_utf8_lossy(line);
+                let line = line.trim_end_matches('\r');
+                if trace {
+                    output::forward_to_tracing(line);
+                }
+                for s in &sinks {
+                    lock_sink(s).line(line);
+                }
+                tee
+            }) as stderr_capture::LineFilter
+        });
+        let tee = match config.tee_writer {
+            Some(ref writer) if config.tee_output => stderr_capture::Tee::Writer(writer.clone()),
+            // A line filter decides per line what reaches stderr.
+            _ => stderr_capture::Tee::stderr_if(config.tee_output || keep.is_some()),
+        };
+        let limit = config.max_output_bytes.map(|max| {
+            let handle = sandbox.handle();
+            stderr_capture::Limit {
+                max: usize::try_from(max).unwrap_or(usize::MAX),
+                overflow: config.output_overflow,
+                on_abort: (config.output_overflow == OutputOverflow::Abort).then(|| {
+                    Box::new(move || {
+                        handle.interrupt();
+                    }) as Box<dyn FnOnce() + Send>
+                }),
+            }
+        });
+        // Time to first output counts from here, not from boot: before
+        // this point nothing the guest prints can reach the host.
+        let capture_start = std::time::Instant::now();
+        let capture = match (config.capture_output, per_sandbox) {
+            (false, _) => None,
+            (true, true) => Some(RunCapture::Sandbox(
+                sandbox.console.capture(tee, keep, limit),
+            )),
+            (true, false) => Some(RunCapture::Pipe(stderr_capture::PipeCapture::start_with(
+                tee, keep, limit,
+            )?)),
+        };
+
+        // Phase 2: restore + call — application runs and produces output
+        let evolve_start = std::time::Instant::now();
+        if let Some(ref cancel) = config.cancel {
+            cancel.attach(sandbox.handle())?;
+        }
+        let watchdog = config
+            .timeout
+            .map(|limit| Watchdog::start(sandbox.handle(), limit))
+            .transpose()?;
+        let (call_result, call_frames) = trace::collect(config.guest_trace, setup_start, || {
+            sandbox
+                .restore()
+                .and_then(|()| sandbox.call_run())
+                .map_err(anyhow::Error::from)
+        });
+        let timed_out = watchdog.is_some_and(Watchdog::finish);
+        let files = self.files.take();
+        frames.extend(call_frames);
+        let evolve_time = evolve_start.elapsed();
+        phases.add(Phase::Evolve, evolve_time);
+        report_phase(config, Phase::Evolve, evolve_time);
+
+        // Restore stderr and collect what the reader thread drained
+        let stderr_capture::CapturedOutput {
+            bytes: raw,
+            first_output_at,
+            dropped: dropped_bytes,
+            ..
+        } = phases
+            .time(Phase::Drain, || capture.map(RunCapture::finish).transpose())?
+            .unwrap_or_default();
+        report_phase(config, Phase::Drain, phases.get(Phase::Drain));
+        let exit = VmExit::from_result(&call_result);
+        for s in &config.sinks {
+            lock_sink(s).end(&exit);
+        }
+        let time_to_first_output = first_output_at.map(|t| t.duration_since(capture_start));
+        if let Some(latency) = time_to_first_output {
+            metrics::startup_latencies().first_output.record(latency);
+        }
+        let captured = phases.time(Phase::Extract, || {
+            let text = String::from_utf8_lossy(&raw);
+            if config.strip_ansi {
+                output::strip_ansi(&text)
+            } else {
+                text.into_owned()
+            }
+        });
+
+        let overflowed = config
+            .max_output_bytes
+            .filter(|_| dropped_bytes > 0 && config.output_overflow == OutputOverflow::Abort);
+        if let Some(limit) = overflowed {
+            // The guest may have finished before the interrupt landed;
+            // the run fails either way.
+            let e = call_result.err().unwrap_or_else(|| anyhow!("stopped"));
+            let failure = format!(
+                "VM call failed: printed more than {}\n--- captured output ---\n{captured}",
+                format_memory(limit)
+            );
+            return Err(e
+                .context(Error::OutputLimitExceeded {
+                    limit,
+                    output: captured,
+                })
+                .context(failure));
+        }
+        if let Err(e) = call_result {
+            if let Some(limit) = config.timeout.filter(|_| timed_out) {
+                let failure = format!(
+                    "VM call failed: timed out after {limit:?}\n--- captured output ---\n{captured}"
+                );
+                return Err(e
+                    .context(Error::TimedOut {
+                        limit,
+                        output: captured,
+                    })
+                    .context(failure));
+            }
+            let classified = error::guest_failed(e, Some(config.heap_size), &captured);
+            let failure = format!(
+                "VM call failed: {}\n--- captured output ---\n{}",
+                classified.root_cause(),
+                captured
+            );
+            // An OOM leads with its advice; other failures keep the captured
+            // output up front with the classification underneath.
+            return Err(match classified.downcast::<Error>() {
+                Ok(oom @ Error::OutOfGuestMemory { .. }) => anyhow!(failure).context(oom),
+                Ok(typed) => anyhow::Error::new(typed).context(failure),
+                Err(_) => anyhow!(failure),
+            });
+        }
+
+        let (stdout, kernel_log) =
+            phases.time(Phase::Extract, || output::split_channels(&captured));
+        report_phase(config, Phase::Extract, phases.get(Phase::Extract));
+        Ok(VmOutput {
+            output: captured,
+            stdout,
+            kernel_log,
+            files,
+            raw,
+            dropped_bytes,
+            setup_time,
+            evolve_time,
+            time_to_first_output,
+            phases,
+            trace: frames,
+            host_calls: self
+                .call_stats
+                .as_ref()
+                .map(|s| {
+                    let summary = s.summary();
+                    s.reset();
+                    summary
+                })
+                .unwrap_or_default(),
+        })
+    }
 }
 
 // ---------------------------------------------------------------------------
@@ -1540,6 +4835,241 @@ mod tests {
     use super::*;
     use std::fs;
 
+    #[test]
+    fn parse_duration_accepts_common_suffixes() {
+        assert_eq!(parse_duration("250ms").unwrap(), Duration::from_millis(250));
+        assert_eq!(parse_duration("30s").unwrap(), Duration::from_secs(30));
+        assert_eq!(parse_duration(" 5m ").unwrap(), Duration::from_secs(300));
+        assert_eq!(parse_duration("1h").unwrap(), Duration::from_secs(3600));
+        assert_eq!(parse_duration("1.5s").unwrap(), Duration::from_millis(1500));
+        assert_eq!(parse_duration("10").unwrap(), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn initrd_preflight_names_both_sizes() {
+        let mib = 1024 * 1024;
+        assert!(check_initrd_fits(100 * mib, 256 * mib).is_ok());
+        let err = check_initrd_fits(85 * mib, 16 * mib).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "heap 16Mi too small for 85Mi rootfs; need at least 128Mi"
+        );
+        // Leaves room for the kernel, not just the rootfs bytes.
+        assert!(check_initrd_fits(256 * mib, 256 * mib).is_err());
+    }
+
+    #[test]
+    fn validate_rejects_sizes_hyperlight_would_choke_on() {
+        let message = |config: VmConfig| config.validate().unwrap_err().to_string();
+        assert!(VmConfig::default().validate().is_ok());
+        assert_eq!(
+            message(VmConfig::default().with_heap_size(100_000_000)),
+            "heap size 100000000 is not a multiple of the 4Ki page size; \
+             use 100003840 or a Ki/Mi/Gi size"
+        );
+        assert_eq!(
+            message(VmConfig::default().with_heap_size(4 << 20)),
+            "heap 4Mi too small; need at least 8Mi"
+        );
+        let config = VmConfig {
+            stack_size: 16 << 10,
+            ..VmConfig::default()
+        };
+        assert_eq!(message(config), "stack 16Ki too small; need at least 64Ki");
+        #[cfg(not(feature = "crashdump"))]
+        assert_eq!(
+            message(VmConfig::default().with_core_dump(true)),
+            "core dumps need the `crashdump` feature"
+        );
+        let err = VmConfig::default()
+            .validate_for_initrd(600 << 20)
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::InvalidConfig { .. })
+        ));
+    }
+
+    #[test]
+    fn channel_run_ends_with_its_exit_event() {
+        let events: Vec<OutputEvent> =
+            run_vm_with_channel("/no/such/kernel", None, Vec::new(), VmConfig::default())
+                .into_iter()
+                .collect();
+        let [OutputEvent::Exit(Err(e))] = &events[..] else {
+            panic!("expected a single failed exit, got {events:?}");
+        };
+        assert!(matches!(e, Error::Kernel { .. }));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn vm_output_serializes_to_a_versioned_record() {
+        let mut out = VmOutput {
+            output: "hi\u{fffd}".into(),
+            stdout: "hi\u{fffd}".into(),
+            kernel_log: String::new(),
+            files: HashMap::new(),
+            raw: b"hi\xff".to_vec(),
+            dropped_bytes: 0,
+            setup_time: Duration::from_micros(1500),
+            evolve_time: Duration::from_millis(3),
+            time_to_first_output: None,
+            phases: PhaseTimings::default(),
+            trace: Vec::new(),
+            host_calls: Vec::new(),
+        };
+        out.phases.add(Phase::Drain, Duration::from_micros(20));
+        let json = serde_json::to_value(&out).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "schema_version": 1,
+                "output": "hi\u{fffd}",
+                "raw_base64": "aGn/",
+                "setup_time_us": 1500,
+                "evolve_time_us": 3000,
+                "time_to_first_output_us": null,
+                "phases_us": {
+                    "asset_load": 0, "initrd_prepare": 0, "sandbox_create": 0,
+                    "evolve": 0, "drain": 20, "extract": 0,
+                },
+            })
+        );
+        assert_eq!(serde_json::from_value::<VmOutput>(json).unwrap(), out);
+
+        out.files.insert("/out/a b.bin".into(), vec![0, 0xff]);
+        let json = serde_json::to_value(&out).unwrap();
+        assert_eq!(json["files"], serde_json::json!({ "/out/a b.bin": "AP8=" }));
+        assert_eq!(serde_json::from_value::<VmOutput>(json).unwrap(), out);
+
+        // Records written before phases existed still load.
+        let older = serde_json::json!({
+            "schema_version": 1, "output": "", "raw_base64": "",
+            "setup_time_us": 0, "evolve_time_us": 0, "time_to_first_output_us": null,
+        });
+        let older = serde_json::from_value::<VmOutput>(older).unwrap();
+        assert_eq!(older.phases, PhaseTimings::default());
+
+        let future = serde_json::json!({
+            "schema_version": 2, "output": "", "raw_base64": "",
+            "setup_time_us": 0, "evolve_time_us": 0, "time_to_first_output_us": null,
+        });
+        assert!(serde_json::from_value::<VmOutput>(future).is_err());
+    }
+
+    #[test]
+    fn customizers_run_in_order_after_the_defaults() {
+        use std::sync::Mutex;
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let (a, b) = (calls.clone(), calls.clone());
+        let config = VmConfig::default()
+            .customize(move |_| a.lock().unwrap().push(1))
+            .customize(move |_| b.lock().unwrap().push(2));
+        config.sandbox_config();
+        assert_eq!(*calls.lock().unwrap(), [1, 2]);
+    }
+
+    #[test]
+    fn memory_fractions_round_down_to_pages() {
+        let gib = 1024 * 1024 * 1024;
+        assert_eq!(fraction_of(16 * gib, 0.25).unwrap(), 4 * gib);
+        assert_eq!(fraction_of(10_000_000, 0.5).unwrap(), 4_997_120);
+        assert!(fraction_of(16 * gib, 0.0).is_err());
+        assert!(fraction_of(16 * gib, 1.5).is_err());
+        assert!(fraction_of(4096, 0.1).is_err());
+    }
+
+    #[test]
+    fn memory_sizes_parse_and_format_round_trip() {
+        let mib = 1024 * 1024;
+        for (text, bytes) in [
+            ("512Mi", 512 * mib),
+            ("1.5Gi", 1536 * mib),
+            ("64MiB", 64 * mib),
+            ("64mib", 64 * mib),
+            ("2 Ti", 2 << 40),
+            ("1Pi", 1 << 50),
+            ("100M", 100_000_000),
+            ("100MB", 100_000_000),
+            ("1.5k", 1500),
+            ("4096", 4096),
+            ("8b", 8),
+        ] {
+            assert_eq!(parse_memory(text).unwrap(), bytes, "{text}");
+        }
+        for bytes in [0, 4097, 64 * mib, 1536 * mib, 3 << 40, 100_000_000] {
+            let text = format_memory(bytes);
+            assert_eq!(parse_memory(&text).unwrap(), bytes, "{text}");
+        }
+        assert_eq!(format_memory(1536 * mib), "1536Mi");
+        assert_eq!(format_memory(2 << 50), "2Pi");
+        for bad in [
+            "20000Pi",
+            "18446744073709551616",
+            "1e30",
+            "-1Mi",
+            "5Xi",
+            "Mi",
+            "",
+        ] {
+            assert!(parse_memory(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn memory_percentages_resolve_against_host_ram() {
+        let total = host_memory().unwrap();
+        let quarter = parse_memory("25%").unwrap();
+        assert!(quarter > 0 && quarter <= total / 4);
+        assert!(parse_memory("abc%").is_err());
+
+        let config = VmConfig::default().with_heap_fraction(0.25);
+        assert_eq!(config.heap_size, quarter);
+        assert!(config.validate().is_ok());
+        let config = VmConfig::default().with_heap_fraction(1.5);
+        assert_eq!(config.heap_size, VmConfig::default().heap_size);
+        let err = config.validate().unwrap_err();
+        assert!(err.to_string().contains("heap fraction"), "{err:#}");
+        assert!(VmConfig::default()
+            .with_heap_fraction(1.5)
+            .with_heap_size(64 << 20)
+            .validate()
+            .is_ok());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn cgroup_limits_are_read_for_v2_and_v1() {
+        let ws = crate::workspace::Workspace::new().unwrap();
+        let root = ws.path();
+        fs::create_dir_all(root.join("app.slice")).unwrap();
+        fs::write(root.join("app.slice/memory.max"), "268435456\n").unwrap();
+        assert_eq!(
+            cgroup_memory_limit("0::/app.slice\n", root),
+            Some(256 << 20)
+        );
+        fs::write(root.join("app.slice/memory.max"), "max\n").unwrap();
+        assert_eq!(cgroup_memory_limit("0::/app.slice\n", root), None);
+
+        fs::create_dir_all(root.join("memory/docker/abc")).unwrap();
+        fs::write(
+            root.join("memory/docker/abc/memory.limit_in_bytes"),
+            "1073741824\n",
+        )
+        .unwrap();
+        let v1 = "12:cpu,cpuacct:/docker/abc\n4:memory:/docker/abc\n";
+        assert_eq!(cgroup_memory_limit(v1, root), Some(1 << 30));
+    }
+
+    #[test]
+    fn parse_duration_rejects_garbage() {
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("ten seconds").is_err());
+        assert!(parse_duration("-1s").is_err());
+    }
+
     fn tmpdir(label: &str) -> std::path::PathBuf {
         let p =
             std::env::temp_dir().join(format!("hl-fs-sandbox-{}-{}", label, std::process::id()));
@@ -1697,7 +5227,15 @@ mod tests {
             Preopen::new(&root_a, "/data").unwrap(),
             Preopen::new(&root_b, "/logs").unwrap(),
         ];
-        let buf = build_cmdline_initdata(&["/hello".to_string()], 0, &preopens).expect("initdata");
+        let buf = build_cmdline_initdata(
+            &BootHeader {
+                app_args: &["/hello".to_string()],
+                preopens: &preopens,
+                ..BootHeader::default()
+            },
+            0,
+        )
+        .expect("initdata");
         assert!(buf.starts_with(CMDLINE_MAGIC), "cmdline magic missing");
         let off = find_subslice(&buf, MOUNT_MAGIC).expect("mount magic missing");
         let count_off = off + MOUNT_MAGIC.len();
@@ -1715,7 +5253,14 @@ mod tests {
 
     #[test]
     fn initdata_omits_mount_tlv_when_no_preopens() {
-        let buf = build_cmdline_initdata(&["/hello".to_string()], 0, &[]).expect("initdata");
+        let buf = build_cmdline_initdata(
+            &BootHeader {
+                app_args: &["/hello".to_string()],
+                ..BootHeader::default()
+            },
+            0,
+        )
+        .expect("initdata");
         assert!(buf.starts_with(CMDLINE_MAGIC));
         assert!(
             find_subslice(&buf, MOUNT_MAGIC).is_none(),
@@ -1723,6 +5268,219 @@ mod tests {
         );
     }
 
+    #[test]
+    fn initdata_carries_timezone_and_locale_env() {
+        let env = guest_env(Some("Europe/Berlin"), Some("de_DE.UTF-8")).unwrap();
+        assert_eq!(
+            env,
+            ["TZ=Europe/Berlin", "LANG=de_DE.UTF-8", "LC_ALL=de_DE.UTF-8"]
+        );
+        let header = BootHeader {
+            env: &env,
+            ..BootHeader::default()
+        };
+        assert!(!header.is_empty());
+        let buf = build_cmdline_initdata(&header, 0).expect("initdata");
+        let off = find_subslice(&buf, ENV_MAGIC).expect("env magic missing");
+        // After HLWALL0, so guests that don't know the block never see it.
+        assert!(off > find_subslice(&buf, WALLTIME_MAGIC).unwrap());
+        let mut p = off + ENV_MAGIC.len();
+        assert_eq!(u32::from_le_bytes(buf[p..p + 4].try_into().unwrap()), 3);
+        p += 4;
+        for expected in &env {
+            let len = u32::from_le_bytes(buf[p..p + 4].try_into().unwrap()) as usize;
+            assert_eq!(&buf[p + 4..p + 4 + len], expected.as_bytes());
+            assert_eq!(buf[p + 4 + len], 0);
+            p += 4 + len + 1;
+        }
+        assert!(buf[p..].iter().all(|&b| b == 0), "padding follows");
+        let (info, _) = parse_extended_initrd(&header_page(&header)).unwrap();
+        assert_eq!(info.env, env);
+
+        assert!(guest_env(None, None).unwrap().is_empty());
+        assert!(guest_env(Some("Europe/Ber lin"), None).is_err());
+        assert!(guest_env(None, Some("")).is_err());
+    }
+
+    #[test]
+    fn extended_initrd_borrows_rootfs_behind_header_page() {
+        let rootfs = vec![7u8; 3 * PAGE_SIZE + 5];
+        let args = ["/app".to_string()];
+        let header = BootHeader {
+            app_args: &args,
+            ..BootHeader::default()
+        };
+        let ext = prepend_boot_header(Some(&rootfs), &header).unwrap();
+        assert!(std::ptr::eq(ext.body().unwrap(), rootfs.as_slice()));
+        assert_eq!(ext.header().len(), PAGE_SIZE);
+        assert_eq!(ext.segments().count(), 2);
+        assert_eq!(ext.len(), PAGE_SIZE + rootfs.len());
+
+        let joined = ext.to_vec();
+        assert_eq!(&joined[PAGE_SIZE..], rootfs.as_slice());
+        let mut written = Vec::new();
+        ext.write_to(&mut written).unwrap();
+        assert_eq!(written, joined);
+
+        // Booting maps the rootfs, so the init data is just the header
+        // page with the rootfs size in its footer.
+        let init = ext.mapped_initdata();
+        assert_eq!(&init[..PAGE_SIZE - 8], &ext.header()[..PAGE_SIZE - 8]);
+        let footer = u64::from_le_bytes(init[PAGE_SIZE - 8..].try_into().unwrap());
+        assert_eq!(footer, rootfs.len() as u64);
+
+        // Nothing to announce: the rootfs passes through untouched.
+        let bare = prepend_boot_header(Some(&rootfs), &BootHeader::default()).unwrap();
+        assert!(bare.header().is_empty());
+        assert_eq!(bare.to_vec(), rootfs);
+        assert!(prepend_boot_header(None, &BootHeader::default()).is_none());
+    }
+
+    #[test]
+    fn extended_initrd_header_parses_back() {
+        let root = tmpdir("parse-header");
+        let preopens = [Preopen::new(&root, "/data").unwrap()];
+        let placed = place_regions(
+            vec![HostRegion::bytes("weights", vec![1u8; 10])],
+            INITRD_MAP_BASE,
+            false,
+        )
+        .unwrap();
+        let env = guest_env(Some("UTC0"), None).unwrap();
+        let wall = std::time::UNIX_EPOCH + Duration::from_nanos(1_700_000_000_123_456_789);
+        let args = ["/app.py".to_string(), "-v".to_string()];
+        let header = BootHeader {
+            app_args: &args,
+            preopens: &preopens,
+            regions: &placed,
+            env: &env,
+            wall_clock: Some(wall),
+        };
+        let rootfs = b"070701rootfs".to_vec();
+        let joined = prepend_boot_header(Some(&rootfs), &header)
+            .unwrap()
+            .to_vec();
+
+        let (info, rest) = parse_extended_initrd(&joined).unwrap();
+        assert_eq!(rest, rootfs.as_slice());
+        assert_eq!(info.cmdline, "/app.py -v");
+        assert_eq!(info.mounts, ["/data"]);
+        assert_eq!(
+            info.regions,
+            [BootRegion {
+                base: placed[0].base,
+                size: 10,
+                name: "weights".into()
+            }]
+        );
+        assert_eq!(info.env, ["TZ=UTC0"]);
+        assert_eq!(info.wall_clock, Some(wall));
+
+        // No header: the rootfs passes through.
+        let (info, rest) = parse_extended_initrd(&rootfs).unwrap();
+        assert_eq!((info, rest), (BootInfo::default(), rootfs.as_slice()));
+        // Damaged headers are errors, not panics.
+        assert!(parse_extended_initrd(&joined[..20]).is_err());
+        let mut bad = joined.clone();
+        bad[8] = 0xff;
+        assert!(parse_extended_initrd(&bad).is_err());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn mapped_initrd_boots_from_its_file_unless_replaced() {
+        let dir = tmpdir("mapped-initrd");
+        let path = dir.join("rootfs.cpio");
+        std::fs::write(&path, vec![3u8; 10_000]).unwrap();
+        let map = rootfs::MappedInitrd::open(&path).unwrap();
+        let initrd = InitrdRef::from(&map);
+        let ext = prepend_boot_header(Some(initrd.data), &BootHeader::default())
+            .unwrap()
+            .backed_by(initrd.file);
+        let mapping = map_initrd_body(&ext, None, false).unwrap().unwrap();
+        assert_eq!(mapping.path, path);
+        assert_eq!((mapping.base, mapping.size), (INITRD_MAP_BASE, 10_000));
+        assert!(mapping._spill.is_none());
+
+        // A buffer without a file, or whose file changed size, is spilled.
+        let bytes = [5u8; 100];
+        let spilled = prepend_boot_header(Some(&bytes), &BootHeader::default()).unwrap();
+        let mapping = map_initrd_body(&spilled, None, false).unwrap().unwrap();
+        assert!(mapping._spill.is_some());
+        assert_eq!(std::fs::read(&mapping.path).unwrap(), bytes);
+        let stale = spilled.backed_by(Some(&path));
+        assert_ne!(
+            map_initrd_body(&stale, None, false).unwrap().unwrap().path,
+            path
+        );
+
+        // Scrubbed spills stay out of the shared cache.
+        let cache = initrd_cache::InitrdCache::new();
+        let scrubbed = map_initrd_body(&stale, Some(&cache), true)
+            .unwrap()
+            .unwrap();
+        assert!(cache.is_empty());
+        assert_eq!(std::fs::read(&scrubbed.path).unwrap(), bytes);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn host_regions_are_placed_page_aligned_and_announced() {
+        let placed = place_regions(
+            vec![
+                HostRegion::bytes("weights", vec![1u8; 5000]),
+                HostRegion::bytes("vocab", vec![2u8; 10]),
+            ],
+            INITRD_MAP_BASE + 100,
+            false,
+        )
+        .unwrap();
+        assert_eq!(placed[0].base, INITRD_MAP_BASE + 4096);
+        assert_eq!(placed[0].size, 5000);
+        assert_eq!(placed[1].base, INITRD_MAP_BASE + 3 * 4096);
+
+        let header = BootHeader {
+            regions: &placed,
+            ..BootHeader::default()
+        };
+        let buf = build_cmdline_initdata(&header, 0).expect("initdata");
+        let off = find_subslice(&buf, REGION_MAGIC).expect("region magic missing");
+        // After HLWALL0, where guests that predate regions stop reading.
+        assert!(off > find_subslice(&buf, WALLTIME_MAGIC).unwrap());
+        let p = off + REGION_MAGIC.len();
+        assert_eq!(u32::from_le_bytes(buf[p..p + 4].try_into().unwrap()), 2);
+        let base = u64::from_le_bytes(buf[p + 4..p + 12].try_into().unwrap());
+        let size = u64::from_le_bytes(buf[p + 12..p + 20].try_into().unwrap());
+        let name_len = u32::from_le_bytes(buf[p + 20..p + 24].try_into().unwrap()) as usize;
+        assert_eq!((base, size), (placed[0].base, 5000));
+        assert_eq!(&buf[p + 24..p + 24 + name_len], b"weights");
+
+        let spill = placed[0].path.clone();
+        assert!(spill.exists());
+        drop(placed);
+        assert!(!spill.exists(), "spilled buffer should be removed on drop");
+    }
+
+    #[test]
+    fn host_regions_reject_duplicates_and_overflow() {
+        let dup = place_regions(
+            vec![
+                HostRegion::bytes("a", vec![0]),
+                HostRegion::bytes("a", vec![0]),
+            ],
+            INITRD_MAP_BASE,
+            false,
+        );
+        assert!(dup.unwrap_err().to_string().contains("twice"));
+
+        let overflow = place_regions(
+            vec![HostRegion::bytes("a", vec![0; 8192])],
+            MAP_LIMIT - 4096,
+            false,
+        );
+        assert!(overflow.unwrap_err().to_string().contains("4 GiB"));
+    }
+
     #[test]
     fn fs_write_then_read_roundtrip() {
         let root = tmpdir("roundtrip");
@@ -1739,4 +5497,92 @@ mod tests {
         let s = std::str::from_utf8(&resp).unwrap();
         assert!(s.contains("\"text\":\"hi\""), "{s}");
     }
+
+    #[test]
+    fn pre_run_hook_sees_prepared_initrd_and_can_abort() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        let post_saw_error = Arc::new(AtomicBool::new(false));
+        let flag = post_saw_error.clone();
+        let config = VmConfig::default()
+            .with_pre_run_hook(|assets| {
+                let initrd = assets.initrd.expect("cmdline header present");
+                assert!(initrd.header().starts_with(CMDLINE_MAGIC));
+                Err(anyhow!("rejected by policy"))
+            })
+            .with_post_run_hook(move |result| {
+                flag.store(result.is_err(), Ordering::SeqCst);
+            });
+        let err = run_vm(
+            Path::new("/nonexistent/kernel"),
+            None,
+            &["/app.py".to_string()],
+            config,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("rejected by policy"), "{err}");
+        assert!(post_saw_error.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn sandbox_builder_runs_hooks() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        let post_saw_error = Arc::new(AtomicBool::new(false));
+        let flag = post_saw_error.clone();
+        let err = Sandbox::builder("/nonexistent/kernel")
+            .initrd_bytes(b"rootfs".to_vec())
+            .arg("/app.py")
+            .pre_run_hook(|assets| {
+                let initrd = assets.initrd.expect("cmdline header present");
+                assert_eq!(initrd.body(), Some(&b"rootfs"[..]));
+                assert_eq!(assets.app_args, ["/app.py"]);
+                Err(anyhow!("rejected by policy"))
+            })
+            .post_run_hook(move |result| {
+                flag.store(result.is_err(), Ordering::SeqCst);
+            })
+            .build()
+            .err()
+            .expect("the pre-run hook aborts the build");
+        assert!(err.to_string().contains("rejected by policy"), "{err}");
+        assert!(post_saw_error.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn env_overrides_replace_only_set_values() {
+        let env = |key: &str| match key {
+            ENV_MEMORY => Some("1Gi".to_string()),
+            ENV_TIMEOUT => Some("1.5s".to_string()),
+            _ => None,
+        };
+        let cfg = VmConfig::default()
+            .with_stack_size(4096)
+            .apply_env_from(env)
+            .unwrap();
+        assert_eq!(cfg.heap_size, 1024 * 1024 * 1024);
+        assert_eq!(cfg.stack_size, 4096);
+        assert_eq!(cfg.timeout, Some(Duration::from_millis(1500)));
+    }
+
+    #[test]
+    fn env_override_errors_name_the_variable() {
+        let env = |key: &str| (key == ENV_STACK).then(|| "lots".to_string());
+        let Err(err) = VmConfig::default().apply_env_from(env) else {
+            panic!("bad stack size should be rejected");
+        };
+        assert!(err.to_string().contains(ENV_STACK), "{err}");
+    }
+
+    #[test]
+    fn kernel_prefetch_overlaps_preparation() {
+        let ws = workspace::Workspace::new().unwrap();
+        let kernel = ws.write("kernel", b"\x7fELF").unwrap();
+        let (image, prepared) = with_kernel_prefetch(&kernel, || 42);
+        assert_eq!(
+            (image.as_ref().map(|i| &i[..]), prepared),
+            (Some(&b"\x7fELF"[..]), 42)
+        );
+
+        let (missing, ()) = with_kernel_prefetch(&ws.path().join("nope"), || ());
+        assert!(missing.is_none());
+    }
 }
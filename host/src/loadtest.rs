@@ -0,0 +1,193 @@
+//! Sustained-load generator behind `hyperlight-unikraft loadtest`.
+//!
+//! Requests are issued open-loop on a fixed schedule (`rps` per second)
+//! by up to `concurrency` workers, one fresh sandbox per request. Latency
+//! is measured from each request's *scheduled* start, so a backlog shows
+//! up in the percentiles instead of silently lowering the offered load.
+
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+
+use crate::metrics::Histogram;
+use crate::{sweep, VmExit};
+
+/// How much load to offer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LoadSpec {
+    /// Requests started per second.
+    pub rps: f64,
+    /// How long to keep starting requests. In-flight ones are waited for.
+    pub duration: Duration,
+    /// Maximum requests in flight.
+    pub concurrency: usize,
+}
+
+impl LoadSpec {
+    pub fn validate(&self) -> Result<()> {
+        if !(self.rps.is_finite() && self.rps > 0.0) {
+            return Err(anyhow!("--rps must be a positive number, got {}", self.rps));
+        }
+        if self.duration.is_zero() {
+            return Err(anyhow!("--duration must be longer than zero"));
+        }
+        if self.concurrency == 0 {
+            return Err(anyhow!("--concurrency must be at least 1"));
+        }
+        Ok(())
+    }
+}
+
+/// What a load test achieved.
+#[derive(Debug)]
+pub struct LoadReport {
+    pub spec: LoadSpec,
+    /// Requests started.
+    pub sent: u64,
+    pub succeeded: u64,
+    /// Failures by exit kind (`abort`, `out_of_memory`, ...).
+    pub errors: BTreeMap<&'static str, u64>,
+    /// From the first scheduled start to the last completion.
+    pub elapsed: Duration,
+    /// Latency of every request, successful or not.
+    pub latency: Histogram,
+}
+
+impl LoadReport {
+    /// Completed requests per second.
+    pub fn throughput(&self) -> f64 {
+        self.sent as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+
+    pub fn error_rate(&self) -> f64 {
+        if self.sent == 0 {
+            return 0.0;
+        }
+        (self.sent - self.succeeded) as f64 / self.sent as f64
+    }
+}
+
+impl std::fmt::Display for LoadReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let ms = |d: Option<Duration>| d.map_or(0.0, |d| d.as_secs_f64() * 1000.0);
+        writeln!(
+            f,
+            "requests  {} sent, {} ok, {:.2}% errors",
+            self.sent,
+            self.succeeded,
+            self.error_rate() * 100.0
+        )?;
+        writeln!(
+            f,
+            "rate      {:.2}/s achieved of {:.2}/s offered over {:.1}s",
+            self.throughput(),
+            self.spec.rps,
+            self.elapsed.as_secs_f64()
+        )?;
+        writeln!(
+            f,
+            "latency   p50={:.1}ms p90={:.1}ms p99={:.1}ms max={:.1}ms",
+            ms(self.latency.percentile(0.5)),
+            ms(self.latency.percentile(0.9)),
+            ms(self.latency.percentile(0.99)),
+            ms(self.latency.max())
+        )?;
+        for (kind, n) in &self.errors {
+            writeln!(f, "error     {kind}: {n}")?;
+        }
+        Ok(())
+    }
+}
+
+fn exit_kind(exit: &VmExit) -> &'static str {
+    match exit {
+        VmExit::Halt => "halt",
+        VmExit::Abort { .. } => "abort",
+        VmExit::StackOverflow => "stack_overflow",
+        VmExit::OutOfMemory => "out_of_memory",
+        VmExit::Interrupted => "interrupted",
+        VmExit::UnexpectedVmExit(_) => "unexpected_vm_exit",
+    }
+}
+
+/// Offer `spec`'s load, calling `request` once per scheduled request on
+/// up to `concurrency` threads, the same bounded workers a sweep uses.
+pub fn run<F>(spec: &LoadSpec, request: F) -> Result<LoadReport>
+where
+    F: Fn() -> Result<()> + Sync,
+{
+    spec.validate()?;
+    let total = (spec.rps * spec.duration.as_secs_f64()).ceil().max(1.0) as usize;
+    let latency = Histogram::new();
+    let start = Instant::now();
+
+    let exits = sweep::map_bounded(total, spec.concurrency, |i| {
+        let scheduled = start + Duration::from_secs_f64(i as f64 / spec.rps);
+        if let Some(wait) = scheduled.checked_duration_since(Instant::now()) {
+            std::thread::sleep(wait);
+        }
+        let result = request();
+        latency.record(scheduled.elapsed());
+        VmExit::from_result(&result)
+    });
+    let elapsed = start.elapsed();
+
+    let mut succeeded = 0;
+    let mut errors = BTreeMap::new();
+    for exit in &exits {
+        match exit {
+            VmExit::Halt => succeeded += 1,
+            exit => *errors.entry(exit_kind(exit)).or_insert(0) += 1,
+        }
+    }
+    Ok(LoadReport {
+        spec: *spec,
+        sent: total as u64,
+        succeeded,
+        errors,
+        elapsed,
+        latency,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    #[test]
+    fn offers_the_scheduled_requests_and_counts_failures() {
+        let calls = AtomicU64::new(0);
+        let spec = LoadSpec {
+            rps: 200.0,
+            duration: Duration::from_millis(50),
+            concurrency: 4,
+        };
+        let report = run(&spec, || {
+            if calls.fetch_add(1, Ordering::Relaxed).is_multiple_of(5) {
+                Err(anyhow!("boom"))
+            } else {
+                Ok(())
+            }
+        })
+        .unwrap();
+        assert_eq!(report.sent, 10);
+        assert_eq!(report.succeeded, 8);
+        assert_eq!(report.errors.get("unexpected_vm_exit"), Some(&2));
+        assert_eq!(report.latency.count(), 10);
+        assert!((report.error_rate() - 0.2).abs() < 1e-9);
+        // Open-loop: the last request can't start before its slot.
+        assert!(report.elapsed >= Duration::from_millis(45));
+    }
+
+    #[test]
+    fn rejects_nonsense_specs() {
+        let spec = LoadSpec {
+            rps: 0.0,
+            duration: Duration::from_secs(1),
+            concurrency: 1,
+        };
+        assert!(run(&spec, || Ok(())).is_err());
+    }
+}
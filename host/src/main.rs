@@ -4,40 +4,381 @@
 //!
 //! ```bash
 //! hyperlight-unikraft <kernel> [--initrd <cpio>] [--memory <size>] [-- <app-args>]
+//! hyperlight-unikraft run ./my-app/ [run options] [-- <app-args>]
+//! hyperlight-unikraft record -o <bundle> <kernel> [run options] [-- <app-args>]
+//! hyperlight-unikraft replay <bundle>
+//! hyperlight-unikraft profile [-o out.folded] [--perf] <kernel> [run options]
+//! hyperlight-unikraft trace-boot <kernel> [run options]
+//! hyperlight-unikraft build [<project-dir>] [--kernel-only|--rootfs-only]
+//! hyperlight-unikraft convert <dir|tarball> -o rootfs.cpio [--compress zstd]
+//! hyperlight-unikraft warm-rootfs <kernel> --initrd python.cpio -o warm.cpio [--script warm.py]
+//! hyperlight-unikraft up [-f hyperlight-compose.toml]
+//! hyperlight-unikraft down [-f hyperlight-compose.toml]
+//! hyperlight-unikraft loadtest --rps 20 --duration 60s <kernel> [run options]
+//! hyperlight-unikraft history [--since 1h] [--failed]
+//! hyperlight-unikraft ps
+//! hyperlight-unikraft logs [-f] <run-id>
+//! hyperlight-unikraft stop [--grace 5s] <run-id>
 //! ```
+//!
+//! In place of a kernel, any command that runs one takes a project
+//! directory with a Kraftfile: its kernel and rootfs are built if missing,
+//! and its `cmd` and `memory` apply unless given on the command line.
+//!
+//! The kernel, initrd, memory, stack and timeout can also come from
+//! `HYPERLIGHT_UNIKRAFT_{KERNEL,INITRD,MEMORY,STACK,TIMEOUT}`, and with
+//! `--features serde` the run settings from a `--config` JSON or TOML
+//! file. Flags win over the environment, which wins over the file, which
+//! wins over the built-in defaults.
+//!
+//! `--quiet` hides the host's status messages; `--quiet=kernel` also hides
+//! the Unikraft banner and kernel log lines so only the application's own
+//! output shows, and `--quiet=all` drops the timing summary too.
+//!
+//! `--jsonl` replaces all of that with a JSON Lines event stream on
+//! stdout (`start`, one `output` record per guest line, `exit`); see
+//! [`hyperlight_unikraft::sink::JsonlSink`].
+//!
+//! `--track` registers a run (with optional `--label KEY=VALUE` tags) in
+//! the run registry, so `ps` in another terminal lists it while it runs
+//! and for a day after, `logs` prints its console, and `stop` ends it;
+//! see [`hyperlight_unikraft::runs`].
+//!
+//! `--console` attaches the terminal for REPLs and shells: raw-mode
+//! keystrokes go to the guest's `stdin_read` tool as they are typed, the
+//! console goes to stdout, and Ctrl-] detaches.
+//!
+//! Status lines are colored when stderr is a terminal (`--color auto`, the
+//! default, which also honors `NO_COLOR`); `--color always|never` forces it.
 
-use anyhow::Result;
-use clap::Parser;
-use hyperlight_unikraft::{parse_memory, Preopen, Sandbox};
+use anyhow::{anyhow, Context, Result};
+use clap::{Args, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use hyperlight_unikraft::compose::{self, Compose};
+#[cfg(feature = "sqlite")]
+use hyperlight_unikraft::history::{self, HistoryEntry, HistoryFilter, RunHistory};
+use hyperlight_unikraft::hostcall::{CallStats, CallSummary, CallTrace};
+use hyperlight_unikraft::kraft::{self, KraftProject};
+use hyperlight_unikraft::kv::KvStore;
+use hyperlight_unikraft::loadtest::{self, LoadSpec};
+use hyperlight_unikraft::output::{boot_timeline, classify_line, LineKind};
+use hyperlight_unikraft::placement::Placer;
+use hyperlight_unikraft::profile;
+use hyperlight_unikraft::progress::Spinner;
+use hyperlight_unikraft::reload::Reloader;
+use hyperlight_unikraft::replay::ReplayBundle;
+use hyperlight_unikraft::rootfs::{self, Compression};
+use hyperlight_unikraft::runs::{RegisteredRun, RunRecord, RunRegistry, RunState};
+use hyperlight_unikraft::runs_server;
+use hyperlight_unikraft::sink::{JsonlSink, OutputSink, RunInfo};
+use hyperlight_unikraft::stderr_capture::PipeCapture;
+use hyperlight_unikraft::warm;
+use hyperlight_unikraft::workspace::Workspace;
+#[cfg(feature = "encrypted-initrd")]
+use hyperlight_unikraft::{encryption, pipeline};
+use hyperlight_unikraft::{
+    format_memory, new_run_id, parse_duration, parse_memory, Hypervisor, Preopen, Sandbox,
+    SandboxBuilder, VmConfig, VmExit, ENV_INITRD, ENV_KERNEL, ENV_MEMORY,
+};
+use std::io::IsTerminal;
 use std::path::PathBuf;
+use std::sync::Arc;
 
 #[derive(Parser, Debug)]
 #[command(
     name = "hyperlight-unikraft",
     version,
-    about = "Run Unikraft unikernels on Hyperlight"
+    about = "Run Unikraft unikernels on Hyperlight",
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
 )]
-struct Args {
-    /// Path to the Unikraft kernel binary
-    kernel: PathBuf,
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
 
-    /// Path to initrd/rootfs CPIO archive
+    /// Print lifecycle events and guest output as JSON Lines on stdout
+    /// instead of streaming the console
     #[arg(long)]
+    jsonl: bool,
+
+    #[command(flatten)]
+    run: RunArgs,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run a kernel, or a project directory with a Kraftfile (the same as
+    /// giving no command)
+    Run {
+        #[command(flatten)]
+        run: RunArgs,
+    },
+    /// Run a kernel once and save a replay bundle: copies of the kernel
+    /// and initrd, the arguments, the injected wall clock and the output
+    Record {
+        /// Bundle directory to write
+        #[arg(long, short = 'o', value_name = "DIR")]
+        output: PathBuf,
+
+        #[command(flatten)]
+        run: RunArgs,
+    },
+    /// Re-run a replay bundle and check its output matches the recording
+    Replay {
+        /// Bundle directory written by `record`
+        bundle: PathBuf,
+
+        /// Don't echo the guest's output while replaying
+        #[arg(long, short = 'q')]
+        quiet: bool,
+    },
+    /// Run a kernel and write a folded-stacks profile (for flamegraph.pl or
+    /// inferno-flamegraph) attributing host time to setup, guest execution,
+    /// VM exits by reason and output handling
+    Profile {
+        /// Folded-stacks file to write
+        #[arg(
+            long,
+            short = 'o',
+            default_value = "profile.folded",
+            value_name = "FILE"
+        )]
+        output: PathBuf,
+
+        /// Sample real call stacks with `perf record` (Linux) instead of
+        /// the built-in phase timer. Weights become sample counts rather
+        /// than microseconds.
+        #[arg(long)]
+        perf: bool,
+
+        #[command(flatten)]
+        run: RunArgs,
+    },
+    /// Run a kernel once and print where boot time goes, stage by stage
+    TraceBoot {
+        #[command(flatten)]
+        run: RunArgs,
+    },
+    /// Build a Unikraft project's Hyperlight kernel (with kraft) and rootfs
+    /// (from its Kraftfile `rootfs` or Dockerfile)
+    Build {
+        /// Directory holding the Kraftfile
+        #[arg(default_value = ".")]
+        project: PathBuf,
+
+        /// kraft binary with the hyperlight platform
+        #[arg(long, env = kraft::ENV_KRAFT, default_value = "kraft-hyperlight")]
+        kraft: PathBuf,
+
+        /// Docker-compatible CLI for Dockerfile rootfs builds
+        #[arg(long, default_value = "docker")]
+        docker: PathBuf,
+
+        /// Build only the kernel
+        #[arg(long, conflicts_with = "rootfs_only")]
+        kernel_only: bool,
+
+        /// Build only the rootfs
+        #[arg(long)]
+        rootfs_only: bool,
+    },
+    /// Build an initrd CPIO from a directory or a tarball (plain, .gz or
+    /// .zst, e.g. from `docker export`)
+    Convert {
+        /// Root directory or tarball to pack
+        source: PathBuf,
+
+        /// CPIO archive to write
+        #[arg(long, short = 'o', value_name = "FILE")]
+        output: PathBuf,
+
+        /// Compress the archive; the host decompresses it when booting
+        #[arg(long, value_name = "FORMAT")]
+        compress: Option<CompressFormat>,
+
+        /// Encrypt the archive (AES-256-GCM) with the key from
+        /// `env:VAR` or `file:PATH`; boot it with `--initrd-key`
+        #[cfg(feature = "encrypted-initrd")]
+        #[arg(long, value_name = "SOURCE")]
+        encrypt_key: Option<String>,
+
+        /// Key id to record in the encrypted archive
+        #[cfg(feature = "encrypted-initrd")]
+        #[arg(long, requires = "encrypt_key", value_name = "ID")]
+        key_id: Option<String>,
+    },
+    /// Boot a Python rootfs once to precompile its modules (and run an
+    /// optional warmup script), and write the rootfs with the results
+    WarmRootfs {
+        /// Python interpreter kernel, built with lib/hostfs
+        #[arg(env = ENV_KERNEL)]
+        kernel: PathBuf,
+
+        /// Rootfs to warm (plain, gzip or zstd CPIO)
+        #[arg(long, env = ENV_INITRD)]
+        initrd: PathBuf,
+
+        /// Warmed CPIO archive to write
+        #[arg(long, short = 'o', value_name = "FILE")]
+        output: PathBuf,
+
+        /// Python script to run after compiling; files it writes under
+        /// `$WARM_OUT/<guest path>` are added to the rootfs as well
+        #[arg(long, value_name = "FILE")]
+        script: Option<PathBuf>,
+
+        /// Memory for the warmup boot (e.g., 512Mi, 1Gi)
+        #[arg(long, short = 'm', default_value = "512Mi", env = ENV_MEMORY)]
+        memory: String,
+
+        /// Compress the output archive
+        #[arg(long, value_name = "FORMAT")]
+        compress: Option<CompressFormat>,
+    },
+    /// Boot every VM in a compose file together and wait for them
+    Up {
+        /// Compose file
+        #[arg(long, short = 'f', default_value = compose::COMPOSE_FILE, value_name = "FILE")]
+        file: PathBuf,
+
+        /// How long `down` (or Ctrl-C) gives the VMs to exit before
+        /// interrupting them
+        #[arg(long, default_value = "5s", value_name = "DURATION")]
+        grace: String,
+    },
+    /// Stop the VMs a running `up` started
+    Down {
+        /// Compose file given to `up`
+        #[arg(long, short = 'f', default_value = compose::COMPOSE_FILE, value_name = "FILE")]
+        file: PathBuf,
+    },
+    /// Boot a fresh sandbox per request at a fixed rate and report
+    /// throughput, latency percentiles and errors
+    Loadtest {
+        /// Requests started per second
+        #[arg(long, default_value = "10")]
+        rps: f64,
+
+        /// How long to generate load (e.g. 30s, 5m)
+        #[arg(long, default_value = "60s", value_name = "DURATION")]
+        duration: String,
+
+        /// Maximum requests in flight
+        #[arg(long, default_value = "8")]
+        concurrency: usize,
+
+        #[command(flatten)]
+        run: RunArgs,
+    },
+    /// List `--track`ed runs: in flight, and finished within the last day
+    Ps,
+    /// Print a `--track`ed run's console so far
+    Logs {
+        /// Run ID from `ps`, or a unique prefix of one
+        run_id: String,
+
+        /// Keep printing the run's output until it ends
+        #[arg(long, short = 'f')]
+        follow: bool,
+    },
+    /// Stop a `--track`ed run: ask its guest to exit, interrupt it after
+    /// the grace period, and print the output it produced
+    Stop {
+        /// Run ID from `ps`, or a unique prefix of one
+        run_id: String,
+
+        /// How long the guest gets to exit before it is interrupted
+        #[arg(long, default_value = "5s", value_name = "DURATION")]
+        grace: String,
+    },
+    /// Serve the tracked runs over HTTP: `GET /runs`,
+    /// `GET /runs/<id>/logs[?follow=1]` and `DELETE /runs/<id>[?grace=5s]`
+    Serve {
+        /// Address to listen on. Anyone who can connect can stop runs.
+        #[arg(long, default_value = "127.0.0.1:7070", value_name = "ADDR")]
+        listen: String,
+    },
+    /// List past runs from the run history, newest first
+    #[cfg(feature = "sqlite")]
+    History {
+        /// Run history database
+        #[arg(long, env = history::ENV_HISTORY, value_name = "FILE")]
+        db: PathBuf,
+
+        /// Only runs started within this long (e.g. 30m, 1h)
+        #[arg(long, value_name = "DURATION")]
+        since: Option<String>,
+
+        /// Only runs that didn't halt normally
+        #[arg(long)]
+        failed: bool,
+
+        /// Show at most N runs
+        #[arg(long, default_value = "50", value_name = "N")]
+        limit: usize,
+    },
+}
+
+#[derive(Args, Debug)]
+struct RunArgs {
+    /// Path to the Unikraft kernel binary, or a project directory with a
+    /// Kraftfile
+    #[arg(env = ENV_KERNEL, required = true)]
+    kernel: Option<PathBuf>,
+
+    /// Path to initrd/rootfs CPIO archive
+    #[arg(long, env = ENV_INITRD)]
     initrd: Option<PathBuf>,
 
-    /// Memory allocation (e.g., 256Mi, 512Mi, 1Gi)
-    #[arg(long, short = 'm', default_value = "512Mi")]
-    memory: String,
+    /// Decrypt an encrypted `--initrd` in memory with the key from
+    /// `env:VAR` or `file:PATH` (see `convert --encrypt-key`)
+    #[cfg(feature = "encrypted-initrd")]
+    #[arg(long, requires = "initrd", value_name = "SOURCE")]
+    initrd_key: Option<String>,
 
-    /// Stack size (e.g., 8Mi)
-    #[arg(long, default_value = "8Mi")]
-    stack: String,
+    /// Memory allocation (e.g., 256Mi, 1.5Gi, 512MiB, or 25% of host RAM,
+    /// capped by the cgroup limit) [default: 512Mi, or $HYPERLIGHT_UNIKRAFT_MEMORY]
+    #[arg(long, short = 'm')]
+    memory: Option<String>,
 
-    /// Quiet mode — suppress host-side status messages
-    #[arg(long, short = 'q')]
-    quiet: bool,
+    /// Stack size (e.g., 8Mi) [default: 8Mi, or $HYPERLIGHT_UNIKRAFT_STACK]
+    #[arg(long)]
+    stack: Option<String>,
 
-    /// Enable tool dispatch via __dispatch host function
+    /// Interrupt the application after this long (e.g., 30s, 5m); boot
+    /// doesn't count [default: none, or $HYPERLIGHT_UNIKRAFT_TIMEOUT]
+    #[arg(long, value_name = "DURATION")]
+    timeout: Option<String>,
+
+    /// Read run settings (`heap_size`, `stack_size`, `timeout`, ...) from
+    /// a JSON or TOML file; the environment and flags override it
+    #[cfg(feature = "serde")]
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// The project's Kraftfile `memory`, below the environment and flags.
+    #[arg(skip)]
+    project_memory: Option<String>,
+
+    /// Quiet mode. `--quiet` suppresses host-side status messages;
+    /// `--quiet=kernel` also hides the Unikraft banner and kernel logs;
+    /// `--quiet=all` leaves nothing but the application's output.
+    #[arg(
+        long,
+        short = 'q',
+        value_enum,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "host",
+        value_name = "LEVEL"
+    )]
+    quiet: Option<Quiet>,
+
+    /// Color host status lines: auto (only on a terminal), always, never
+    #[arg(long, value_enum, default_value = "auto", value_name = "WHEN")]
+    color: ColorWhen,
+
+    /// Enable tool dispatch via __dispatch host function, with the
+    /// built-in `echo`, `getrandom` and `clock_realtime` tools
     #[arg(long)]
     enable_tools: bool,
 
@@ -57,11 +398,104 @@ struct Args {
     #[arg(long, value_name = "HOST[:GUEST]")]
     mount: Vec<String>,
 
+    /// Let the guest read this host file, or anything under this host
+    /// directory, by host path via the `host_open`/`host_read`/
+    /// `host_stat` tools. Read-only; repeatable. For large reference
+    /// data the guest should load lazily rather than from the initrd.
+    #[arg(long, value_name = "PATH")]
+    allow_read: Vec<PathBuf>,
+
+    /// Persist small key-value state across runs in this host directory,
+    /// exposed to the guest via the `kv_get`/`kv_put`/`kv_delete`/
+    /// `kv_list` tools. Runs sharing a directory and `--kv-namespace`
+    /// see each other's keys.
+    #[arg(long, value_name = "DIR")]
+    kv_store: Option<PathBuf>,
+
+    /// Namespace (e.g. the tenant) within `--kv-store`
+    #[arg(long, default_value = "default", requires = "kv_store")]
+    kv_namespace: String,
+
+    /// Copy application arguments that are relative paths to existing
+    /// host files into the rootfs under `/args/`, and pass the guest
+    /// paths instead: `-- ./script.py ./data.csv` runs without building
+    /// a new initrd
+    #[arg(long)]
+    inject_args: bool,
+
+    /// Timezone for the guest (`TZ`), e.g. `Europe/Berlin` or a POSIX
+    /// rule like `UTC0`. Named zones need tzdata in the rootfs.
+    #[arg(long, value_name = "ZONE")]
+    tz: Option<String>,
+
+    /// Locale for the guest (`LANG` and `LC_ALL`), e.g. `de_DE.UTF-8`
+    #[arg(long, value_name = "NAME")]
+    locale: Option<String>,
+
+    /// Log every host call the guest makes — tool, arguments, response,
+    /// start time and duration — to FILE, one strace-like line each
+    #[arg(long, value_name = "FILE")]
+    trace_calls: Option<PathBuf>,
+
+    /// After the run, print each host tool the guest called with its
+    /// call count, errors and latency, busiest first
+    #[arg(long)]
+    call_stats: bool,
+
+    /// Zero host copies of the rootfs and regions once they are mapped,
+    /// and overwrite spilled files before deleting them, so secrets
+    /// don't linger in freed host memory or the page cache. Guest RAM
+    /// is not touched
+    #[arg(long)]
+    scrub_inputs: bool,
+
+    /// Hypervisor to run on: auto, kvm or mshv. A backend this host
+    /// can't provide fails before boot, saying why
+    #[arg(long, default_value = "auto", value_name = "NAME")]
+    hypervisor: Hypervisor,
+
+    /// Append this run to a SQLite run history, queried with `history`
+    #[cfg(feature = "sqlite")]
+    #[arg(long, env = history::ENV_HISTORY, value_name = "FILE")]
+    history: Option<PathBuf>,
+
     /// Run the application N additional times via snapshot/restore + call.
     /// The first run always happens. --repeat=2 means 3 total runs.
     #[arg(long, default_value = "0")]
     repeat: u32,
 
+    /// After boot, write the booted VM's snapshot to FILE, for a later
+    /// `--from-snapshot` to start from
+    #[arg(long, value_name = "FILE")]
+    save_snapshot: Option<PathBuf>,
+
+    /// Start from a snapshot written by `--save-snapshot` instead of
+    /// booting the kernel. Only `--mount` host functions are served
+    #[arg(long, value_name = "FILE", conflicts_with = "watch")]
+    from_snapshot: Option<PathBuf>,
+
+    /// When a `--track`ed run is stopped (`stop`, SIGTERM or Ctrl-C) and
+    /// its guest returns within the grace period, save the guest to FILE
+    /// so `--from-snapshot FILE` resumes it. A guest interrupted at the
+    /// end of the grace period can't be saved
+    #[arg(long, value_name = "FILE", requires = "track")]
+    checkpoint: Option<PathBuf>,
+
+    /// Stage argument files as `--inject-args` does, then rerun whenever
+    /// one of them changes. `initrd` layers the new contents onto the
+    /// rootfs already in memory; `hostfs` serves them from a mounted
+    /// directory and reuses the booted VM, which needs lib/hostfs
+    #[arg(
+        long,
+        value_enum,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "initrd",
+        value_name = "MODE",
+        conflicts_with = "repeat"
+    )]
+    watch: Option<WatchMode>,
+
     /// Inline code snippet. The guest interpreter is invoked with
     /// `["-c", <code>]` — works for Python, `sh`, `node -e` style
     /// interpreters that treat `-c` as "run the next arg as code".
@@ -72,11 +506,99 @@ struct Args {
     #[arg(long, short = 'e', conflicts_with = "app_args", value_name = "CODE")]
     exec: Option<String>,
 
+    /// Register the run so `ps` (from any terminal) lists it, with its
+    /// console kept for the registry
+    #[arg(long)]
+    track: bool,
+
+    /// Tag a `--track`ed run, e.g. `--label tenant=acme`. Repeatable
+    #[arg(long, requires = "track", value_name = "KEY=VALUE", value_parser = parse_label)]
+    label: Vec<(String, String)>,
+
+    /// Attach this terminal to the guest: keystrokes go to its stdin
+    /// (the `stdin_read` tool) unbuffered, its console comes to stdout,
+    /// and Ctrl-] detaches, stopping the guest. For REPLs and shells
+    #[arg(long, conflicts_with_all = ["watch", "repeat", "from_snapshot"])]
+    console: bool,
+
     /// Application arguments (passed after --)
     #[arg(last = true)]
     app_args: Vec<String>,
 }
 
+/// How much `--quiet` hides. Each level includes the ones before it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum Quiet {
+    /// Host-side status messages.
+    Host,
+    /// Also the Unikraft banner and kernel log lines.
+    Kernel,
+    /// Also the run and timing summaries.
+    All,
+}
+
+/// Where `--watch` puts the files it reloads.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum WatchMode {
+    /// In the initrd, rebuilding only the layer holding them.
+    Initrd,
+    /// In a host directory mounted at `/args`.
+    Hostfs,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum CompressFormat {
+    Zstd,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum ColorWhen {
+    Auto,
+    Always,
+    Never,
+}
+
+/// ANSI styling for host status lines; a no-op when color is off.
+#[derive(Clone, Copy)]
+struct Paint {
+    enabled: bool,
+}
+
+impl Paint {
+    fn new(when: ColorWhen) -> Self {
+        let enabled = match when {
+            ColorWhen::Always => true,
+            ColorWhen::Never => false,
+            ColorWhen::Auto => {
+                std::io::stderr().is_terminal() && std::env::var_os("NO_COLOR").is_none()
+            }
+        };
+        Self { enabled }
+    }
+
+    fn sgr(self, code: &str, s: &str) -> String {
+        if self.enabled {
+            format!("\x1b[{code}m{s}\x1b[0m")
+        } else {
+            s.to_string()
+        }
+    }
+
+    fn bold(self, s: &str) -> String {
+        self.sgr("1", s)
+    }
+
+    /// Field labels in the startup banner.
+    fn label(self, s: &str) -> String {
+        self.sgr("36", s)
+    }
+
+    /// Phase tags such as `[run 1/3]` and `[timing]`.
+    fn phase(self, s: &str) -> String {
+        self.sgr("32", s)
+    }
+}
+
 /// Escape a string so that the guest-side `uk_argparse` tokenizer preserves
 /// it as a single argv entry, regardless of embedded whitespace or quotes.
 ///
@@ -100,73 +622,446 @@ fn argparse_escape(code: &str) -> String {
     out
 }
 
+impl RunArgs {
+    fn kernel(&self) -> &std::path::Path {
+        self.kernel.as_deref().expect("clap requires <KERNEL>")
+    }
+
+    /// If `<KERNEL>` is a project directory, build what it's missing and
+    /// run its kernel and rootfs. The manifest's `cmd` fills in for
+    /// arguments not given, and its `memory` for a memory set neither by
+    /// `--memory` nor the environment.
+    fn open_project(&mut self) -> Result<()> {
+        let dir = self.kernel();
+        if !dir.is_dir() {
+            return Ok(());
+        }
+        let project = KraftProject::open(dir)?;
+        let assets = project.resolve(&kraft::BuildOptions::default())?;
+        self.kernel = Some(assets.kernel);
+        if self.initrd.is_none() {
+            self.initrd = assets.initrd;
+        }
+        if self.app_args.is_empty() && self.exec.is_none() {
+            self.app_args = project.cmd().to_vec();
+        }
+        self.project_memory = project.memory().map(str::to_string);
+        Ok(())
+    }
+
+    /// The run's settings, each from the first of: its flag, its
+    /// `HYPERLIGHT_UNIKRAFT_*` variable, the Kraftfile (memory only),
+    /// `--config`, and the built-in default.
+    fn settings(&self) -> Result<VmConfig> {
+        let mut config = self.config_file()?;
+        if let Some(ref memory) = self.project_memory {
+            config = config.with_heap_size(parse_memory(memory)?);
+        }
+        let mut config = config.with_env_overrides()?;
+        if let Some(ref memory) = self.memory {
+            config = config.with_heap_size(parse_memory(memory)?);
+        }
+        if let Some(ref stack) = self.stack {
+            config = config.with_stack_size(parse_memory(stack)?);
+        }
+        if let Some(ref timeout) = self.timeout {
+            config = config.with_timeout(parse_duration(timeout)?);
+        }
+        Ok(config)
+    }
+
+    #[cfg(feature = "serde")]
+    fn config_file(&self) -> Result<VmConfig> {
+        self.config
+            .as_ref()
+            .map_or_else(|| Ok(VmConfig::default()), VmConfig::from_file)
+    }
+
+    #[cfg(not(feature = "serde"))]
+    fn config_file(&self) -> Result<VmConfig> {
+        Ok(VmConfig::default())
+    }
+
+    /// Parse `--mount` specs, rejecting duplicate guest paths before the
+    /// VM boots — two mounts on the same guest path would silently shadow
+    /// each other.
+    fn preopens(&self) -> Result<Vec<Preopen>> {
+        let preopens: Vec<Preopen> = self
+            .mount
+            .iter()
+            .map(|spec| Preopen::parse_cli(spec))
+            .collect::<Result<_>>()?;
+        for i in 0..preopens.len() {
+            for j in (i + 1)..preopens.len() {
+                if preopens[i].guest_path == preopens[j].guest_path {
+                    return Err(anyhow!(
+                        "duplicate --mount guest path: {:?}",
+                        preopens[i].guest_path
+                    ));
+                }
+            }
+        }
+        Ok(preopens)
+    }
+
+    /// Sandbox builder for these options. Zero-copy initrd via
+    /// map_file_cow. Preopened directories get the FsSandbox handlers
+    /// wired in and lib/hostfs in the guest mounts them at their
+    /// configured guest paths.
+    fn builder(&self, settings: &VmConfig, preopens: Vec<Preopen>) -> Result<SandboxBuilder> {
+        let staged = if self.inject_args {
+            rootfs::inject_arg_files(&self.app_args())?
+        } else {
+            rootfs::ArgFiles {
+                args: self.app_args(),
+                ..Default::default()
+            }
+        };
+        let builder = self.builder_with_args(staged.args.clone(), settings, preopens)?;
+        if staged.files.is_empty() {
+            return self.with_initrd(builder);
+        }
+        Ok(builder.initrd_bytes(staged.apply(&self.base_initrd()?)?))
+    }
+
+    /// `--initrd` as given: decrypted in memory when `--initrd-key` is
+    /// set, otherwise mapped from the file.
+    fn with_initrd(&self, builder: SandboxBuilder) -> Result<SandboxBuilder> {
+        Ok(if let Some(bytes) = self.decrypted_initrd()? {
+            builder.initrd_bytes(bytes)
+        } else if let Some(ref p) = self.initrd {
+            builder.initrd_file(p)
+        } else {
+            builder
+        })
+    }
+
+    /// `--initrd` read whole, decrypted and decompressed, for adding
+    /// files to. Empty without one.
+    fn base_initrd(&self) -> Result<Vec<u8>> {
+        if let Some(bytes) = self.decrypted_initrd()? {
+            return Ok(bytes);
+        }
+        let Some(ref p) = self.initrd else {
+            return Ok(Vec::new());
+        };
+        match rootfs::read_if_zstd(p)? {
+            Some(bytes) => Ok(bytes),
+            None => std::fs::read(p).with_context(|| format!("reading initrd {p:?}")),
+        }
+    }
+
+    /// [`builder`](Self::builder) for the guest argv `args`, minus the
+    /// initrd.
+    fn builder_with_args(
+        &self,
+        args: Vec<String>,
+        settings: &VmConfig,
+        preopens: Vec<Preopen>,
+    ) -> Result<SandboxBuilder> {
+        let mut builder = Sandbox::builder(self.kernel())
+            .args(args)
+            .heap_size(settings.heap_size)
+            .stack_size(settings.stack_size)
+            .hypervisor(self.hypervisor);
+        if let Some(limit) = settings.timeout {
+            builder = builder.timeout(limit);
+        }
+        if let Some(ref tz) = self.tz {
+            builder = builder.timezone(tz);
+        }
+        if let Some(ref locale) = self.locale {
+            builder = builder.locale(locale);
+        }
+        if self.scrub_inputs {
+            builder = builder.scrub_inputs();
+        }
+        for p in preopens {
+            builder = builder.preopen(p);
+        }
+        for p in &self.allow_read {
+            builder = builder.allow_read(p);
+        }
+        if let Some(ref dir) = self.kv_store {
+            builder = builder.kv(KvStore::open(dir)?.namespace(&self.kv_namespace)?);
+        }
+        if self.enable_tools {
+            builder = builder.tool("echo", Ok).getrandom().clock();
+        }
+        if let Some(ref path) = self.trace_calls {
+            builder = builder.observe_calls(Arc::new(CallTrace::create(path)?));
+        }
+        Ok(builder)
+    }
+
+    /// `--initrd` decrypted (and decompressed) in memory when
+    /// `--initrd-key` is set.
+    #[cfg(feature = "encrypted-initrd")]
+    fn decrypted_initrd(&self) -> Result<Option<Vec<u8>>> {
+        let (Some(spec), Some(p)) = (&self.initrd_key, &self.initrd) else {
+            return Ok(None);
+        };
+        let source = encryption::KeySource::parse(spec)?;
+        let sealed = std::fs::read(p).with_context(|| format!("reading initrd {p:?}"))?;
+        let plain = encryption::decrypt(&sealed, &source)
+            .with_context(|| format!("opening encrypted initrd {p:?}"))?;
+        Ok(Some(pipeline::decompress(plain)?))
+    }
+
+    #[cfg(not(feature = "encrypted-initrd"))]
+    fn decrypted_initrd(&self) -> Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+
+    /// The guest argv. `--exec CODE` is sugar for `-- -c <CODE>`, but with
+    /// the argparse escaping applied so the user doesn't have to think
+    /// about it.
+    fn app_args(&self) -> Vec<String> {
+        match self.exec {
+            Some(ref code) => vec!["-c".into(), argparse_escape(code)],
+            None => self.app_args.clone(),
+        }
+    }
+}
+
 fn main() -> Result<()> {
     let t0 = std::time::Instant::now();
-    let args = Args::parse();
+    let matches = Cli::command().get_matches();
+    let mut cli = Cli::from_arg_matches(&matches)?;
+    let run_args = match cli.command {
+        None => Some(&mut cli.run),
+        Some(ref mut command) => command.run_args_mut(),
+    };
+    if let Some(args) = run_args {
+        args.open_project()?;
+    }
+    match cli.command {
+        None => run_any(cli.run, cli.jsonl, t0),
+        Some(Command::Run { run }) => run_any(run, false, t0),
+        Some(Command::Record { output, run }) => record(&output, run),
+        Some(Command::Replay { bundle, quiet }) => replay(&bundle, quiet),
+        Some(Command::Profile { output, perf, run }) => profile(&output, perf, run),
+        Some(Command::TraceBoot { run }) => trace_boot(run),
+        Some(Command::Build {
+            project,
+            kraft,
+            docker,
+            kernel_only,
+            rootfs_only,
+        }) => build(
+            &project,
+            kraft::BuildOptions {
+                kraft,
+                docker,
+                kernel: !rootfs_only,
+                rootfs: !kernel_only,
+            },
+        ),
+        Some(Command::Convert {
+            source,
+            output,
+            compress,
+            #[cfg(feature = "encrypted-initrd")]
+            encrypt_key,
+            #[cfg(feature = "encrypted-initrd")]
+            key_id,
+        }) => {
+            #[cfg(feature = "encrypted-initrd")]
+            if let Some(spec) = encrypt_key {
+                let key_id = key_id.unwrap_or_default();
+                return convert_encrypted(&source, &output, compress, &spec, &key_id);
+            }
+            convert(&source, &output, compress)
+        }
+        Some(Command::WarmRootfs {
+            kernel,
+            initrd,
+            output,
+            script,
+            memory,
+            compress,
+        }) => warm_rootfs(
+            &kernel,
+            &initrd,
+            &output,
+            script.as_deref(),
+            &memory,
+            compress,
+        ),
+        Some(Command::Up { file, grace }) => up(&file, &grace),
+        Some(Command::Down { file }) => down(&file),
+        Some(Command::Loadtest {
+            rps,
+            duration,
+            concurrency,
+            run,
+        }) => loadtest(rps, &duration, concurrency, run),
+        #[cfg(feature = "sqlite")]
+        Some(Command::History {
+            db,
+            since,
+            failed,
+            limit,
+        }) => show_history(&db, since.as_deref(), failed, limit),
+        Some(Command::Ps) => ps(),
+        Some(Command::Stop { run_id, grace }) => stop(&run_id, &grace),
+        Some(Command::Logs { run_id, follow }) => {
+            RunRegistry::open_default()?.copy_log(&run_id, &mut std::io::stdout().lock(), follow)
+        }
+        Some(Command::Serve { listen }) => serve(&listen),
+    }
+}
+
+impl Command {
+    fn run_args_mut(&mut self) -> Option<&mut RunArgs> {
+        match self {
+            Command::Run { run }
+            | Command::Record { run, .. }
+            | Command::Profile { run, .. }
+            | Command::TraceBoot { run }
+            | Command::Loadtest { run, .. } => Some(run),
+            _ => None,
+        }
+    }
+}
+
+/// A plain run, as a JSON Lines stream or recorded to the run history
+/// when asked.
+fn run_any(args: RunArgs, jsonl: bool, t0: std::time::Instant) -> Result<()> {
+    if args.console {
+        if jsonl {
+            return Err(anyhow!("--console and --jsonl can't be used together"));
+        }
+        return run_console(args);
+    }
+    if let Some(mode) = args.watch {
+        return watch(args, mode);
+    }
+    if jsonl {
+        return run_jsonl(args);
+    }
+    #[cfg(feature = "sqlite")]
+    if args.history.is_some() {
+        return run_recorded(args, t0);
+    }
+    run(args, t0)
+}
+
+fn run(args: RunArgs, t0: std::time::Instant) -> Result<()> {
+    if !args.track {
+        return run_with(args, t0, None);
+    }
+    let record = RunRecord {
+        id: new_run_id(),
+        pid: std::process::id(),
+        kernel: args.kernel().to_path_buf(),
+        heap_size: args.settings()?.heap_size,
+        started: std::time::SystemTime::now(),
+        labels: args.label.clone(),
+    };
+    let registered = RunRegistry::open_default()?.register(&record)?;
+    if args.quiet.is_none() {
+        eprintln!("{} {}", Paint::new(args.color).label("Run ID:"), record.id);
+    }
+    let result = run_with(args, t0, Some(&registered));
+    registered.finish(&match result {
+        Ok(()) => "ok".to_string(),
+        Err(ref e) => format!("{e:#}"),
+    })?;
+    result
+}
 
-    let heap_size = parse_memory(&args.memory)?;
-    let stack_size = parse_memory(&args.stack)?;
+/// [`run`], with the console also appended to `tracked`'s log.
+fn run_with(args: RunArgs, t0: std::time::Instant, tracked: Option<&RegisteredRun>) -> Result<()> {
+    let settings = args.settings()?;
+    let quiet = |level: Quiet| args.quiet.is_some_and(|q| q >= level);
+    let paint = Paint::new(args.color);
+    // Before any VM thread exists, so they all inherit the blocked mask.
+    let stop_requests = tracked.map(|_| lifecycle::stop_requests()).transpose()?;
 
-    if !args.quiet {
-        eprintln!("hyperlight-unikraft v{}", env!("CARGO_PKG_VERSION"));
-        eprintln!("Kernel: {:?}", args.kernel);
+    if !quiet(Quiet::Host) {
+        eprintln!(
+            "{}",
+            paint.bold(&format!(
+                "hyperlight-unikraft v{}",
+                env!("CARGO_PKG_VERSION")
+            ))
+        );
+        eprintln!("{} {:?}", paint.label("Kernel:"), args.kernel());
         if let Some(ref p) = args.initrd {
-            eprintln!("Initrd: {:?}", p);
-        }
-        eprintln!("Memory: {heap_size} B, Stack: {stack_size} B");
-    }
-
-    let preopens: Vec<Preopen> = args
-        .mount
-        .iter()
-        .map(|spec| Preopen::parse_cli(spec))
-        .collect::<Result<_>>()?;
-
-    // Reject duplicate guest paths before the VM boots — two mounts
-    // on the same guest path would silently shadow each other.
-    for i in 0..preopens.len() {
-        for j in (i + 1)..preopens.len() {
-            if preopens[i].guest_path == preopens[j].guest_path {
-                return Err(anyhow::anyhow!(
-                    "duplicate --mount guest path: {:?}",
-                    preopens[i].guest_path
-                ));
-            }
+            eprintln!("{} {:?}", paint.label("Initrd:"), p);
         }
+        eprintln!(
+            "{} {}, {} {}",
+            paint.label("Memory:"),
+            format_memory(settings.heap_size),
+            paint.label("Stack:"),
+            format_memory(settings.stack_size)
+        );
     }
 
-    if !args.quiet {
+    let preopens = args.preopens()?;
+
+    if !quiet(Quiet::Host) {
         for p in &preopens {
-            eprintln!("Preopened: {:?} -> {} (guest)", p.host_dir, p.guest_path);
+            eprintln!(
+                "{} {:?} -> {} (guest)",
+                paint.label("Preopened:"),
+                p.host_dir,
+                p.guest_path
+            );
         }
     }
 
     // Phase 1: evolve — boots kernel, loads ELF, signals ready.
-    // Zero-copy initrd via map_file_cow. If --mount is set, the directory is
-    // preopened: the FsSandbox handlers get wired in and lib/hostfs in the
-    // guest mounts it at the configured guest path.
-    // --exec CODE is sugar for `-- -c <CODE>`, but with the argparse
-    // escaping applied so the user doesn't have to think about it.
-    let app_args: Vec<String> = match args.exec {
-        Some(ref code) => vec!["-c".into(), argparse_escape(code)],
-        None => args.app_args.clone(),
+    let snapshot_preopens = preopens.clone();
+    let mut builder = args.builder(&settings, preopens)?;
+    let call_stats = args.call_stats.then(|| Arc::new(CallStats::default()));
+    if let Some(ref stats) = call_stats {
+        builder = builder.observe_calls(stats.clone());
+    }
+    if tracked.is_some() {
+        builder = builder.shutdown_signal();
+    }
+    // Only spin while the kernel's own boot output is hidden; it would
+    // otherwise be drawn over.
+    let spinner = Spinner::start(quiet(Quiet::Kernel) && !quiet(Quiet::All), "Booting");
+    // Guest console output arrives on our stderr; filter kernel lines out
+    // of it line by line so app output still streams live.
+    let app_only = |line: &[u8]| classify_line(&String::from_utf8_lossy(line)) == LineKind::App;
+    let console = match (tracked, quiet(Quiet::Kernel)) {
+        (Some(run), true) => Some(PipeCapture::filter_to(LogTee::new(run)?, app_only)?),
+        (Some(run), false) => Some(PipeCapture::forward_to(LogTee::new(run)?)?),
+        (None, true) => Some(PipeCapture::filter(app_only)?),
+        (None, false) => None,
     };
 
-    let mut builder = Sandbox::builder(&args.kernel)
-        .args(app_args)
-        .heap_size(heap_size)
-        .stack_size(stack_size);
-    if let Some(ref p) = args.initrd {
-        builder = builder.initrd_file(p);
-    }
-    for p in preopens {
-        builder = builder.preopen(p);
+    let sandbox = match args.from_snapshot {
+        Some(ref path) => Sandbox::from_snapshot_file_with(path, &snapshot_preopens)
+            .with_context(|| format!("loading snapshot {path:?}")),
+        None => builder.build().map_err(Into::into),
+    };
+    spinner.finish();
+    let mut sandbox = sandbox?;
+    let evolve_time = t0.elapsed();
+    if let (Some(requests), Some(run)) = (stop_requests, tracked) {
+        let (handle, run) = (sandbox.handle(), run.clone());
+        std::thread::spawn(move || {
+            if requests.recv().is_ok() {
+                // Skip any remaining --repeat runs as well.
+                handle.shutdown(run.stop_grace());
+                handle.kill();
+            }
+        });
     }
-    if args.enable_tools {
-        builder = builder.tool("echo", Ok);
+    if let Some(ref path) = args.save_snapshot {
+        sandbox
+            .save_snapshot(path)
+            .with_context(|| format!("saving snapshot to {path:?}"))?;
+        if !quiet(Quiet::Host) {
+            eprintln!("{} {:?}", paint.label("Snapshot:"), path);
+        }
     }
-    let mut sandbox = builder.build()?;
-    let evolve_time = t0.elapsed();
 
     // Phase 2: restore + call — runs the application
     let total_runs = 1 + args.repeat;
@@ -179,21 +1074,1111 @@ fn main() -> Result<()> {
         sandbox.call_run()?;
         let call_time = t_call.elapsed();
 
-        if !args.quiet || args.repeat > 0 {
+        if !quiet(Quiet::Host) || (args.repeat > 0 && !quiet(Quiet::All)) {
             eprintln!(
-                "[run {}/{}] restore={:.1}ms call={:.1}ms",
-                i + 1,
-                total_runs,
+                "{} restore={:.1}ms call={:.1}ms",
+                paint.phase(&format!("[run {}/{}]", i + 1, total_runs)),
                 restore_time.as_secs_f64() * 1000.0,
                 call_time.as_secs_f64() * 1000.0,
             );
         }
+        // The guest returned on its own after a stop request, so its
+        // memory is a state it can pick up from.
+        if let (Some(path), Some(run)) = (&args.checkpoint, tracked) {
+            if sandbox.shutdown_requested() {
+                sandbox
+                    .checkpoint(path)
+                    .with_context(|| format!("checkpointing to {path:?}"))?;
+                run.checkpointed(path)?;
+                if !quiet(Quiet::Host) {
+                    eprintln!("{} {:?}", paint.label("Checkpoint:"), path);
+                }
+                break;
+            }
+        }
     }
 
+    if let Some(capture) = console {
+        capture.finish()?;
+    }
+    if quiet(Quiet::All) {
+        return Ok(());
+    }
+    if let Some(stats) = call_stats {
+        print_call_stats(paint, &stats.summary());
+    }
     eprintln!(
-        "[timing] evolve={:.1}ms total={:.1}ms",
+        "{} evolve={:.1}ms total={:.1}ms",
+        paint.phase("[timing]"),
         evolve_time.as_secs_f64() * 1000.0,
         t0.elapsed().as_secs_f64() * 1000.0,
     );
     Ok(())
 }
+
+/// `--console`: run once with the terminal in raw mode, its input on the
+/// guest's stdin and the guest console on stdout, until the guest exits
+/// or Ctrl-] detaches.
+fn run_console(args: RunArgs) -> Result<()> {
+    let settings = args.settings()?;
+    let (input, reader) = console::input();
+    let sandbox = args
+        .builder(&settings, args.preopens()?)?
+        .stdin(hyperlight_unikraft::hostfn::Stdin::interactive(reader))
+        .build();
+    let mut sandbox = sandbox?;
+    if args.quiet.is_none() {
+        eprintln!(
+            "{}",
+            Paint::new(args.color).label("Console attached; Ctrl-] to detach")
+        );
+    }
+
+    let raw = console::RawMode::enter()?;
+    let capture = PipeCapture::forward_to(std::io::stdout())?;
+    let detached = input.start(sandbox.handle());
+    let result = sandbox.restore().and_then(|()| sandbox.call_run());
+    capture.finish()?;
+    drop(raw);
+
+    if detached.load(std::sync::atomic::Ordering::SeqCst) {
+        eprintln!("detached");
+        return Ok(());
+    }
+    Ok(result?)
+}
+
+/// A tracked run's console: echoed to the terminal and appended to the
+/// registry's log.
+struct LogTee {
+    log: std::fs::File,
+    echo: std::fs::File,
+}
+
+impl LogTee {
+    fn new(run: &RegisteredRun) -> Result<Self> {
+        Ok(Self {
+            log: run.log()?,
+            echo: original_stderr()?,
+        })
+    }
+}
+
+impl std::io::Write for LogTee {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.log.write_all(buf)?;
+        self.echo.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.echo.flush()
+    }
+}
+
+/// The terminal's stderr, which stays put when a capture redirects fd 2.
+#[cfg(unix)]
+fn original_stderr() -> Result<std::fs::File> {
+    use std::os::fd::AsFd;
+    Ok(std::io::stderr().as_fd().try_clone_to_owned()?.into())
+}
+
+#[cfg(windows)]
+fn original_stderr() -> Result<std::fs::File> {
+    use std::os::windows::io::AsHandle;
+    Ok(std::io::stderr().as_handle().try_clone_to_owned()?.into())
+}
+
+/// `--label KEY=VALUE`.
+fn parse_label(s: &str) -> Result<(String, String)> {
+    match s.split_once('=') {
+        Some((k, v)) if !k.is_empty() => Ok((k.to_string(), v.to_string())),
+        _ => Err(anyhow!("expected KEY=VALUE, got {s:?}")),
+    }
+}
+
+/// `stop`: end a tracked run, killing its process if the guest hasn't
+/// stopped well after the grace period, and print its output.
+fn stop(run_id: &str, grace: &str) -> Result<()> {
+    let registry = RunRegistry::open_default()?;
+    let (run, state) = registry.stop(run_id, parse_duration(grace)?)?;
+    registry.copy_log(&run.id, &mut std::io::stdout().lock(), false)?;
+    match state {
+        RunState::Finished { exit, .. } => eprintln!("{}: {exit}", run.id),
+        RunState::Suspended { checkpoint, .. } => eprintln!(
+            "{}: checkpointed; resume with --from-snapshot {checkpoint:?}",
+            run.id
+        ),
+        _ => eprintln!("{}: stopped", run.id),
+    }
+    Ok(())
+}
+
+/// `serve`: the registry's `ps`, `logs` and `stop` as HTTP endpoints.
+fn serve(listen: &str) -> Result<()> {
+    let registry = RunRegistry::open_default()?;
+    let listener =
+        std::net::TcpListener::bind(listen).with_context(|| format!("listening on {listen}"))?;
+    eprintln!("serving runs on http://{}", listener.local_addr()?);
+    runs_server::serve(&registry, &listener)
+}
+
+/// `ps`: the registry's runs, oldest first.
+fn ps() -> Result<()> {
+    let now = std::time::SystemTime::now();
+    println!(
+        "{:<24} {:<9} {:>9} {:>8}  {:<24} LABELS",
+        "RUN ID", "STATE", "ELAPSED", "MEMORY", "KERNEL"
+    );
+    for (run, state) in RunRegistry::open_default()?.list()? {
+        let until = match state {
+            RunState::Finished { at, .. } | RunState::Suspended { at, .. } => at,
+            RunState::Running | RunState::Lost => now,
+        };
+        let kernel = run.kernel.file_name().unwrap_or(run.kernel.as_os_str());
+        let labels: Vec<_> = run.labels.iter().map(|(k, v)| format!("{k}={v}")).collect();
+        println!(
+            "{:<24} {:<9} {:>9} {:>8}  {:<24} {}",
+            run.id,
+            state.label(),
+            format_age(until.duration_since(run.started).unwrap_or_default()),
+            format_memory(run.heap_size),
+            kernel.to_string_lossy(),
+            labels.join(","),
+        );
+    }
+    Ok(())
+}
+
+/// `--watch`: run, then run again each time a file staged from the
+/// arguments changes. The kernel, rootfs and other options are prepared
+/// once; only the staged files are re-read, and in hostfs mode the VM
+/// booted for the first run is restored and called again.
+fn watch(args: RunArgs, mode: WatchMode) -> Result<()> {
+    const POLL: std::time::Duration = std::time::Duration::from_millis(200);
+    let settings = args.settings()?;
+    let paint = Paint::new(args.color);
+    let verbose = args.quiet.is_none();
+
+    let base = match mode {
+        WatchMode::Initrd => args.base_initrd()?,
+        WatchMode::Hostfs => Vec::new(),
+    };
+    let mut reloader = Reloader::new(&args.app_args(), base)?;
+    if reloader.is_empty() {
+        return Err(anyhow!(
+            "--watch: no application argument is a relative path to a host file"
+        ));
+    }
+    let mut preopens = args.preopens()?;
+    let workspace = Workspace::new()?;
+    if mode == WatchMode::Hostfs {
+        let dir = workspace.dir("args")?;
+        reloader.sync_dir(&dir)?;
+        preopens.push(Preopen::new(dir, rootfs::ARG_FILES_DIR)?);
+    }
+    if verbose {
+        for path in reloader.sources() {
+            eprintln!("{} {}", paint.label("Watching:"), path.display());
+        }
+    }
+
+    let mut sandbox: Option<Sandbox> = None;
+    let mut n = 0;
+    loop {
+        n += 1;
+        let t0 = std::time::Instant::now();
+        let result = (|| -> Result<()> {
+            let sandbox = match sandbox {
+                Some(ref mut sandbox) => sandbox,
+                None => {
+                    let builder = args.builder_with_args(
+                        reloader.args().to_vec(),
+                        &settings,
+                        preopens.clone(),
+                    )?;
+                    let builder = match mode {
+                        WatchMode::Initrd => builder.initrd_bytes(reloader.initrd()?),
+                        WatchMode::Hostfs => args.with_initrd(builder)?,
+                    };
+                    sandbox.insert(builder.build()?)
+                }
+            };
+            sandbox.restore()?;
+            Ok(sandbox.call_run()?)
+        })();
+        if mode == WatchMode::Initrd {
+            sandbox = None;
+        }
+        let status = match result {
+            Ok(()) => "ok".to_string(),
+            Err(e) => {
+                // A VM that failed to boot or crashed is booted afresh.
+                sandbox = None;
+                format!("failed: {e:#}")
+            }
+        };
+        if args.quiet.is_none_or(|q| q < Quiet::All) {
+            eprintln!(
+                "{} {status} in {:.1}ms; waiting for changes",
+                paint.phase(&format!("[run {n}]")),
+                t0.elapsed().as_secs_f64() * 1000.0,
+            );
+        }
+        let changed = reloader.wait(POLL)?;
+        if verbose {
+            for path in &changed {
+                eprintln!("{} {}", paint.label("Changed:"), path.display());
+            }
+        }
+        if mode == WatchMode::Hostfs {
+            reloader.sync_dir(&workspace.dir("args")?)?;
+        }
+    }
+}
+
+/// The `--call-stats` table, on stderr with the other run summaries.
+fn print_call_stats(paint: Paint, calls: &[CallSummary]) {
+    let ms = |d: std::time::Duration| d.as_secs_f64() * 1000.0;
+    eprintln!(
+        "{} {:<20} {:>7} {:>6} {:>10} {:>9} {:>9} {:>9}",
+        paint.phase("[calls]"),
+        "tool",
+        "count",
+        "errors",
+        "total",
+        "p50",
+        "p99",
+        "max"
+    );
+    for c in calls {
+        eprintln!(
+            "{} {:<20} {:>7} {:>6} {:>8.3}ms {:>7.3}ms {:>7.3}ms {:>7.3}ms",
+            paint.phase("[calls]"),
+            c.tool,
+            c.count,
+            c.errors,
+            ms(c.total),
+            ms(c.p50),
+            ms(c.p99),
+            ms(c.max)
+        );
+    }
+}
+
+/// `--history`: [`run`], then append its outcome to the run history.
+#[cfg(feature = "sqlite")]
+fn run_recorded(args: RunArgs, t0: std::time::Instant) -> Result<()> {
+    let db = args.history.clone().expect("checked by caller");
+    let history = RunHistory::open(&db)?;
+    let settings = args.settings()?;
+    let rootfs = match args.initrd {
+        Some(ref p) => Some(std::fs::read(p).with_context(|| format!("reading initrd {p:?}"))?),
+        None => None,
+    };
+    let mut entry = HistoryEntry {
+        started: std::time::SystemTime::now(),
+        kernel: args.kernel().to_path_buf(),
+        args: args.app_args(),
+        inputs_sha256: history::inputs_hash(args.kernel(), &args.app_args(), rootfs.as_deref()),
+        heap_size: settings.heap_size,
+        stack_size: settings.stack_size,
+        exit: VmExit::Halt,
+        total_time: Default::default(),
+        setup_time: None,
+        evolve_time: None,
+        output_bytes: None,
+    };
+    drop(rootfs);
+    let result = run(args, t0);
+    entry.exit = VmExit::from_result(&result);
+    entry.total_time = t0.elapsed();
+    if let Err(e) = history.record(&entry) {
+        eprintln!("warning: recording run history in {}: {e:#}", db.display());
+    }
+    result
+}
+
+/// `history`: print past runs, newest first.
+#[cfg(feature = "sqlite")]
+fn show_history(
+    db: &std::path::Path,
+    since: Option<&str>,
+    failed: bool,
+    limit: usize,
+) -> Result<()> {
+    let now = std::time::SystemTime::now();
+    let filter = HistoryFilter {
+        since: since.map(parse_duration).transpose()?.map(|d| now - d),
+        failed_only: failed,
+        limit: Some(limit),
+    };
+    for (id, run) in RunHistory::open(db)?.query(&filter)? {
+        let age = now.duration_since(run.started).unwrap_or_default();
+        println!(
+            "{id:>6}  {:>9} ago  {:>8.1}ms  {:.12}  {}  {} {}",
+            format_age(age),
+            run.total_time.as_secs_f64() * 1000.0,
+            run.inputs_sha256,
+            run.exit,
+            run.kernel.display(),
+            run.args.join(" "),
+        );
+    }
+    Ok(())
+}
+
+/// Coarse age for `history` and `ps`: `42s`, `5m12s`, `3h05m`, `2d04h`.
+fn format_age(age: std::time::Duration) -> String {
+    let s = age.as_secs();
+    match s {
+        0..60 => format!("{s}s"),
+        60..3600 => format!("{}m{:02}s", s / 60, s % 60),
+        3600..86400 => format!("{}h{:02}m", s / 3600, s % 3600 / 60),
+        _ => format!("{}d{:02}h", s / 86400, s % 86400 / 3600),
+    }
+}
+
+/// `record`: run once with a pinned wall clock, capturing the output, and
+/// save everything needed to reproduce the run as a [`ReplayBundle`].
+/// `--jsonl`: the same run, reported through the library's [`JsonlSink`].
+/// `--repeat` runs all belong to one `start`/`exit` pair.
+fn run_jsonl(args: RunArgs) -> Result<()> {
+    let settings = args.settings()?;
+    let builder = args.builder(&settings, args.preopens()?)?;
+
+    let sink = std::sync::Arc::new(std::sync::Mutex::new(JsonlSink::new(std::io::stdout())));
+    let run_id = new_run_id();
+    sink.lock().unwrap().begin(&RunInfo {
+        run_id: &run_id,
+        kernel: args.kernel(),
+    })?;
+    let lines = sink.clone();
+    let capture = PipeCapture::filter(move |line| {
+        let line = String::from_utf8_lossy(line);
+        lines.lock().unwrap().line(line.trim_end_matches('\r'));
+        false
+    })?;
+    let result = builder
+        .build()
+        .and_then(|mut sandbox| {
+            for _ in 0..=args.repeat {
+                sandbox.restore()?;
+                sandbox.call_run()?;
+            }
+            Ok(())
+        })
+        .map_err(anyhow::Error::from);
+    capture.finish()?;
+    sink.lock().unwrap().end(&VmExit::from_result(&result));
+    result
+}
+
+fn record(dir: &std::path::Path, args: RunArgs) -> Result<()> {
+    if !args.mount.is_empty() {
+        return Err(anyhow!(
+            "record does not support --mount: host directory contents are not captured"
+        ));
+    }
+    if !args.allow_read.is_empty() {
+        return Err(anyhow!(
+            "record does not support --allow-read: host file contents are not captured"
+        ));
+    }
+    if args.kv_store.is_some() {
+        return Err(anyhow!(
+            "record does not support --kv-store: stored state is not captured"
+        ));
+    }
+    if args.inject_args {
+        return Err(anyhow!(
+            "record does not support --inject-args: injected files are not captured"
+        ));
+    }
+    #[cfg(feature = "encrypted-initrd")]
+    if args.initrd_key.is_some() {
+        return Err(anyhow!(
+            "record does not support --initrd-key: the bundle would hold the decrypted rootfs"
+        ));
+    }
+    if args.repeat > 0 {
+        return Err(anyhow!("record does not support --repeat"));
+    }
+
+    let settings = args.settings()?;
+    let mut bundle = ReplayBundle {
+        kernel: args.kernel().to_path_buf(),
+        initrd: args.initrd.clone(),
+        args: args.app_args(),
+        heap_size: settings.heap_size,
+        stack_size: settings.stack_size,
+        wall_clock: std::time::SystemTime::now(),
+        timezone: args.tz.clone(),
+        locale: args.locale.clone(),
+        timeout: settings.timeout,
+        enable_tools: args.enable_tools,
+        color: args
+            .color
+            .to_possible_value()
+            .map_or_else(|| "auto".into(), |v| v.get_name().to_string()),
+        output: Vec::new(),
+    };
+    bundle.output = run_bundle(&bundle, args.quiet.is_none())?;
+    bundle.save(dir)?;
+    eprintln!(
+        "recorded {} bytes of output to {}",
+        bundle.output.len(),
+        dir.display()
+    );
+    Ok(())
+}
+
+/// `replay`: re-run a bundle and fail if the output diverges.
+fn replay(dir: &std::path::Path, quiet: bool) -> Result<()> {
+    let bundle = ReplayBundle::load(dir)?;
+    let paint = Paint::new(ColorWhen::from_str(&bundle.color, true).unwrap_or(ColorWhen::Auto));
+    let output = run_bundle(&bundle, !quiet)?;
+    match bundle.first_divergence(&output) {
+        None => {
+            eprintln!(
+                "{} matches recording ({} bytes)",
+                paint.phase("[replay]"),
+                output.len()
+            );
+            Ok(())
+        }
+        Some(at) => Err(anyhow!(
+            "replay diverged from recording at byte {at} ({} bytes recorded, {} replayed)",
+            bundle.output.len(),
+            output.len()
+        )),
+    }
+}
+
+/// Boot and run a bundle's kernel once, returning the application's
+/// console output. Boot output isn't recorded; it is echoed only with
+/// `tee`.
+fn run_bundle(bundle: &ReplayBundle, tee: bool) -> Result<Vec<u8>> {
+    let mut builder = Sandbox::builder(&bundle.kernel)
+        .args(bundle.args.clone())
+        .heap_size(bundle.heap_size)
+        .stack_size(bundle.stack_size)
+        .wall_clock(bundle.wall_clock);
+    if let Some(ref p) = bundle.initrd {
+        builder = builder.initrd_file(p);
+    }
+    if let Some(ref tz) = bundle.timezone {
+        builder = builder.timezone(tz);
+    }
+    if let Some(ref locale) = bundle.locale {
+        builder = builder.locale(locale);
+    }
+    if let Some(limit) = bundle.timeout {
+        builder = builder.timeout(limit);
+    }
+    if bundle.enable_tools {
+        builder = builder.tool("echo", Ok).getrandom().clock();
+    }
+
+    let boot = (!tee).then(|| PipeCapture::filter(|_| false)).transpose()?;
+    let sandbox = builder.build();
+    if let Some(boot) = boot {
+        boot.finish()?;
+    }
+    let mut sandbox = sandbox?;
+    let capture = PipeCapture::start(tee)?;
+    sandbox.restore()?;
+    sandbox.call_run()?;
+    Ok(capture.finish()?.bytes)
+}
+
+/// `profile`: run once (plus `--repeat`) and write folded stacks.
+fn profile(out: &std::path::Path, use_perf: bool, args: RunArgs) -> Result<()> {
+    let settings = args.settings()?;
+    let calls = Arc::new(CallStats::default());
+    let builder = args
+        .builder(&settings, args.preopens()?)?
+        .observe_calls(calls.clone());
+
+    let perf = if use_perf {
+        Some(perf::Recorder::start()?)
+    } else {
+        None
+    };
+    let capture = PipeCapture::start(args.quiet.is_none())?;
+
+    let sandbox = builder.build();
+    let mut restore = std::time::Duration::ZERO;
+    let mut call = std::time::Duration::ZERO;
+    let result = sandbox.and_then(|mut sandbox| {
+        for _ in 0..=args.repeat {
+            let t = std::time::Instant::now();
+            sandbox.restore()?;
+            restore += t.elapsed();
+            let t = std::time::Instant::now();
+            sandbox.call_run()?;
+            call += t.elapsed();
+        }
+        Ok(sandbox.boot_timings())
+    });
+    let captured = capture.finish()?;
+    let exits = profile::host_call_exits(&calls.summary(), 2 + u64::from(args.repeat));
+    let folded = match perf {
+        Some(recorder) => recorder.finish()?,
+        None => {
+            let boot = result.as_ref().copied().unwrap_or_default();
+            // Host calls happen inside the guest phases, mostly the run's;
+            // count their time once, under `vmexit`.
+            let in_exits: std::time::Duration = exits.iter().map(|e| e.total).sum();
+            let from_run = call.min(in_exits);
+            let mut folded = profile::fold_phases(
+                "hyperlight-unikraft",
+                &[
+                    ("boot;setup", boot.setup),
+                    (
+                        "boot;guest",
+                        boot.evolve.saturating_sub(in_exits - from_run),
+                    ),
+                    ("run;restore", restore),
+                    ("run;guest", call - from_run),
+                    ("output", captured.busy),
+                ],
+            );
+            folded += &profile::fold_exits("hyperlight-unikraft", &exits);
+            folded
+        }
+    };
+    result?;
+
+    if args.quiet.is_none() {
+        for e in &exits {
+            eprintln!(
+                "{} {:<32} {:>8} {:>10.3}ms",
+                Paint::new(args.color).phase("[vmexit]"),
+                e.reason,
+                e.count,
+                e.total.as_secs_f64() * 1000.0
+            );
+        }
+    }
+
+    std::fs::write(out, folded)?;
+    eprintln!("wrote {}", out.display());
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+mod perf {
+    use anyhow::{anyhow, Context, Result};
+    use hyperlight_unikraft::profile::collapse_perf_script;
+    use nix::sys::signal::{kill, Signal};
+    use nix::unistd::Pid;
+    use std::path::PathBuf;
+    use std::process::{Child, Command, Stdio};
+
+    /// `perf record -g` attached to this process for the duration of a run.
+    pub struct Recorder {
+        child: Child,
+        data: PathBuf,
+    }
+
+    impl Recorder {
+        pub fn start() -> Result<Self> {
+            let data = std::env::temp_dir().join(format!("hl-perf-{}.data", std::process::id()));
+            let child = Command::new("perf")
+                .args(["record", "-g", "-F", "999", "-o"])
+                .arg(&data)
+                .args(["-p", &std::process::id().to_string()])
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .spawn()
+                .context("running `perf record` (is perf installed?)")?;
+            // Give perf a moment to attach before the interesting work starts.
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            Ok(Self { child, data })
+        }
+
+        /// Stop recording and return the samples as folded stacks.
+        pub fn finish(mut self) -> Result<String> {
+            kill(Pid::from_raw(self.child.id() as i32), Signal::SIGINT)?;
+            self.child.wait()?;
+            let script = Command::new("perf")
+                .args(["script", "-i"])
+                .arg(&self.data)
+                .stderr(Stdio::null())
+                .output()
+                .context("running `perf script`")?;
+            let _ = std::fs::remove_file(&self.data);
+            if !script.status.success() {
+                return Err(anyhow!("`perf script` failed ({})", script.status));
+            }
+            Ok(collapse_perf_script(&String::from_utf8_lossy(
+                &script.stdout,
+            )))
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod perf {
+    use anyhow::{anyhow, Result};
+
+    pub struct Recorder;
+
+    impl Recorder {
+        pub fn start() -> Result<Self> {
+            Err(anyhow!("--perf is only supported on Linux"))
+        }
+
+        pub fn finish(self) -> Result<String> {
+            unreachable!("start() never succeeds off Linux")
+        }
+    }
+}
+
+fn convert(
+    source: &std::path::Path,
+    output: &std::path::Path,
+    compress: Option<CompressFormat>,
+) -> Result<()> {
+    let compress = compress.map(|CompressFormat::Zstd| Compression::Zstd);
+    let spinner = Spinner::start(true, "Building rootfs");
+    let stats = rootfs::convert(source, output, compress);
+    spinner.finish();
+    let stats = stats?;
+    let size = std::fs::metadata(output)?.len();
+    eprintln!(
+        "wrote {} entries ({} bytes) to {}",
+        stats.entries,
+        size,
+        output.display()
+    );
+    if stats.skipped > 0 {
+        eprintln!("skipped {} device, fifo or socket entries", stats.skipped);
+    }
+    Ok(())
+}
+
+/// `convert --encrypt-key`: build the archive in memory and write only
+/// the sealed copy, so the plaintext never reaches disk.
+#[cfg(feature = "encrypted-initrd")]
+fn convert_encrypted(
+    source: &std::path::Path,
+    output: &std::path::Path,
+    compress: Option<CompressFormat>,
+    key_spec: &str,
+    key_id: &str,
+) -> Result<()> {
+    let key = encryption::KeySource::parse(key_spec)?.key(key_id)?;
+    let compress = compress.map(|CompressFormat::Zstd| Compression::Zstd);
+    let spinner = Spinner::start(true, "Building rootfs");
+    let built = rootfs::convert_to_vec(source, compress);
+    spinner.finish();
+    let (archive, stats) = built?;
+    let sealed = encryption::encrypt(&archive, &key, key_id)?;
+    std::fs::write(output, &sealed).with_context(|| format!("writing {output:?}"))?;
+    eprintln!(
+        "wrote {} entries ({} bytes, encrypted) to {}",
+        stats.entries,
+        sealed.len(),
+        output.display()
+    );
+    if stats.skipped > 0 {
+        eprintln!("skipped {} device, fifo or socket entries", stats.skipped);
+    }
+    Ok(())
+}
+
+/// `build`: build a project's kernel and rootfs and say where they are.
+fn build(project: &std::path::Path, options: kraft::BuildOptions) -> Result<()> {
+    let project = KraftProject::open(project)?;
+    let assets = project.build(&options)?;
+    eprintln!("kernel: {}", assets.kernel.display());
+    match assets.initrd {
+        Some(initrd) => eprintln!("rootfs: {}", initrd.display()),
+        None => eprintln!("rootfs: none (no Kraftfile rootfs or Dockerfile)"),
+    }
+    Ok(())
+}
+
+/// `warm-rootfs`: precompile a Python rootfs and write the result.
+fn warm_rootfs(
+    kernel: &std::path::Path,
+    initrd: &std::path::Path,
+    output: &std::path::Path,
+    script: Option<&std::path::Path>,
+    memory: &str,
+    compress: Option<CompressFormat>,
+) -> Result<()> {
+    let options = warm::WarmOptions {
+        script: script
+            .map(|p| std::fs::read(p).with_context(|| format!("reading {p:?}")))
+            .transpose()?,
+        heap_size: Some(parse_memory(memory)?),
+    };
+    let rootfs = std::fs::read(initrd).with_context(|| format!("reading initrd {initrd:?}"))?;
+    let warmed = warm::warm_rootfs(kernel, &rootfs, &options)?;
+    let data = match compress {
+        Some(CompressFormat::Zstd) => zstd::encode_all(&warmed.rootfs[..], 0)?,
+        None => warmed.rootfs,
+    };
+    std::fs::write(output, &data).with_context(|| format!("writing {output:?}"))?;
+    eprintln!(
+        "added {} files for {} Python sources ({} bytes) to {}",
+        warmed.added,
+        warmed.sources,
+        data.len(),
+        output.display()
+    );
+    Ok(())
+}
+
+/// Where `up` records its pid for `down`: beside the compose file.
+fn compose_pid_file(file: &std::path::Path) -> PathBuf {
+    let name = file.file_name().unwrap_or_default().to_string_lossy();
+    file.with_file_name(format!(".{name}.pid"))
+}
+
+/// `up`: boot every VM in the compose file and wait for them all, stopping
+/// them on `down`, SIGINT or SIGTERM.
+fn up(file: &std::path::Path, grace: &str) -> Result<()> {
+    let grace = parse_duration(grace)?;
+    let compose = Compose::load(file)?;
+    let pid_file = compose_pid_file(file);
+    if let Some(pid) = read_pid(&pid_file).filter(|&pid| lifecycle::is_alive(pid)) {
+        return Err(anyhow!("{} is already up (pid {pid})", file.display()));
+    }
+    // Before any VM thread exists, so they all inherit the blocked mask.
+    let stop = lifecycle::stop_requests()?;
+    std::fs::write(&pid_file, std::process::id().to_string())
+        .with_context(|| format!("writing {}", pid_file.display()))?;
+    let _pid_guard = RemoveOnDrop(pid_file);
+
+    let running = compose.up()?;
+    eprintln!("up: {}", running.names().join(", "));
+    let stopper = running.stopper();
+    std::thread::spawn(move || {
+        if stop.recv().is_ok() {
+            eprintln!("stopping (grace {grace:?})");
+            stopper.down(grace);
+        }
+    });
+
+    let results = running.wait();
+    let failed = results.iter().filter(|r| !r.exit.is_halt()).count();
+    for r in &results {
+        eprintln!("{}: {}", r.name, r.exit);
+    }
+    if failed > 0 {
+        return Err(anyhow!("{failed} of {} VMs failed", results.len()));
+    }
+    Ok(())
+}
+
+/// `down`: stop the `up` running for this compose file and wait for it to
+/// exit.
+fn down(file: &std::path::Path) -> Result<()> {
+    let pid_file = compose_pid_file(file);
+    let pid = read_pid(&pid_file)
+        .filter(|&pid| lifecycle::is_alive(pid))
+        .ok_or_else(|| anyhow!("{} is not up", file.display()))?;
+    lifecycle::request_stop(pid)?;
+    while pid_file.exists() && lifecycle::is_alive(pid) {
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+    eprintln!("down: pid {pid} stopped");
+    Ok(())
+}
+
+fn read_pid(path: &std::path::Path) -> Option<u32> {
+    std::fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+struct RemoveOnDrop(PathBuf);
+
+impl Drop for RemoveOnDrop {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// SIGINT/SIGTERM handling for `up` and signalling it from `down`.
+#[cfg(unix)]
+mod lifecycle {
+    use anyhow::Result;
+    use nix::sys::signal::{kill, SigSet, Signal};
+    use nix::unistd::Pid;
+    use std::sync::mpsc;
+
+    /// Block SIGINT and SIGTERM in this thread (and threads it spawns
+    /// later) and report each one on the returned channel.
+    pub fn stop_requests() -> Result<mpsc::Receiver<()>> {
+        let mut set = SigSet::empty();
+        set.add(Signal::SIGINT);
+        set.add(Signal::SIGTERM);
+        set.thread_block()?;
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || while set.wait().is_ok() && tx.send(()).is_ok() {});
+        Ok(rx)
+    }
+
+    pub fn request_stop(pid: u32) -> Result<()> {
+        kill(Pid::from_raw(pid as i32), Signal::SIGTERM)?;
+        Ok(())
+    }
+
+    pub fn is_alive(pid: u32) -> bool {
+        kill(Pid::from_raw(pid as i32), None).is_ok()
+    }
+}
+
+#[cfg(not(unix))]
+mod lifecycle {
+    use anyhow::{anyhow, Result};
+    use std::sync::mpsc;
+
+    /// Ctrl-C ends the process as usual; there is no `down`.
+    pub fn stop_requests() -> Result<mpsc::Receiver<()>> {
+        Ok(mpsc::channel().1)
+    }
+
+    pub fn request_stop(_pid: u32) -> Result<()> {
+        Err(anyhow!(
+            "`down` is only supported on Unix; stop `up` with Ctrl-C"
+        ))
+    }
+
+    pub fn is_alive(_pid: u32) -> bool {
+        false
+    }
+}
+
+/// The terminal side of `--console`.
+#[cfg(unix)]
+mod console {
+    use anyhow::Result;
+    use hyperlight_unikraft::VmHandle;
+    use nix::sys::termios::{self, OutputFlags, SetArg, Termios};
+    use std::io::Read;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{mpsc, Arc};
+
+    /// Ctrl-], as telnet and `virsh console` use it.
+    const DETACH: u8 = 0x1d;
+
+    /// The terminal in raw mode until dropped. Output processing stays
+    /// on so the guest's `\n` still returns the cursor.
+    pub struct RawMode(Termios);
+
+    impl RawMode {
+        pub fn enter() -> Result<Self> {
+            let stdin = std::io::stdin();
+            let saved = termios::tcgetattr(&stdin)?;
+            let mut raw = saved.clone();
+            termios::cfmakeraw(&mut raw);
+            raw.output_flags |= OutputFlags::OPOST | OutputFlags::ONLCR;
+            termios::tcsetattr(&stdin, SetArg::TCSANOW, &raw)?;
+            Ok(Self(saved))
+        }
+    }
+
+    impl Drop for RawMode {
+        fn drop(&mut self) {
+            let _ = termios::tcsetattr(std::io::stdin(), SetArg::TCSANOW, &self.0);
+        }
+    }
+
+    /// Keystrokes not yet read by the guest.
+    pub struct Input(mpsc::Sender<Vec<u8>>);
+
+    /// The guest's end of [`Input`]: each read returns what one
+    /// terminal read gave, and the end of input once the terminal closes
+    /// or detaches.
+    pub struct Reader {
+        rx: mpsc::Receiver<Vec<u8>>,
+        pending: Vec<u8>,
+    }
+
+    pub fn input() -> (Input, Reader) {
+        let (tx, rx) = mpsc::channel();
+        let reader = Reader {
+            rx,
+            pending: Vec::new(),
+        };
+        (Input(tx), reader)
+    }
+
+    impl Input {
+        /// Pump the terminal into the guest from a background thread. On
+        /// Ctrl-] the guest is killed and the returned flag set.
+        pub fn start(self, handle: VmHandle) -> Arc<AtomicBool> {
+            let detached = Arc::new(AtomicBool::new(false));
+            let flag = detached.clone();
+            std::thread::spawn(move || {
+                let mut stdin = std::io::stdin().lock();
+                let mut buf = [0u8; 1024];
+                loop {
+                    let n = match stdin.read(&mut buf) {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => n,
+                    };
+                    let chunk = &buf[..n];
+                    let before = chunk.iter().position(|&b| b == DETACH);
+                    let keep = &chunk[..before.unwrap_or(n)];
+                    if !keep.is_empty() && self.0.send(keep.to_vec()).is_err() {
+                        return;
+                    }
+                    if before.is_some() {
+                        flag.store(true, Ordering::SeqCst);
+                        handle.kill();
+                        return;
+                    }
+                }
+            });
+            detached
+        }
+    }
+
+    impl Read for Reader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.pending.is_empty() {
+                match self.rx.recv() {
+                    Ok(chunk) => self.pending = chunk,
+                    Err(_) => return Ok(0),
+                }
+            }
+            let n = buf.len().min(self.pending.len());
+            buf[..n].copy_from_slice(&self.pending[..n]);
+            self.pending.drain(..n);
+            Ok(n)
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod console {
+    use anyhow::{anyhow, Result};
+    use hyperlight_unikraft::VmHandle;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+
+    pub struct RawMode;
+
+    impl RawMode {
+        pub fn enter() -> Result<Self> {
+            Err(anyhow!("--console is only supported on Unix"))
+        }
+    }
+
+    pub struct Input;
+
+    pub struct Reader;
+
+    pub fn input() -> (Input, Reader) {
+        (Input, Reader)
+    }
+
+    impl Input {
+        pub fn start(self, _handle: VmHandle) -> Arc<AtomicBool> {
+            Arc::new(AtomicBool::new(false))
+        }
+    }
+
+    impl std::io::Read for Reader {
+        fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+            Ok(0)
+        }
+    }
+}
+
+/// `loadtest`: offer a steady request rate, one sandbox per request, with
+/// the guest console discarded. A `HostPrint` kernel's is dropped in each
+/// sandbox; a port 0xE9 kernel's reaches the one fd 2, which the shared
+/// pipe drains without making the requests take turns.
+fn loadtest(rps: f64, duration: &str, concurrency: usize, args: RunArgs) -> Result<()> {
+    let spec = LoadSpec {
+        rps,
+        duration: parse_duration(duration)?,
+        concurrency,
+    };
+    spec.validate()?;
+    let settings = args.settings()?;
+    let preopens = args.preopens()?;
+    eprintln!(
+        "offering {rps}/s for {duration} (up to {concurrency} in flight) to {}",
+        args.kernel().display()
+    );
+
+    // Before the capture, so it still draws to the terminal.
+    let spinner = Spinner::start(true, "Load testing");
+    let capture = PipeCapture::start(false)?;
+    let report = loadtest::run(&spec, || {
+        let mut sandbox = args
+            .builder(&settings, preopens.clone())?
+            .placement(Placer::global())
+            .quiet()
+            .build()?;
+        sandbox.restore()?;
+        Ok(sandbox.call_run()?)
+    });
+    capture.finish()?;
+    spinner.finish();
+    print!("{}", report?);
+    Ok(())
+}
+
+/// `trace-boot`: run once and print a per-stage breakdown. Stages come
+/// from the console: each line is timestamped as the host receives it and
+/// grouped by source (banner, Unikraft library tag, app).
+fn trace_boot(args: RunArgs) -> Result<()> {
+    let settings = args.settings()?;
+    let builder = args.builder(&settings, args.preopens()?)?;
+
+    let capture = PipeCapture::start(false)?;
+    let t0 = std::time::Instant::now();
+    let result = builder.build().and_then(|mut sandbox| {
+        sandbox.restore()?;
+        sandbox.call_run()?;
+        Ok(sandbox.boot_timings())
+    });
+    let end = t0.elapsed();
+    let captured = capture.finish()?;
+    let boot = result?;
+
+    let mut lines = Vec::with_capacity(captured.line_starts.len());
+    for (i, &(offset, at)) in captured.line_starts.iter().enumerate() {
+        let next = captured
+            .line_starts
+            .get(i + 1)
+            .map_or(captured.bytes.len(), |&(o, _)| o);
+        let line = String::from_utf8_lossy(&captured.bytes[offset..next]);
+        lines.push((at.duration_since(t0), line.trim_end().to_string()));
+    }
+    let borrowed: Vec<(std::time::Duration, &str)> =
+        lines.iter().map(|(at, l)| (*at, l.as_str())).collect();
+    let stages = boot_timeline(&borrowed, end);
+
+    let ms = |d: std::time::Duration| d.as_secs_f64() * 1000.0;
+    println!(
+        "{:<24} {:>10} {:>10} {:>6}",
+        "stage", "start", "duration", "lines"
+    );
+    println!(
+        "{:<24} {:>8.3}ms {:>8.3}ms {:>6}",
+        "host setup",
+        0.0,
+        ms(boot.setup),
+        "-"
+    );
+    for stage in &stages {
+        println!(
+            "{:<24} {:>8.3}ms {:>8.3}ms {:>6}",
+            stage.name,
+            ms(stage.start),
+            ms(stage.duration),
+            stage.lines
+        );
+    }
+    println!("{:<24} {:>10} {:>8.3}ms", "total", "", ms(end));
+    Ok(())
+}
@@ -0,0 +1,268 @@
+//! Process-wide startup latency histograms.
+//!
+//! Every sandbox build records its [`Phase::SandboxCreate`] time and
+//! every captured run its capture-to-first-output latency (see
+//! [`VmOutput::time_to_first_output`]) into HDR-style [`Histogram`]s, so
+//! tail latencies of a long-running embedder can be read with
+//! [`startup_latencies`] or scraped via [`render_prometheus`]. Guest
+//! host calls are counted per tool in [`host_calls`].
+//!
+//! [`Phase::SandboxCreate`]: crate::Phase::SandboxCreate
+//! [`VmOutput::time_to_first_output`]: crate::VmOutput::time_to_first_output
+
+use std::fmt::Write as _;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+use crate::hostcall::CallStats;
+
+/// Sub-buckets per power of two: values are kept to within 1/32 (~3%).
+const SUB_BITS: u32 = 5;
+const SUB: u64 = 1 << SUB_BITS;
+const BUCKETS: usize = (SUB + (64 - SUB_BITS as u64) * SUB) as usize;
+
+/// A log-linear histogram of durations at microsecond resolution:
+/// exact below 32µs, within ~3% above. Fixed size, no allocation per
+/// sample.
+#[derive(Debug)]
+pub struct Histogram {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug, Clone)]
+struct Inner {
+    counts: Vec<u64>,
+    count: u64,
+    sum_us: u128,
+    min_us: u64,
+    max_us: u64,
+}
+
+fn bucket(us: u64) -> usize {
+    if us < SUB {
+        return us as usize;
+    }
+    let exp = 63 - us.leading_zeros();
+    let mantissa = us >> (exp - SUB_BITS);
+    (SUB + (exp - SUB_BITS) as u64 * SUB + (mantissa - SUB)) as usize
+}
+
+/// Highest value that lands in bucket `i`.
+fn bucket_high(i: usize) -> u64 {
+    let i = i as u64;
+    if i < SUB {
+        return i;
+    }
+    let shift = (i - SUB) / SUB;
+    let mantissa = SUB + (i - SUB) % SUB;
+    ((mantissa + 1) << shift).wrapping_sub(1)
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Histogram {
+    pub fn new() -> Self {
+        Self {
+            inner: Mutex::new(Inner {
+                counts: vec![0; BUCKETS],
+                count: 0,
+                sum_us: 0,
+                min_us: u64::MAX,
+                max_us: 0,
+            }),
+        }
+    }
+
+    pub fn record(&self, d: Duration) {
+        let us = d.as_micros().min(u64::MAX as u128) as u64;
+        let mut h = self.lock();
+        h.counts[bucket(us)] += 1;
+        h.count += 1;
+        h.sum_us += us as u128;
+        h.min_us = h.min_us.min(us);
+        h.max_us = h.max_us.max(us);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.lock().count
+    }
+
+    pub fn sum(&self) -> Duration {
+        Duration::from_micros(self.lock().sum_us.min(u64::MAX as u128) as u64)
+    }
+
+    pub fn min(&self) -> Option<Duration> {
+        let h = self.lock();
+        (h.count > 0).then(|| Duration::from_micros(h.min_us))
+    }
+
+    pub fn max(&self) -> Option<Duration> {
+        let h = self.lock();
+        (h.count > 0).then(|| Duration::from_micros(h.max_us))
+    }
+
+    /// The value below which `q` (0.0–1.0) of the samples fall, rounded
+    /// up to its bucket's upper edge. `None` when empty.
+    pub fn percentile(&self, q: f64) -> Option<Duration> {
+        let h = self.lock();
+        if h.count == 0 {
+            return None;
+        }
+        let rank = ((q.clamp(0.0, 1.0) * h.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (i, &c) in h.counts.iter().enumerate() {
+            seen += c;
+            if seen >= rank {
+                let us = bucket_high(i).clamp(h.min_us, h.max_us);
+                return Some(Duration::from_micros(us));
+            }
+        }
+        Some(Duration::from_micros(h.max_us))
+    }
+
+    /// Forget every sample.
+    pub fn reset(&self) {
+        *self.lock() = Histogram::new().inner.into_inner().unwrap();
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// The histograms this crate records into.
+#[derive(Debug, Default)]
+pub struct StartupLatencies {
+    /// Creating the Hyperlight sandbox, per build.
+    pub sandbox_create: Histogram,
+    /// Start of output capture to the guest's first console byte.
+    pub first_output: Histogram,
+}
+
+/// The process-wide latency histograms.
+pub fn startup_latencies() -> &'static StartupLatencies {
+    static LATENCIES: OnceLock<StartupLatencies> = OnceLock::new();
+    LATENCIES.get_or_init(StartupLatencies::default)
+}
+
+/// Every host call any guest in this process has made, per tool.
+pub fn host_calls() -> &'static CallStats {
+    static CALLS: OnceLock<CallStats> = OnceLock::new();
+    CALLS.get_or_init(CallStats::default)
+}
+
+const QUANTILES: [f64; 4] = [0.5, 0.9, 0.99, 0.999];
+
+/// The histograms in Prometheus text exposition format, as summaries
+/// in seconds.
+pub fn render_prometheus() -> String {
+    let l = startup_latencies();
+    let mut out = String::new();
+    for (name, help, h) in [
+        (
+            "hyperlight_unikraft_sandbox_create_seconds",
+            "Time to create a Hyperlight sandbox.",
+            &l.sandbox_create,
+        ),
+        (
+            "hyperlight_unikraft_first_output_seconds",
+            "Time from capture start to the guest's first console output.",
+            &l.first_output,
+        ),
+    ] {
+        let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} summary");
+        for q in QUANTILES {
+            if let Some(v) = h.percentile(q) {
+                let _ = writeln!(out, "{name}{{quantile=\"{q}\"}} {}", v.as_secs_f64());
+            }
+        }
+        let _ = writeln!(out, "{name}_sum {}", h.sum().as_secs_f64());
+        let _ = writeln!(out, "{name}_count {}", h.count());
+    }
+    let calls = host_calls().summary();
+    if !calls.is_empty() {
+        let name = "hyperlight_unikraft_host_call_seconds";
+        let _ = writeln!(
+            out,
+            "# HELP {name} Time the host spent serving guest tool calls.\n# TYPE {name} summary"
+        );
+        for c in &calls {
+            let tool = &c.tool;
+            for (q, v) in [("0.5", c.p50), ("0.99", c.p99)] {
+                let _ = writeln!(
+                    out,
+                    "{name}{{tool=\"{tool}\",quantile=\"{q}\"}} {}",
+                    v.as_secs_f64()
+                );
+            }
+            let _ = writeln!(
+                out,
+                "{name}_sum{{tool=\"{tool}\"}} {}",
+                c.total.as_secs_f64()
+            );
+            let _ = writeln!(out, "{name}_count{{tool=\"{tool}\"}} {}", c.count);
+        }
+        let name = "hyperlight_unikraft_host_call_errors_total";
+        let _ = writeln!(
+            out,
+            "# HELP {name} Guest tool calls that returned an error.\n# TYPE {name} counter"
+        );
+        for c in &calls {
+            let _ = writeln!(out, "{name}{{tool=\"{}\"}} {}", c.tool, c.errors);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_are_exact_when_small_and_within_three_percent_above() {
+        for us in [0, 1, 31, 32, 33, 1000, 123_456, 10_000_000_000] {
+            let high = bucket_high(bucket(us));
+            assert!(high >= us && (high - us) as f64 <= us as f64 / 32.0, "{us}");
+        }
+        assert_eq!(bucket(u64::MAX), BUCKETS - 1);
+    }
+
+    #[test]
+    fn percentiles_track_the_tail() {
+        let h = Histogram::new();
+        assert_eq!(h.percentile(0.99), None);
+        for ms in 1..=100 {
+            h.record(Duration::from_millis(ms));
+        }
+        let p50 = h.percentile(0.5).unwrap();
+        let p99 = h.percentile(0.99).unwrap();
+        assert!(p50 >= Duration::from_millis(50) && p50 <= Duration::from_micros(51_600));
+        assert!(p99 >= Duration::from_millis(99) && p99 <= Duration::from_millis(100));
+        assert_eq!(h.max(), Some(Duration::from_millis(100)));
+        assert_eq!(h.count(), 100);
+        h.reset();
+        assert_eq!(h.count(), 0);
+    }
+
+    #[test]
+    fn prometheus_output_has_help_type_and_totals() {
+        let text = render_prometheus();
+        assert!(text.contains("# TYPE hyperlight_unikraft_sandbox_create_seconds summary"));
+        assert!(text.contains("hyperlight_unikraft_first_output_seconds_count "));
+    }
+
+    #[test]
+    fn host_calls_are_labelled_by_tool() {
+        host_calls().record("metrics_test_tool", Duration::from_micros(40), false);
+        let text = render_prometheus();
+        assert!(text.contains("# TYPE hyperlight_unikraft_host_call_seconds summary"));
+        assert!(text
+            .contains("hyperlight_unikraft_host_call_seconds_count{tool=\"metrics_test_tool\"} 1"));
+        assert!(text
+            .contains("hyperlight_unikraft_host_call_errors_total{tool=\"metrics_test_tool\"} 1"));
+    }
+}
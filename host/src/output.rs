@@ -0,0 +1,403 @@
+//! Post-processing for captured guest console output.
+//!
+//! Guest runtimes (Python's rich tracebacks, Node's colored logs, the
+//! Unikraft banner) freely emit terminal escape sequences. These helpers
+//! clean up the *captured* copy only — the live tee stream is left
+//! untouched so terminals still render colors.
+//!
+//! The guest console interleaves the kernel's own chatter (boot banner,
+//! `uk_pr_*` log lines) with the application's output. [`classify_line`]
+//! tells them apart so callers can hide the former ([`split_channels`]
+//! does so for a whole capture), and [`boot_timeline`]
+//! groups timestamped lines into per-stage durations.
+//! [`forward_to_tracing`] re-emits lines as `tracing` events at the level
+//! their prefix implies.
+//!
+//! Guests that print artifacts (a base64 blob, a compressed payload)
+//! should be read from the raw bytes, not the lossy text: [`find_marker`]
+//! pulls a tagged value out of [`VmOutput::raw`](crate::VmOutput::raw)
+//! byte for byte. Whole files are better sent through the `output_file`
+//! tool ([`hostfn::OutputFiles`](crate::hostfn::OutputFiles)), which
+//! fills [`VmOutput::files`](crate::VmOutput::files) without touching the
+//! console.
+
+use std::time::Duration;
+
+/// Who produced a line of guest console output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineKind {
+    /// Unikraft's boot banner or kernel log output.
+    Kernel,
+    /// Everything else: the application's own output.
+    App,
+}
+
+/// Log-level tags Unikraft's `uk_pr_*` macros print, with or without
+/// the leading `[  sec.usec]` timestamp.
+const KERNEL_LOG_LEVELS: &[&str] = &["Info:", "Warn:", "ERR:", "CRIT:", "dbg:"];
+
+/// Leading fragments of the "Powered by Unikraft" ASCII-art banner.
+const BANNER_PREFIXES: &[&str] = &[
+    "Powered by",
+    "o.   .o",
+    "Oo   Oo",
+    "oO   oO",
+    "oOo oOO",
+    " OoOoO",
+];
+
+/// Classify one line (without its trailing newline) of guest console
+/// output. Heuristic: matches the banner and Unikraft's log format, so an
+/// app that deliberately mimics them will be hidden along with the kernel.
+pub fn classify_line(line: &str) -> LineKind {
+    let line = line.trim_end_matches('\r');
+    let rest = strip_timestamp(line);
+    if KERNEL_LOG_LEVELS.iter().any(|lvl| rest.starts_with(lvl)) {
+        return LineKind::Kernel;
+    }
+    if BANNER_PREFIXES.iter().any(|p| line.starts_with(p)) || is_banner_release_line(line) {
+        return LineKind::Kernel;
+    }
+    LineKind::App
+}
+
+/// Drop an optional printk timestamp: `"[    0.012345] "`.
+fn strip_timestamp(line: &str) -> &str {
+    if let Some((ts, tail)) = line.strip_prefix('[').and_then(|l| l.split_once(']')) {
+        let ts = ts.trim();
+        if !ts.is_empty() && ts.chars().all(|c| c.is_ascii_digit() || c == '.') {
+            return tail.trim_start();
+        }
+    }
+    line
+}
+
+/// The log level a guest line's prefix implies: Unikraft's `uk_pr_*`
+/// tags, or a leading `ERROR:`/`WARNING:`/`DEBUG` style word as Python's
+/// `logging` and most app loggers print. Unmarked lines are `INFO`.
+pub fn guest_log_level(line: &str) -> tracing::Level {
+    use tracing::Level;
+    let rest = strip_timestamp(line.trim_end_matches('\r'));
+    match rest.split(':').next().unwrap_or_default() {
+        "CRIT" | "ERR" => return Level::ERROR,
+        "Warn" => return Level::WARN,
+        "Info" => return Level::INFO,
+        "dbg" => return Level::DEBUG,
+        _ => {}
+    }
+    let word = rest
+        .split(|c: char| !c.is_ascii_alphabetic())
+        .next()
+        .unwrap_or_default()
+        .to_ascii_uppercase();
+    match word.as_str() {
+        "ERROR" | "CRITICAL" | "FATAL" | "PANIC" => Level::ERROR,
+        "WARN" | "WARNING" => Level::WARN,
+        "DEBUG" => Level::DEBUG,
+        "TRACE" => Level::TRACE,
+        _ => Level::INFO,
+    }
+}
+
+/// Emit one guest console line (without its newline) as a `tracing`
+/// event with target `guest`, fields `guest = true` and `kind`
+/// (`"kernel"` or `"app"`), at [`guest_log_level`].
+pub fn forward_to_tracing(line: &str) {
+    use tracing::Level;
+    let kind = match classify_line(line) {
+        LineKind::Kernel => "kernel",
+        LineKind::App => "app",
+    };
+    let line = line.trim_end_matches('\r');
+    // `event!` needs the level as a constant.
+    match guest_log_level(line) {
+        Level::ERROR => tracing::error!(target: "guest", guest = true, kind, "{line}"),
+        Level::WARN => tracing::warn!(target: "guest", guest = true, kind, "{line}"),
+        Level::INFO => tracing::info!(target: "guest", guest = true, kind, "{line}"),
+        Level::DEBUG => tracing::debug!(target: "guest", guest = true, kind, "{line}"),
+        Level::TRACE => tracing::trace!(target: "guest", guest = true, kind, "{line}"),
+    }
+}
+
+/// One stage of a boot timeline: a run of consecutive console lines from
+/// the same source.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BootStage {
+    /// The Unikraft library tag for kernel log lines (`"libukboot"`),
+    /// `"banner"`, `"kernel"` for untagged kernel lines, or `"app"`.
+    pub name: String,
+    /// Offset of the stage's first line from the start of the run.
+    pub start: Duration,
+    /// Time until the next stage starts (or the end of the run).
+    pub duration: Duration,
+    pub lines: usize,
+}
+
+/// Group `(arrival offset, line)` pairs into stages. Consecutive lines
+/// with the same source merge; `end` closes the last stage.
+pub fn boot_timeline(lines: &[(Duration, &str)], end: Duration) -> Vec<BootStage> {
+    let mut stages: Vec<BootStage> = Vec::new();
+    for &(at, line) in lines {
+        let name = stage_name(line);
+        match stages.last_mut() {
+            Some(last) if last.name == name => last.lines += 1,
+            _ => stages.push(BootStage {
+                name,
+                start: at,
+                duration: Duration::ZERO,
+                lines: 1,
+            }),
+        }
+    }
+    let mut next_start = end;
+    for stage in stages.iter_mut().rev() {
+        stage.duration = next_start.saturating_sub(stage.start);
+        next_start = stage.start;
+    }
+    stages
+}
+
+fn stage_name(line: &str) -> String {
+    if classify_line(line) == LineKind::App {
+        return "app".into();
+    }
+    let line = line.trim_end_matches('\r');
+    if BANNER_PREFIXES.iter().any(|p| line.starts_with(p)) || is_banner_release_line(line) {
+        return "banner".into();
+    }
+    // "...Info: [libukboot] <boot.c @  291> ..." → "libukboot"
+    KERNEL_LOG_LEVELS
+        .iter()
+        .find_map(|lvl| line.find(lvl).map(|i| &line[i + lvl.len()..]))
+        .and_then(|rest| rest.trim_start().strip_prefix('['))
+        .and_then(|rest| rest.split_once(']'))
+        .map_or_else(|| "kernel".into(), |(tag, _)| tag.to_string())
+}
+
+/// The banner's last line: deep indentation, a codename and a version,
+/// e.g. `"                  Pandora 0.18.0~1234abcd"`.
+fn is_banner_release_line(line: &str) -> bool {
+    let body = line.trim_start_matches(' ');
+    if line.len() - body.len() < 10 {
+        return false;
+    }
+    let mut words = body.split_whitespace();
+    let (Some(codename), Some(version), None) = (words.next(), words.next(), words.next()) else {
+        return false;
+    };
+    codename.chars().all(|c| c.is_ascii_alphabetic())
+        && version.split('~').next().is_some_and(|v| {
+            let parts: Vec<&str> = v.split('.').collect();
+            parts.len() == 3
+                && parts
+                    .iter()
+                    .all(|p| !p.is_empty() && p.chars().all(|c| c.is_ascii_digit()))
+        })
+}
+
+/// Split console text into the application's lines and the kernel's,
+/// by [`classify_line`], each keeping its line endings and order.
+pub fn split_channels(text: &str) -> (String, String) {
+    let (mut app, mut kernel) = (String::new(), String::new());
+    for line in text.split_inclusive('\n') {
+        match classify_line(line.trim_end_matches('\n')) {
+            LineKind::App => app.push_str(line),
+            LineKind::Kernel => kernel.push_str(line),
+        }
+    }
+    (app, kernel)
+}
+
+/// The rest of the line after the first `marker` in `raw`, without its
+/// line ending; `None` if the marker never appears. Works on bytes, so
+/// invalid UTF-8 around or inside the value is returned as written.
+///
+/// ```
+/// use hyperlight_unikraft::output::find_marker;
+///
+/// let raw = b"boot\n\xffRESULT:\x00\x01\r\nbye";
+/// assert_eq!(find_marker(raw, "RESULT:"), Some(&b"\x00\x01"[..]));
+/// ```
+pub fn find_marker<'a>(raw: &'a [u8], marker: &str) -> Option<&'a [u8]> {
+    let marker = marker.as_bytes();
+    if marker.is_empty() {
+        return None;
+    }
+    let start = raw.windows(marker.len()).position(|w| w == marker)? + marker.len();
+    let rest = &raw[start..];
+    let line = rest.split(|&b| b == b'\n').next().unwrap_or(rest);
+    Some(line.strip_suffix(b"\r").unwrap_or(line))
+}
+
+/// What a capturing run does once the guest has printed more than
+/// [`VmConfig::with_max_output_bytes`](crate::VmConfig::with_max_output_bytes)
+/// allows. Whatever is left out is counted in
+/// [`VmOutput::dropped_bytes`](crate::VmOutput::dropped_bytes).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum OutputOverflow {
+    /// Keep the start of the output and drop the rest.
+    #[default]
+    KeepHead,
+    /// Keep the end of the output, where errors usually are. Up to twice
+    /// the limit is held while the run lasts.
+    KeepTail,
+    /// Keep the start and stop the guest; the run fails with
+    /// [`Error::OutputLimitExceeded`](crate::Error::OutputLimitExceeded).
+    Abort,
+}
+
+/// Remove ANSI escape sequences from `s`.
+///
+/// Handles CSI (`ESC [ … final`), OSC (`ESC ] … BEL` / `ESC ] … ESC \`)
+/// and the two-byte `ESC <char>` forms. A dangling `ESC` at the end of
+/// the input is dropped.
+pub fn strip_ansi(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            // CSI: parameters/intermediates until a final byte in 0x40..=0x7E.
+            Some('[') => {
+                for c in chars.by_ref() {
+                    if ('\x40'..='\x7e').contains(&c) {
+                        break;
+                    }
+                }
+            }
+            // OSC: terminated by BEL or ST (ESC \).
+            Some(']') => {
+                while let Some(c) = chars.next() {
+                    if c == '\x07' {
+                        break;
+                    }
+                    if c == '\x1b' && chars.peek() == Some(&'\\') {
+                        chars.next();
+                        break;
+                    }
+                }
+            }
+            // Two-byte escape (ESC 7, ESC c, …) or trailing ESC.
+            _ => {}
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn channels_split_app_output_from_kernel_logs() {
+        let text = "Powered by Unikraft\n[    0.010000] Info: [libukboot] init\nresult=42\r\nbase64:QUJD\n[    0.200000] ERR: late\ntail";
+        let (app, kernel) = split_channels(text);
+        assert_eq!(app, "result=42\r\nbase64:QUJD\ntail");
+        assert_eq!(
+            kernel,
+            "Powered by Unikraft\n[    0.010000] Info: [libukboot] init\n[    0.200000] ERR: late\n"
+        );
+        assert_eq!(split_channels(""), (String::new(), String::new()));
+    }
+
+    #[test]
+    fn markers_are_found_in_raw_bytes() {
+        let raw = b"\xfe\xff kernel noise\nDATA:\x89PNG\xff";
+        assert_eq!(find_marker(raw, "DATA:"), Some(&b"\x89PNG\xff"[..]));
+        assert_eq!(find_marker(b"DATA:\n", "DATA:"), Some(&b""[..]));
+        assert_eq!(find_marker(raw, "MISSING:"), None);
+        assert_eq!(find_marker(raw, ""), None);
+    }
+
+    #[test]
+    fn strips_sgr_colors() {
+        assert_eq!(strip_ansi("\x1b[1;31merror\x1b[0m: boom"), "error: boom");
+    }
+
+    #[test]
+    fn strips_osc_titles_with_both_terminators() {
+        assert_eq!(strip_ansi("\x1b]0;title\x07ok"), "ok");
+        assert_eq!(strip_ansi("\x1b]0;title\x1b\\ok"), "ok");
+    }
+
+    #[test]
+    fn leaves_plain_text_and_unicode_alone() {
+        let s = "plain — text ✓\n";
+        assert_eq!(strip_ansi(s), s);
+    }
+
+    #[test]
+    fn classifies_kernel_logs_and_banner() {
+        for line in [
+            "[    0.001234] Info: [libukboot] <boot.c @  291> Unikraft booting",
+            "Warn: [libposix_process] <deprecated.c @  348> stub",
+            "Powered by",
+            "Oo   Oo  ___ (_) | __ __  __ _ ' _) :_",
+            "                  Pandora 0.18.0~5a6c3a4c",
+        ] {
+            assert_eq!(classify_line(line), LineKind::Kernel, "{line:?}");
+        }
+    }
+
+    #[test]
+    fn leaves_app_output_as_app() {
+        for line in ["Hello, world!", "[1, 2, 3]", "    indented code 1.2.3", ""] {
+            assert_eq!(classify_line(line), LineKind::App, "{line:?}");
+        }
+    }
+
+    #[test]
+    fn boot_timeline_merges_consecutive_sources() {
+        let ms = Duration::from_millis;
+        let lines = [
+            (ms(1), "Powered by"),
+            (ms(1), "o.   .o       _ _               __ _"),
+            (ms(3), "[    0.001] Info: [libukboot] <boot.c @  291> init"),
+            (ms(4), "[    0.002] Info: [libukboot] <boot.c @  300> more"),
+            (ms(9), "[    0.007] Info: [libvfscore] <main.c @  12> mount"),
+            (ms(12), "hello"),
+        ];
+        let stages = boot_timeline(&lines, ms(20));
+        let summary: Vec<(&str, Duration, usize)> = stages
+            .iter()
+            .map(|s| (s.name.as_str(), s.duration, s.lines))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("banner", ms(2), 2),
+                ("libukboot", ms(6), 2),
+                ("libvfscore", ms(3), 1),
+                ("app", ms(8), 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn drops_dangling_escape() {
+        assert_eq!(strip_ansi("tail\x1b"), "tail");
+    }
+
+    #[test]
+    fn guest_log_levels_follow_prefixes() {
+        use tracing::Level;
+        assert_eq!(
+            guest_log_level("[    0.001] CRIT: [libukboot] panic"),
+            Level::ERROR
+        );
+        assert_eq!(guest_log_level("Warn: [libvfscore] no root"), Level::WARN);
+        assert_eq!(guest_log_level("dbg:  [libuknetdev] up"), Level::DEBUG);
+        assert_eq!(guest_log_level("WARNING:root:disk low"), Level::WARN);
+        assert_eq!(guest_log_level("error: bad input"), Level::ERROR);
+        assert_eq!(guest_log_level("Errors are fine here"), Level::INFO);
+        assert_eq!(guest_log_level("hello"), Level::INFO);
+    }
+}
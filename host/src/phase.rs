@@ -0,0 +1,126 @@
+//! A fixed breakdown of where a run's time goes.
+//!
+//! The phase names are part of the API: they stay the same across
+//! releases so timings from two library versions can be compared
+//! phase by phase.
+
+use std::time::{Duration, Instant};
+
+/// One stage of a run, in the order they happen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Phase {
+    /// Checking and reading the kernel and initrd from disk, including
+    /// decompressing a zstd initrd.
+    AssetLoad,
+    /// Laying out host regions and building the boot header.
+    InitrdPrepare,
+    /// Creating the Hyperlight sandbox: memory setup, file mappings and
+    /// host function registration.
+    SandboxCreate,
+    /// Guest execution: boot and init, plus the application call for
+    /// runs that make one.
+    Evolve,
+    /// Restoring stderr and collecting console output still in flight.
+    Drain,
+    /// Turning the captured bytes into the returned text.
+    Extract,
+}
+
+impl Phase {
+    pub const ALL: [Phase; 6] = [
+        Phase::AssetLoad,
+        Phase::InitrdPrepare,
+        Phase::SandboxCreate,
+        Phase::Evolve,
+        Phase::Drain,
+        Phase::Extract,
+    ];
+
+    /// Stable `snake_case` name, used as the serialized key.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Phase::AssetLoad => "asset_load",
+            Phase::InitrdPrepare => "initrd_prepare",
+            Phase::SandboxCreate => "sandbox_create",
+            Phase::Evolve => "evolve",
+            Phase::Drain => "drain",
+            Phase::Extract => "extract",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|p| p.as_str() == name)
+    }
+}
+
+impl std::fmt::Display for Phase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Time spent in each [`Phase`] of one run. Phases a run never entered
+/// read as zero.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PhaseTimings {
+    durations: [Duration; Phase::ALL.len()],
+}
+
+impl PhaseTimings {
+    pub fn get(&self, phase: Phase) -> Duration {
+        self.durations[phase as usize]
+    }
+
+    /// Every phase with its duration, in run order.
+    pub fn iter(&self) -> impl Iterator<Item = (Phase, Duration)> + '_ {
+        Phase::ALL.into_iter().map(|p| (p, self.get(p)))
+    }
+
+    pub fn total(&self) -> Duration {
+        self.durations.iter().sum()
+    }
+
+    /// Add `elapsed` to `phase`. Phases can be entered more than once
+    /// (e.g. boot and the app call both count as [`Phase::Evolve`]).
+    pub fn add(&mut self, phase: Phase, elapsed: Duration) {
+        self.durations[phase as usize] += elapsed;
+    }
+
+    /// Run `f`, charging its wall time to `phase`.
+    pub(crate) fn time<T>(&mut self, phase: Phase, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let out = f();
+        self.add(phase, start.elapsed());
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_round_trip_and_phases_accumulate() {
+        for phase in Phase::ALL {
+            assert_eq!(Phase::from_name(phase.as_str()), Some(phase));
+        }
+        let mut t = PhaseTimings::default();
+        t.add(Phase::Evolve, Duration::from_millis(3));
+        t.add(Phase::Evolve, Duration::from_millis(4));
+        t.add(Phase::Drain, Duration::from_millis(1));
+        assert_eq!(t.get(Phase::Evolve), Duration::from_millis(7));
+        assert_eq!(t.get(Phase::AssetLoad), Duration::ZERO);
+        assert_eq!(t.total(), Duration::from_millis(8));
+        assert_eq!(
+            t.iter().map(|(p, _)| p.as_str()).collect::<Vec<_>>(),
+            [
+                "asset_load",
+                "initrd_prepare",
+                "sandbox_create",
+                "evolve",
+                "drain",
+                "extract"
+            ]
+        );
+    }
+}
@@ -0,0 +1,324 @@
+//! Host-mediated byte pipes between sandboxes.
+//!
+//! A pipe is a bounded host buffer with a writing end served to one guest
+//! as `pipe_out_*` tools and a reading end served to another as
+//! `pipe_in_*`, so unikernel stages compose like a shell pipeline:
+//!
+//! ```no_run
+//! use hyperlight_unikraft::{pipe, Sandbox};
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! let stages = vec![
+//!     Sandbox::builder("python-kernel").initrd_file("gen.cpio").arg("/gen.py"),
+//!     Sandbox::builder("python-kernel").initrd_file("sort.cpio").arg("/sort.py"),
+//! ];
+//! for result in pipe::chain(stages, pipe::DEFAULT_CAPACITY) {
+//!     result?;
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Writers block while the buffer is full and readers while it is empty,
+//! so a fast producer can't run ahead of its consumer by more than the
+//! capacity. Closing the writing end is end-of-file for the reader;
+//! closing the reading end makes further writes fail, like `EPIPE`.
+
+use anyhow::{anyhow, bail, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde_json::json;
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+
+use crate::placement::Placer;
+use crate::{SandboxBuilder, ToolRegistry};
+
+/// Buffer size [`chain`] callers usually want.
+pub const DEFAULT_CAPACITY: usize = 1024 * 1024;
+
+/// Largest `pipe_in_read` chunk served in one call.
+pub const PIPE_READ_MAX_LEN: u64 = 1024 * 1024;
+
+/// A new pipe buffering at most `capacity` bytes.
+pub fn pipe(capacity: usize) -> (PipeWriter, PipeReader) {
+    let shared = Arc::new(Shared {
+        state: Mutex::default(),
+        readable: Condvar::new(),
+        writable: Condvar::new(),
+        capacity: capacity.max(1),
+    });
+    (PipeWriter(shared.clone()), PipeReader(shared))
+}
+
+#[derive(Debug)]
+struct Shared {
+    state: Mutex<State>,
+    readable: Condvar,
+    writable: Condvar,
+    capacity: usize,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    buf: VecDeque<u8>,
+    write_closed: bool,
+    read_closed: bool,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// The writing end. Clones share the end; it stays open until
+/// [`close`](Self::close) is called on any of them.
+#[derive(Debug, Clone)]
+pub struct PipeWriter(Arc<Shared>);
+
+/// The reading end. Clones share the end, as for [`PipeWriter`].
+#[derive(Debug, Clone)]
+pub struct PipeReader(Arc<Shared>);
+
+impl PipeWriter {
+    /// Write all of `data`, blocking while the buffer is full. Fails if
+    /// the reading end is closed (or this end already was).
+    pub fn write(&self, mut data: &[u8]) -> Result<()> {
+        let shared = &*self.0;
+        let mut state = shared.lock();
+        while !data.is_empty() {
+            if state.read_closed {
+                bail!("broken pipe: the reading end is closed");
+            }
+            if state.write_closed {
+                bail!("write to a closed pipe");
+            }
+            let room = shared.capacity.saturating_sub(state.buf.len());
+            if room == 0 {
+                state = shared
+                    .writable
+                    .wait(state)
+                    .unwrap_or_else(|e| e.into_inner());
+                continue;
+            }
+            let n = room.min(data.len());
+            state.buf.extend(&data[..n]);
+            data = &data[n..];
+            shared.readable.notify_all();
+        }
+        Ok(())
+    }
+
+    /// Close the writing end: the reader sees end-of-file once it has
+    /// drained the buffer.
+    pub fn close(&self) {
+        self.0.lock().write_closed = true;
+        self.0.readable.notify_all();
+    }
+
+    /// Register the guest-facing tools on `registry`:
+    ///
+    /// - `pipe_out_write` — `{ data: "<base64>" }` → `{}`, blocking while
+    ///   the pipe is full.
+    /// - `pipe_out_close` — `{}` → `{}`.
+    pub fn register(self, registry: &mut ToolRegistry) {
+        let writer = self.clone();
+        registry.register("pipe_out_write", move |args| {
+            let data = args["data"]
+                .as_str()
+                .ok_or_else(|| anyhow!("pipe_out_write: missing 'data'"))?;
+            let data = STANDARD
+                .decode(data)
+                .map_err(|e| anyhow!("pipe_out_write: bad base64 data: {}", e))?;
+            writer
+                .write(&data)
+                .map_err(|e| anyhow!("pipe_out_write: {e}"))?;
+            Ok(json!({}))
+        });
+        registry.register("pipe_out_close", move |_| {
+            self.close();
+            Ok(json!({}))
+        });
+    }
+}
+
+impl PipeReader {
+    /// Read up to `max` bytes, blocking until some are buffered. An empty
+    /// result means end-of-file: the writer closed and everything it
+    /// wrote has been read (or this end was closed).
+    pub fn read(&self, max: usize) -> Vec<u8> {
+        let shared = &*self.0;
+        let mut state = shared.lock();
+        while state.buf.is_empty() && !state.write_closed && !state.read_closed {
+            state = shared
+                .readable
+                .wait(state)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+        if state.read_closed {
+            return Vec::new();
+        }
+        let n = max.min(state.buf.len());
+        let out: Vec<u8> = state.buf.drain(..n).collect();
+        shared.writable.notify_all();
+        out
+    }
+
+    /// Everything until end-of-file.
+    pub fn read_to_end(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        loop {
+            let chunk = self.read(PIPE_READ_MAX_LEN as usize);
+            if chunk.is_empty() {
+                return out;
+            }
+            out.extend(chunk);
+        }
+    }
+
+    /// Close the reading end: buffered data is dropped and writers fail
+    /// instead of blocking.
+    pub fn close(&self) {
+        let mut state = self.0.lock();
+        state.read_closed = true;
+        state.buf.clear();
+        self.0.writable.notify_all();
+        self.0.readable.notify_all();
+    }
+
+    /// Register `pipe_in_read` on `registry`: `{ max }` →
+    /// `{ data: "<base64>", eof }`, blocking until data is available.
+    /// `eof` is set once the writer has closed and nothing is left, so
+    /// the guest can stop without another round trip.
+    pub fn register(self, registry: &mut ToolRegistry) {
+        registry.register("pipe_in_read", move |args| {
+            let max = args["max"].as_u64().unwrap_or(PIPE_READ_MAX_LEN);
+            if max == 0 || max > PIPE_READ_MAX_LEN {
+                bail!("pipe_in_read: 'max' must be 1..={PIPE_READ_MAX_LEN}");
+            }
+            let data = self.read(max as usize);
+            let eof = {
+                let state = self.0.lock();
+                state.buf.is_empty() && (state.write_closed || state.read_closed)
+            };
+            Ok(json!({ "data": STANDARD.encode(&data), "eof": eof }))
+        });
+    }
+}
+
+/// Run `stages` concurrently, each on its own thread, with a pipe of
+/// `capacity` bytes from every stage's `pipe_out_*` tools to the next
+/// one's `pipe_in_read`. A stage's output end is closed when it exits
+/// and its input end too, so neighbours never wait on a finished stage.
+/// Returns each stage's result, in order.
+pub fn chain(stages: Vec<SandboxBuilder>, capacity: usize) -> Vec<Result<()>> {
+    let mut inputs = vec![None];
+    let mut outputs = Vec::new();
+    for _ in 1..stages.len() {
+        let (w, r) = pipe(capacity);
+        outputs.push(Some(w));
+        inputs.push(Some(r));
+    }
+    outputs.push(None);
+
+    std::thread::scope(|s| {
+        let threads: Vec<_> = stages
+            .into_iter()
+            .zip(inputs.into_iter().zip(outputs))
+            .map(|(mut builder, (input, output))| {
+                if let Some(ref r) = input {
+                    builder = builder.pipe_in(r.clone());
+                }
+                if let Some(ref w) = output {
+                    builder = builder.pipe_out(w.clone());
+                }
+                builder = builder.placement(Placer::global());
+                s.spawn(move || {
+                    let result = builder
+                        .build()
+                        .and_then(|mut sandbox| {
+                            sandbox.restore()?;
+                            sandbox.call_run()
+                        })
+                        .map_err(anyhow::Error::from);
+                    if let Some(w) = output {
+                        w.close();
+                    }
+                    if let Some(r) = input {
+                        r.close();
+                    }
+                    result
+                })
+            })
+            .collect();
+        threads
+            .into_iter()
+            .map(|t| {
+                t.join()
+                    .unwrap_or_else(|_| Err(anyhow!("pipeline stage panicked")))
+            })
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn call(registry: &ToolRegistry, name: &str, args: serde_json::Value) -> serde_json::Value {
+        let req = json!({ "name": name, "args": args });
+        serde_json::from_slice(&registry.dispatch(req.to_string().as_bytes())).unwrap()
+    }
+
+    #[test]
+    fn writer_blocks_at_capacity_until_read() {
+        let (w, r) = pipe(4);
+        let producer = std::thread::spawn(move || {
+            w.write(b"hello, pipe").unwrap();
+            w.close();
+        });
+        std::thread::sleep(Duration::from_millis(20));
+        // Never more than the capacity buffered.
+        assert_eq!(r.0.lock().buf.len(), 4);
+        assert_eq!(r.read(2), b"he");
+        assert_eq!(r.read_to_end(), b"llo, pipe");
+        assert!(r.read(8).is_empty());
+        producer.join().unwrap();
+    }
+
+    #[test]
+    fn closing_the_reader_breaks_the_pipe() {
+        let (w, r) = pipe(2);
+        let producer = std::thread::spawn(move || w.write(b"more than two"));
+        std::thread::sleep(Duration::from_millis(20));
+        r.close();
+        assert!(producer.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn tools_carry_base64_and_report_eof() {
+        let (w, r) = pipe(64);
+        let mut out = ToolRegistry::new();
+        w.register(&mut out);
+        let mut input = ToolRegistry::new();
+        r.register(&mut input);
+
+        let sent = call(
+            &out,
+            "pipe_out_write",
+            json!({ "data": STANDARD.encode("a\0b") }),
+        );
+        assert_eq!(sent["result"], json!({}));
+        call(&out, "pipe_out_close", json!({}));
+        let got = call(&input, "pipe_in_read", json!({ "max": 2 }))["result"].clone();
+        assert_eq!(got["data"], STANDARD.encode("a\0"));
+        assert_eq!(got["eof"], false);
+        let got = call(&input, "pipe_in_read", json!({}))["result"].clone();
+        assert_eq!(got["data"], STANDARD.encode("b"));
+        assert_eq!(got["eof"], true);
+        assert!(call(&out, "pipe_out_write", json!({ "data": "" }))["error"].is_null());
+        assert!(call(&out, "pipe_out_write", json!({ "data": "eA==" }))["error"].is_string());
+    }
+}
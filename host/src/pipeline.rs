@@ -0,0 +1,186 @@
+//! Composable initrd transformations.
+//!
+//! An [`InitrdPipeline`] runs a list of [`InitrdLayer`]s over the initrd
+//! bytes in order, so embedders can slot their own steps (license
+//! stamping, policy file injection, ...) next to the built-in ones
+//! instead of rebuilding the archive by hand:
+//!
+//! ```no_run
+//! use hyperlight_unikraft::pipeline::{decompress, inject_files, InitrdPipeline};
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! let pipeline = InitrdPipeline::new()
+//!     .layer(decompress)
+//!     .layer(inject_files([("etc/policy.json", b"{}".to_vec())]))
+//!     .layer(|initrd: Vec<u8>| -> anyhow::Result<Vec<u8>> { Ok(initrd) });
+//! let initrd = pipeline.run(std::fs::read("app.cpio.zst")?)?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Pass a pipeline to [`VmConfig::with_initrd_pipeline`] to have the run
+//! functions apply it; they add their own boot header afterwards, so
+//! [`prepend_header`] is only for callers assembling an initrd for
+//! [`run_vm`](crate::run_vm)-style APIs themselves.
+//!
+//! [`VmConfig::with_initrd_pipeline`]: crate::VmConfig::with_initrd_pipeline
+
+use anyhow::{Context, Result};
+use std::sync::Arc;
+
+use crate::rootfs;
+
+/// One step of an [`InitrdPipeline`]: takes the initrd so far and returns
+/// the transformed one. An empty buffer means "no initrd".
+///
+/// Implemented for any `Fn(Vec<u8>) -> Result<Vec<u8>>`.
+pub trait InitrdLayer: Send + Sync {
+    fn apply(&self, initrd: Vec<u8>) -> Result<Vec<u8>>;
+}
+
+impl<F> InitrdLayer for F
+where
+    F: Fn(Vec<u8>) -> Result<Vec<u8>> + Send + Sync,
+{
+    fn apply(&self, initrd: Vec<u8>) -> Result<Vec<u8>> {
+        self(initrd)
+    }
+}
+
+/// An ordered list of [`InitrdLayer`]s. Cheap to clone; layers are shared.
+#[derive(Clone, Default)]
+pub struct InitrdPipeline {
+    layers: Vec<Arc<dyn InitrdLayer>>,
+}
+
+impl InitrdPipeline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append `layer`; it runs after every layer added before it.
+    pub fn layer<L: InitrdLayer + 'static>(mut self, layer: L) -> Self {
+        self.layers.push(Arc::new(layer));
+        self
+    }
+
+    /// Run every layer in order. Errors name the failing layer's position.
+    pub fn run(&self, mut initrd: Vec<u8>) -> Result<Vec<u8>> {
+        for (i, layer) in self.layers.iter().enumerate() {
+            initrd = layer
+                .apply(initrd)
+                .with_context(|| format!("initrd pipeline layer {}", i + 1))?;
+        }
+        Ok(initrd)
+    }
+
+    pub fn len(&self) -> usize {
+        self.layers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.layers.is_empty()
+    }
+}
+
+impl std::fmt::Debug for InitrdPipeline {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("InitrdPipeline")
+            .field("layers", &self.layers.len())
+            .finish()
+    }
+}
+
+/// Layer: decompress a gzip or zstd initrd; anything else passes through.
+pub fn decompress(initrd: Vec<u8>) -> Result<Vec<u8>> {
+    use std::io::Read;
+    if initrd.starts_with(rootfs::ZSTD_MAGIC) {
+        return zstd::decode_all(&initrd[..]).context("decompressing zstd initrd");
+    }
+    if initrd.starts_with(rootfs::GZIP_MAGIC) {
+        let mut out = Vec::new();
+        flate2::read::GzDecoder::new(&initrd[..])
+            .read_to_end(&mut out)
+            .context("decompressing gzip initrd")?;
+        return Ok(out);
+    }
+    Ok(initrd)
+}
+
+/// Layer: add `(path, contents)` files to the cpio (see
+/// [`rootfs::append_files`]). With no initrd, a new archive is created.
+pub fn inject_files<I, S>(files: I) -> impl InitrdLayer
+where
+    I: IntoIterator<Item = (S, Vec<u8>)>,
+    S: Into<String>,
+{
+    let files: Vec<(String, Vec<u8>)> = files.into_iter().map(|(p, d)| (p.into(), d)).collect();
+    move |initrd: Vec<u8>| -> Result<Vec<u8>> {
+        let files: Vec<(&str, &[u8])> = files
+            .iter()
+            .map(|(p, d)| (p.as_str(), d.as_slice()))
+            .collect();
+        rootfs::append_files(&initrd, &files)
+    }
+}
+
+/// Layer: prepend the boot header carrying `app_args`, as
+/// [`prepend_cmdline_to_initrd`](crate::prepend_cmdline_to_initrd) does.
+pub fn prepend_header<I, S>(app_args: I) -> impl InitrdLayer
+where
+    I: IntoIterator<Item = S>,
+    S: Into<String>,
+{
+    let args: Vec<String> = app_args.into_iter().map(Into::into).collect();
+    move |initrd: Vec<u8>| -> Result<Vec<u8>> {
+        let data = (!initrd.is_empty()).then_some(&initrd[..]);
+        Ok(crate::prepend_cmdline_to_initrd(data, &args, &[]).unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layers_run_in_order_and_errors_name_the_layer() {
+        let pipeline = InitrdPipeline::new()
+            .layer(|mut b: Vec<u8>| -> Result<Vec<u8>> {
+                b.push(1);
+                Ok(b)
+            })
+            .layer(|mut b: Vec<u8>| -> Result<Vec<u8>> {
+                b.push(2);
+                Ok(b)
+            });
+        assert_eq!(pipeline.run(vec![0]).unwrap(), [0, 1, 2]);
+
+        let failing = pipeline.layer(|_| -> Result<Vec<u8>> { anyhow::bail!("stamp missing") });
+        let err = failing.run(Vec::new()).unwrap_err();
+        assert_eq!(format!("{err:#}"), "initrd pipeline layer 3: stamp missing");
+    }
+
+    #[test]
+    fn built_in_layers_compose() {
+        let mut cpio = rootfs::CpioWriter::new(Vec::new());
+        cpio.file("hello.txt", 0o644, 0, b"hi").unwrap();
+        let plain = cpio.finish().unwrap();
+        let compressed = zstd::encode_all(&plain[..], 0).unwrap();
+
+        let out = InitrdPipeline::new()
+            .layer(decompress)
+            .layer(inject_files([("etc/policy.json", b"{}".to_vec())]))
+            .run(compressed)
+            .unwrap();
+        let (entries, _) = rootfs::scan(&out).unwrap();
+        let names: Vec<_> = entries.iter().map(|e| e.name).collect();
+        assert_eq!(names, ["hello.txt", "etc", "etc/policy.json"]);
+
+        let with_header = InitrdPipeline::new()
+            .layer(prepend_header(["/app.py"]))
+            .run(out.clone())
+            .unwrap();
+        assert!(with_header.starts_with(crate::CMDLINE_MAGIC));
+        assert!(with_header.ends_with(&out));
+    }
+}
@@ -0,0 +1,444 @@
+//! Spreading VM threads across CPUs and NUMA nodes.
+//!
+//! A vCPU runs on the thread that calls into the sandbox, and guest
+//! memory is allocated on first touch — during the build — on that
+//! thread's NUMA node. A [`Placer`] picks a CPU for each new sandbox and
+//! pins the building thread to it:
+//!
+//! - a node whose free memory fits the guest heap (on top of the heaps
+//!   already placed there) is preferred;
+//! - within that, the CPU with the fewest placed sandboxes, then the
+//!   least heap placed on it, then the lowest recent utilization (from
+//!   `/proc/stat`).
+//!
+//! Only CPUs in the process's affinity mask are used, so `taskset` and
+//! cgroup cpusets are respected. Placements are released when the
+//! sandbox is dropped, and the pinned thread gets back the affinity it
+//! had before. Elsewhere than Linux placement is a no-op.
+
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// How stale the utilization sample may get before it is re-read.
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Assigns sandboxes to CPUs; see the [module docs](self).
+#[derive(Debug)]
+pub struct Placer {
+    cpus: Vec<Cpu>,
+    nodes: usize,
+    /// Free memory of a NUMA node, in bytes.
+    free_memory: fn(usize) -> Option<u64>,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Cpu {
+    id: usize,
+    node: usize,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// Per CPU (index into `cpus`): sandboxes and heap bytes placed.
+    vms: Vec<usize>,
+    heap: Vec<u64>,
+    /// Per CPU: busy fraction over the last sample interval.
+    busy: Vec<f64>,
+    sample: Option<(Instant, Vec<(u64, u64)>)>,
+}
+
+/// A sandbox's claim on a CPU, released on drop. A thread it
+/// [`pin`](Placement::pin)ned gets its previous affinity back then.
+#[derive(Debug)]
+pub struct Placement {
+    placer: Arc<Placer>,
+    index: usize,
+    heap: u64,
+    pinned: Option<sys::Pinned>,
+}
+
+impl Placer {
+    /// A placer over the CPUs this process may run on.
+    pub fn detect() -> Arc<Self> {
+        let cpus = sys::allowed_cpus();
+        let cpus = if cpus.is_empty() {
+            vec![Cpu { id: 0, node: 0 }]
+        } else {
+            cpus
+        };
+        Arc::new(Self::with_cpus(cpus))
+    }
+
+    /// The process-wide placer, shared by everything that starts VMs
+    /// concurrently.
+    pub fn global() -> Arc<Self> {
+        static GLOBAL: OnceLock<Arc<Placer>> = OnceLock::new();
+        GLOBAL.get_or_init(Self::detect).clone()
+    }
+
+    fn with_cpus(cpus: Vec<Cpu>) -> Self {
+        let nodes = cpus.iter().map(|c| c.node + 1).max().unwrap_or(1);
+        let state = State {
+            vms: vec![0; cpus.len()],
+            heap: vec![0; cpus.len()],
+            busy: vec![0.0; cpus.len()],
+            sample: None,
+        };
+        Self {
+            cpus,
+            nodes,
+            free_memory: sys::node_free_memory,
+            state: Mutex::new(state),
+        }
+    }
+
+    /// CPU ids placements choose from.
+    pub fn cpus(&self) -> Vec<usize> {
+        self.cpus.iter().map(|c| c.id).collect()
+    }
+
+    /// Claim a CPU for a sandbox with a `heap_size`-byte heap.
+    pub fn place(self: &Arc<Self>, heap_size: u64) -> Placement {
+        let node_free: Vec<Option<u64>> = (0..self.nodes).map(self.free_memory).collect();
+        let mut state = self.lock();
+        self.refresh_busy(&mut state);
+        let index = choose(&self.cpus, &state, &node_free, heap_size);
+        state.vms[index] += 1;
+        state.heap[index] += heap_size;
+        Placement {
+            placer: self.clone(),
+            index,
+            heap: heap_size,
+            pinned: None,
+        }
+    }
+
+    fn refresh_busy(&self, state: &mut State) {
+        if state
+            .sample
+            .as_ref()
+            .is_some_and(|(at, _)| at.elapsed() < SAMPLE_INTERVAL)
+        {
+            return;
+        }
+        let ids: Vec<usize> = self.cpus.iter().map(|c| c.id).collect();
+        let Some(now) = sys::cpu_ticks(&ids) else {
+            return;
+        };
+        if let Some((_, ref before)) = state.sample {
+            state.busy = before
+                .iter()
+                .zip(&now)
+                .map(|(&(b0, t0), &(b1, t1))| {
+                    let total = t1.saturating_sub(t0);
+                    if total == 0 {
+                        0.0
+                    } else {
+                        b1.saturating_sub(b0) as f64 / total as f64
+                    }
+                })
+                .collect();
+        }
+        state.sample = Some((Instant::now(), now));
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// The index of the best CPU for a `heap`-byte guest.
+fn choose(cpus: &[Cpu], state: &State, node_free: &[Option<u64>], heap: u64) -> usize {
+    let mut node_heap = vec![0u64; node_free.len()];
+    for (cpu, placed) in cpus.iter().zip(&state.heap) {
+        node_heap[cpu.node] += placed;
+    }
+    // Unknown free memory counts as fitting.
+    let fits = |node: usize| {
+        node_free[node].is_none_or(|free| free.saturating_sub(node_heap[node]) >= heap)
+    };
+    (0..cpus.len())
+        .min_by(|&a, &b| {
+            let key = |i: usize| (!fits(cpus[i].node), state.vms[i], state.heap[i]);
+            key(a)
+                .cmp(&key(b))
+                .then(state.busy[a].total_cmp(&state.busy[b]))
+        })
+        .unwrap_or(0)
+}
+
+impl Placement {
+    /// The chosen CPU id.
+    pub fn cpu(&self) -> usize {
+        self.placer.cpus[self.index].id
+    }
+
+    /// The chosen CPU's NUMA node.
+    pub fn node(&self) -> usize {
+        self.placer.cpus[self.index].node
+    }
+
+    /// Pin the calling thread to the chosen CPU until the placement is
+    /// dropped, which restores the affinity the thread had before.
+    /// Pinning again moves the pin to the calling thread.
+    pub fn pin(&mut self) -> anyhow::Result<()> {
+        if let Some(pinned) = self.pinned.take() {
+            pinned.restore();
+        }
+        self.pinned = Some(sys::pin_current_thread(self.cpu())?);
+        Ok(())
+    }
+}
+
+impl Drop for Placement {
+    fn drop(&mut self) {
+        if let Some(pinned) = self.pinned.take() {
+            pinned.restore();
+        }
+        let mut state = self.placer.lock();
+        state.vms[self.index] -= 1;
+        state.heap[self.index] -= self.heap;
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use super::Cpu;
+    use nix::sched::{sched_getaffinity, sched_setaffinity, CpuSet};
+    use nix::unistd::Pid;
+
+    pub fn allowed_cpus() -> Vec<Cpu> {
+        let Ok(mask) = sched_getaffinity(Pid::from_raw(0)) else {
+            return Vec::new();
+        };
+        let nodes = node_cpus();
+        (0..CpuSet::count())
+            .filter(|&id| mask.is_set(id).unwrap_or(false))
+            .map(|id| Cpu {
+                id,
+                node: nodes
+                    .iter()
+                    .find(|(_, cpus)| cpus.contains(&id))
+                    .map_or(0, |(node, _)| *node),
+            })
+            .collect()
+    }
+
+    /// `(node, cpus)` from sysfs; empty without NUMA support.
+    fn node_cpus() -> Vec<(usize, Vec<usize>)> {
+        let Ok(dir) = std::fs::read_dir("/sys/devices/system/node") else {
+            return Vec::new();
+        };
+        dir.filter_map(|e| {
+            let e = e.ok()?;
+            let node = e.file_name().to_str()?.strip_prefix("node")?.parse().ok()?;
+            let list = std::fs::read_to_string(e.path().join("cpulist")).ok()?;
+            Some((node, super::parse_cpu_list(&list)))
+        })
+        .collect()
+    }
+
+    pub fn node_free_memory(node: usize) -> Option<u64> {
+        let path = format!("/sys/devices/system/node/node{node}/meminfo");
+        let kb = match std::fs::read_to_string(path) {
+            Ok(text) => text
+                .lines()
+                .find_map(|l| l.split_once("MemFree:"))?
+                .1
+                .trim()
+                .strip_suffix("kB")?
+                .trim()
+                .parse::<u64>()
+                .ok()?,
+            Err(_) if node == 0 => std::fs::read_to_string("/proc/meminfo")
+                .ok()?
+                .lines()
+                .find_map(|l| l.strip_prefix("MemAvailable:"))?
+                .trim()
+                .strip_suffix("kB")?
+                .trim()
+                .parse::<u64>()
+                .ok()?,
+            Err(_) => return None,
+        };
+        Some(kb * 1024)
+    }
+
+    /// `(busy, total)` jiffies for each of `ids`, from `/proc/stat`.
+    pub fn cpu_ticks(ids: &[usize]) -> Option<Vec<(u64, u64)>> {
+        let stat = std::fs::read_to_string("/proc/stat").ok()?;
+        let mut by_id = std::collections::HashMap::new();
+        for line in stat.lines() {
+            let mut fields = line.split_whitespace();
+            let Some(id) = fields.next()?.strip_prefix("cpu") else {
+                continue;
+            };
+            let Ok(id) = id.parse::<usize>() else {
+                continue;
+            };
+            let ticks: Vec<u64> = fields.filter_map(|f| f.parse().ok()).collect();
+            let total: u64 = ticks.iter().sum();
+            // idle + iowait
+            let idle = ticks.get(3).copied().unwrap_or(0) + ticks.get(4).copied().unwrap_or(0);
+            by_id.insert(id, (total - idle, total));
+        }
+        Some(
+            ids.iter()
+                .map(|id| by_id.get(id).copied().unwrap_or((0, 0)))
+                .collect(),
+        )
+    }
+
+    /// A pinned thread and the affinity it had before.
+    #[derive(Debug)]
+    pub struct Pinned {
+        thread: Pid,
+        mask: CpuSet,
+    }
+
+    impl Pinned {
+        /// Give the thread its old affinity back. By thread ID, so it
+        /// works from whichever thread drops the placement.
+        pub fn restore(self) {
+            if let Err(e) = sched_setaffinity(self.thread, &self.mask) {
+                tracing::debug!(thread = %self.thread, "restoring CPU affinity failed: {e}");
+            }
+        }
+    }
+
+    pub fn pin_current_thread(cpu: usize) -> anyhow::Result<Pinned> {
+        let thread = nix::unistd::gettid();
+        let mask = sched_getaffinity(thread)?;
+        let mut set = CpuSet::new();
+        set.set(cpu)?;
+        sched_setaffinity(thread, &set)?;
+        Ok(Pinned { thread, mask })
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use super::Cpu;
+
+    pub fn allowed_cpus() -> Vec<Cpu> {
+        Vec::new()
+    }
+
+    pub fn node_free_memory(_node: usize) -> Option<u64> {
+        None
+    }
+
+    pub fn cpu_ticks(_ids: &[usize]) -> Option<Vec<(u64, u64)>> {
+        None
+    }
+
+    #[derive(Debug)]
+    pub struct Pinned;
+
+    impl Pinned {
+        pub fn restore(self) {}
+    }
+
+    pub fn pin_current_thread(_cpu: usize) -> anyhow::Result<Pinned> {
+        Ok(Pinned)
+    }
+}
+
+/// Parse a sysfs CPU list such as `0-3,8,10-11`.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_cpu_list(list: &str) -> Vec<usize> {
+    list.trim()
+        .split(',')
+        .filter(|s| !s.is_empty())
+        .flat_map(|range| {
+            let (lo, hi) = range.split_once('-').unwrap_or((range, range));
+            lo.parse::<usize>()
+                .ok()
+                .zip(hi.parse::<usize>().ok())
+                .into_iter()
+                .flat_map(|(lo, hi)| lo..=hi)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GIB: u64 = 1 << 30;
+
+    fn two_nodes() -> Arc<Placer> {
+        let cpus = (0..4).map(|id| Cpu { id, node: id / 2 }).collect();
+        Arc::new(Placer {
+            free_memory: |_| None,
+            ..Placer::with_cpus(cpus)
+        })
+    }
+
+    #[test]
+    fn sandboxes_spread_before_stacking() {
+        let placer = two_nodes();
+        let placed: Vec<Placement> = (0..4).map(|_| placer.place(GIB)).collect();
+        let mut cpus: Vec<usize> = placed.iter().map(Placement::cpu).collect();
+        cpus.sort();
+        assert_eq!(cpus, [0, 1, 2, 3]);
+
+        drop(placed);
+        let again = placer.place(GIB);
+        assert_eq!(placer.lock().vms.iter().sum::<usize>(), 1);
+        drop(again);
+        assert!(placer.lock().heap.iter().all(|&h| h == 0));
+    }
+
+    #[test]
+    fn heap_that_does_not_fit_goes_to_another_node() {
+        let placer = two_nodes();
+        let state = placer.lock();
+        // Node 0 has 2 GiB free, node 1 has 8 GiB.
+        let free = [Some(2 * GIB), Some(8 * GIB)];
+        assert_eq!(
+            placer.cpus[choose(&placer.cpus, &state, &free, GIB)].node,
+            0
+        );
+        assert_eq!(
+            placer.cpus[choose(&placer.cpus, &state, &free, 4 * GIB)].node,
+            1
+        );
+    }
+
+    #[test]
+    fn busy_cpus_lose_ties() {
+        let placer = two_nodes();
+        let mut state = placer.lock();
+        state.busy = vec![0.9, 0.8, 0.1, 0.95];
+        assert_eq!(choose(&placer.cpus, &state, &[None, None], GIB), 2);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn dropping_a_placement_unpins_the_thread() {
+        use nix::sched::sched_getaffinity;
+        use nix::unistd::Pid;
+
+        // On a thread of its own so the test harness's stays untouched.
+        std::thread::spawn(|| {
+            let before = sched_getaffinity(Pid::from_raw(0)).unwrap();
+            let placer = Placer::detect();
+            let mut placement = placer.place(GIB);
+            placement.pin().unwrap();
+            let pinned = sched_getaffinity(Pid::from_raw(0)).unwrap();
+            assert!(pinned.is_set(placement.cpu()).unwrap());
+            drop(placement);
+            assert_eq!(sched_getaffinity(Pid::from_raw(0)).unwrap(), before);
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn cpu_lists_parse() {
+        assert_eq!(parse_cpu_list("0-3,8,10-11\n"), [0, 1, 2, 3, 8, 10, 11]);
+        assert!(parse_cpu_list("").is_empty());
+    }
+}
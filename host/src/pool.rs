@@ -0,0 +1,363 @@
+//! A pool of VMs booted ahead of requests.
+//!
+//! Creating the sandbox and booting the kernel dominate the latency of a
+//! short run. A [`VmPool`] keeps a few [`Vm`]s [`create`](Vm::create)d
+//! for one configuration and hands one to each request, so the request
+//! pays only for the application; a background thread boots a
+//! replacement for every VM taken.
+//!
+//! ```no_run
+//! use hyperlight_unikraft::pool::{PoolOptions, VmPool};
+//! use hyperlight_unikraft::Vm;
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! let rootfs = std::fs::read("python.cpio")?;
+//! let pool = VmPool::new(
+//!     move || {
+//!         Vm::builder()
+//!             .kernel("python-kernel")
+//!             .initrd_bytes(rootfs.clone())
+//!             .arg("/handler.py")
+//!             .build()
+//!     },
+//!     PoolOptions { size: 4, max_concurrency: 8 },
+//! );
+//! let out = pool.run()?; // per request
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! A failed boot is retried with exponential backoff. After
+//! [`QUARANTINE_AFTER`] failures in a row the pool stops booting, and
+//! runs that find it empty fail with the last error until
+//! [`VmPool::resume`] is called.
+//!
+//! Every pooled VM is the same run, booted before its request is known;
+//! per-request input reaches it through a host function registered with
+//! [`VmBuilder::tool`](crate::VmBuilder::tool), e.g. one that pops the
+//! request from a queue the caller fills.
+
+use anyhow::{anyhow, Result};
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::{Vm, VmOutput};
+
+/// How long the refill thread waits after a failed boot before trying
+/// again. Doubles with each further failure, up to [`REFILL_RETRY_MAX`].
+pub const REFILL_RETRY: Duration = Duration::from_secs(1);
+
+/// The longest wait between boot attempts.
+pub const REFILL_RETRY_MAX: Duration = Duration::from_secs(30);
+
+/// Boot failures in a row after which the pool stops trying.
+pub const QUARANTINE_AFTER: u32 = 5;
+
+/// How big a [`VmPool`] is.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
+pub struct PoolOptions {
+    /// VMs kept booted and waiting.
+    pub size: usize,
+    /// Most runs in progress at once; further requests wait their turn.
+    pub max_concurrency: usize,
+}
+
+impl Default for PoolOptions {
+    fn default() -> Self {
+        Self {
+            size: 2,
+            max_concurrency: 4,
+        }
+    }
+}
+
+/// VMs booted in the background and taken one per run.
+pub struct VmPool {
+    shared: Arc<Shared>,
+    refill: Option<JoinHandle<()>>,
+}
+
+type MakeVm = Box<dyn Fn() -> Result<Vm> + Send + Sync>;
+
+struct Shared {
+    make: MakeVm,
+    options: PoolOptions,
+    /// The first backoff; [`REFILL_RETRY`] outside tests.
+    retry: Duration,
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    ready: VecDeque<Vm>,
+    running: usize,
+    /// The last boot failure, until a boot succeeds again.
+    error: Option<String>,
+    /// Boot failures since the last success.
+    failures: u32,
+    /// Refilling stopped after [`QUARANTINE_AFTER`] failures.
+    quarantined: bool,
+    closed: bool,
+}
+
+impl VmPool {
+    /// Keep `options.size` VMs from `make` booted. `make` is called on
+    /// the refill thread, once per VM.
+    pub fn new<F>(make: F, options: PoolOptions) -> Self
+    where
+        F: Fn() -> Result<Vm> + Send + Sync + 'static,
+    {
+        Self::with_retry(make, options, REFILL_RETRY)
+    }
+
+    fn with_retry<F>(make: F, options: PoolOptions, retry: Duration) -> Self
+    where
+        F: Fn() -> Result<Vm> + Send + Sync + 'static,
+    {
+        let options = PoolOptions {
+            size: options.size.max(1),
+            max_concurrency: options.max_concurrency.max(1),
+        };
+        let shared = Arc::new(Shared {
+            make: Box::new(make),
+            options,
+            retry,
+            state: Mutex::default(),
+            changed: Condvar::new(),
+        });
+        let refiller = shared.clone();
+        let refill = std::thread::Builder::new()
+            .name("hl-pool-refill".into())
+            .spawn(move || refiller.refill())
+            .ok();
+        if refill.is_none() {
+            shared.lock().error = Some("spawning the pool's refill thread failed".into());
+        }
+        Self { shared, refill }
+    }
+
+    /// Run the application on a booted VM, waiting for one (and for a
+    /// free slot under `max_concurrency`) if need be. Fails without
+    /// waiting if the pool is empty because booting is failing, or has
+    /// been quarantined.
+    pub fn run(&self) -> Result<VmOutput> {
+        let (vm, _slot) = self.take()?;
+        vm.run()
+    }
+
+    /// VMs booted and waiting.
+    pub fn ready(&self) -> usize {
+        self.shared.lock().ready.len()
+    }
+
+    /// Runs in progress.
+    pub fn running(&self) -> usize {
+        self.shared.lock().running
+    }
+
+    /// Whether the pool stopped booting after [`QUARANTINE_AFTER`]
+    /// failures in a row.
+    pub fn quarantined(&self) -> bool {
+        self.shared.lock().quarantined
+    }
+
+    /// Start booting again after a quarantine, e.g. once the kernel or
+    /// host problem is fixed. Runs keep failing fast until a boot
+    /// succeeds.
+    pub fn resume(&self) {
+        let mut state = self.shared.lock();
+        state.quarantined = false;
+        state.failures = 0;
+        self.shared.changed.notify_all();
+    }
+
+    fn take(&self) -> Result<(Vm, Slot<'_>)> {
+        let mut state = self.shared.lock();
+        loop {
+            if state.running < self.shared.options.max_concurrency {
+                if let Some(vm) = state.ready.pop_front() {
+                    state.running += 1;
+                    self.shared.changed.notify_all();
+                    return Ok((vm, Slot(&self.shared)));
+                }
+            }
+            if state.ready.is_empty() {
+                match state.error {
+                    Some(ref e) if state.quarantined => {
+                        return Err(anyhow!(
+                            "pool stopped booting VMs after {} failures in a row: {e}",
+                            state.failures
+                        ));
+                    }
+                    Some(ref e) => return Err(anyhow!("pool couldn't boot a VM: {e}")),
+                    None => {}
+                }
+            }
+            state = self.shared.wait(state);
+        }
+    }
+}
+
+impl Drop for VmPool {
+    fn drop(&mut self) {
+        self.shared.lock().closed = true;
+        self.shared.changed.notify_all();
+        if let Some(refill) = self.refill.take() {
+            let _ = refill.join();
+        }
+    }
+}
+
+/// A run's claim on `max_concurrency`, given back when dropped.
+struct Slot<'a>(&'a Shared);
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        self.0.lock().running -= 1;
+        self.0.changed.notify_all();
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn wait<'a>(&self, state: MutexGuard<'a, State>) -> MutexGuard<'a, State> {
+        self.changed.wait(state).unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Boot VMs until the pool is full, then wait for one to be taken.
+    fn refill(&self) {
+        loop {
+            let mut state = self.lock();
+            while !state.closed && (state.quarantined || state.ready.len() >= self.options.size) {
+                state = self.wait(state);
+            }
+            if state.closed {
+                return;
+            }
+            drop(state);
+
+            let made = (self.make)().and_then(|mut vm| vm.create().map(|()| vm));
+            let mut state = self.lock();
+            let failed = match made {
+                Ok(vm) => {
+                    state.ready.push_back(vm);
+                    state.error = None;
+                    state.failures = 0;
+                    false
+                }
+                Err(e) => {
+                    tracing::warn!("pool: booting a VM failed: {e:#}");
+                    state.error = Some(format!("{e:#}"));
+                    state.failures += 1;
+                    if state.failures >= QUARANTINE_AFTER {
+                        tracing::error!(
+                            "pool: quarantined after {} boot failures in a row",
+                            state.failures
+                        );
+                        state.quarantined = true;
+                    }
+                    true
+                }
+            };
+            self.changed.notify_all();
+            if failed && !state.quarantined {
+                // Other notifications (runs finishing) must not cut the
+                // backoff short.
+                let deadline = Instant::now() + self.backoff(state.failures);
+                while !state.closed {
+                    let left = deadline.saturating_duration_since(Instant::now());
+                    if left.is_zero() {
+                        break;
+                    }
+                    state = match self.changed.wait_timeout(state, left) {
+                        Ok((state, _)) => state,
+                        Err(e) => e.into_inner().0,
+                    };
+                }
+            }
+        }
+    }
+
+    /// The wait after the `failures`th failure in a row.
+    fn backoff(&self, failures: u32) -> Duration {
+        let doublings = failures.saturating_sub(1).min(16);
+        self.retry
+            .saturating_mul(1 << doublings)
+            .min(REFILL_RETRY_MAX.max(self.retry))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn boot_failures_reach_waiting_runs() {
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let counted = attempts.clone();
+        let pool = VmPool::new(
+            move || {
+                counted.fetch_add(1, Ordering::SeqCst);
+                Vm::builder().build()
+            },
+            PoolOptions::default(),
+        );
+        let err = pool.run().unwrap_err().to_string();
+        assert!(err.contains("no kernel"), "{err}");
+        assert_eq!((pool.ready(), pool.running()), (0, 0));
+        assert!(attempts.load(Ordering::SeqCst) >= 1);
+        // Dropping the pool stops the refill thread mid-backoff.
+        drop(pool);
+    }
+
+    #[test]
+    fn repeated_boot_failures_quarantine_the_pool() {
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let counted = attempts.clone();
+        let pool = VmPool::with_retry(
+            move || {
+                counted.fetch_add(1, Ordering::SeqCst);
+                Vm::builder().build()
+            },
+            PoolOptions::default(),
+            Duration::from_millis(1),
+        );
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !pool.quarantined() {
+            assert!(Instant::now() < deadline, "pool never quarantined");
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(attempts.load(Ordering::SeqCst), QUARANTINE_AFTER as usize);
+        let err = pool.run().unwrap_err().to_string();
+        assert!(err.contains("after 5 failures in a row"), "{err}");
+        assert!(err.contains("no kernel"), "{err}");
+
+        pool.resume();
+        while attempts.load(Ordering::SeqCst) == QUARANTINE_AFTER as usize {
+            assert!(Instant::now() < deadline, "pool never resumed");
+            std::thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let pool = VmPool::with_retry(|| Err(anyhow!("off")), PoolOptions::default(), REFILL_RETRY);
+        let waits: Vec<u64> = [1, 2, 3, 6, 40]
+            .map(|n| pool.shared.backoff(n).as_secs())
+            .into();
+        assert_eq!(waits, [1, 2, 4, 30, 30]);
+    }
+}
@@ -0,0 +1,213 @@
+//! Folded-stack output for `hyperlight-unikraft profile`.
+//!
+//! Both producers emit the `frame;frame;frame weight` lines that
+//! `flamegraph.pl` and `inferno-flamegraph` consume:
+//!
+//! - [`fold_phases`] turns measured phase durations into stacks weighted
+//!   in microseconds (the built-in, always-available profiler).
+//! - [`collapse_perf_script`] folds `perf script` output into stacks
+//!   weighted by sample count, rooted at the sampled thread's name so the
+//!   vCPU thread and the `hl-capture` output reader separate cleanly.
+//!
+//! [`host_call_exits`] counts and times the VM exits the host handles by
+//! reason, and [`fold_exits`] adds them to the built-in profile under
+//! `vmexit`.
+
+use crate::hostcall::CallSummary;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// VM exits of one reason: how many, and the host time they took.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExitCount {
+    /// `halt`, or `host_call;<tool>` for an I/O exit into a host tool.
+    pub reason: String,
+    pub count: u64,
+    pub total: Duration,
+}
+
+/// The exits behind a run's host calls: every `__dispatch` call is an I/O
+/// exit into the host, attributed to its tool. `halts` adds the exits
+/// that ended boot and each call. Exits Hyperlight serves without the
+/// host's involvement (console port writes, page faults) aren't visible
+/// here; `perf` sees them in the hypervisor frames.
+pub fn host_call_exits(calls: &[CallSummary], halts: u64) -> Vec<ExitCount> {
+    let mut exits: Vec<ExitCount> = calls
+        .iter()
+        .map(|c| ExitCount {
+            reason: format!("host_call;{}", c.tool),
+            count: c.count,
+            total: c.total,
+        })
+        .collect();
+    if halts > 0 {
+        exits.push(ExitCount {
+            reason: "halt".into(),
+            count: halts,
+            total: Duration::ZERO,
+        });
+    }
+    exits
+}
+
+/// Fold exits into stacks under `root;vmexit`, weighted by host time in
+/// microseconds like [`fold_phases`].
+pub fn fold_exits(root: &str, exits: &[ExitCount]) -> String {
+    let phases: Vec<(String, Duration)> = exits
+        .iter()
+        .map(|e| (format!("vmexit;{}", e.reason), e.total))
+        .collect();
+    let phases: Vec<(&str, Duration)> = phases.iter().map(|(p, d)| (p.as_str(), *d)).collect();
+    fold_phases(root, &phases)
+}
+
+/// Fold `(path, duration)` pairs into stacks under `root`. `path` is a
+/// `;`-separated frame list such as `"boot;setup"`. Zero-length phases
+/// are skipped; repeated paths are summed.
+pub fn fold_phases(root: &str, phases: &[(&str, Duration)]) -> String {
+    let mut stacks: BTreeMap<String, u128> = BTreeMap::new();
+    for (path, d) in phases {
+        let us = d.as_micros();
+        if us > 0 {
+            *stacks.entry(format!("{root};{path}")).or_default() += us;
+        }
+    }
+    render(stacks)
+}
+
+/// Collapse `perf script` text into folded stacks.
+///
+/// Each sample is a header line (`comm pid [cpu] time: period event:`)
+/// followed by indented `addr symbol+off (dso)` frames, leaf first, and a
+/// blank line.
+pub fn collapse_perf_script(script: &str) -> String {
+    let mut stacks: BTreeMap<String, u128> = BTreeMap::new();
+    let mut comm: Option<&str> = None;
+    let mut frames: Vec<&str> = Vec::new();
+
+    let mut flush = |comm: &mut Option<&str>, frames: &mut Vec<&str>| {
+        if let Some(c) = comm.take() {
+            let mut stack = c.to_string();
+            for f in frames.iter().rev() {
+                stack.push(';');
+                stack.push_str(f);
+            }
+            *stacks.entry(stack).or_default() += 1;
+        }
+        frames.clear();
+    };
+
+    for line in script.lines() {
+        if line.trim().is_empty() {
+            flush(&mut comm, &mut frames);
+        } else if line.starts_with(char::is_whitespace) {
+            if comm.is_some() {
+                frames.push(perf_frame_symbol(line));
+            }
+        } else if !line.starts_with('#') {
+            flush(&mut comm, &mut frames);
+            comm = line.split_whitespace().next();
+        }
+    }
+    flush(&mut comm, &mut frames);
+    render(stacks)
+}
+
+/// `"    55d0c0 hyperlight_host::run+0x1f (/bin/x)"` → `"hyperlight_host::run"`.
+fn perf_frame_symbol(line: &str) -> &str {
+    let line = line.trim();
+    let rest = line.split_once(' ').map_or("", |(_addr, rest)| rest);
+    let rest = match rest.rfind(" (") {
+        Some(i) if rest.ends_with(')') => &rest[..i],
+        _ => rest,
+    };
+    let sym = match rest.rfind("+0x") {
+        Some(i) => &rest[..i],
+        None => rest,
+    };
+    if sym.is_empty() {
+        "[unknown]"
+    } else {
+        sym
+    }
+}
+
+fn render(stacks: BTreeMap<String, u128>) -> String {
+    stacks
+        .into_iter()
+        .map(|(stack, weight)| format!("{stack} {weight}\n"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn phases_fold_in_microseconds_and_skip_empty() {
+        let folded = fold_phases(
+            "hl",
+            &[
+                ("boot;setup", Duration::from_micros(1500)),
+                ("run;call", Duration::from_micros(20)),
+                ("run;call", Duration::from_micros(5)),
+                ("output", Duration::ZERO),
+            ],
+        );
+        assert_eq!(folded, "hl;boot;setup 1500\nhl;run;call 25\n");
+    }
+
+    #[test]
+    fn host_calls_become_timed_exits() {
+        let calls = [CallSummary {
+            tool: "fs_read_bytes".into(),
+            count: 3,
+            errors: 0,
+            total: Duration::from_micros(90),
+            p50: Duration::from_micros(30),
+            p99: Duration::from_micros(30),
+            max: Duration::from_micros(30),
+        }];
+        let exits = host_call_exits(&calls, 2);
+        assert_eq!(exits[0].reason, "host_call;fs_read_bytes");
+        assert_eq!(exits[0].count, 3);
+        assert_eq!(exits[1].reason, "halt");
+        assert_eq!(exits[1].count, 2);
+        assert_eq!(
+            fold_exits("hl", &exits),
+            "hl;vmexit;host_call;fs_read_bytes 90\n"
+        );
+    }
+
+    #[test]
+    fn perf_script_collapses_leaf_last_per_thread() {
+        let script = "\
+# header comment
+hyperlight-unik 4242 [001] 100.000001:     250000 cpu-clock:u:
+\t    7f01 ioctl+0x7 (/usr/lib/libc.so.6)
+\t    5501 hyperlight_host::kvm::run+0x1f (/bin/hl)
+\t    5502 main (/bin/hl)
+
+hl-capture 4243 [002] 100.000002:     250000 cpu-clock:u:
+\t    7f02 read+0x10 (/usr/lib/libc.so.6)
+
+hyperlight-unik 4242 [001] 100.000003:     250000 cpu-clock:u:
+\t    7f01 ioctl+0x7 (/usr/lib/libc.so.6)
+\t    5501 hyperlight_host::kvm::run+0x1f (/bin/hl)
+\t    5502 main (/bin/hl)
+";
+        assert_eq!(
+            collapse_perf_script(script),
+            "hl-capture;read 1\nhyperlight-unik;main;hyperlight_host::kvm::run;ioctl 2\n"
+        );
+    }
+
+    #[test]
+    fn unknown_frames_keep_a_placeholder() {
+        assert_eq!(
+            perf_frame_symbol("\t  ffff [unknown] ([kernel])"),
+            "[unknown]"
+        );
+        assert_eq!(perf_frame_symbol("\t  ffff"), "[unknown]");
+    }
+}
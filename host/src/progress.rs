@@ -0,0 +1,165 @@
+//! Terminal spinners for the CLI's long phases.
+//!
+//! A [`Spinner`] redraws one status line (`⠹ Booting 1.2s`) on the
+//! terminal until it's finished, then erases it. It draws only when
+//! stderr is a terminal, so piped and machine-readable output never sees
+//! it. The line goes to a duplicate of stderr taken at start, so a
+//! spinner started before a [`PipeCapture`](crate::stderr_capture::PipeCapture)
+//! keeps drawing while the guest console is captured.
+//!
+//! Guest output that reaches the terminal would be garbled by a redraw,
+//! so callers only spin over phases where the console is hidden.
+
+use std::fs::File;
+use std::io::{IsTerminal, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+const FRAMES: [char; 10] = ['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧', '⠇', '⠏'];
+const TICK: Duration = Duration::from_millis(80);
+
+/// A running spinner; stops and erases its line when finished or dropped.
+pub struct Spinner {
+    shared: Option<Arc<Shared>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+struct Shared {
+    label: Mutex<String>,
+    done: AtomicBool,
+}
+
+impl Spinner {
+    /// Start spinning with `label` if `enabled` and stderr is a terminal
+    /// (and `TERM` isn't `dumb`); otherwise a no-op spinner.
+    pub fn start(enabled: bool, label: &str) -> Self {
+        let tty =
+            std::io::stderr().is_terminal() && std::env::var_os("TERM").is_none_or(|t| t != "dumb");
+        match (enabled && tty).then(terminal).flatten() {
+            Some(out) => Self::draw_to(out, label),
+            None => Self::hidden(),
+        }
+    }
+
+    /// A spinner that never draws.
+    pub fn hidden() -> Self {
+        Self {
+            shared: None,
+            thread: None,
+        }
+    }
+
+    fn draw_to(mut out: File, label: &str) -> Self {
+        let shared = Arc::new(Shared {
+            label: Mutex::new(label.to_string()),
+            done: AtomicBool::new(false),
+        });
+        let s = shared.clone();
+        let thread = std::thread::spawn(move || {
+            let start = Instant::now();
+            let mut tick = 0;
+            while !s.done.load(Ordering::Acquire) {
+                let label = s.label.lock().unwrap_or_else(|e| e.into_inner()).clone();
+                let _ = write!(out, "{}", line(tick, &label, start.elapsed()));
+                let _ = out.flush();
+                tick += 1;
+                std::thread::park_timeout(TICK);
+            }
+            let _ = write!(out, "\r\x1b[2K");
+            let _ = out.flush();
+        });
+        Self {
+            shared: Some(shared),
+            thread: Some(thread),
+        }
+    }
+
+    /// Replace the label shown next to the spinner.
+    pub fn set_label(&self, label: &str) {
+        if let Some(ref s) = self.shared {
+            *s.label.lock().unwrap_or_else(|e| e.into_inner()) = label.to_string();
+        }
+    }
+
+    pub fn is_hidden(&self) -> bool {
+        self.shared.is_none()
+    }
+
+    /// Stop and erase the line. Returns once it's gone, so the caller's
+    /// next write starts on a clean line.
+    pub fn finish(mut self) {
+        self.stop();
+    }
+
+    fn stop(&mut self) {
+        if let Some(s) = self.shared.take() {
+            s.done.store(true, Ordering::Release);
+        }
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for Spinner {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// One redraw of the status line.
+fn line(tick: usize, label: &str, elapsed: Duration) -> String {
+    format!(
+        "\r\x1b[2K{} {} {:.1}s",
+        FRAMES[tick % FRAMES.len()],
+        label,
+        elapsed.as_secs_f64()
+    )
+}
+
+/// A handle on the terminal stderr currently points at, independent of
+/// later redirections of fd 2.
+fn terminal() -> Option<File> {
+    #[cfg(unix)]
+    {
+        use std::os::fd::AsFd;
+        std::io::stderr()
+            .as_fd()
+            .try_clone_to_owned()
+            .ok()
+            .map(File::from)
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::io::AsHandle;
+        std::io::stderr()
+            .as_handle()
+            .try_clone_to_owned()
+            .ok()
+            .map(File::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines_overwrite_in_place_and_cycle_frames() {
+        let first = line(0, "Booting", Duration::from_millis(1250));
+        assert_eq!(first, "\r\x1b[2K⠋ Booting 1.2s");
+        assert!(line(FRAMES.len(), "x", Duration::ZERO).contains('⠋'));
+        assert!(line(1, "x", Duration::ZERO).contains('⠙'));
+    }
+
+    #[test]
+    fn disabled_spinners_never_draw() {
+        let s = Spinner::start(false, "Booting");
+        assert!(s.is_hidden());
+        s.set_label("Running");
+        s.finish();
+    }
+}
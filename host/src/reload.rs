@@ -0,0 +1,229 @@
+//! Hot-reloading files injected from application arguments.
+//!
+//! The edit-run loop behind `run --watch`. A [`Reloader`] stages the host
+//! files named in the arguments as [`rootfs::inject_arg_files`] does,
+//! scans the base rootfs once, and when a staged file changes re-reads
+//! just that file. Everything else prepared for the first run is kept:
+//!
+//! - Layered into the initrd ([`Reloader::initrd`]), only the few entries
+//!   after the base archive are encoded again; the base is not re-read,
+//!   decompressed, decrypted or scanned.
+//! - Served from a host directory mounted at [`rootfs::ARG_FILES_DIR`]
+//!   ([`Reloader::sync_dir`]), the guest image doesn't change at all, so
+//!   one sandbox is restored to its snapshot and called again. The
+//!   kernel needs `lib/hostfs`.
+//!
+//! ```no_run
+//! use hyperlight_unikraft::reload::Reloader;
+//! use hyperlight_unikraft::Sandbox;
+//! use std::time::Duration;
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! let base = std::fs::read("python.cpio")?;
+//! let mut reloader = Reloader::new(&["./app.py".to_string()], base)?;
+//! loop {
+//!     let mut sandbox = Sandbox::builder("python-kernel")
+//!         .args(reloader.args().to_vec())
+//!         .initrd_bytes(reloader.initrd()?)
+//!         .build()?;
+//!     sandbox.restore()?;
+//!     sandbox.call_run()?;
+//!     reloader.wait(Duration::from_millis(200))?;
+//! }
+//! # }
+//! ```
+
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::rootfs::{self, LayerBase, ARG_FILES_DIR};
+
+/// Stages argument files over a base rootfs and tracks their changes.
+#[derive(Debug)]
+pub struct Reloader {
+    args: Vec<String>,
+    files: Vec<Staged>,
+    base: LayerBase<'static>,
+}
+
+#[derive(Debug)]
+struct Staged {
+    host: PathBuf,
+    guest: String,
+    stamp: Option<Stamp>,
+    data: Vec<u8>,
+}
+
+/// What a change is detected by.
+type Stamp = (SystemTime, u64);
+
+fn stamp(path: &Path) -> Option<Stamp> {
+    let meta = std::fs::metadata(path).ok()?;
+    Some((meta.modified().ok()?, meta.len()))
+}
+
+impl Reloader {
+    /// Stage the files named in `args` over `base` (the decompressed
+    /// rootfs, or empty for none).
+    pub fn new(args: &[String], base: Vec<u8>) -> Result<Self> {
+        let staged = rootfs::inject_arg_files(args)?;
+        let files = staged
+            .files
+            .into_iter()
+            .zip(staged.sources)
+            .map(|((guest, data), host)| Staged {
+                stamp: stamp(&host),
+                host,
+                guest,
+                data,
+            })
+            .collect();
+        Ok(Self {
+            args: staged.args,
+            files,
+            base: LayerBase::new(base)?,
+        })
+    }
+
+    /// The arguments with staged files swapped for their guest paths.
+    pub fn args(&self) -> &[String] {
+        &self.args
+    }
+
+    /// Whether no argument named a host file, leaving nothing to watch.
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// The host files being watched.
+    pub fn sources(&self) -> impl Iterator<Item = &Path> {
+        self.files.iter().map(|f| f.host.as_path())
+    }
+
+    /// The base rootfs with the current contents of the staged files.
+    pub fn initrd(&self) -> Result<Vec<u8>> {
+        let files: Vec<(&str, &[u8])> = self
+            .files
+            .iter()
+            .map(|f| (f.guest.as_str(), f.data.as_slice()))
+            .collect();
+        self.base.append(&files)
+    }
+
+    /// Write the staged files under `dir`, laid out as they are under
+    /// [`ARG_FILES_DIR`] in the guest, for a kernel that mounts `dir`
+    /// there instead of taking them in the initrd.
+    pub fn sync_dir(&self, dir: &Path) -> Result<()> {
+        for f in &self.files {
+            let rel = f.guest[ARG_FILES_DIR.len()..].trim_start_matches('/');
+            let path = dir.join(rel);
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(&path, &f.data).with_context(|| format!("writing {path:?}"))?;
+        }
+        Ok(())
+    }
+
+    /// Re-read the staged files modified since they were last read and
+    /// return the host paths of those that changed. A file that is
+    /// missing (an editor part-way through replacing it) is left for a
+    /// later poll.
+    pub fn poll(&mut self) -> Result<Vec<PathBuf>> {
+        let mut changed = Vec::new();
+        for f in &mut self.files {
+            let now = stamp(&f.host);
+            if now.is_none() || now == f.stamp {
+                continue;
+            }
+            let Ok(data) = std::fs::read(&f.host) else {
+                continue;
+            };
+            f.stamp = now;
+            if data != f.data {
+                f.data = data;
+                changed.push(f.host.clone());
+            }
+        }
+        Ok(changed)
+    }
+
+    /// Block until a staged file changes, polling every `interval`, and
+    /// return the changed host paths. Changes landing in the interval
+    /// after the first are picked up too, so a save that writes several
+    /// files reloads once.
+    pub fn wait(&mut self, interval: Duration) -> Result<Vec<PathBuf>> {
+        loop {
+            std::thread::sleep(interval);
+            let mut changed = self.poll()?;
+            if changed.is_empty() {
+                continue;
+            }
+            std::thread::sleep(interval);
+            for path in self.poll()? {
+                if !changed.contains(&path) {
+                    changed.push(path);
+                }
+            }
+            return Ok(changed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rootfs::CpioWriter;
+    use crate::workspace::Workspace;
+
+    #[test]
+    fn only_changed_files_are_reread_and_relayered() {
+        let ws = Workspace::new().unwrap();
+        let script = ws.write("app.py", "print(1)\n").unwrap();
+        let data = ws.write("data.csv", "a,b\n").unwrap();
+        let mut base = CpioWriter::new(Vec::new());
+        base.file("etc/hostname", 0o644, 0, b"vm\n").unwrap();
+        let base = base.finish().unwrap();
+
+        // Only relative paths are staged.
+        let rel = |p: &Path| {
+            let cwd = std::env::current_dir().unwrap();
+            let up = "../".repeat(cwd.components().count() - 1);
+            format!("{up}{}", p.to_str().unwrap().trim_start_matches('/'))
+        };
+        let args = vec!["-u".to_string(), rel(&script), rel(&data)];
+        let mut reloader = Reloader::new(&args, base.clone()).unwrap();
+        assert_eq!(reloader.args(), ["-u", "/args/app.py", "/args/data.csv"]);
+        assert!(reloader.poll().unwrap().is_empty());
+
+        std::fs::write(&script, "print(2)  # edited\n").unwrap();
+        let changed = reloader.poll().unwrap();
+        assert_eq!(changed, [script.canonicalize().unwrap()]);
+        assert!(reloader.poll().unwrap().is_empty());
+
+        let initrd = reloader.initrd().unwrap();
+        let (entries, _) = rootfs::scan(&initrd).unwrap();
+        let find = |name: &str| entries.iter().find(|e| e.name == name).map(|e| e.data);
+        assert_eq!(find("etc/hostname"), Some(&b"vm\n"[..]));
+        assert_eq!(find("args/app.py"), Some(&b"print(2)  # edited\n"[..]));
+        assert_eq!(find("args/data.csv"), Some(&b"a,b\n"[..]));
+        // Same as layering onto the base from scratch.
+        let fresh = rootfs::append_files(
+            &base,
+            &[
+                ("/args/app.py", b"print(2)  # edited\n"),
+                ("/args/data.csv", b"a,b\n"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(initrd, fresh);
+
+        let mount = ws.dir("mount").unwrap();
+        reloader.sync_dir(&mount).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(mount.join("app.py")).unwrap(),
+            "print(2)  # edited\n"
+        );
+    }
+}
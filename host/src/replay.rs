@@ -0,0 +1,212 @@
+//! Record/replay bundles: everything needed to re-run a guest on another
+//! machine and check it behaves the same.
+//!
+//! A bundle is a directory:
+//!
+//! ```text
+//! bundle/
+//!   manifest.json   args, heap/stack, timeout, tools, color, injected
+//!                   wall clock, TZ/locale
+//!   kernel          copy of the kernel ELF
+//!   initrd.cpio     copy of the initrd (if any)
+//!   output.bin      console output captured while recording
+//! ```
+//!
+//! The host's only nondeterministic input to a guest is the boot-time
+//! wall clock, which the bundle pins. Entropy the guest draws itself
+//! (e.g. `RDRAND`) is not captured, so a guest that uses it can diverge.
+
+use anyhow::{anyhow, Context, Result};
+use serde_json::json;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const MANIFEST: &str = "manifest.json";
+const KERNEL: &str = "kernel";
+const INITRD: &str = "initrd.cpio";
+const OUTPUT: &str = "output.bin";
+const FORMAT_VERSION: u64 = 1;
+
+/// A recorded run. After [`load`](Self::load), `kernel` and `initrd`
+/// point at the copies inside the bundle directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplayBundle {
+    pub kernel: PathBuf,
+    pub initrd: Option<PathBuf>,
+    pub args: Vec<String>,
+    pub heap_size: u64,
+    pub stack_size: u64,
+    pub wall_clock: SystemTime,
+    pub timezone: Option<String>,
+    pub locale: Option<String>,
+    /// The run's `--timeout`.
+    pub timeout: Option<Duration>,
+    /// Whether the built-in tools (`--enable-tools`) were served.
+    pub enable_tools: bool,
+    /// The `--color` setting, reused for replay's status lines.
+    pub color: String,
+    /// Console output of the recorded run.
+    pub output: Vec<u8>,
+}
+
+impl ReplayBundle {
+    /// Write the bundle to `dir` (created if missing), copying the kernel
+    /// and initrd so the bundle is self-contained.
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        std::fs::create_dir_all(dir).with_context(|| format!("creating {dir:?}"))?;
+        std::fs::copy(&self.kernel, dir.join(KERNEL))
+            .with_context(|| format!("copying kernel {:?}", self.kernel))?;
+        if let Some(ref initrd) = self.initrd {
+            std::fs::copy(initrd, dir.join(INITRD))
+                .with_context(|| format!("copying initrd {initrd:?}"))?;
+        }
+        std::fs::write(dir.join(OUTPUT), &self.output)?;
+
+        let wall_ns = self
+            .wall_clock
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        let manifest = json!({
+            "version": FORMAT_VERSION,
+            "args": self.args,
+            "heap_size": self.heap_size,
+            "stack_size": self.stack_size,
+            "wall_clock_ns": wall_ns,
+            "timezone": self.timezone,
+            "locale": self.locale,
+            "timeout_ms": self.timeout.map(|t| t.as_millis() as u64),
+            "enable_tools": self.enable_tools,
+            "color": self.color,
+            "initrd": self.initrd.is_some(),
+        });
+        std::fs::write(dir.join(MANIFEST), serde_json::to_vec_pretty(&manifest)?)?;
+        Ok(())
+    }
+
+    /// Read a bundle written by [`save`](Self::save).
+    pub fn load(dir: &Path) -> Result<Self> {
+        let raw = std::fs::read(dir.join(MANIFEST))
+            .with_context(|| format!("{dir:?} is not a replay bundle"))?;
+        let m: serde_json::Value = serde_json::from_slice(&raw)?;
+        let version = m["version"].as_u64().unwrap_or(0);
+        if version != FORMAT_VERSION {
+            return Err(anyhow!(
+                "unsupported replay bundle version {version} (expected {FORMAT_VERSION})"
+            ));
+        }
+        let field = |name: &str| {
+            m[name]
+                .as_u64()
+                .ok_or_else(|| anyhow!("replay manifest is missing {name:?}"))
+        };
+        let args = m["args"]
+            .as_array()
+            .ok_or_else(|| anyhow!("replay manifest is missing \"args\""))?
+            .iter()
+            .map(|a| {
+                a.as_str()
+                    .map(str::to_string)
+                    .ok_or_else(|| anyhow!("replay manifest args must be strings"))
+            })
+            .collect::<Result<_>>()?;
+        let text = |name: &str| m[name].as_str().map(str::to_string);
+
+        Ok(Self {
+            kernel: dir.join(KERNEL),
+            initrd: m["initrd"]
+                .as_bool()
+                .unwrap_or(false)
+                .then(|| dir.join(INITRD)),
+            args,
+            heap_size: field("heap_size")?,
+            stack_size: field("stack_size")?,
+            wall_clock: UNIX_EPOCH + Duration::from_nanos(field("wall_clock_ns")?),
+            timezone: text("timezone"),
+            locale: text("locale"),
+            timeout: m["timeout_ms"].as_u64().map(Duration::from_millis),
+            enable_tools: m["enable_tools"].as_bool().unwrap_or(false),
+            color: text("color").unwrap_or_else(|| "auto".into()),
+            output: std::fs::read(dir.join(OUTPUT))?,
+        })
+    }
+
+    /// Compare a replayed run's output with the recording. Returns the
+    /// byte offset of the first difference, or `None` if identical.
+    pub fn first_divergence(&self, replayed: &[u8]) -> Option<usize> {
+        let common = self.output.len().min(replayed.len());
+        (0..common)
+            .find(|&i| self.output[i] != replayed[i])
+            .or((self.output.len() != replayed.len()).then_some(common))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tmpdir(label: &str) -> PathBuf {
+        let p = std::env::temp_dir().join(format!("hl-replay-{}-{}", label, std::process::id()));
+        let _ = std::fs::remove_dir_all(&p);
+        std::fs::create_dir_all(&p).unwrap();
+        p
+    }
+
+    #[test]
+    fn save_then_load_roundtrips_and_copies_assets() {
+        let src = tmpdir("src");
+        std::fs::write(src.join("k"), b"ELF").unwrap();
+        std::fs::write(src.join("i"), b"070701").unwrap();
+        let bundle = ReplayBundle {
+            kernel: src.join("k"),
+            initrd: Some(src.join("i")),
+            args: vec!["/app.py".into(), "--flag".into()],
+            heap_size: 256 << 20,
+            stack_size: 8 << 20,
+            wall_clock: UNIX_EPOCH + Duration::from_nanos(1_700_000_000_123_456_789),
+            timezone: Some("Asia/Tokyo".into()),
+            locale: None,
+            timeout: Some(Duration::from_secs(30)),
+            enable_tools: true,
+            color: "never".into(),
+            output: b"hello\n".to_vec(),
+        };
+        let dir = tmpdir("bundle");
+        bundle.save(&dir).unwrap();
+        std::fs::remove_dir_all(&src).unwrap();
+
+        let loaded = ReplayBundle::load(&dir).unwrap();
+        assert_eq!(loaded.kernel, dir.join(KERNEL));
+        assert_eq!(std::fs::read(&loaded.kernel).unwrap(), b"ELF");
+        assert_eq!(
+            ReplayBundle {
+                kernel: bundle.kernel.clone(),
+                initrd: bundle.initrd.clone(),
+                ..loaded
+            },
+            bundle
+        );
+    }
+
+    #[test]
+    fn divergence_reports_first_differing_byte() {
+        let bundle = ReplayBundle {
+            kernel: PathBuf::new(),
+            initrd: None,
+            args: Vec::new(),
+            heap_size: 0,
+            stack_size: 0,
+            wall_clock: UNIX_EPOCH,
+            timezone: None,
+            locale: None,
+            timeout: None,
+            enable_tools: false,
+            color: "auto".into(),
+            output: b"abcdef".to_vec(),
+        };
+        assert_eq!(bundle.first_divergence(b"abcdef"), None);
+        assert_eq!(bundle.first_divergence(b"abXdef"), Some(2));
+        assert_eq!(bundle.first_divergence(b"abc"), Some(3));
+        assert_eq!(bundle.first_divergence(b"abcdefg"), Some(6));
+    }
+}
@@ -0,0 +1,920 @@
+//! Rootfs builders: produce the newc cpio archives the guest unpacks as
+//! its initrd, without shelling out to `find | cpio -o -H newc`.
+//!
+//! [`CpioWriter`] emits entries one at a time; [`append_dir`] and
+//! [`append_tar`] feed it from a host directory or a (optionally gzip- or
+//! zstd-compressed) tarball such as `docker export` produces. [`convert`]
+//! ties them together for `hyperlight-unikraft convert`.
+//!
+//! Archives compressed with `--compress zstd` are decompressed by the host
+//! when the sandbox is built, whether given as a file (see
+//! [`read_if_zstd`]) or as bytes to the `run_vm*` functions (see
+//! [`decode_if_zstd`]); the guest always sees a plain cpio.
+//!
+//! [`MappedInitrd`] memory-maps a plain archive for the `run_vm*`
+//! functions, so large rootfs files are read through the shared page cache
+//! instead of into a heap buffer per run.
+
+use anyhow::{bail, Context, Result};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::error::{self, Error};
+
+const NEWC_MAGIC: &[u8] = b"070701";
+const TRAILER: &str = "TRAILER!!!";
+pub(crate) const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+pub(crate) const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+
+/// Streaming writer for newc (`070701`) cpio archives.
+///
+/// Entries are owned by root; inode numbers are assigned sequentially.
+/// Parent directories are not created implicitly, so add them first.
+pub struct CpioWriter<W: Write> {
+    out: W,
+    next_ino: u32,
+    offset: u64,
+}
+
+impl<W: Write> CpioWriter<W> {
+    pub fn new(out: W) -> Self {
+        Self {
+            out,
+            next_ino: 1,
+            offset: 0,
+        }
+    }
+
+    /// Add a directory. `mode` holds permission bits only.
+    pub fn dir(&mut self, name: &str, mode: u32, mtime: u32) -> Result<()> {
+        self.entry(
+            name,
+            S_IFDIR | (mode & 0o7777),
+            mtime,
+            2,
+            0,
+            &mut std::io::empty(),
+        )
+    }
+
+    /// Add a regular file whose contents are `data`.
+    pub fn file(&mut self, name: &str, mode: u32, mtime: u32, data: &[u8]) -> Result<()> {
+        self.file_from_reader(name, mode, mtime, data.len() as u64, &mut &data[..])
+    }
+
+    /// Add a regular file of `size` bytes streamed from `data`.
+    pub fn file_from_reader(
+        &mut self,
+        name: &str,
+        mode: u32,
+        mtime: u32,
+        size: u64,
+        data: &mut dyn Read,
+    ) -> Result<()> {
+        self.entry(name, S_IFREG | (mode & 0o7777), mtime, 1, size, data)
+    }
+
+    /// Add a symlink pointing at `target`.
+    pub fn symlink(&mut self, name: &str, target: &str, mtime: u32) -> Result<()> {
+        let target = target.as_bytes();
+        self.entry(
+            name,
+            S_IFLNK | 0o777,
+            mtime,
+            1,
+            target.len() as u64,
+            &mut &target[..],
+        )
+    }
+
+    /// Write the trailer and return the underlying writer.
+    pub fn finish(mut self) -> Result<W> {
+        self.header(TRAILER, 0, 0, 0, 1, 0)?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    /// Number inodes from `ino` on, to continue an existing archive
+    /// without reusing its inode numbers.
+    pub(crate) fn starting_at_inode(mut self, ino: u32) -> Self {
+        self.next_ino = ino;
+        self
+    }
+
+    /// Bytes written so far.
+    pub fn len(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.offset == 0
+    }
+
+    fn entry(
+        &mut self,
+        name: &str,
+        mode: u32,
+        mtime: u32,
+        nlink: u32,
+        size: u64,
+        data: &mut dyn Read,
+    ) -> Result<()> {
+        let name = name.trim_start_matches("./").trim_start_matches('/');
+        if name.is_empty() || name == TRAILER {
+            bail!("invalid cpio entry name {name:?}");
+        }
+        if size > u32::MAX as u64 {
+            bail!("{name}: {size} bytes exceeds the newc 4 GiB file limit");
+        }
+        let ino = self.next_ino;
+        self.next_ino += 1;
+        self.header(name, ino, mode, mtime, nlink, size as u32)?;
+        let copied = std::io::copy(&mut data.take(size), &mut self.out)?;
+        if copied != size {
+            bail!("{name}: expected {size} bytes, read {copied}");
+        }
+        self.offset += copied;
+        self.pad()
+    }
+
+    fn header(
+        &mut self,
+        name: &str,
+        ino: u32,
+        mode: u32,
+        mtime: u32,
+        nlink: u32,
+        size: u32,
+    ) -> Result<()> {
+        let namesize = name.len() as u32 + 1;
+        let fields = [ino, mode, 0, 0, nlink, mtime, size, 0, 0, 0, 0, namesize, 0];
+        let mut hdr = Vec::with_capacity(110 + name.len() + 4);
+        hdr.extend_from_slice(NEWC_MAGIC);
+        for f in fields {
+            hdr.extend_from_slice(format!("{f:08X}").as_bytes());
+        }
+        hdr.extend_from_slice(name.as_bytes());
+        hdr.push(0);
+        self.out.write_all(&hdr)?;
+        self.offset += hdr.len() as u64;
+        self.pad()
+    }
+
+    fn pad(&mut self) -> Result<()> {
+        let pad = (4 - (self.offset % 4) as usize) % 4;
+        self.out.write_all(&[0u8; 3][..pad])?;
+        self.offset += pad as u64;
+        Ok(())
+    }
+}
+
+/// Entry counts from a conversion.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConvertStats {
+    /// Entries written to the archive.
+    pub entries: usize,
+    /// Source entries with no cpio equivalent here (devices, fifos,
+    /// sockets), left out.
+    pub skipped: usize,
+}
+
+/// Add everything under `root` (not `root` itself), in sorted order so
+/// the output is reproducible.
+pub fn append_dir<W: Write>(cpio: &mut CpioWriter<W>, root: &Path) -> Result<ConvertStats> {
+    let mut stats = ConvertStats::default();
+    walk(cpio, root, "", &mut stats)?;
+    Ok(stats)
+}
+
+#[cfg(unix)]
+fn walk<W: Write>(
+    cpio: &mut CpioWriter<W>,
+    dir: &Path,
+    prefix: &str,
+    stats: &mut ConvertStats,
+) -> Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    let mut entries = std::fs::read_dir(dir)
+        .with_context(|| format!("reading {dir:?}"))?
+        .collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        let file_name = entry.file_name();
+        let Some(file_name) = file_name.to_str() else {
+            bail!("{:?}: non-UTF-8 file name", entry.path());
+        };
+        let name = format!("{prefix}{file_name}");
+        let path = entry.path();
+        let meta = std::fs::symlink_metadata(&path)?;
+        let mtime = meta.mtime().clamp(0, u32::MAX as i64) as u32;
+        let ft = meta.file_type();
+        if ft.is_dir() {
+            cpio.dir(&name, meta.mode(), mtime)?;
+            stats.entries += 1;
+            walk(cpio, &path, &format!("{name}/"), stats)?;
+        } else if ft.is_file() {
+            let mut f = File::open(&path).with_context(|| format!("opening {path:?}"))?;
+            cpio.file_from_reader(&name, meta.mode(), mtime, meta.len(), &mut f)?;
+            stats.entries += 1;
+        } else if ft.is_symlink() {
+            let target = std::fs::read_link(&path)?;
+            let Some(target) = target.to_str() else {
+                bail!("{path:?}: non-UTF-8 symlink target");
+            };
+            cpio.symlink(&name, target, mtime)?;
+            stats.entries += 1;
+        } else {
+            stats.skipped += 1;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn walk<W: Write>(
+    _cpio: &mut CpioWriter<W>,
+    _dir: &Path,
+    _prefix: &str,
+    _stats: &mut ConvertStats,
+) -> Result<()> {
+    bail!("converting a directory is only supported on Unix hosts")
+}
+
+/// The files hard links in a tar stream point at, to pass to
+/// [`append_tar`].
+pub fn hard_link_targets<R: Read>(tar: R) -> Result<HashSet<String>> {
+    let mut targets = HashSet::new();
+    for entry in tar::Archive::new(tar).entries()? {
+        let entry = entry?;
+        if entry.header().entry_type().is_hard_link() {
+            if let Some(target) = entry.link_name()? {
+                targets.insert(tar_name(&target)?);
+            }
+        }
+    }
+    Ok(targets)
+}
+
+/// Add the entries of a tar stream. A hard link is written as a copy of
+/// its target, which the guest's cpio unpacker can't tell from a link
+/// for a read-only rootfs. Entries are streamed, so the contents of the
+/// files in `link_targets` (from a first pass with
+/// [`hard_link_targets`]) are kept in memory until the end; a hard link
+/// to any other file is an error.
+pub fn append_tar<W: Write, R: Read>(
+    cpio: &mut CpioWriter<W>,
+    tar: R,
+    link_targets: &HashSet<String>,
+) -> Result<ConvertStats> {
+    let mut stats = ConvertStats::default();
+    let mut kept: HashMap<String, Vec<u8>> = HashMap::new();
+    let mut archive = tar::Archive::new(tar);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name = tar_name(&entry.path()?)?;
+        if name.is_empty() || name == "." {
+            continue;
+        }
+        let header = entry.header();
+        let mode = header.mode()?;
+        let mtime = header.mtime()?.min(u32::MAX as u64) as u32;
+        let kind = header.entry_type();
+        if kind.is_dir() {
+            cpio.dir(&name, mode, mtime)?;
+        } else if kind.is_file() && link_targets.contains(&name) {
+            let mut data = Vec::with_capacity(entry.size() as usize);
+            entry.read_to_end(&mut data)?;
+            cpio.file(&name, mode, mtime, &data)?;
+            kept.insert(name, data);
+        } else if kind.is_file() {
+            let size = entry.size();
+            cpio.file_from_reader(&name, mode, mtime, size, &mut entry)?;
+        } else if kind.is_hard_link() {
+            let Some(target) = entry.link_name()? else {
+                bail!("{name}: link without a target");
+            };
+            let target = tar_name(&target)?;
+            let Some(data) = kept.get(&target) else {
+                bail!("{name}: hard link to {target:?}, which is not an earlier file");
+            };
+            cpio.file(&name, mode, mtime, data)?;
+        } else if kind.is_symlink() {
+            let Some(target) = entry.link_name()? else {
+                bail!("{name}: link without a target");
+            };
+            let Some(target) = target.to_str() else {
+                bail!("{name}: non-UTF-8 link target");
+            };
+            cpio.symlink(&name, target, mtime)?;
+        } else {
+            stats.skipped += 1;
+            continue;
+        }
+        stats.entries += 1;
+    }
+    Ok(stats)
+}
+
+/// A tar member's path as a cpio entry name: relative, no trailing `/`.
+fn tar_name(path: &Path) -> Result<String> {
+    let Some(name) = path.to_str() else {
+        bail!("{path:?}: non-UTF-8 path in tarball");
+    };
+    Ok(name
+        .trim_start_matches("./")
+        .trim_start_matches('/')
+        .trim_end_matches('/')
+        .to_string())
+}
+
+/// Output compression for [`convert`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Zstd,
+}
+
+/// Build a cpio at `dst` from a directory or tarball at `src`. Tarballs
+/// may be plain, gzip, or zstd; the format is detected from the content.
+pub fn convert(src: &Path, dst: &Path, compress: Option<Compression>) -> Result<ConvertStats> {
+    let out = BufWriter::new(File::create(dst).with_context(|| format!("creating {dst:?}"))?);
+    convert_to(src, out, compress)
+}
+
+/// [`convert`] into memory, for callers that post-process the archive
+/// (e.g. encrypt it) before it touches disk.
+pub fn convert_to_vec(
+    src: &Path,
+    compress: Option<Compression>,
+) -> Result<(Vec<u8>, ConvertStats)> {
+    let mut out = Vec::new();
+    let stats = convert_to(src, &mut out, compress)?;
+    Ok((out, stats))
+}
+
+fn convert_to<W: Write>(src: &Path, out: W, compress: Option<Compression>) -> Result<ConvertStats> {
+    match compress {
+        None => convert_into(src, out),
+        Some(Compression::Zstd) => {
+            let mut enc = zstd::Encoder::new(out, 0)?;
+            let stats = convert_into(src, &mut enc)?;
+            enc.finish()?.flush()?;
+            Ok(stats)
+        }
+    }
+}
+
+fn convert_into<W: Write>(src: &Path, out: W) -> Result<ConvertStats> {
+    let mut cpio = CpioWriter::new(out);
+    let stats = if src.is_dir() {
+        append_dir(&mut cpio, src)?
+    } else {
+        let targets = hard_link_targets(open_tar(src)?)?;
+        append_tar(&mut cpio, open_tar(src)?, &targets)?
+    };
+    cpio.finish()?.flush()?;
+    Ok(stats)
+}
+
+/// Open a tarball, decompressing it if it is gzip or zstd.
+fn open_tar(src: &Path) -> Result<Box<dyn Read>> {
+    let mut input = BufReader::new(File::open(src).with_context(|| format!("opening {src:?}"))?);
+    let magic = peek(&mut input, 4)?;
+    Ok(if magic.starts_with(GZIP_MAGIC) {
+        Box::new(flate2::read::GzDecoder::new(input))
+    } else if magic.starts_with(ZSTD_MAGIC) {
+        Box::new(zstd::Decoder::with_buffer(input)?)
+    } else {
+        Box::new(input)
+    })
+}
+
+/// One entry of an in-memory newc archive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpioEntry<'a> {
+    pub name: &'a str,
+    pub ino: u32,
+    /// File type and permission bits, as in `st_mode`.
+    pub mode: u32,
+    pub data: &'a [u8],
+}
+
+impl CpioEntry<'_> {
+    pub fn is_dir(&self) -> bool {
+        self.mode & 0o170000 == S_IFDIR
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.mode & 0o170000 == S_IFREG
+    }
+}
+
+/// The entries of a newc archive, and the offset of its trailer.
+pub(crate) fn scan(archive: &[u8]) -> Result<(Vec<CpioEntry<'_>>, usize)> {
+    scan_entries(archive).map_err(|e| Error::invalid_initrd(format!("{e:#}")).into())
+}
+
+fn scan_entries(archive: &[u8]) -> Result<(Vec<CpioEntry<'_>>, usize)> {
+    let align = |n: usize| (n + 3) & !3;
+    let field = |at: usize, i: usize| -> Result<u32> {
+        let raw = archive
+            .get(at + 6 + i * 8..at + 14 + i * 8)
+            .context("truncated cpio header")?;
+        let text = std::str::from_utf8(raw).context("malformed cpio header")?;
+        u32::from_str_radix(text, 16).context("malformed cpio header")
+    };
+    let mut entries = Vec::new();
+    let mut at = 0;
+    loop {
+        if archive.get(at..at + 6) != Some(NEWC_MAGIC) {
+            bail!("not a newc cpio archive (bad magic at offset {at})");
+        }
+        let (ino, mode, size, namesize) = (
+            field(at, 0)?,
+            field(at, 1)?,
+            field(at, 6)? as usize,
+            field(at, 11)? as usize,
+        );
+        let name = archive
+            .get(at + 110..(at + 110 + namesize).saturating_sub(1))
+            .context("truncated cpio entry name")?;
+        let name = std::str::from_utf8(name).context("cpio entry name is not UTF-8")?;
+        if name == TRAILER {
+            return Ok((entries, at));
+        }
+        let data_at = align(at + 110 + namesize);
+        let data = archive
+            .get(data_at..data_at + size)
+            .with_context(|| format!("{name}: truncated cpio entry"))?;
+        entries.push(CpioEntry {
+            name,
+            ino,
+            mode,
+            data,
+        });
+        at = align(data_at + size);
+    }
+}
+
+/// Return `archive` with `files` (`(path, contents)`, mode 0644) added
+/// before its trailer, creating missing parent directories. A path that
+/// already exists is shadowed: the guest unpacks entries in order, so
+/// the added copy wins.
+pub fn append_files(archive: &[u8], files: &[(&str, &[u8])]) -> Result<Vec<u8>> {
+    LayerBase::new(archive)?.append(files)
+}
+
+/// An archive scanned once, so layers of files can be added to it
+/// repeatedly without walking its entries again, as [`append_files`]
+/// would each time.
+#[derive(Debug, Clone)]
+pub struct LayerBase<'a> {
+    /// The archive up to, not including, its trailer.
+    head: Cow<'a, [u8]>,
+    dirs: HashSet<String>,
+    next_ino: u32,
+}
+
+impl<'a> LayerBase<'a> {
+    /// Scan `archive` (borrowed or owned; empty for none).
+    pub fn new(archive: impl Into<Cow<'a, [u8]>>) -> Result<Self> {
+        let archive = archive.into();
+        let (trailer_at, dirs, next_ino) = if archive.is_empty() {
+            (0, HashSet::new(), 1)
+        } else {
+            let (entries, trailer_at) = scan(&archive)?;
+            let dirs = entries
+                .iter()
+                .filter(|e| e.is_dir())
+                .map(|e| e.name.trim_start_matches("./").to_string())
+                .collect();
+            let next_ino = entries.iter().map(|e| e.ino).max().unwrap_or(0) + 1;
+            (trailer_at, dirs, next_ino)
+        };
+        let head = match archive {
+            Cow::Borrowed(b) => Cow::Borrowed(&b[..trailer_at]),
+            Cow::Owned(mut v) => {
+                v.truncate(trailer_at);
+                Cow::Owned(v)
+            }
+        };
+        Ok(Self {
+            head,
+            dirs,
+            next_ino,
+        })
+    }
+
+    /// The base archive with `files` added; see [`append_files`].
+    pub fn append(&self, files: &[(&str, &[u8])]) -> Result<Vec<u8>> {
+        let mut dirs: HashSet<&str> = self.dirs.iter().map(String::as_str).collect();
+        let mut out = Vec::with_capacity(
+            self.head.len() + files.iter().map(|(_, d)| d.len() + 256).sum::<usize>(),
+        );
+        out.extend_from_slice(&self.head);
+        let mut cpio = CpioWriter::new(&mut out).starting_at_inode(self.next_ino);
+        for (path, data) in files {
+            let path = path.trim_start_matches('/');
+            let mut parent = 0;
+            while let Some(slash) = path[parent..].find('/') {
+                parent += slash;
+                if dirs.insert(&path[..parent]) {
+                    cpio.dir(&path[..parent], 0o755, 0)?;
+                }
+                parent += 1;
+            }
+            cpio.file(path, 0o644, 0, data)?;
+        }
+        cpio.finish()?;
+        Ok(out)
+    }
+}
+
+/// Guest directory [`inject_arg_files`] places host files under.
+pub const ARG_FILES_DIR: &str = "/args";
+
+/// Application arguments with host file paths swapped for guest paths,
+/// and the files to add to the rootfs; see [`inject_arg_files`].
+#[derive(Debug, Default)]
+pub struct ArgFiles {
+    pub args: Vec<String>,
+    /// `(guest path, contents)` pairs for [`append_files`].
+    pub files: Vec<(String, Vec<u8>)>,
+    /// The host file each of `files` was read from.
+    pub sources: Vec<PathBuf>,
+}
+
+impl ArgFiles {
+    /// `archive` with the staged files added.
+    pub fn apply(&self, archive: &[u8]) -> Result<Vec<u8>> {
+        let files: Vec<(&str, &[u8])> = self
+            .files
+            .iter()
+            .map(|(p, d)| (p.as_str(), d.as_slice()))
+            .collect();
+        append_files(archive, &files)
+    }
+}
+
+/// Find application arguments naming host files and stage them for the
+/// rootfs, rewriting each such argument to its guest path under
+/// [`ARG_FILES_DIR`].
+///
+/// Only relative paths to existing regular files are taken; absolute
+/// paths are left alone since they usually name something inside the
+/// guest (`/usr/bin/python3`). Files keep their base name unless two
+/// different files share one, in which case the later one is put in a
+/// subdirectory named after its argument index. Repeating a path reuses
+/// the first copy.
+pub fn inject_arg_files(args: &[String]) -> Result<ArgFiles> {
+    let mut rewritten = Vec::with_capacity(args.len());
+    let mut files: Vec<(String, Vec<u8>)> = Vec::new();
+    let mut sources = Vec::new();
+    let mut seen: std::collections::HashMap<PathBuf, String> = Default::default();
+    for (i, arg) in args.iter().enumerate() {
+        let path = Path::new(arg);
+        if path.is_absolute() || !path.is_file() {
+            rewritten.push(arg.clone());
+            continue;
+        }
+        let canon = path
+            .canonicalize()
+            .with_context(|| format!("resolving argument {arg:?}"))?;
+        if let Some(guest) = seen.get(&canon) {
+            rewritten.push(guest.clone());
+            continue;
+        }
+        let Some(name) = canon.file_name().and_then(|n| n.to_str()) else {
+            rewritten.push(arg.clone());
+            continue;
+        };
+        let mut guest = format!("{ARG_FILES_DIR}/{name}");
+        if files.iter().any(|(p, _)| *p == guest) {
+            guest = format!("{ARG_FILES_DIR}/{i}/{name}");
+        }
+        let data = std::fs::read(&canon).with_context(|| format!("reading argument {arg:?}"))?;
+        files.push((guest.clone(), data));
+        sources.push(canon.clone());
+        seen.insert(canon, guest.clone());
+        rewritten.push(guest);
+    }
+    Ok(ArgFiles {
+        args: rewritten,
+        files,
+        sources,
+    })
+}
+
+/// If `path` holds a zstd-compressed initrd, return it decompressed.
+/// Plain archives return `None` so callers can keep mapping them
+/// zero-copy.
+pub fn read_if_zstd(path: &Path) -> Result<Option<Vec<u8>>> {
+    let mut input = BufReader::new(File::open(path).map_err(|e| error::initrd_io(path, e))?);
+    if !peek(&mut input, 4)?.starts_with(ZSTD_MAGIC) {
+        return Ok(None);
+    }
+    let data = zstd::decode_all(input)
+        .map_err(|e| Error::invalid_initrd(format!("decompressing {path:?}: {e}")))?;
+    Ok(Some(data))
+}
+
+/// [`read_if_zstd`] for an archive already in memory.
+pub fn decode_if_zstd(data: &[u8]) -> Result<Option<Vec<u8>>> {
+    if !data.starts_with(ZSTD_MAGIC) {
+        return Ok(None);
+    }
+    let data = zstd::decode_all(data)
+        .map_err(|e| Error::invalid_initrd(format!("decompressing initrd: {e}")))?;
+    Ok(Some(data))
+}
+
+/// A rootfs archive mapped read-only from disk.
+///
+/// Derefs to the archive bytes, so it can go anywhere an initrd buffer
+/// does. Pages are shared with the page cache and every other mapping of
+/// the same file, so concurrent runs of one rootfs hold a single copy.
+/// [`run_vm_capture_output_mapped`](crate::run_vm_capture_output_mapped)
+/// also hands the file itself to the guest mapping rather than spilling
+/// the buffer.
+///
+/// The file must not be truncated or rewritten in place while mapped;
+/// replace it by renaming instead.
+pub struct MappedInitrd {
+    path: PathBuf,
+    map: memmap2::Mmap,
+}
+
+impl MappedInitrd {
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let file = File::open(&path).map_err(|e| error::initrd_io(&path, e))?;
+        // SAFETY: the mapping is read-only; the documented contract is
+        // that the file isn't modified in place while mapped.
+        let map = unsafe { memmap2::Mmap::map(&file) }.map_err(|e| error::initrd_io(&path, e))?;
+        Ok(Self { path, map })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl std::ops::Deref for MappedInitrd {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.map
+    }
+}
+
+fn peek<R: Read>(input: &mut BufReader<R>, n: usize) -> Result<Vec<u8>> {
+    use std::io::BufRead;
+    Ok(input.fill_buf()?.iter().take(n).copied().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::workspace::Workspace;
+
+    /// Minimal newc reader: `(name, mode, data)` per entry, trailer excluded.
+    fn parse(mut buf: &[u8]) -> Vec<(String, u32, Vec<u8>)> {
+        let hex = |b: &[u8]| u32::from_str_radix(std::str::from_utf8(b).unwrap(), 16).unwrap();
+        let align = |n: usize| (n + 3) & !3;
+        let mut out = Vec::new();
+        loop {
+            assert_eq!(&buf[..6], NEWC_MAGIC);
+            let mode = hex(&buf[14..22]);
+            let size = hex(&buf[54..62]) as usize;
+            let namesize = hex(&buf[94..102]) as usize;
+            let name = std::str::from_utf8(&buf[110..110 + namesize - 1])
+                .unwrap()
+                .to_string();
+            let data_at = align(110 + namesize);
+            if name == TRAILER {
+                return out;
+            }
+            out.push((name, mode, buf[data_at..data_at + size].to_vec()));
+            buf = &buf[align(data_at + size)..];
+        }
+    }
+
+    #[test]
+    fn writer_emits_aligned_newc_entries() {
+        let mut cpio = CpioWriter::new(Vec::new());
+        cpio.dir("./bin", 0o755, 0).unwrap();
+        cpio.file("bin/hello", 0o644, 0, b"hi\n").unwrap();
+        cpio.symlink("/sh", "bin/hello", 0).unwrap();
+        let buf = cpio.finish().unwrap();
+        assert_eq!(buf.len() % 4, 0);
+        assert_eq!(
+            parse(&buf),
+            vec![
+                ("bin".into(), S_IFDIR | 0o755, vec![]),
+                ("bin/hello".into(), S_IFREG | 0o644, b"hi\n".to_vec()),
+                ("sh".into(), S_IFLNK | 0o777, b"bin/hello".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn tar_entries_convert_and_hard_links_copy_their_target() {
+        let mut tar = tar::Builder::new(Vec::new());
+        let mut dir = tar::Header::new_gnu();
+        dir.set_entry_type(tar::EntryType::Directory);
+        dir.set_mode(0o755);
+        dir.set_size(0);
+        tar.append_data(&mut dir, "./app/", &[][..]).unwrap();
+        let mut file = tar::Header::new_gnu();
+        file.set_mode(0o600);
+        file.set_size(5);
+        tar.append_data(&mut file, "./app/main.py", &b"pass\n"[..])
+            .unwrap();
+        let mut link = tar::Header::new_gnu();
+        link.set_entry_type(tar::EntryType::Link);
+        link.set_mode(0o600);
+        link.set_size(0);
+        tar.append_link(&mut link, "app/alias.py", "./app/main.py")
+            .unwrap();
+        let mut fifo = tar::Header::new_gnu();
+        fifo.set_entry_type(tar::EntryType::Fifo);
+        fifo.set_mode(0o600);
+        fifo.set_size(0);
+        tar.append_data(&mut fifo, "app/pipe", &[][..]).unwrap();
+        let tarball = tar.into_inner().unwrap();
+
+        let targets = hard_link_targets(&tarball[..]).unwrap();
+        assert_eq!(targets, HashSet::from(["app/main.py".to_string()]));
+        let mut cpio = CpioWriter::new(Vec::new());
+        let stats = append_tar(&mut cpio, &tarball[..], &targets).unwrap();
+        assert_eq!(
+            stats,
+            ConvertStats {
+                entries: 3,
+                skipped: 1
+            }
+        );
+        let entries = parse(&cpio.finish().unwrap());
+        assert_eq!(entries[0].0, "app");
+        assert_eq!(
+            entries[1],
+            ("app/main.py".into(), S_IFREG | 0o600, b"pass\n".to_vec())
+        );
+        assert_eq!(
+            entries[2],
+            ("app/alias.py".into(), S_IFREG | 0o600, b"pass\n".to_vec())
+        );
+
+        let mut cpio = CpioWriter::new(Vec::new());
+        let err = append_tar(&mut cpio, &tarball[..], &HashSet::new()).unwrap_err();
+        assert!(err.to_string().contains("not an earlier file"), "{err:#}");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn appended_files_land_before_the_trailer_with_their_parents() {
+        let mut cpio = CpioWriter::new(Vec::new());
+        cpio.dir("app", 0o755, 0).unwrap();
+        cpio.file("app/main.py", 0o644, 0, b"old").unwrap();
+        let base = cpio.finish().unwrap();
+
+        let out = append_files(
+            &base,
+            &[("/app/main.py", b"new"), ("etc/policy/allow.json", b"{}")],
+        )
+        .unwrap();
+        let names: Vec<_> = parse(&out).into_iter().map(|(n, _, _)| n).collect();
+        assert_eq!(
+            names,
+            [
+                "app",
+                "app/main.py",
+                "app/main.py",
+                "etc",
+                "etc/policy",
+                "etc/policy/allow.json"
+            ]
+        );
+        let (entries, _) = scan(&out).unwrap();
+        assert_eq!(entries[2].data, b"new");
+        assert_eq!(entries[2].ino, 3);
+        assert!(scan(b"not a cpio").is_err());
+    }
+
+    #[test]
+    fn zstd_output_roundtrips_through_read_if_zstd() {
+        let ws = Workspace::new().unwrap();
+        let base = ws.path();
+        let src = base.join("src");
+        std::fs::create_dir_all(src.join("etc")).unwrap();
+        std::fs::write(src.join("etc/hostname"), b"guest\n").unwrap();
+
+        let plain = base.join("rootfs.cpio");
+        let packed = base.join("rootfs.cpio.zst");
+        convert(&src, &plain, None).unwrap();
+        convert(&src, &packed, Some(Compression::Zstd)).unwrap();
+
+        assert!(read_if_zstd(&plain).unwrap().is_none());
+        let unpacked = read_if_zstd(&packed).unwrap().unwrap();
+        assert_eq!(unpacked, std::fs::read(&plain).unwrap());
+        let bytes = std::fs::read(&packed).unwrap();
+        assert_eq!(decode_if_zstd(&bytes).unwrap().unwrap(), unpacked);
+        assert!(decode_if_zstd(&unpacked).unwrap().is_none());
+        let names: Vec<_> = parse(&unpacked).into_iter().map(|e| e.0).collect();
+        assert_eq!(names, ["etc", "etc/hostname"]);
+    }
+
+    #[test]
+    fn mapped_initrd_derefs_to_file_contents() {
+        let ws = Workspace::new().unwrap();
+        let base = ws.path();
+        let path = base.join("rootfs.cpio");
+        std::fs::write(&path, b"070701 archive").unwrap();
+        let map = MappedInitrd::open(&path).unwrap();
+        assert_eq!(&*map, b"070701 archive");
+        assert_eq!(map.path(), path);
+
+        std::fs::write(base.join("empty"), b"").unwrap();
+        assert!(MappedInitrd::open(base.join("empty")).unwrap().is_empty());
+        let err = MappedInitrd::open(base.join("missing")).err().unwrap();
+        assert!(err.to_string().starts_with("initrd not found: "), "{err:#}");
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::InitrdIo { .. })
+        ));
+    }
+
+    #[test]
+    fn arg_files_are_staged_and_rewritten_to_guest_paths() {
+        let ws = Workspace::new().unwrap();
+        let base = ws.path();
+        std::fs::create_dir_all(base.join("a")).unwrap();
+        std::fs::create_dir_all(base.join("b")).unwrap();
+        std::fs::write(base.join("a/script.py"), b"print(1)").unwrap();
+        std::fs::write(base.join("b/script.py"), b"print(2)").unwrap();
+        std::fs::write(base.join("data.csv"), b"x,y").unwrap();
+        let rel = |p: &str| {
+            let cwd = std::env::current_dir().unwrap();
+            let up = "../".repeat(cwd.components().count() - 1);
+            format!(
+                "{up}{}",
+                base.join(p).to_str().unwrap().trim_start_matches('/')
+            )
+        };
+        let args = vec![
+            rel("a/script.py"),
+            "--rows".to_string(),
+            rel("data.csv"),
+            rel("b/script.py"),
+            rel("data.csv"),
+            "/usr/bin/python3".to_string(),
+            rel("missing.txt"),
+        ];
+        let staged = inject_arg_files(&args).unwrap();
+        assert_eq!(
+            staged.args,
+            [
+                "/args/script.py",
+                "--rows",
+                "/args/data.csv",
+                "/args/3/script.py",
+                "/args/data.csv",
+                "/usr/bin/python3",
+                &args[6],
+            ]
+        );
+        let contents: Vec<_> = staged
+            .files
+            .iter()
+            .map(|(p, d)| (p.as_str(), &d[..]))
+            .collect();
+        assert_eq!(
+            contents,
+            [
+                ("/args/script.py", &b"print(1)"[..]),
+                ("/args/data.csv", b"x,y"),
+                ("/args/3/script.py", b"print(2)"),
+            ]
+        );
+        let archive = staged.apply(&[]).unwrap();
+        let (entries, _) = scan(&archive).unwrap();
+        let names: Vec<_> = entries.iter().map(|e| e.name).collect();
+        assert_eq!(
+            names,
+            [
+                "args",
+                "args/script.py",
+                "args/data.csv",
+                "args/3",
+                "args/3/script.py"
+            ]
+        );
+    }
+}
@@ -0,0 +1,610 @@
+//! A registry of tracked CLI runs, so `ps` in another terminal can list
+//! what is running and what recently finished.
+//!
+//! The registry is a directory with one subdirectory per run, named by
+//! its run ID:
+//!
+//! ```text
+//! runs/
+//!   <run-id>/
+//!     run.json     pid, kernel, memory, start time, labels
+//!     output.log   the console as the run printed it
+//!     exit.json    how and when it ended, once it has
+//!     checkpoint   where a stopped run saved its guest, if it did
+//! ```
+//!
+//! The registry is private to the user: it is created with mode 0700
+//! under `$XDG_RUNTIME_DIR` (or a per-user directory in the temp dir),
+//! a directory another user owns or can write to is refused, and entries
+//! not owned by the current user are ignored, since `stop` signals the
+//! PIDs they name.
+//!
+//! A run without `exit.json` whose process is gone (killed, crashed) is
+//! reported as [`RunState::Lost`]. Finished runs are pruned after
+//! [`KEEP_FINISHED`], when the next run registers; suspended ones once
+//! their checkpoint file is gone.
+//!
+//! [`crate::runs_server`] serves the same list, logs and stop over HTTP
+//! for `hyperlight-unikraft serve`.
+//!
+//! [`RunRegistry::copy_log`] serves `logs`: a run's console so far, and
+//! with `follow` what it prints until it ends.
+//!
+//! [`RunRegistry::request_stop`] serves `stop`: it leaves the grace
+//! period in a `stop` file and sends the run's process SIGTERM; the run
+//! asks its guest to shut down, reads [`RegisteredRun::stop_grace`], and
+//! interrupts the guest once that has passed. A run started with
+//! `--checkpoint` whose guest returned within the grace period saves it
+//! and records [`RegisteredRun::checkpointed`]; it is then listed as
+//! [`RunState::Suspended`] until resumed with `--from-snapshot`.
+
+use anyhow::{anyhow, Context, Result};
+use serde_json::json;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Environment variable naming the registry directory (default:
+/// `hyperlight-unikraft-runs` in `$XDG_RUNTIME_DIR`, or
+/// `hyperlight-unikraft-runs-<uid>` in the system temp dir).
+pub const ENV_RUNS: &str = "HYPERLIGHT_UNIKRAFT_RUNS";
+
+/// How long a finished run stays listed.
+pub const KEEP_FINISHED: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How often a followed log is checked for more output.
+const FOLLOW_POLL: Duration = Duration::from_millis(200);
+
+const RECORD: &str = "run.json";
+const LOG: &str = "output.log";
+const EXIT: &str = "exit.json";
+const STOP: &str = "stop";
+const CHECKPOINT: &str = "checkpoint";
+
+/// How long a stopped run's guest gets to exit when no grace period was
+/// given (e.g. on Ctrl-C).
+pub const DEFAULT_STOP_GRACE: Duration = Duration::from_secs(5);
+
+/// What `ps` shows about a run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunRecord {
+    pub id: String,
+    /// The process running it.
+    pub pid: u32,
+    pub kernel: PathBuf,
+    pub heap_size: u64,
+    pub started: SystemTime,
+    /// `KEY=VALUE` tags given at start, e.g. the tenant.
+    pub labels: Vec<(String, String)>,
+}
+
+/// Where a run is.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RunState {
+    Running,
+    /// Ended at `at`, described by `exit` (`ok`, or the error).
+    Finished {
+        at: SystemTime,
+        exit: String,
+    },
+    /// Stopped at `at` with its guest saved to `checkpoint`, for
+    /// `--from-snapshot` to resume from.
+    Suspended {
+        at: SystemTime,
+        checkpoint: PathBuf,
+    },
+    /// Its process went away without recording an exit.
+    Lost,
+}
+
+impl RunState {
+    /// The state as `ps` and `GET /runs` name it.
+    pub fn label(&self) -> &'static str {
+        match self {
+            RunState::Running => "running",
+            RunState::Finished { exit, .. } if exit == "ok" => "exited",
+            RunState::Finished { .. } => "failed",
+            RunState::Suspended { .. } => "suspended",
+            RunState::Lost => "lost",
+        }
+    }
+}
+
+/// The registry directory.
+#[derive(Debug, Clone)]
+pub struct RunRegistry {
+    dir: PathBuf,
+}
+
+impl RunRegistry {
+    /// The registry under [`ENV_RUNS`], or the default directory.
+    pub fn open_default() -> Result<Self> {
+        let dir = std::env::var_os(ENV_RUNS)
+            .map(PathBuf::from)
+            .unwrap_or_else(default_dir);
+        Self::open(dir)
+    }
+
+    /// The registry in `dir` (created with mode 0700 if missing). Fails
+    /// if `dir` is owned by another user or writable by anyone else.
+    pub fn open(dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = dir.into();
+        create_private_dir(&dir)?;
+        Ok(Self { dir })
+    }
+
+    /// Add a run, pruning runs that finished more than [`KEEP_FINISHED`]
+    /// ago. The returned handle records its exit.
+    pub fn register(&self, record: &RunRecord) -> Result<RegisteredRun> {
+        self.prune(KEEP_FINISHED)?;
+        let dir = self.dir.join(&record.id);
+        let mut builder = std::fs::DirBuilder::new();
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+        builder
+            .create(&dir)
+            .with_context(|| format!("registering run in {dir:?}"))?;
+        let labels: serde_json::Map<_, _> = record
+            .labels
+            .iter()
+            .map(|(k, v)| (k.clone(), json!(v)))
+            .collect();
+        let doc = json!({
+            "id": record.id,
+            "pid": record.pid,
+            "kernel": record.kernel,
+            "heap_size": record.heap_size,
+            "started_ms": millis(record.started),
+            "labels": labels,
+        });
+        std::fs::write(dir.join(RECORD), serde_json::to_vec_pretty(&doc)?)?;
+        File::create(dir.join(LOG))?;
+        Ok(RegisteredRun { dir })
+    }
+
+    /// Every run in the registry with its state, oldest first.
+    pub fn list(&self) -> Result<Vec<(RunRecord, RunState)>> {
+        let mut runs = Vec::new();
+        for entry in std::fs::read_dir(&self.dir)? {
+            let dir = entry?.path();
+            // Entries planted by another user are never acted on.
+            if !owned_by_current_user(&dir) || !owned_by_current_user(&dir.join(RECORD)) {
+                continue;
+            }
+            // Half-written or foreign entries are skipped, not fatal.
+            if let Ok(record) = read_record(&dir) {
+                let state = read_state(&dir, record.pid);
+                runs.push((record, state));
+            }
+        }
+        runs.sort_by_key(|(r, _)| r.started);
+        Ok(runs)
+    }
+
+    /// The run whose ID is or starts with `id`.
+    pub fn find(&self, id: &str) -> Result<(RunRecord, RunState)> {
+        let mut found = self
+            .list()?
+            .into_iter()
+            .filter(|(r, _)| r.id.starts_with(id));
+        match (found.next(), found.next()) {
+            (Some(run), None) => Ok(run),
+            (None, _) => Err(anyhow!("no run {id:?} in {:?}", self.dir)),
+            (Some(_), Some(_)) => Err(anyhow!("run ID {id:?} is ambiguous")),
+        }
+    }
+
+    /// The console log of run `id`.
+    pub fn log_path(&self, id: &str) -> PathBuf {
+        self.dir.join(id).join(LOG)
+    }
+
+    /// Copy the console of the run `id` (or an ID prefix) to `out`. With
+    /// `follow`, keep copying what it prints until it is no longer
+    /// running.
+    pub fn copy_log(&self, id: &str, out: &mut dyn Write, follow: bool) -> Result<()> {
+        let (record, _) = self.find(id)?;
+        let path = self.log_path(&record.id);
+        let mut log = File::open(&path).with_context(|| format!("opening {path:?}"))?;
+        loop {
+            // Check before draining, so the last output isn't missed.
+            let running =
+                follow && read_state(&self.dir.join(&record.id), record.pid) == RunState::Running;
+            std::io::copy(&mut log, out)?;
+            out.flush()?;
+            if !running {
+                return Ok(());
+            }
+            std::thread::sleep(FOLLOW_POLL);
+        }
+    }
+
+    /// Ask the run `id` (or an ID prefix) to stop, giving its guest
+    /// `grace` before it is interrupted. Returns the run.
+    pub fn request_stop(&self, id: &str, grace: Duration) -> Result<RunRecord> {
+        let (record, state) = self.find(id)?;
+        if state != RunState::Running {
+            return Err(anyhow!("run {} is not running", record.id));
+        }
+        let dir = self.dir.join(&record.id);
+        std::fs::write(dir.join(STOP), (grace.as_millis() as u64).to_string())?;
+        signal(record.pid, false)?;
+        Ok(record)
+    }
+
+    /// Wait up to `timeout` for the run `id` to stop running, and return
+    /// its state.
+    pub fn wait(&self, id: &str, timeout: Duration) -> Result<RunState> {
+        let deadline = std::time::Instant::now() + timeout;
+        loop {
+            let (_, state) = self.find(id)?;
+            if state != RunState::Running || std::time::Instant::now() >= deadline {
+                return Ok(state);
+            }
+            std::thread::sleep(FOLLOW_POLL);
+        }
+    }
+
+    /// End the run `id` (or an ID prefix): ask it to stop with `grace`,
+    /// and kill its process if it still hasn't ended well after that.
+    /// Returns the run and how it ended.
+    pub fn stop(&self, id: &str, grace: Duration) -> Result<(RunRecord, RunState)> {
+        const MARGIN: Duration = Duration::from_secs(5);
+        let run = self.request_stop(id, grace)?;
+        let state = match self.wait(&run.id, grace + MARGIN)? {
+            RunState::Running => {
+                self.kill(&run.id)?;
+                self.wait(&run.id, MARGIN)?
+            }
+            state => state,
+        };
+        Ok((run, state))
+    }
+
+    /// Kill the process of a run that didn't stop when asked, and record
+    /// it as killed.
+    pub fn kill(&self, id: &str) -> Result<()> {
+        let (record, _) = self.find(id)?;
+        signal(record.pid, true)?;
+        write_exit(&self.dir.join(&record.id), "killed")
+    }
+
+    fn prune(&self, keep: Duration) -> Result<()> {
+        let now = SystemTime::now();
+        for (record, state) in self.list()? {
+            let expired = match state {
+                RunState::Finished { at, .. } => now.duration_since(at).unwrap_or_default() > keep,
+                RunState::Lost => now.duration_since(record.started).unwrap_or_default() > keep,
+                // Listed for as long as there is something to resume.
+                RunState::Suspended { ref checkpoint, .. } => !checkpoint.exists(),
+                RunState::Running => false,
+            };
+            if expired {
+                let _ = std::fs::remove_dir_all(self.dir.join(&record.id));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A registered run, to be marked finished when it ends.
+#[derive(Debug, Clone)]
+pub struct RegisteredRun {
+    dir: PathBuf,
+}
+
+impl RegisteredRun {
+    /// The run's console log, for the run to append to.
+    pub fn log(&self) -> Result<File> {
+        let path = self.dir.join(LOG);
+        File::options()
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("opening {path:?}"))
+    }
+
+    /// The grace period a `stop` asked for, or [`DEFAULT_STOP_GRACE`].
+    pub fn stop_grace(&self) -> Duration {
+        std::fs::read_to_string(self.dir.join(STOP))
+            .ok()
+            .and_then(|ms| ms.trim().parse().ok())
+            .map_or(DEFAULT_STOP_GRACE, Duration::from_millis)
+    }
+
+    /// Record that the guest was saved to `checkpoint` on its way out;
+    /// with a successful [`finish`](Self::finish) the run is then
+    /// [`RunState::Suspended`].
+    pub fn checkpointed(&self, checkpoint: &Path) -> Result<()> {
+        let path = std::path::absolute(checkpoint)?;
+        std::fs::write(
+            self.dir.join(CHECKPOINT),
+            path.as_os_str().as_encoded_bytes(),
+        )?;
+        Ok(())
+    }
+
+    /// Record how the run ended: `ok`, or the error.
+    pub fn finish(self, exit: &str) -> Result<()> {
+        write_exit(&self.dir, exit)
+    }
+}
+
+fn write_exit(dir: &Path, exit: &str) -> Result<()> {
+    let doc = json!({ "exit": exit, "finished_ms": millis(SystemTime::now()) });
+    std::fs::write(dir.join(EXIT), serde_json::to_vec(&doc)?)?;
+    Ok(())
+}
+
+fn read_record(dir: &Path) -> Result<RunRecord> {
+    let m: serde_json::Value = serde_json::from_slice(&std::fs::read(dir.join(RECORD))?)?;
+    let text = |name: &str| {
+        m[name]
+            .as_str()
+            .map(str::to_string)
+            .ok_or_else(|| anyhow!("run record is missing {name:?}"))
+    };
+    let number = |name: &str| {
+        m[name]
+            .as_u64()
+            .ok_or_else(|| anyhow!("run record is missing {name:?}"))
+    };
+    let labels = m["labels"]
+        .as_object()
+        .map(|o| {
+            o.iter()
+                .map(|(k, v)| (k.clone(), v.as_str().unwrap_or_default().to_string()))
+                .collect()
+        })
+        .unwrap_or_default();
+    Ok(RunRecord {
+        id: text("id")?,
+        pid: number("pid")? as u32,
+        kernel: text("kernel")?.into(),
+        heap_size: number("heap_size")?,
+        started: UNIX_EPOCH + Duration::from_millis(number("started_ms")?),
+        labels,
+    })
+}
+
+fn read_state(dir: &Path, pid: u32) -> RunState {
+    let exit = std::fs::read(dir.join(EXIT))
+        .ok()
+        .and_then(|raw| serde_json::from_slice::<serde_json::Value>(&raw).ok());
+    match exit {
+        Some(m) => {
+            let at = UNIX_EPOCH + Duration::from_millis(m["finished_ms"].as_u64().unwrap_or(0));
+            let exit = m["exit"].as_str().unwrap_or("unknown").to_string();
+            match std::fs::read_to_string(dir.join(CHECKPOINT)) {
+                Ok(checkpoint) if exit == "ok" => RunState::Suspended {
+                    at,
+                    checkpoint: checkpoint.into(),
+                },
+                _ => RunState::Finished { at, exit },
+            }
+        }
+        None if process_alive(pid) => RunState::Running,
+        None => RunState::Lost,
+    }
+}
+
+fn default_dir() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(runtime) if !runtime.is_empty() => {
+            PathBuf::from(runtime).join("hyperlight-unikraft-runs")
+        }
+        #[cfg(unix)]
+        _ => std::env::temp_dir().join(format!(
+            "hyperlight-unikraft-runs-{}",
+            nix::unistd::getuid()
+        )),
+        #[cfg(not(unix))]
+        _ => std::env::temp_dir().join("hyperlight-unikraft-runs"),
+    }
+}
+
+/// Create `dir` with mode 0700, and refuse it (or a symlink in its
+/// place) if another user owns it or anyone else can write to it.
+#[cfg(unix)]
+fn create_private_dir(dir: &Path) -> Result<()> {
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+    std::fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)
+        .with_context(|| format!("creating {dir:?}"))?;
+    let meta = std::fs::symlink_metadata(dir)?;
+    if !meta.is_dir() || meta.uid() != nix::unistd::getuid().as_raw() || meta.mode() & 0o022 != 0 {
+        return Err(anyhow!(
+            "run registry {dir:?} must be a directory owned by and writable only by the current user"
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn create_private_dir(dir: &Path) -> Result<()> {
+    std::fs::create_dir_all(dir).with_context(|| format!("creating {dir:?}"))
+}
+
+#[cfg(unix)]
+fn owned_by_current_user(path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    std::fs::symlink_metadata(path).is_ok_and(|m| m.uid() == nix::unistd::getuid().as_raw())
+}
+
+#[cfg(not(unix))]
+fn owned_by_current_user(_path: &Path) -> bool {
+    true
+}
+
+pub(crate) fn millis(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// The PID as `kill` takes it. 0 and negative values address process
+/// groups, so a record naming one is refused.
+#[cfg(unix)]
+fn run_pid(pid: u32) -> Result<nix::unistd::Pid> {
+    match i32::try_from(pid) {
+        Ok(raw) if raw > 0 => Ok(nix::unistd::Pid::from_raw(raw)),
+        _ => Err(anyhow!(
+            "run record names pid {pid}, which is not a process"
+        )),
+    }
+}
+
+/// EPERM means the process exists but belongs to someone else, so only
+/// ESRCH counts as gone.
+#[cfg(unix)]
+fn process_alive(pid: u32) -> bool {
+    use nix::errno::Errno;
+    use nix::sys::signal::kill;
+    run_pid(pid).is_ok_and(|pid| !matches!(kill(pid, None), Err(Errno::ESRCH)))
+}
+
+/// Without a way to probe the process, an unfinished run is assumed to
+/// still be going.
+#[cfg(not(unix))]
+fn process_alive(_pid: u32) -> bool {
+    true
+}
+
+/// SIGTERM, or SIGKILL with `force`.
+#[cfg(unix)]
+fn signal(pid: u32, force: bool) -> Result<()> {
+    use nix::sys::signal::{kill, Signal};
+    let sig = if force {
+        Signal::SIGKILL
+    } else {
+        Signal::SIGTERM
+    };
+    kill(run_pid(pid)?, sig).with_context(|| format!("signalling pid {pid}"))
+}
+
+#[cfg(not(unix))]
+fn signal(_pid: u32, _force: bool) -> Result<()> {
+    Err(anyhow!(
+        "stopping a run is only supported on Unix; stop it with Ctrl-C"
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::workspace::Workspace;
+
+    fn record(id: &str, pid: u32, started: SystemTime) -> RunRecord {
+        RunRecord {
+            id: id.into(),
+            pid,
+            kernel: "/k/python".into(),
+            heap_size: 512 << 20,
+            started,
+            labels: vec![("tenant".into(), "acme".into())],
+        }
+    }
+
+    #[test]
+    fn runs_are_listed_with_their_state_and_found_by_prefix() {
+        let ws = Workspace::new().unwrap();
+        let dir = ws.path().join("runs");
+        let registry = RunRegistry::open(&dir).unwrap();
+        let now = SystemTime::now();
+        let t0 = UNIX_EPOCH + Duration::from_millis(millis(now));
+
+        let done = registry
+            .register(&record("aa-1", std::process::id(), t0))
+            .unwrap();
+        let live = registry
+            .register(&record(
+                "bb-2",
+                std::process::id(),
+                t0 + Duration::from_secs(1),
+            ))
+            .unwrap();
+        // A PID that can't belong to a live process.
+        registry
+            .register(&record(
+                "cc-3",
+                i32::MAX as u32,
+                t0 + Duration::from_secs(2),
+            ))
+            .unwrap();
+        done.finish("ok").unwrap();
+
+        let runs = registry.list().unwrap();
+        let ids: Vec<_> = runs.iter().map(|(r, _)| r.id.as_str()).collect();
+        assert_eq!(ids, ["aa-1", "bb-2", "cc-3"]);
+        assert_eq!(runs[0].0, record("aa-1", std::process::id(), t0));
+        assert!(matches!(runs[0].1, RunState::Finished { ref exit, .. } if exit == "ok"));
+        assert_eq!(runs[1].1, RunState::Running);
+        #[cfg(unix)]
+        assert_eq!(runs[2].1, RunState::Lost);
+
+        assert_eq!(registry.find("bb").unwrap().0.id, "bb-2");
+        std::fs::write(registry.log_path("aa-1"), b"hello\n").unwrap();
+        let mut out = Vec::new();
+        registry.copy_log("aa", &mut out, true).unwrap();
+        assert_eq!(out, b"hello\n");
+        let err = registry
+            .request_stop("aa", Duration::from_secs(1))
+            .unwrap_err();
+        assert!(err.to_string().contains("not running"), "{err:#}");
+        assert_eq!(live.stop_grace(), DEFAULT_STOP_GRACE);
+        std::fs::write(dir.join("bb-2").join(STOP), "1500").unwrap();
+        assert_eq!(live.stop_grace(), Duration::from_millis(1500));
+        assert!(registry.find("zz").is_err());
+        registry.prune(Duration::ZERO).unwrap();
+        assert!(registry.find("aa").is_err());
+        assert!(registry.find("bb").is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn pids_that_address_process_groups_are_never_signalled() {
+        for pid in [0, i32::MAX as u32 + 1, u32::MAX] {
+            assert!(!process_alive(pid), "{pid}");
+            let err = signal(pid, false).unwrap_err();
+            assert!(err.to_string().contains("not a process"), "{err:#}");
+        }
+        // Alive even when owned by another user (EPERM).
+        assert!(process_alive(1));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_registry_others_can_write_to_is_refused() {
+        use std::os::unix::fs::PermissionsExt;
+        let ws = Workspace::new().unwrap();
+        let created = ws.path().join("runs");
+        RunRegistry::open(&created).unwrap();
+        let mode = std::fs::metadata(&created).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+
+        let shared = ws.path().join("shared");
+        std::fs::create_dir(&shared).unwrap();
+        std::fs::set_permissions(&shared, std::fs::Permissions::from_mode(0o777)).unwrap();
+        let err = RunRegistry::open(&shared).unwrap_err();
+        assert!(err.to_string().contains("writable only by"), "{err:#}");
+    }
+
+    #[test]
+    fn a_checkpointed_run_is_suspended_once_it_finishes() {
+        let ws = Workspace::new().unwrap();
+        let registry = RunRegistry::open(ws.path().join("runs")).unwrap();
+        let run = registry
+            .register(&record("aa-1", std::process::id(), SystemTime::now()))
+            .unwrap();
+        let saved = ws.path().join("job.snap");
+        run.checkpointed(&saved).unwrap();
+        assert_eq!(registry.find("aa").unwrap().1, RunState::Running);
+
+        run.finish("ok").unwrap();
+        assert!(matches!(
+            registry.find("aa").unwrap().1,
+            RunState::Suspended { ref checkpoint, .. } if *checkpoint == saved
+        ));
+    }
+}
@@ -0,0 +1,259 @@
+//! The run registry over HTTP, behind `hyperlight-unikraft serve`, so
+//! operators can list, tail and stop runs that other clients started:
+//!
+//! ```text
+//! GET    /runs                        tracked runs, oldest first (JSON)
+//! GET    /runs/<id>/logs[?follow=1]   a run's console; with follow, until it ends
+//! DELETE /runs/<id>[?grace=5s]        stop a run and return its output (JSON)
+//! ```
+//!
+//! `<id>` is a run ID or a unique prefix of one, as for `ps`, `logs` and
+//! `stop`. There is no authentication: anyone who can connect can stop
+//! any run in the registry, so the CLI listens on loopback by default.
+
+use anyhow::{anyhow, Result};
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::{Duration, SystemTime};
+
+use crate::runs::{self, RunRecord, RunRegistry, RunState, DEFAULT_STOP_GRACE};
+
+/// Longest request line plus headers accepted.
+const MAX_HEAD: u64 = 16 * 1024;
+
+/// How long a client gets to send its request.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Answer requests on `listener` until accepting fails, one thread per
+/// connection.
+pub fn serve(registry: &RunRegistry, listener: &TcpListener) -> Result<()> {
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let registry = registry.clone();
+        std::thread::spawn(move || {
+            // The client went away or sent garbage; nothing to report to.
+            let _ = handle(&registry, stream);
+        });
+    }
+    Ok(())
+}
+
+struct Request {
+    method: String,
+    path: String,
+    query: Vec<(String, String)>,
+}
+
+impl Request {
+    fn param(&self, name: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+fn handle(registry: &RunRegistry, mut stream: TcpStream) -> Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let request = match read_request(&stream) {
+        Ok(request) => request,
+        Err(e) => return respond(&mut stream, 400, &json!({ "error": format!("{e:#}") })),
+    };
+    let segments: Vec<&str> = request.path.split('/').filter(|s| !s.is_empty()).collect();
+    match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["runs"]) => {
+            let runs: Vec<Value> = registry
+                .list()?
+                .iter()
+                .map(|(run, state)| run_json(run, state))
+                .collect();
+            respond(&mut stream, 200, &json!(runs))
+        }
+        ("GET", ["runs", id, "logs"]) => {
+            let (run, _) = match registry.find(id) {
+                Ok(found) => found,
+                Err(e) => return respond(&mut stream, 404, &json!({ "error": e.to_string() })),
+            };
+            // The body ends when the connection closes, so a followed log
+            // can stream for as long as the run goes on.
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nConnection: close\r\n\r\n"
+            )?;
+            let follow = matches!(request.param("follow"), Some("1" | "true"));
+            registry.copy_log(&run.id, &mut stream, follow)
+        }
+        ("DELETE", ["runs", id]) => {
+            let run = match registry.find(id) {
+                Ok((run, RunState::Running)) => run,
+                Ok((run, _)) => {
+                    let error = format!("run {} is not running", run.id);
+                    return respond(&mut stream, 409, &json!({ "error": error }));
+                }
+                Err(e) => return respond(&mut stream, 404, &json!({ "error": e.to_string() })),
+            };
+            let grace = match request.param("grace") {
+                Some(grace) => match crate::parse_duration(grace) {
+                    Ok(grace) => grace,
+                    Err(e) => return respond(&mut stream, 400, &json!({ "error": e.to_string() })),
+                },
+                None => DEFAULT_STOP_GRACE,
+            };
+            let (run, state) = registry.stop(&run.id, grace)?;
+            let mut output = Vec::new();
+            registry.copy_log(&run.id, &mut output, false)?;
+            let mut doc = run_json(&run, &state);
+            doc["output"] = json!(String::from_utf8_lossy(&output));
+            respond(&mut stream, 200, &doc)
+        }
+        _ => respond(
+            &mut stream,
+            404,
+            &json!({ "error": format!("no endpoint {} {}", request.method, request.path) }),
+        ),
+    }
+}
+
+fn read_request(stream: &TcpStream) -> Result<Request> {
+    let mut reader = BufReader::new(stream.take(MAX_HEAD));
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return Err(anyhow!("malformed request line {:?}", line.trim_end()));
+    };
+    // Headers and any body are not needed by these endpoints.
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let query = query
+        .split('&')
+        .filter(|p| !p.is_empty())
+        .map(|p| {
+            let (k, v) = p.split_once('=').unwrap_or((p, ""));
+            (k.to_string(), v.to_string())
+        })
+        .collect();
+    Ok(Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        query,
+    })
+}
+
+fn respond(stream: &mut TcpStream, status: u16, body: &Value) -> Result<()> {
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        409 => "Conflict",
+        _ => "Error",
+    };
+    let body = serde_json::to_vec(body)?;
+    write!(
+        stream,
+        "HTTP/1.1 {status} {reason}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    )?;
+    stream.write_all(&body)?;
+    Ok(())
+}
+
+/// A run as `GET /runs` lists it: what `ps` shows, with times in
+/// milliseconds.
+fn run_json(run: &RunRecord, state: &RunState) -> Value {
+    let until = match state {
+        RunState::Finished { at, .. } | RunState::Suspended { at, .. } => *at,
+        RunState::Running | RunState::Lost => SystemTime::now(),
+    };
+    let labels: serde_json::Map<_, _> = run
+        .labels
+        .iter()
+        .map(|(k, v)| (k.clone(), json!(v)))
+        .collect();
+    let mut doc = json!({
+        "id": run.id,
+        "pid": run.pid,
+        "state": state.label(),
+        "kernel": run.kernel,
+        "heap_size": run.heap_size,
+        "started_ms": runs::millis(run.started),
+        "elapsed_ms": until.duration_since(run.started).unwrap_or_default().as_millis() as u64,
+        "labels": labels,
+    });
+    match state {
+        RunState::Finished { at, exit } => {
+            doc["exit"] = json!(exit);
+            doc["finished_ms"] = json!(runs::millis(*at));
+        }
+        RunState::Suspended { at, checkpoint } => {
+            doc["checkpoint"] = json!(checkpoint);
+            doc["finished_ms"] = json!(runs::millis(*at));
+        }
+        RunState::Running | RunState::Lost => {}
+    }
+    doc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::workspace::Workspace;
+
+    fn request(port: u16, method: &str, target: &str) -> (u16, String) {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        write!(
+            stream,
+            "{method} {target} HTTP/1.1\r\nHost: localhost\r\n\r\n"
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head.split_whitespace().nth(1).unwrap().parse().unwrap();
+        (status, body.to_string())
+    }
+
+    #[test]
+    fn runs_are_listed_tailed_and_refused_a_stop_once_finished() {
+        let ws = Workspace::new().unwrap();
+        let registry = RunRegistry::open(ws.path().join("runs")).unwrap();
+        let run = registry
+            .register(&RunRecord {
+                id: "aa-1".into(),
+                pid: std::process::id(),
+                kernel: "/k/python".into(),
+                heap_size: 512 << 20,
+                started: SystemTime::now(),
+                labels: vec![("tenant".into(), "acme".into())],
+            })
+            .unwrap();
+        std::fs::write(registry.log_path("aa-1"), b"hello\n").unwrap();
+        run.finish("ok").unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let served = registry.clone();
+        std::thread::spawn(move || serve(&served, &listener));
+
+        let (status, body) = request(port, "GET", "/runs");
+        assert_eq!(status, 200);
+        let runs: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(runs[0]["id"], "aa-1");
+        assert_eq!(runs[0]["state"], "exited");
+        assert_eq!(runs[0]["labels"]["tenant"], "acme");
+
+        assert_eq!(
+            request(port, "GET", "/runs/aa/logs?follow=1"),
+            (200, "hello\n".into())
+        );
+        assert_eq!(request(port, "GET", "/runs/zz/logs").0, 404);
+        assert_eq!(request(port, "DELETE", "/runs/aa?grace=1s").0, 409);
+        assert_eq!(request(port, "POST", "/runs").0, 404);
+    }
+}
@@ -0,0 +1,538 @@
+//! Destinations that receive guest console output line by line while a
+//! captured run is in progress (attach with [`VmConfig::with_sink`]).
+//!
+//! [`JournalSink`] and [`SyslogSink`] forward lines to the local systemd
+//! journal or syslog daemon with the run ID, the kernel's SHA-256, the
+//! line's source (kernel or app) and a priority derived from its log
+//! prefix. [`JsonlSink`] turns a run into a JSON Lines event stream.
+//!
+//! A run can feed any number of sinks at once. [`MemorySink`],
+//! [`FileSink`] and [`from_fn`] cover the common destinations, and
+//! [`OutputSink::filter`] gives each sink its own line filter:
+//!
+//! ```no_run
+//! use hyperlight_unikraft::output::{classify_line, LineKind};
+//! use hyperlight_unikraft::sink::{self, FileSink, MemorySink, OutputSink};
+//! use hyperlight_unikraft::VmConfig;
+//!
+//! let app_lines = MemorySink::default();
+//! let config = VmConfig::default()
+//!     .with_sink(app_lines.clone().filter(|l| classify_line(l) == LineKind::App))
+//!     .with_sink(FileSink::create("console.log")?)
+//!     .with_sink(sink::from_fn(|l| {
+//!         if l.contains("panic") {
+//!             eprintln!("guest panicked: {l}");
+//!         }
+//!     }));
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! [`VmConfig::with_sink`]: crate::VmConfig::with_sink
+
+use anyhow::Result;
+use std::collections::HashMap;
+use std::io::Write as _;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::output::{classify_line, guest_log_level, LineKind};
+use crate::{Phase, VmExit};
+
+/// The run a sink is about to receive lines from.
+#[derive(Debug, Clone, Copy)]
+pub struct RunInfo<'a> {
+    /// Unique per run within this host process.
+    pub run_id: &'a str,
+    pub kernel: &'a Path,
+}
+
+/// Receives a run's console lines as the guest writes them.
+pub trait OutputSink: Send {
+    /// Called before the run boots. An error aborts the run.
+    fn begin(&mut self, _run: &RunInfo<'_>) -> Result<()> {
+        Ok(())
+    }
+
+    /// One console line, without its trailing newline.
+    fn line(&mut self, line: &str);
+
+    /// A [`Phase`] of the run finished after `took`. The phases up to
+    /// boot are reported together once the guest has booted.
+    fn phase(&mut self, _phase: Phase, _took: Duration) {}
+
+    /// Called once the run's output has been fully drained, with how
+    /// the guest exited.
+    fn end(&mut self, _exit: &VmExit) {}
+
+    /// Only pass this sink the lines for which `keep` returns true.
+    /// `begin`, `phase` and `end` are always forwarded.
+    fn filter<F>(self, keep: F) -> Filter<Self, F>
+    where
+        Self: Sized,
+        F: FnMut(&str) -> bool + Send,
+    {
+        Filter { sink: self, keep }
+    }
+}
+
+/// A sink behind a line filter; see [`OutputSink::filter`].
+pub struct Filter<S, F> {
+    sink: S,
+    keep: F,
+}
+
+impl<S: OutputSink, F: FnMut(&str) -> bool + Send> OutputSink for Filter<S, F> {
+    fn begin(&mut self, run: &RunInfo<'_>) -> Result<()> {
+        self.sink.begin(run)
+    }
+
+    fn line(&mut self, line: &str) {
+        if (self.keep)(line) {
+            self.sink.line(line);
+        }
+    }
+
+    fn phase(&mut self, phase: Phase, took: Duration) {
+        self.sink.phase(phase, took)
+    }
+
+    fn end(&mut self, exit: &VmExit) {
+        self.sink.end(exit)
+    }
+}
+
+/// Collects lines in memory. Clones share the buffer, so keep one to
+/// read from after passing another to [`VmConfig::with_sink`].
+///
+/// [`VmConfig::with_sink`]: crate::VmConfig::with_sink
+#[derive(Debug, Clone, Default)]
+pub struct MemorySink {
+    lines: std::sync::Arc<std::sync::Mutex<Vec<String>>>,
+}
+
+impl MemorySink {
+    /// Lines received so far, across all runs.
+    pub fn lines(&self) -> Vec<String> {
+        self.lines.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Lines received so far, newline-joined.
+    pub fn text(&self) -> String {
+        self.lines().iter().map(|l| format!("{l}\n")).collect()
+    }
+
+    /// Forget everything received so far.
+    pub fn clear(&self) {
+        self.lines.lock().unwrap_or_else(|e| e.into_inner()).clear();
+    }
+}
+
+impl OutputSink for MemorySink {
+    fn line(&mut self, line: &str) {
+        self.lines
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(line.to_string());
+    }
+}
+
+/// Appends lines to a file.
+pub struct FileSink {
+    file: std::io::BufWriter<std::fs::File>,
+}
+
+impl FileSink {
+    /// Create (or truncate) `path`.
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = std::fs::File::create(path.as_ref())?;
+        Ok(Self {
+            file: std::io::BufWriter::new(file),
+        })
+    }
+}
+
+impl OutputSink for FileSink {
+    fn line(&mut self, line: &str) {
+        let _ = writeln!(self.file, "{line}");
+    }
+
+    fn end(&mut self, _exit: &VmExit) {
+        let _ = self.file.flush();
+    }
+}
+
+/// A sink that calls `f` for every line.
+pub fn from_fn<F: FnMut(&str) + Send>(f: F) -> FnSink<F> {
+    FnSink(f)
+}
+
+/// See [`from_fn`].
+pub struct FnSink<F>(F);
+
+impl<F: FnMut(&str) + Send> OutputSink for FnSink<F> {
+    fn line(&mut self, line: &str) {
+        (self.0)(line)
+    }
+}
+
+/// syslog severity for a guest line (facility `user`).
+fn severity(line: &str) -> u8 {
+    match guest_log_level(line) {
+        tracing::Level::ERROR => 3,
+        tracing::Level::WARN => 4,
+        tracing::Level::INFO => 6,
+        _ => 7,
+    }
+}
+
+fn kind_name(line: &str) -> &'static str {
+    match classify_line(line) {
+        LineKind::Kernel => "kernel",
+        LineKind::App => "app",
+    }
+}
+
+/// Per-run fields shared by the journal and syslog sinks, with kernel
+/// hashes cached across runs.
+#[derive(Default)]
+struct RunFields {
+    run_id: String,
+    kernel_sha256: String,
+    hashes: HashMap<PathBuf, String>,
+}
+
+impl RunFields {
+    fn begin(&mut self, run: &RunInfo<'_>) -> Result<()> {
+        use sha2::{Digest, Sha256};
+        self.run_id = run.run_id.to_string();
+        if let Some(h) = self.hashes.get(run.kernel) {
+            self.kernel_sha256 = h.clone();
+            return Ok(());
+        }
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut std::fs::File::open(run.kernel)?, &mut hasher)?;
+        let hash: String = hasher
+            .finalize()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+        self.hashes.insert(run.kernel.to_path_buf(), hash.clone());
+        self.kernel_sha256 = hash;
+        Ok(())
+    }
+}
+
+/// Writes a run as JSON Lines, one object per event:
+///
+/// ```text
+/// {"event":"start","run":"…","kernel":"/path/to/kernel"}
+/// {"event":"output","run":"…","data":"Hello, world"}
+/// {"event":"exit","run":"…","exit":{"kind":"halt"}}
+/// ```
+///
+/// `output` carries one console line without its newline; `exit` is a
+/// serialized [`VmExit`].
+pub struct JsonlSink {
+    target: JsonlTarget,
+    run_id: String,
+}
+
+enum JsonlTarget {
+    Writer(Box<dyn std::io::Write + Send>),
+    Channel(std::sync::mpsc::Sender<String>),
+}
+
+impl JsonlSink {
+    /// Write records to `writer`, flushing after each.
+    pub fn new<W: std::io::Write + Send + 'static>(writer: W) -> Self {
+        Self {
+            target: JsonlTarget::Writer(Box::new(writer)),
+            run_id: String::new(),
+        }
+    }
+
+    /// Send each record (without newline) down a channel instead.
+    pub fn channel() -> (Self, std::sync::mpsc::Receiver<String>) {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let sink = Self {
+            target: JsonlTarget::Channel(tx),
+            run_id: String::new(),
+        };
+        (sink, rx)
+    }
+
+    fn emit(&mut self, mut record: serde_json::Value) {
+        record["run"] = self.run_id.clone().into();
+        let line = record.to_string();
+        match &mut self.target {
+            JsonlTarget::Writer(w) => {
+                let _ = writeln!(w, "{line}").and_then(|()| w.flush());
+            }
+            JsonlTarget::Channel(tx) => {
+                let _ = tx.send(line);
+            }
+        }
+    }
+}
+
+impl OutputSink for JsonlSink {
+    fn begin(&mut self, run: &RunInfo<'_>) -> Result<()> {
+        self.run_id = run.run_id.to_string();
+        self.emit(serde_json::json!({
+            "event": "start",
+            "kernel": run.kernel.display().to_string(),
+        }));
+        Ok(())
+    }
+
+    fn line(&mut self, line: &str) {
+        self.emit(serde_json::json!({ "event": "output", "data": line }));
+    }
+
+    fn end(&mut self, exit: &VmExit) {
+        self.emit(serde_json::json!({ "event": "exit", "exit": exit.to_json() }));
+    }
+}
+
+#[cfg(unix)]
+pub use unix::{JournalSink, SyslogSink};
+
+#[cfg(unix)]
+mod unix {
+    use super::*;
+    use std::os::unix::net::UnixDatagram;
+
+    const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+    const SYSLOG_SOCKET: &str = "/dev/log";
+    const IDENTIFIER: &str = "hyperlight-unikraft";
+
+    /// Sends lines to systemd-journald over its native protocol. Each
+    /// entry carries `RUN_ID`, `KERNEL_SHA256` and `GUEST_KIND` fields
+    /// (query with `journalctl RUN_ID=…`).
+    pub struct JournalSink {
+        socket: UnixDatagram,
+        identifier: String,
+        run: RunFields,
+    }
+
+    impl JournalSink {
+        /// Connect to the system journal.
+        pub fn new() -> Result<Self> {
+            Self::connect(Path::new(JOURNAL_SOCKET))
+        }
+
+        /// Connect to a journal socket at a non-default path.
+        pub fn connect(path: &Path) -> Result<Self> {
+            let socket = UnixDatagram::unbound()?;
+            socket.connect(path)?;
+            Ok(Self {
+                socket,
+                identifier: IDENTIFIER.into(),
+                run: RunFields::default(),
+            })
+        }
+
+        /// `SYSLOG_IDENTIFIER` for entries (default `hyperlight-unikraft`).
+        pub fn with_identifier(mut self, identifier: impl Into<String>) -> Self {
+            self.identifier = identifier.into();
+            self
+        }
+    }
+
+    /// Append `KEY=value\n`, or the length-prefixed form for values
+    /// containing a newline.
+    fn journal_field(buf: &mut Vec<u8>, key: &str, value: &str) {
+        buf.extend_from_slice(key.as_bytes());
+        if value.contains('\n') {
+            buf.push(b'\n');
+            buf.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        } else {
+            buf.push(b'=');
+        }
+        buf.extend_from_slice(value.as_bytes());
+        buf.push(b'\n');
+    }
+
+    impl OutputSink for JournalSink {
+        fn begin(&mut self, run: &RunInfo<'_>) -> Result<()> {
+            self.run.begin(run)
+        }
+
+        fn line(&mut self, line: &str) {
+            let mut buf = Vec::with_capacity(line.len() + 200);
+            journal_field(&mut buf, "MESSAGE", line);
+            journal_field(&mut buf, "PRIORITY", &severity(line).to_string());
+            journal_field(&mut buf, "SYSLOG_IDENTIFIER", &self.identifier);
+            journal_field(&mut buf, "GUEST_KIND", kind_name(line));
+            journal_field(&mut buf, "RUN_ID", &self.run.run_id);
+            journal_field(&mut buf, "KERNEL_SHA256", &self.run.kernel_sha256);
+            // Best effort: a full or missing journal must not stall the guest.
+            let _ = self.socket.send(&buf);
+        }
+    }
+
+    /// Sends lines to the local syslog daemon (`/dev/log`, RFC 3164
+    /// framing, facility `user`). The run ID, a short kernel hash and the
+    /// line's source are prefixed to the message as `[run=… kernel=… app]`.
+    pub struct SyslogSink {
+        socket: UnixDatagram,
+        identifier: String,
+        run: RunFields,
+    }
+
+    impl SyslogSink {
+        /// Connect to `/dev/log`.
+        pub fn new() -> Result<Self> {
+            Self::connect(Path::new(SYSLOG_SOCKET))
+        }
+
+        /// Connect to a syslog socket at a non-default path.
+        pub fn connect(path: &Path) -> Result<Self> {
+            let socket = UnixDatagram::unbound()?;
+            socket.connect(path)?;
+            Ok(Self {
+                socket,
+                identifier: IDENTIFIER.into(),
+                run: RunFields::default(),
+            })
+        }
+
+        /// Syslog tag (default `hyperlight-unikraft`).
+        pub fn with_identifier(mut self, identifier: impl Into<String>) -> Self {
+            self.identifier = identifier.into();
+            self
+        }
+    }
+
+    impl OutputSink for SyslogSink {
+        fn begin(&mut self, run: &RunInfo<'_>) -> Result<()> {
+            self.run.begin(run)
+        }
+
+        fn line(&mut self, line: &str) {
+            let pri = 8 + severity(line);
+            let msg = format!(
+                "<{pri}>{}[{}]: [run={} kernel={} {}] {line}",
+                self.identifier,
+                std::process::id(),
+                self.run.run_id,
+                &self.run.kernel_sha256[..self.run.kernel_sha256.len().min(12)],
+                kind_name(line),
+            );
+            let _ = self.socket.send(msg.as_bytes());
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn listener(label: &str) -> (PathBuf, UnixDatagram, PathBuf) {
+            let dir = std::env::temp_dir().join(format!("hl-sink-{label}-{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            let sock = dir.join("sock");
+            let rx = UnixDatagram::bind(&sock).unwrap();
+            let kernel = dir.join("kernel");
+            std::fs::write(&kernel, b"abc").unwrap();
+            (sock, rx, kernel)
+        }
+
+        fn recv(rx: &UnixDatagram) -> String {
+            let mut buf = [0u8; 4096];
+            let n = rx.recv(&mut buf).unwrap();
+            String::from_utf8_lossy(&buf[..n]).into_owned()
+        }
+
+        const ABC_SHA256: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+        #[test]
+        fn journal_entries_carry_run_fields_and_priority() {
+            let (sock, rx, kernel) = listener("journal");
+            let mut sink = JournalSink::connect(&sock).unwrap();
+            sink.begin(&RunInfo {
+                run_id: "r1",
+                kernel: &kernel,
+            })
+            .unwrap();
+            sink.line("Warn: [libvfscore] no root");
+            assert_eq!(
+                recv(&rx),
+                format!(
+                    "MESSAGE=Warn: [libvfscore] no root\nPRIORITY=4\n\
+                     SYSLOG_IDENTIFIER=hyperlight-unikraft\nGUEST_KIND=kernel\n\
+                     RUN_ID=r1\nKERNEL_SHA256={ABC_SHA256}\n"
+                )
+            );
+        }
+
+        #[test]
+        fn syslog_messages_use_rfc3164_framing() {
+            let (sock, rx, kernel) = listener("syslog");
+            let mut sink = SyslogSink::connect(&sock).unwrap().with_identifier("app");
+            sink.begin(&RunInfo {
+                run_id: "r2",
+                kernel: &kernel,
+            })
+            .unwrap();
+            sink.line("hello");
+            assert_eq!(
+                recv(&rx),
+                format!(
+                    "<14>app[{}]: [run=r2 kernel=ba7816bf8f01 app] hello",
+                    std::process::id()
+                )
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filters_apply_per_sink() {
+        let all = MemorySink::default();
+        let app = MemorySink::default();
+        let mut count = 0;
+        let mut sinks: Vec<Box<dyn OutputSink + '_>> = vec![
+            Box::new(all.clone()),
+            Box::new(app.clone().filter(|l| classify_line(l) == LineKind::App)),
+            Box::new(from_fn(|_| count += 1)),
+        ];
+        for line in ["Info: [libukboot] booting", "hello", "world"] {
+            for s in sinks.iter_mut() {
+                s.line(line);
+            }
+        }
+        drop(sinks);
+        assert_eq!(all.lines().len(), 3);
+        assert_eq!(app.text(), "hello\nworld\n");
+        assert_eq!(count, 3);
+    }
+
+    #[test]
+    fn jsonl_records_cover_the_run_lifecycle() {
+        let (mut sink, rx) = JsonlSink::channel();
+        sink.begin(&RunInfo {
+            run_id: "r1",
+            kernel: Path::new("/k"),
+        })
+        .unwrap();
+        sink.line("Hello \"world\"");
+        sink.end(&VmExit::Halt);
+        let records: Vec<serde_json::Value> = rx
+            .try_iter()
+            .map(|l| serde_json::from_str(&l).unwrap())
+            .collect();
+        assert_eq!(
+            records,
+            [
+                serde_json::json!({"event": "start", "run": "r1", "kernel": "/k"}),
+                serde_json::json!({"event": "output", "run": "r1", "data": "Hello \"world\""}),
+                serde_json::json!({"event": "exit", "run": "r1", "exit": {"kind": "halt"}}),
+            ]
+        );
+    }
+}
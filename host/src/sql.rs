@@ -0,0 +1,385 @@
+//! Host-owned SQLite databases queried on the guest's behalf (`sqlite`
+//! feature).
+//!
+//! An [`SqlStore`] keeps one database file per tenant under a host
+//! directory. [`SqlStore::tenant`] opens (and, with
+//! [`with_schema`](SqlStore::with_schema), initializes) the tenant's
+//! database; [`SqlTenant::register`] exposes it to the guest as the
+//! `sql_query` / `sql_execute` tools. Guests send one parameterized
+//! statement per call and never see a file path. `ATTACH` is disabled,
+//! so a tenant can't reach another tenant's file, and every statement
+//! runs under [`SqlLimits`].
+//!
+//! ```no_run
+//! use hyperlight_unikraft::sql::SqlStore;
+//! use hyperlight_unikraft::Sandbox;
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! let store = SqlStore::open("/var/lib/tenants/sql")?
+//!     .with_schema("CREATE TABLE IF NOT EXISTS notes (id INTEGER PRIMARY KEY, body TEXT)");
+//! let sandbox = Sandbox::builder("kernel")
+//!     .sql(store.tenant("tenant-42")?)
+//!     .build()?;
+//! # Ok(())
+//! # }
+//! ```
+
+use anyhow::{anyhow, Context, Result};
+use base64::Engine;
+use rusqlite::limits::Limit;
+use rusqlite::types::{Value, ValueRef};
+use rusqlite::{Batch, Connection, Statement};
+use serde_json::json;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::ToolRegistry;
+
+/// Per-statement limits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SqlLimits {
+    /// Longest statement text, in bytes.
+    pub max_sql_len: usize,
+    /// Most bound parameters.
+    pub max_params: usize,
+    /// Most rows returned by `sql_query`; the rest are dropped and the
+    /// result is marked `truncated`.
+    pub max_rows: usize,
+    /// Wall-clock budget; longer statements are interrupted.
+    pub timeout: Duration,
+}
+
+impl Default for SqlLimits {
+    fn default() -> Self {
+        Self {
+            max_sql_len: 16 * 1024,
+            max_params: 256,
+            max_rows: 1000,
+            timeout: Duration::from_secs(2),
+        }
+    }
+}
+
+/// A directory of per-tenant SQLite databases.
+#[derive(Debug, Clone)]
+pub struct SqlStore {
+    root: PathBuf,
+    schema: Option<String>,
+    limits: SqlLimits,
+}
+
+impl SqlStore {
+    /// Open (creating if needed) the store rooted at `dir`.
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        let root = dir.as_ref();
+        std::fs::create_dir_all(root)
+            .with_context(|| format!("creating sql store {}", root.display()))?;
+        Ok(Self {
+            root: root.to_path_buf(),
+            schema: None,
+            limits: SqlLimits::default(),
+        })
+    }
+
+    /// SQL run on every tenant database when it's opened, e.g.
+    /// `CREATE TABLE IF NOT EXISTS ...` statements. Must be idempotent.
+    pub fn with_schema<S: Into<String>>(mut self, sql: S) -> Self {
+        self.schema = Some(sql.into());
+        self
+    }
+
+    pub fn with_limits(mut self, limits: SqlLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Open the database of tenant `name` (same naming rules as
+    /// [`KvStore::namespace`](crate::kv::KvStore::namespace)).
+    pub fn tenant(&self, name: &str) -> Result<SqlTenant> {
+        crate::kv::check_tenant_name("sql tenant", name)?;
+        let path = self.root.join(format!("{name}.sqlite"));
+        let conn =
+            Connection::open(&path).with_context(|| format!("opening {}", path.display()))?;
+        conn.set_limit(Limit::SQLITE_LIMIT_ATTACHED, 0);
+        conn.set_limit(
+            Limit::SQLITE_LIMIT_SQL_LENGTH,
+            self.limits.max_sql_len as i32,
+        );
+        conn.set_limit(
+            Limit::SQLITE_LIMIT_VARIABLE_NUMBER,
+            self.limits.max_params as i32,
+        );
+        if let Some(ref schema) = self.schema {
+            conn.execute_batch(schema)
+                .with_context(|| format!("applying schema to tenant {name:?}"))?;
+        }
+        Ok(SqlTenant {
+            conn: Arc::new(Mutex::new(conn)),
+            limits: self.limits,
+        })
+    }
+}
+
+/// Rows returned by [`SqlTenant::query`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct SqlRows {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<serde_json::Value>>,
+    /// More rows matched than [`SqlLimits::max_rows`].
+    pub truncated: bool,
+}
+
+/// One tenant's database. Cheap to clone; clones share the connection.
+#[derive(Clone)]
+pub struct SqlTenant {
+    conn: Arc<Mutex<Connection>>,
+    limits: SqlLimits,
+}
+
+impl SqlTenant {
+    /// Run a statement that returns rows. Parameters and values use the
+    /// JSON mapping described on [`register`](Self::register).
+    pub fn query(&self, sql: &str, params: &[serde_json::Value]) -> Result<SqlRows> {
+        let params = to_sql_params(params)?;
+        self.with_deadline(|conn| {
+            let mut stmt = prepare_one(conn, sql)?;
+            let columns: Vec<String> = stmt.column_names().iter().map(|c| c.to_string()).collect();
+            let mut rows = stmt.query(rusqlite::params_from_iter(params))?;
+            let mut out = Vec::new();
+            let mut truncated = false;
+            while let Some(row) = rows.next()? {
+                if out.len() == self.limits.max_rows {
+                    truncated = true;
+                    break;
+                }
+                out.push(
+                    (0..columns.len())
+                        .map(|i| row.get_ref(i).map(from_sql))
+                        .collect::<rusqlite::Result<_>>()?,
+                );
+            }
+            Ok(SqlRows {
+                columns,
+                rows: out,
+                truncated,
+            })
+        })
+    }
+
+    /// Run a statement for its effect. Returns the number of rows changed
+    /// and the last inserted rowid.
+    pub fn execute(&self, sql: &str, params: &[serde_json::Value]) -> Result<(usize, i64)> {
+        let params = to_sql_params(params)?;
+        self.with_deadline(|conn| {
+            let changes = prepare_one(conn, sql)?.execute(rusqlite::params_from_iter(params))?;
+            Ok((changes, conn.last_insert_rowid()))
+        })
+    }
+
+    /// Register the guest-facing tools on `registry`:
+    ///
+    /// - `sql_query` — `{ sql, params? }` → `{ columns, rows, truncated }`.
+    /// - `sql_execute` — `{ sql, params? }` → `{ changes, last_insert_rowid }`.
+    ///
+    /// `params` is a JSON array bound to `?` placeholders in order.
+    /// Numbers, strings, booleans (as 0/1) and `null` map directly;
+    /// blobs travel as `{ "blob": "<base64>" }` both ways.
+    pub fn register(self, registry: &mut ToolRegistry) {
+        let t = self.clone();
+        registry.register("sql_query", move |args| {
+            let (sql, params) = statement_args(&args, "sql_query")?;
+            let rows = t
+                .query(sql, params)
+                .map_err(|e| anyhow!("sql_query: {:#}", e))?;
+            Ok(json!({ "columns": rows.columns, "rows": rows.rows, "truncated": rows.truncated }))
+        });
+
+        registry.register("sql_execute", move |args| {
+            let (sql, params) = statement_args(&args, "sql_execute")?;
+            let (changes, rowid) = self
+                .execute(sql, params)
+                .map_err(|e| anyhow!("sql_execute: {:#}", e))?;
+            Ok(json!({ "changes": changes, "last_insert_rowid": rowid }))
+        });
+    }
+
+    /// Run `f` on the connection with the progress handler enforcing
+    /// [`SqlLimits::timeout`].
+    fn with_deadline<T>(&self, f: impl FnOnce(&Connection) -> rusqlite::Result<T>) -> Result<T> {
+        let conn = self.conn.lock().unwrap_or_else(|e| e.into_inner());
+        let start = Instant::now();
+        let timeout = self.limits.timeout;
+        conn.progress_handler(1000, Some(move || start.elapsed() > timeout));
+        let result = f(&conn);
+        conn.progress_handler(0, None::<fn() -> bool>);
+        result.map_err(|e| match e {
+            rusqlite::Error::SqliteFailure(err, _)
+                if err.code == rusqlite::ErrorCode::OperationInterrupted =>
+            {
+                anyhow!("statement exceeded the {:?} time limit", timeout)
+            }
+            e => e.into(),
+        })
+    }
+}
+
+impl std::fmt::Debug for SqlTenant {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SqlTenant")
+            .field("limits", &self.limits)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Compile `sql`, which must be exactly one statement: a guest can't
+/// smuggle a second one past the per-call checks.
+fn prepare_one<'c>(conn: &'c Connection, sql: &str) -> rusqlite::Result<Statement<'c>> {
+    let mut batch = Batch::new(conn, sql);
+    let stmt = batch.next()?.ok_or(rusqlite::Error::InvalidQuery)?;
+    if batch.next()?.is_some() {
+        return Err(rusqlite::Error::MultipleStatement);
+    }
+    Ok(stmt)
+}
+
+fn statement_args<'a>(
+    args: &'a serde_json::Value,
+    tool: &str,
+) -> Result<(&'a str, &'a [serde_json::Value])> {
+    let sql = args["sql"]
+        .as_str()
+        .ok_or_else(|| anyhow!("{}: missing 'sql'", tool))?;
+    let params = match args.get("params") {
+        None | Some(serde_json::Value::Null) => &[][..],
+        Some(serde_json::Value::Array(a)) => a.as_slice(),
+        Some(_) => return Err(anyhow!("{}: 'params' must be an array", tool)),
+    };
+    Ok((sql, params))
+}
+
+fn to_sql_params(params: &[serde_json::Value]) -> Result<Vec<Value>> {
+    use serde_json::Value as J;
+    params
+        .iter()
+        .map(|p| {
+            Ok(match p {
+                J::Null => Value::Null,
+                J::Bool(b) => Value::Integer(*b as i64),
+                J::Number(n) => match n.as_i64() {
+                    Some(i) => Value::Integer(i),
+                    None => Value::Real(n.as_f64().unwrap_or(f64::NAN)),
+                },
+                J::String(s) => Value::Text(s.clone()),
+                J::Object(o) if o.len() == 1 && o["blob"].is_string() => Value::Blob(
+                    base64::engine::general_purpose::STANDARD
+                        .decode(o["blob"].as_str().unwrap_or_default())
+                        .map_err(|e| anyhow!("bad base64 blob parameter: {}", e))?,
+                ),
+                other => return Err(anyhow!("unsupported sql parameter: {}", other)),
+            })
+        })
+        .collect()
+}
+
+fn from_sql(v: ValueRef<'_>) -> serde_json::Value {
+    match v {
+        ValueRef::Null => serde_json::Value::Null,
+        ValueRef::Integer(i) => json!(i),
+        ValueRef::Real(f) => json!(f),
+        ValueRef::Text(t) => json!(String::from_utf8_lossy(t)),
+        ValueRef::Blob(b) => {
+            json!({ "blob": base64::engine::general_purpose::STANDARD.encode(b) })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::workspace::Workspace;
+
+    const SCHEMA: &str =
+        "CREATE TABLE IF NOT EXISTS notes (id INTEGER PRIMARY KEY, body TEXT, raw BLOB)";
+
+    #[test]
+    fn tenants_get_their_own_persistent_database() {
+        let ws = Workspace::new().unwrap();
+        let store = SqlStore::open(ws.path()).unwrap().with_schema(SCHEMA);
+        let a = store.tenant("a").unwrap();
+        let (changes, id) = a
+            .execute(
+                "INSERT INTO notes (body, raw) VALUES (?, ?)",
+                &[json!("hi"), json!({ "blob": "AAE=" })],
+            )
+            .unwrap();
+        assert_eq!((changes, id), (1, 1));
+
+        let reopened = store.tenant("a").unwrap();
+        let rows = reopened.query("SELECT body, raw FROM notes", &[]).unwrap();
+        assert_eq!(rows.columns, ["body", "raw"]);
+        assert_eq!(rows.rows, [[json!("hi"), json!({ "blob": "AAE=" })]]);
+        assert!(store
+            .tenant("b")
+            .unwrap()
+            .query("SELECT * FROM notes", &[])
+            .unwrap()
+            .rows
+            .is_empty());
+    }
+
+    #[test]
+    fn limits_and_isolation_are_enforced() {
+        let ws = Workspace::new().unwrap();
+        let store = SqlStore::open(ws.path())
+            .unwrap()
+            .with_schema(SCHEMA)
+            .with_limits(SqlLimits {
+                max_rows: 2,
+                timeout: Duration::from_millis(50),
+                ..SqlLimits::default()
+            });
+        let t = store.tenant("t").unwrap();
+        for body in ["x", "y", "z"] {
+            t.execute("INSERT INTO notes (body) VALUES (?)", &[json!(body)])
+                .unwrap();
+        }
+        assert!(t.query("SELECT body FROM notes", &[]).unwrap().truncated);
+
+        let other = ws.path().join("other.sqlite");
+        let attach = format!("ATTACH DATABASE '{}' AS o", other.display());
+        assert!(t.execute(&attach, &[]).is_err());
+        assert!(t
+            .execute("DELETE FROM notes; DROP TABLE notes", &[])
+            .is_err());
+
+        let forever = "WITH RECURSIVE c(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM c) \
+                       SELECT count(*) FROM c";
+        let err = t.query(forever, &[]).unwrap_err();
+        assert!(err.to_string().contains("time limit"), "{err}");
+    }
+
+    #[test]
+    fn guest_tools_report_errors_instead_of_failing() {
+        let ws = Workspace::new().unwrap();
+        let mut registry = ToolRegistry::new();
+        SqlStore::open(ws.path())
+            .unwrap()
+            .with_schema(SCHEMA)
+            .tenant("t")
+            .unwrap()
+            .register(&mut registry);
+        let call = |name: &str, args: serde_json::Value| -> serde_json::Value {
+            let req = json!({ "name": name, "args": args });
+            serde_json::from_slice(&registry.dispatch(req.to_string().as_bytes())).unwrap()
+        };
+        let ok = call(
+            "sql_execute",
+            json!({ "sql": "INSERT INTO notes (body) VALUES (?)", "params": ["a"] }),
+        );
+        assert_eq!(ok["result"]["changes"], 1);
+        let rows = call("sql_query", json!({ "sql": "SELECT id, body FROM notes" }));
+        assert_eq!(rows["result"]["rows"], json!([[1, "a"]]));
+        assert!(call("sql_query", json!({ "sql": "SELEC" }))["error"].is_string());
+    }
+}
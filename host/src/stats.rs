@@ -0,0 +1,163 @@
+//! Live resource readings for a running guest, polled through
+//! [`VmHandle::stats`](crate::VmHandle::stats).
+//!
+//! Readings come from `/proc` on Linux; elsewhere the optional fields are
+//! `None`.
+
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// A point-in-time reading of a sandbox's resource use.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VmStats {
+    /// Resident set size of the whole host process in bytes. Guest
+    /// memory lives in host mappings, so this includes every guest page
+    /// that has been touched.
+    pub rss_bytes: Option<u64>,
+    /// CPU time the vCPU thread has spent in the current call, or in the
+    /// last one once it has returned. `None` before the first call.
+    pub cpu_time: Option<Duration>,
+    /// Console bytes captured since the current (or last) call started.
+    /// Stays 0 when nothing is capturing the guest's output. A guest
+    /// printing to port 0xE9 is counted with whatever else fd 2 carried
+    /// meanwhile; one printing through `HostPrint` only by itself.
+    pub output_bytes: u64,
+}
+
+/// Tracks the current call so [`VmStats`] can be read from any thread.
+#[derive(Debug, Default)]
+pub(crate) struct RunMeter {
+    state: Mutex<MeterState>,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct MeterState {
+    tid: Option<i32>,
+    cpu_start: Option<Duration>,
+    output_start: u64,
+    /// `(cpu_time, output_bytes)` frozen when the call returned.
+    finished: Option<(Option<Duration>, u64)>,
+    running: bool,
+}
+
+impl RunMeter {
+    /// Mark the start of a call on the current thread. `output` is the
+    /// sandbox's console count so far
+    /// ([`SandboxConsole::captured_bytes`](stderr_capture::SandboxConsole::captured_bytes)),
+    /// as are the counts passed to the other methods.
+    pub(crate) fn begin(&self, output: u64) {
+        let tid = current_tid();
+        *self.lock() = MeterState {
+            tid,
+            cpu_start: tid.and_then(thread_cpu_time),
+            output_start: output,
+            finished: None,
+            running: true,
+        };
+    }
+
+    /// Mark the end of the call started by [`begin`](Self::begin).
+    pub(crate) fn end(&self, output: u64) {
+        let mut state = self.lock();
+        state.finished = Some(live(&state, output));
+        state.running = false;
+    }
+
+    /// True between [`begin`](Self::begin) and [`end`](Self::end).
+    pub(crate) fn is_running(&self) -> bool {
+        self.lock().running
+    }
+
+    pub(crate) fn stats(&self, output: u64) -> VmStats {
+        let state = *self.lock();
+        let (cpu_time, output_bytes) = state.finished.unwrap_or_else(|| live(&state, output));
+        VmStats {
+            rss_bytes: process_rss(),
+            cpu_time,
+            output_bytes,
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, MeterState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn live(state: &MeterState, output: u64) -> (Option<Duration>, u64) {
+    let cpu = match (state.tid.and_then(thread_cpu_time), state.cpu_start) {
+        (Some(now), Some(start)) => Some(now.saturating_sub(start)),
+        _ => None,
+    };
+    (cpu, output.saturating_sub(state.output_start))
+}
+
+#[cfg(target_os = "linux")]
+fn current_tid() -> Option<i32> {
+    Some(nix::unistd::gettid().as_raw())
+}
+
+/// Nanoseconds on CPU, the first field of `schedstat`.
+#[cfg(target_os = "linux")]
+fn thread_cpu_time(tid: i32) -> Option<Duration> {
+    let schedstat = std::fs::read_to_string(format!("/proc/self/task/{tid}/schedstat")).ok()?;
+    let ns = schedstat.split_whitespace().next()?.parse().ok()?;
+    Some(Duration::from_nanos(ns))
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn process_rss() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let kb: u64 = status
+        .lines()
+        .find_map(|l| l.strip_prefix("VmRSS:"))?
+        .trim()
+        .strip_suffix("kB")?
+        .trim()
+        .parse()
+        .ok()?;
+    Some(kb * 1024)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn current_tid() -> Option<i32> {
+    None
+}
+
+#[cfg(not(target_os = "linux"))]
+fn thread_cpu_time(_tid: i32) -> Option<Duration> {
+    None
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn process_rss() -> Option<u64> {
+    None
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn meter_tracks_the_calling_thread_and_freezes_at_end() {
+        let meter = RunMeter::default();
+        assert_eq!(meter.stats(0).cpu_time, None);
+        assert!(!meter.is_running());
+
+        meter.begin(100);
+        assert!(meter.is_running());
+        let t = std::time::Instant::now();
+        while t.elapsed() < Duration::from_millis(20) {
+            std::hint::black_box(0u64.wrapping_add(1));
+        }
+        assert_eq!(meter.stats(110).output_bytes, 10);
+        meter.end(130);
+        assert!(!meter.is_running());
+
+        let frozen = meter.stats(500);
+        assert_eq!(frozen.output_bytes, 30);
+        assert!(frozen.cpu_time.unwrap() > Duration::ZERO);
+        assert!(frozen.rss_bytes.unwrap() > 0);
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(meter.stats(500).cpu_time, frozen.cpu_time);
+    }
+}
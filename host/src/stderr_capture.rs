@@ -1,40 +1,578 @@
 //! Cross-platform stderr redirection used to capture VM console output.
 //!
-//! On Unix: dup2-based redirect to a temp file.
+//! On Unix: dup2-based redirect to a temp file ([`Capture`]) or to a pipe
+//! drained by a reader thread ([`PipeCapture`]). The pipe variant can
+//! optionally tee every chunk back to the original stderr as it arrives,
+//! or only the lines a filter keeps, or to any [`Write`] instead.
 //! On Windows: no-op (VM output goes to inherited stderr, which the
 //! kraftkit subprocess driver captures via exec.Command).
+//!
+//! Guests that print through Hyperlight's `HostPrint` function instead
+//! reach their own sandbox's [`SandboxConsole`], which captures the same
+//! way without touching fd 2 (see [`Console::HostPrint`](crate::Console)).
+
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::output::OutputOverflow;
+
+/// What a [`PipeCapture`] collected.
+#[derive(Debug, Default)]
+pub struct CapturedOutput {
+    /// Everything written to fd 2 while the capture was active.
+    pub bytes: Vec<u8>,
+    /// When the reader thread received the first byte, if any.
+    pub first_output_at: Option<std::time::Instant>,
+    /// Time the reader thread spent handling output (buffering, tee,
+    /// filtering), excluding time blocked waiting for the guest.
+    pub busy: std::time::Duration,
+    /// Byte offset in `bytes` where each line starts, with the time the
+    /// reader received it.
+    pub line_starts: Vec<(usize, std::time::Instant)>,
+    /// Bytes left out of `bytes` because of the capture's [`Limit`].
+    pub dropped: u64,
+}
+
+static CONSOLE: std::sync::Mutex<()> = std::sync::Mutex::new(());
+static CAPTURED_BYTES: AtomicU64 = AtomicU64::new(0);
+
+/// Total bytes every [`PipeCapture`] in this process has received from
+/// fd 2. A [`SandboxConsole`] counts what it captures separately; see
+/// [`SandboxConsole::captured_bytes`].
+pub(crate) fn captured_bytes() -> u64 {
+    CAPTURED_BYTES.load(Ordering::Relaxed)
+}
+
+/// Serialize users of the process-wide console. Guest output reaches the
+/// host on fd 2, so only one run at a time can boot and capture without
+/// its output landing in another run's capture.
+pub(crate) fn lock_console() -> std::sync::MutexGuard<'static, ()> {
+    CONSOLE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+pub(crate) type LineFilter = Box<dyn FnMut(&[u8]) -> bool + Send>;
+
+/// A live-output destination shared by every run of a config; see
+/// [`VmConfig::with_tee_to`](crate::VmConfig::with_tee_to).
+pub(crate) type SharedWriter = Mutex<dyn Write + Send>;
+
+/// Where a capture copies the console as it arrives.
+pub(crate) enum Tee {
+    Off,
+    /// The host's stderr, as it was before any redirect.
+    Stderr,
+    Writer(Arc<SharedWriter>),
+}
+
+impl Tee {
+    pub(crate) fn stderr_if(on: bool) -> Self {
+        if on {
+            Self::Stderr
+        } else {
+            Self::Off
+        }
+    }
+
+    /// The passthrough for a collector, with `stderr` opening the
+    /// host's stderr when that is the destination.
+    fn into_writer(
+        self,
+        stderr: impl FnOnce() -> std::io::Result<Box<dyn Write + Send>>,
+    ) -> std::io::Result<Option<Box<dyn Write + Send>>> {
+        Ok(match self {
+            Self::Off => None,
+            Self::Stderr => Some(stderr()?),
+            Self::Writer(shared) => Some(Box::new(Shared(shared))),
+        })
+    }
+}
+
+struct Shared(Arc<SharedWriter>);
+
+impl Write for Shared {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut out = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        out.write(buf)
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        // One lock per chunk, so concurrent runs don't interleave mid-chunk.
+        let mut out = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        out.write_all(buf)?;
+        out.flush()
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).flush()
+    }
+}
+
+/// How much of the console a capture keeps; see
+/// [`VmConfig::with_max_output_bytes`](crate::VmConfig::with_max_output_bytes).
+/// The live copy to stderr is never cut.
+pub(crate) struct Limit {
+    pub max: usize,
+    pub overflow: OutputOverflow,
+    /// Called once, from whichever thread collects the output, when an
+    /// [`OutputOverflow::Abort`] limit is exceeded.
+    pub on_abort: Option<Box<dyn FnOnce() + Send>>,
+}
+
+/// Collects console bytes as they arrive, copying them (or, with a
+/// filter, the lines it keeps) to `passthrough`.
+struct Collector {
+    captured: CapturedOutput,
+    passthrough: Option<Box<dyn Write + Send>>,
+    keep: Option<LineFilter>,
+    limit: Option<Limit>,
+    /// Filtered tee holds partial lines here until complete.
+    pending: Vec<u8>,
+    at_line_start: bool,
+    /// False for a capture that only forwards: then nothing but the
+    /// current partial line is held.
+    retain: bool,
+}
+
+impl Collector {
+    fn new(
+        passthrough: Option<Box<dyn Write + Send>>,
+        keep: Option<LineFilter>,
+        limit: Option<Limit>,
+    ) -> Self {
+        Self {
+            captured: CapturedOutput::default(),
+            passthrough,
+            keep,
+            limit,
+            pending: Vec::new(),
+            at_line_start: true,
+            retain: true,
+        }
+    }
+
+    /// Forward only, keeping none of the output.
+    fn forward_only(self) -> Self {
+        Self {
+            retain: false,
+            ..self
+        }
+    }
+
+    fn push(&mut self, chunk: &[u8]) {
+        let now = std::time::Instant::now();
+        self.store(chunk, now);
+        self.captured.busy += now.elapsed();
+    }
+
+    fn store(&mut self, chunk: &[u8], now: std::time::Instant) {
+        self.captured.first_output_at.get_or_insert(now);
+        if self.retain {
+            self.retain_chunk(chunk, now);
+        }
+        let Some(out) = self.passthrough.as_mut() else {
+            return;
+        };
+        let Some(keep) = self.keep.as_mut() else {
+            let _ = out.write_all(chunk);
+            return;
+        };
+        self.pending.extend_from_slice(chunk);
+        while let Some(pos) = self.pending.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.pending.drain(..=pos).collect();
+            if keep(&line[..pos]) {
+                let _ = out.write_all(&line);
+            }
+        }
+    }
+
+    fn retain_chunk(&mut self, chunk: &[u8], now: std::time::Instant) {
+        let kept = self.admit(chunk);
+        let base = self.captured.bytes.len();
+        for (i, &b) in kept.iter().enumerate() {
+            if self.at_line_start {
+                self.captured.line_starts.push((base + i, now));
+            }
+            self.at_line_start = b == b'\n';
+        }
+        self.captured.bytes.extend_from_slice(kept);
+        if let Some(max) = self.tail_limit() {
+            // Trim in batches so a chatty guest doesn't pay a copy per chunk.
+            if self.captured.bytes.len() > max.saturating_mul(2) {
+                self.trim_front(self.captured.bytes.len() - max);
+            }
+        }
+    }
+
+    /// The part of `chunk` a head-keeping limit leaves room for.
+    fn admit<'a>(&mut self, chunk: &'a [u8]) -> &'a [u8] {
+        let Some(limit) = self.limit.as_mut() else {
+            return chunk;
+        };
+        if limit.overflow == OutputOverflow::KeepTail {
+            return chunk;
+        }
+        let room = limit.max.saturating_sub(self.captured.bytes.len());
+        if chunk.len() <= room {
+            return chunk;
+        }
+        self.captured.dropped += (chunk.len() - room) as u64;
+        if let Some(abort) = limit.on_abort.take() {
+            abort();
+        }
+        &chunk[..room]
+    }
+
+    fn tail_limit(&self) -> Option<usize> {
+        self.limit
+            .as_ref()
+            .filter(|l| l.overflow == OutputOverflow::KeepTail)
+            .map(|l| l.max)
+    }
+
+    /// Drop the first `n` bytes. The line they cut keeps its start time,
+    /// now at offset 0.
+    fn trim_front(&mut self, n: usize) {
+        let captured = &mut self.captured;
+        captured.bytes.drain(..n);
+        captured.dropped += n as u64;
+        let cut = captured
+            .line_starts
+            .partition_point(|&(offset, _)| offset <= n)
+            .saturating_sub(1);
+        captured.line_starts.drain(..cut);
+        for (offset, _) in &mut captured.line_starts {
+            *offset = offset.saturating_sub(n);
+        }
+    }
+
+    fn finish(mut self) -> CapturedOutput {
+        if let (Some(out), Some(keep)) = (self.passthrough.as_mut(), self.keep.as_mut()) {
+            if !self.pending.is_empty() && keep(&self.pending) {
+                let _ = out.write_all(&self.pending);
+            }
+        }
+        if let Some(max) = self.tail_limit() {
+            if let Some(excess) = self.captured.bytes.len().checked_sub(max) {
+                self.trim_front(excess);
+            }
+        }
+        self.captured
+    }
+}
+
+/// One sandbox's console: what its guest prints through Hyperlight's
+/// `HostPrint` function. Written straight to stderr, except while a
+/// [`SandboxCapture`] collects it; other sandboxes and threads writing
+/// to stderr never end up in that capture.
+#[derive(Default)]
+pub(crate) struct SandboxConsole {
+    capture: Mutex<Option<Collector>>,
+    printed: AtomicBool,
+    captured: AtomicU64,
+    discard: AtomicBool,
+}
+
+impl std::fmt::Debug for SandboxConsole {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SandboxConsole")
+            .field("printed", &self.printed())
+            .field("captured", &self.captured.load(Ordering::Relaxed))
+            .finish_non_exhaustive()
+    }
+}
+
+impl SandboxConsole {
+    /// A console whose guest is already known to print through it, or
+    /// not yet.
+    pub(crate) fn new(printed: bool) -> Self {
+        Self {
+            printed: AtomicBool::new(printed),
+            ..Self::default()
+        }
+    }
+
+    /// True once the guest has printed through `HostPrint`.
+    pub(crate) fn printed(&self) -> bool {
+        self.printed.load(Ordering::Relaxed)
+    }
+
+    /// Console bytes captured from this sandbox's guest so far: counted
+    /// here for one printing through `HostPrint`, otherwise every
+    /// [`PipeCapture`]'s, which is all fd 2 can tell apart.
+    pub(crate) fn captured_bytes(&self) -> u64 {
+        if self.printed() {
+            self.captured.load(Ordering::Relaxed)
+        } else {
+            captured_bytes()
+        }
+    }
+
+    /// The `HostPrint` implementation for this console.
+    pub(crate) fn print(&self, message: &str) -> i32 {
+        self.printed.store(true, Ordering::Relaxed);
+        match self.lock().as_mut() {
+            Some(collector) => {
+                self.captured
+                    .fetch_add(message.len() as u64, Ordering::Relaxed);
+                collector.push(message.as_bytes());
+            }
+            None if self.discard.load(Ordering::Relaxed) => {}
+            None => {
+                let _ = std::io::stderr().write_all(message.as_bytes());
+            }
+        }
+        message.len().try_into().unwrap_or(i32::MAX)
+    }
+
+    /// Drop what the guest prints while nothing captures it, rather than
+    /// writing it to stderr.
+    pub(crate) fn discard(&self) {
+        self.discard.store(true, Ordering::Relaxed);
+    }
+
+    /// Collect everything printed until the capture is finished, up to
+    /// `limit`, also forwarding it to `tee`: every line, or those `keep`
+    /// returns true for. The counterpart of [`PipeCapture::start_with`].
+    pub(crate) fn capture(
+        self: &Arc<Self>,
+        tee: Tee,
+        keep: Option<LineFilter>,
+        limit: Option<Limit>,
+    ) -> SandboxCapture {
+        let passthrough = tee
+            .into_writer(|| Ok(Box::new(std::io::stderr())))
+            .unwrap_or_default();
+        *self.lock() = Some(Collector::new(passthrough, keep, limit));
+        SandboxCapture {
+            console: self.clone(),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Option<Collector>> {
+        self.capture.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// An active capture of a [`SandboxConsole`]. Dropping it without
+/// [`finish`](Self::finish) discards what was collected.
+pub(crate) struct SandboxCapture {
+    console: Arc<SandboxConsole>,
+}
+
+impl SandboxCapture {
+    pub(crate) fn finish(self) -> CapturedOutput {
+        self.console
+            .lock()
+            .take()
+            .map(Collector::finish)
+            .unwrap_or_default()
+    }
+}
+
+impl Drop for SandboxCapture {
+    fn drop(&mut self) {
+        self.console.lock().take();
+    }
+}
 
 #[cfg(unix)]
 mod imp {
-    use anyhow::Result;
+    use super::{CapturedOutput, Collector, Limit, LineFilter, SharedWriter, Tee};
+    use anyhow::{anyhow, Result};
     use nix::unistd;
-    use std::os::fd::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd};
+    use std::io::{Read, Write};
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
     use std::path::Path;
+    use std::sync::atomic::Ordering;
+    use std::sync::{Arc, Mutex};
+    use std::thread::JoinHandle;
+
+    /// Restores fd 2 from the saved duplicate. Shared by both capture
+    /// kinds so a panic or early `?` unwinding through a caller always
+    /// puts the process's stderr back.
+    struct StderrGuard {
+        original_stderr: Option<OwnedFd>,
+    }
+
+    impl StderrGuard {
+        fn save() -> Result<Self> {
+            let raw = unistd::dup(2)?;
+            Ok(Self {
+                original_stderr: Some(unsafe { OwnedFd::from_raw_fd(raw) }),
+            })
+        }
+
+        fn restore(&mut self) -> Result<()> {
+            if let Some(fd) = self.original_stderr.take() {
+                std::io::stderr().flush().ok();
+                unistd::dup2(fd.as_raw_fd(), 2)?;
+            }
+            Ok(())
+        }
+    }
+
+    impl Drop for StderrGuard {
+        fn drop(&mut self) {
+            let _ = self.restore();
+        }
+    }
 
     pub struct Capture {
-        original_stderr: OwnedFd,
+        guard: StderrGuard,
     }
 
     impl Capture {
         pub fn redirect_to_file(path: &Path) -> Result<Self> {
-            let capture_fd = std::fs::File::create(path)?.into_raw_fd();
-            let original_stderr_raw = unistd::dup(2)?;
-            unistd::dup2(capture_fd, 2)?;
-            unistd::close(capture_fd)?;
-            let original_stderr = unsafe { OwnedFd::from_raw_fd(original_stderr_raw) };
-            Ok(Self { original_stderr })
+            let capture_file = std::fs::File::create(path)?;
+            let guard = StderrGuard::save()?;
+            unistd::dup2(capture_file.as_raw_fd(), 2)?;
+            Ok(Self { guard })
         }
 
-        pub fn restore(self) -> Result<()> {
-            unistd::dup2(self.original_stderr.as_raw_fd(), 2)?;
-            Ok(())
+        pub fn restore(mut self) -> Result<()> {
+            self.guard.restore()
+        }
+    }
+
+    /// Redirects fd 2 into a pipe. A reader thread drains the pipe into
+    /// memory and, when `tee` is set, copies each chunk to the original
+    /// stderr as it arrives so the terminal still sees live output.
+    ///
+    /// Dropping a `PipeCapture` without calling [`finish`](Self::finish)
+    /// (panic, early return) still restores fd 2 and joins the reader, so
+    /// the process is never left writing into a dead pipe.
+    pub struct PipeCapture {
+        guard: StderrGuard,
+        reader: Option<JoinHandle<CapturedOutput>>,
+    }
+
+    impl PipeCapture {
+        pub fn start(tee: bool) -> Result<Self> {
+            Self::start_with(Tee::stderr_if(tee), None, None)
+        }
+
+        /// Like `start(true)`, but only lines for which `keep` returns
+        /// true (given without the newline) reach the original stderr.
+        /// Everything is still captured.
+        pub fn start_filtered<F>(keep: F) -> Result<Self>
+        where
+            F: FnMut(&[u8]) -> bool + Send + 'static,
+        {
+            Self::start_with(Tee::Stderr, Some(Box::new(keep)), None)
+        }
+
+        /// [`start_filtered`](Self::start_filtered) without the capture:
+        /// lines stream through `keep` to the original stderr and none
+        /// are kept, so memory stays flat however long the run. `finish`
+        /// returns no bytes.
+        pub fn filter<F>(keep: F) -> Result<Self>
+        where
+            F: FnMut(&[u8]) -> bool + Send + 'static,
+        {
+            Self::start_collector(Tee::Stderr, Some(Box::new(keep)), None, false)
+        }
+
+        /// Send the console to `out` instead of stderr as it arrives,
+        /// keeping none of it.
+        pub fn forward_to(out: impl Write + Send + 'static) -> Result<Self> {
+            let out: Arc<SharedWriter> = Arc::new(Mutex::new(out));
+            Self::start_collector(Tee::Writer(out), None, None, false)
+        }
+
+        /// [`filter`](Self::filter) into `out` instead of stderr.
+        pub fn filter_to<F>(out: impl Write + Send + 'static, keep: F) -> Result<Self>
+        where
+            F: FnMut(&[u8]) -> bool + Send + 'static,
+        {
+            let out: Arc<SharedWriter> = Arc::new(Mutex::new(out));
+            Self::start_collector(Tee::Writer(out), Some(Box::new(keep)), None, false)
+        }
+
+        /// The general form of `start`: the chunks (or the lines `keep`
+        /// passes) go to `tee`, and `limit` caps what is kept.
+        pub(crate) fn start_with(
+            tee: Tee,
+            keep: Option<LineFilter>,
+            limit: Option<Limit>,
+        ) -> Result<Self> {
+            Self::start_collector(tee, keep, limit, true)
+        }
+
+        fn start_collector(
+            tee: Tee,
+            keep: Option<LineFilter>,
+            limit: Option<Limit>,
+            retain: bool,
+        ) -> Result<Self> {
+            let (read_end, write_end) = unistd::pipe()?;
+            let guard = StderrGuard::save()?;
+            let passthrough = tee.into_writer(|| {
+                let fd = unsafe { OwnedFd::from_raw_fd(unistd::dup(2)?) };
+                Ok(Box::new(std::fs::File::from(fd)))
+            })?;
+
+            let reader = std::thread::Builder::new()
+                .name("hl-capture".into())
+                .spawn(move || {
+                    let mut pipe = std::fs::File::from(read_end);
+                    let mut collector = Collector::new(passthrough, keep, limit);
+                    if !retain {
+                        collector = collector.forward_only();
+                    }
+                    let mut chunk = [0u8; 8192];
+                    loop {
+                        match pipe.read(&mut chunk) {
+                            Ok(0) => break,
+                            Ok(n) => {
+                                super::CAPTURED_BYTES.fetch_add(n as u64, Ordering::Relaxed);
+                                collector.push(&chunk[..n]);
+                            }
+                            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                            Err(_) => break,
+                        }
+                    }
+                    collector.finish()
+                })?;
+
+            // Point fd 2 at the pipe; dropping `write_end` leaves fd 2 as
+            // the only writer, so restoring stderr delivers EOF to the reader.
+            unistd::dup2(write_end.as_raw_fd(), 2)?;
+            drop(write_end);
+
+            Ok(Self {
+                guard,
+                reader: Some(reader),
+            })
+        }
+
+        /// Restore fd 2 and return everything the guest wrote while the
+        /// capture was active.
+        pub fn finish(mut self) -> Result<CapturedOutput> {
+            self.guard.restore()?;
+            self.reader
+                .take()
+                .expect("reader is only taken by finish/drop")
+                .join()
+                .map_err(|_| anyhow!("stderr capture reader thread panicked"))
+        }
+    }
+
+    impl Drop for PipeCapture {
+        fn drop(&mut self) {
+            let _ = self.guard.restore();
+            if let Some(reader) = self.reader.take() {
+                let _ = reader.join();
+            }
         }
     }
 }
 
 #[cfg(windows)]
 mod imp {
+    use super::{CapturedOutput, Limit, LineFilter, Tee};
     use anyhow::Result;
+    use std::io::Write;
     use std::path::Path;
 
     /// No-op on Windows. VM console output goes to inherited stderr,
@@ -50,6 +588,187 @@ mod imp {
             Ok(())
         }
     }
+
+    /// No-op on Windows, for the same reason as [`Capture`]. Output is
+    /// always "teed" because it was never redirected in the first place.
+    pub struct PipeCapture;
+
+    impl PipeCapture {
+        pub fn start(_tee: bool) -> Result<Self> {
+            Ok(Self)
+        }
+
+        /// No filtering is possible without a redirect; all output shows.
+        pub fn start_filtered<F>(_keep: F) -> Result<Self>
+        where
+            F: FnMut(&[u8]) -> bool + Send + 'static,
+        {
+            Ok(Self)
+        }
+
+        pub fn filter<F>(_keep: F) -> Result<Self>
+        where
+            F: FnMut(&[u8]) -> bool + Send + 'static,
+        {
+            Ok(Self)
+        }
+
+        pub fn forward_to(_out: impl Write + Send + 'static) -> Result<Self> {
+            Ok(Self)
+        }
+
+        pub fn filter_to<F>(_out: impl Write + Send + 'static, _keep: F) -> Result<Self>
+        where
+            F: FnMut(&[u8]) -> bool + Send + 'static,
+        {
+            Ok(Self)
+        }
+
+        pub(crate) fn start_with(
+            _tee: Tee,
+            _keep: Option<LineFilter>,
+            _limit: Option<Limit>,
+        ) -> Result<Self> {
+            Ok(Self)
+        }
+
+        pub fn finish(self) -> Result<CapturedOutput> {
+            Ok(CapturedOutput::default())
+        }
+    }
 }
 
-pub use imp::Capture;
+pub use imp::{Capture, PipeCapture};
+
+// Both scenarios live in one test: they rewire the process-wide fd 2, so
+// running them as separate (parallel) tests would race each other.
+#[cfg(all(test, unix))]
+mod tests {
+    use super::{Limit, OutputOverflow, PipeCapture, SandboxConsole, SharedWriter, Tee};
+    use nix::sys::stat::fstat;
+    use std::io::Write;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    fn stderr_inode() -> u64 {
+        fstat(2).unwrap().st_ino as u64
+    }
+
+    #[test]
+    fn pipe_capture_collects_bytes_and_restores_fd2_even_when_dropped() {
+        let before = stderr_inode();
+
+        let capture = PipeCapture::start(false).unwrap();
+        std::io::stderr()
+            .write_all(b"captured\xff\nline two")
+            .unwrap();
+        let captured = capture.finish().unwrap();
+        assert_eq!(captured.bytes, b"captured\xff\nline two");
+        let offsets: Vec<usize> = captured.line_starts.iter().map(|&(o, _)| o).collect();
+        assert_eq!(offsets, [0, 10]);
+        assert!(captured.first_output_at.is_some());
+        assert_eq!(stderr_inode(), before);
+
+        let result = std::panic::catch_unwind(|| {
+            let _capture = PipeCapture::start(false).unwrap();
+            panic!("unwind through an active capture");
+        });
+        assert!(result.is_err());
+        assert_eq!(stderr_inode(), before, "drop must restore fd 2");
+    }
+
+    #[test]
+    fn sandbox_consoles_capture_only_their_own_guest() {
+        // No tee and nothing printed outside a capture: fd 2 stays
+        // untouched, so this can run alongside the pipe test.
+        let a = Arc::new(SandboxConsole::default());
+        let b = Arc::new(SandboxConsole::default());
+        assert!(!a.printed());
+        let (capture_a, capture_b) = (
+            a.capture(Tee::Off, None, None),
+            b.capture(Tee::Off, None, None),
+        );
+        std::thread::scope(|s| {
+            s.spawn(|| assert_eq!(a.print("from a\n"), 7));
+            s.spawn(|| b.print("from b"));
+        });
+        a.print("again\n");
+        let (out_a, out_b) = (capture_a.finish(), capture_b.finish());
+        assert_eq!(out_a.bytes, b"from a\nagain\n");
+        assert_eq!(out_b.bytes, b"from b");
+        assert_eq!((a.captured_bytes(), b.captured_bytes()), (13, 6));
+        assert!(a.printed() && b.printed());
+        assert!(SandboxConsole::new(true).printed());
+        let offsets: Vec<usize> = out_a.line_starts.iter().map(|&(o, _)| o).collect();
+        assert_eq!(offsets, [0, 7]);
+        assert!(out_a.first_output_at.is_some());
+
+        // A dropped capture collects nothing more.
+        drop(a.capture(Tee::Off, None, None));
+        assert!(a.lock().is_none());
+    }
+
+    #[test]
+    fn forward_only_filter_streams_lines_and_keeps_nothing() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let out = Box::new(super::Shared(seen.clone() as Arc<SharedWriter>));
+        let keep = Box::new(|line: &[u8]| !line.starts_with(b"kernel"));
+        let mut collector = super::Collector::new(Some(out), Some(keep), None).forward_only();
+        collector.push(b"app one\nkernel: bo");
+        assert_eq!(*seen.lock().unwrap(), b"app one\n");
+        collector.push(b"ot\napp two");
+        let captured = collector.finish();
+        assert_eq!(*seen.lock().unwrap(), b"app one\napp two");
+        assert!(captured.bytes.is_empty() && captured.line_starts.is_empty());
+        assert!(captured.first_output_at.is_some());
+    }
+
+    #[test]
+    fn tee_writer_gets_every_byte_even_past_the_limit() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let tee = Tee::Writer(seen.clone() as Arc<SharedWriter>);
+        let limit = Limit {
+            max: 3,
+            overflow: OutputOverflow::KeepHead,
+            on_abort: None,
+        };
+        let console = Arc::new(SandboxConsole::default());
+        let capture = console.capture(tee, None, Some(limit));
+        console.print("live\n");
+        assert_eq!(capture.finish().bytes, b"liv");
+        assert_eq!(*seen.lock().unwrap(), b"live\n");
+    }
+
+    #[test]
+    fn limits_keep_head_or_tail_and_abort_once() {
+        let run = |overflow, aborts: Arc<AtomicUsize>| {
+            let on_abort = Box::new(move || {
+                aborts.fetch_add(1, Ordering::SeqCst);
+            });
+            let limit = Limit {
+                max: 8,
+                overflow,
+                on_abort: (overflow == OutputOverflow::Abort).then_some(on_abort as _),
+            };
+            let console = Arc::new(SandboxConsole::default());
+            let capture = console.capture(Tee::Off, None, Some(limit));
+            for line in ["one\n", "two\n", "three\n", "four\n"] {
+                console.print(line);
+            }
+            capture.finish()
+        };
+        let aborts = Arc::new(AtomicUsize::new(0));
+
+        let head = run(OutputOverflow::KeepHead, aborts.clone());
+        assert_eq!((&head.bytes[..], head.dropped), (&b"one\ntwo\n"[..], 11));
+
+        let tail = run(OutputOverflow::KeepTail, aborts.clone());
+        assert_eq!((&tail.bytes[..], tail.dropped), (&b"ee\nfour\n"[..], 11));
+        let offsets: Vec<usize> = tail.line_starts.iter().map(|&(o, _)| o).collect();
+        assert_eq!(offsets, [0, 3]);
+
+        let abort = run(OutputOverflow::Abort, aborts.clone());
+        assert_eq!(abort.bytes, b"one\ntwo\n");
+        assert_eq!(aborts.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,301 @@
+//! Parameter sweeps: run one kernel over many argument sets, or over
+//! argument/environment combinations with [`sweep_runs`].
+//!
+//! The base rootfs is memory-mapped once and shared by every run; each
+//! guest maps the same file.
+//!
+//! Up to `jobs` workers load assets, assemble initrds and run pre-run
+//! hooks at once. How far the guests themselves overlap depends on the
+//! config's [`Console`](crate::Console):
+//!
+//! - a kernel printing to port 0xE9 reaches the one process stderr, so
+//!   its boots and output captures hold the process-wide console lock
+//!   and the VMs run one at a time;
+//! - with the default [`Console::Auto`](crate::Console::Auto), a kernel
+//!   that prints through `HostPrint` boots one at a time only until one
+//!   of its boots has shown it does; after that boots and runs overlap,
+//!   each capture per sandbox;
+//! - with [`Console::HostPrint`](crate::Console::HostPrint), they
+//!   overlap from the first boot.
+//!
+//! [`run_many`] does the same for jobs that each bring their own rootfs.
+
+use anyhow::Result;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::rootfs::MappedInitrd;
+use crate::{Error, VmConfig, VmOutput};
+
+/// One run of a [`sweep_runs`]: its application arguments and the
+/// environment variables set for it on top of the config's
+/// [`with_env`](VmConfig::with_env) ones.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SweepRun {
+    pub args: Vec<String>,
+    pub env: Vec<(String, String)>,
+}
+
+impl SweepRun {
+    /// A run with `args` and no extra environment.
+    pub fn new(args: Vec<String>) -> Self {
+        Self {
+            args,
+            env: Vec::new(),
+        }
+    }
+
+    /// Set `key` to `value` in this run's guest environment.
+    pub fn env(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.env.push((key.into(), value.into()));
+        self
+    }
+}
+
+/// Run `kernel` once per entry of `arg_sets`, with `base_rootfs` as the
+/// initrd of every run. Results come back in `arg_sets` order; a failed
+/// run is an `Err` in its slot and doesn't stop the others. The outer
+/// error is only for problems shared by all runs, such as an unreadable
+/// rootfs.
+///
+/// `config` applies to every run, including its pre- and post-run hooks.
+/// `jobs` bounds how many runs are in flight at once and is clamped to
+/// at least 1; whether their guests overlap depends on the console (see
+/// the module docs).
+pub fn sweep(
+    kernel: &Path,
+    base_rootfs: Option<&Path>,
+    arg_sets: &[Vec<String>],
+    config: &VmConfig,
+    jobs: usize,
+) -> Result<Vec<Result<VmOutput, Error>>, Error> {
+    let runs: Vec<SweepRun> = arg_sets.iter().cloned().map(SweepRun::new).collect();
+    sweep_runs(kernel, base_rootfs, &runs, config, jobs)
+}
+
+/// [`sweep`] over argument/environment combinations: each [`SweepRun`]
+/// boots with its own arguments and its own environment variables, which
+/// replace any config variable of the same name for that run only.
+pub fn sweep_runs(
+    kernel: &Path,
+    base_rootfs: Option<&Path>,
+    runs: &[SweepRun],
+    config: &VmConfig,
+    jobs: usize,
+) -> Result<Vec<Result<VmOutput, Error>>, Error> {
+    let rootfs = base_rootfs.map(MappedInitrd::open).transpose()?;
+    Ok(in_parallel(runs, config, jobs, |run| {
+        crate::capture_run(
+            kernel.into(),
+            rootfs.as_ref().map(Into::into),
+            &run.args,
+            &run.env,
+            config,
+            None,
+            &[],
+        )
+    }))
+}
+
+/// Run `kernel` once per `(initrd, args)` job, for callers that would
+/// otherwise put their own thread pool around
+/// [`run_vm_capture_output`](crate::run_vm_capture_output).
+/// Results come back in `jobs` order; a failed run is an `Err` in its
+/// slot and doesn't stop the others.
+///
+/// `config` applies to every run, including its pre- and post-run hooks.
+/// Up to `concurrency` jobs are in flight at once (clamped to at least
+/// 1); as with [`sweep`], the console decides whether guests overlap.
+pub fn run_many(
+    kernel: &Path,
+    jobs: &[(Option<&[u8]>, Vec<String>)],
+    config: &VmConfig,
+    concurrency: usize,
+) -> Vec<Result<VmOutput, Error>> {
+    in_parallel(jobs, config, concurrency, |(initrd, args)| {
+        crate::capture_run(
+            kernel.into(),
+            initrd.map(Into::into),
+            args,
+            &[],
+            config,
+            None,
+            &[],
+        )
+    })
+}
+
+/// `run` each of `items` on up to `workers` threads, in `items` order.
+fn in_parallel<T: Sync>(
+    items: &[T],
+    config: &VmConfig,
+    workers: usize,
+    run: impl Fn(&T) -> Result<VmOutput> + Sync,
+) -> Vec<Result<VmOutput, Error>> {
+    map_bounded(items.len(), workers, |i| {
+        let result = run(&items[i]);
+        config.run_post_hooks(&result);
+        result.map_err(Error::from)
+    })
+}
+
+/// `f` of every index below `count` on up to `workers` threads, each
+/// taking the next index as it frees up; the results in index order.
+/// Shared by the sweeps and [`loadtest`](crate::loadtest).
+pub(crate) fn map_bounded<R: Send>(
+    count: usize,
+    workers: usize,
+    f: impl Fn(usize) -> R + Sync,
+) -> Vec<R> {
+    let workers = workers.clamp(1, count.max(1));
+    let next = AtomicUsize::new(0);
+
+    let mut done: Vec<(usize, R)> = std::thread::scope(|s| {
+        let workers: Vec<_> = (0..workers)
+            .map(|_| {
+                s.spawn(|| {
+                    let mut mine = Vec::new();
+                    loop {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        if i >= count {
+                            break mine;
+                        }
+                        mine.push((i, f(i)));
+                    }
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|w| w.join().expect("sweep worker panicked"))
+            .collect()
+    });
+    done.sort_by_key(|(i, _)| *i);
+    done.into_iter().map(|(_, result)| result).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn results_keep_arg_set_order_and_hooks_see_every_run() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let hook_seen = seen.clone();
+        // A failing pre-run hook stops each run before any VM is created.
+        let config = VmConfig::default().with_pre_run_hook(move |assets| {
+            let arg = assets.app_args.join(" ");
+            hook_seen.lock().unwrap().push(arg.clone());
+            Err(anyhow::anyhow!("skipped {arg}"))
+        });
+        let arg_sets: Vec<Vec<String>> = (0..5).map(|i| vec![i.to_string()]).collect();
+
+        let results = sweep(Path::new("kernel"), None, &arg_sets, &config, 3).unwrap();
+        let errors: Vec<String> = results
+            .into_iter()
+            .map(|r| r.err().unwrap().to_string())
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                "skipped 0",
+                "skipped 1",
+                "skipped 2",
+                "skipped 3",
+                "skipped 4"
+            ]
+        );
+        assert_eq!(seen.lock().unwrap().len(), 5);
+    }
+
+    #[test]
+    fn run_many_passes_each_job_its_own_rootfs() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let hook_seen = seen.clone();
+        let config = VmConfig::default().with_pre_run_hook(move |assets| {
+            let size = assets.initrd.and_then(|i| i.body()).map_or(0, <[u8]>::len);
+            hook_seen.lock().unwrap().push(size);
+            Err(anyhow::anyhow!("skipped {}", assets.app_args.join(" ")))
+        });
+        let big = [0u8; 64];
+        let jobs = [
+            (None, vec!["a".to_string()]),
+            (Some(&big[..]), vec!["b".to_string()]),
+        ];
+
+        let results = run_many(Path::new("kernel"), &jobs, &config, 0);
+        let errors: Vec<String> = results
+            .into_iter()
+            .map(|r| r.err().unwrap().to_string())
+            .collect();
+        assert_eq!(errors, ["skipped a", "skipped b"]);
+        let mut sizes = seen.lock().unwrap().clone();
+        sizes.sort();
+        assert_eq!(sizes, [0, 64]);
+    }
+
+    #[test]
+    fn sweep_runs_set_env_per_run() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let hook_seen = seen.clone();
+        let config = VmConfig::default()
+            .with_env("MODE", "base")
+            .with_pre_run_hook(move |assets| {
+                let header = assets.initrd.unwrap().header();
+                let (info, _) = crate::parse_extended_initrd(header)?;
+                hook_seen.lock().unwrap().push(info.env);
+                Err(anyhow::anyhow!("skipped"))
+            });
+        let runs = [
+            SweepRun::new(vec!["a".into()]),
+            SweepRun::new(vec!["b".into()])
+                .env("MODE", "fast")
+                .env("SEED", "7"),
+        ];
+
+        let results = sweep_runs(Path::new("kernel"), None, &runs, &config, 1).unwrap();
+        assert!(results.iter().all(Result::is_err));
+        let mut envs = seen.lock().unwrap().clone();
+        envs.sort();
+        assert_eq!(
+            envs,
+            [
+                vec!["MODE=base".to_string()],
+                vec!["MODE=fast".to_string(), "SEED=7".to_string()],
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_run_env_fails_only_that_run() {
+        let config = VmConfig::default().with_pre_run_hook(|_| Err(anyhow::anyhow!("skipped")));
+        let runs = [
+            SweepRun::default(),
+            SweepRun::default().env("NOT VALID", "x"),
+        ];
+
+        let results = sweep_runs(Path::new("kernel"), None, &runs, &config, 2).unwrap();
+        let errors: Vec<String> = results
+            .into_iter()
+            .map(|r| r.err().unwrap().to_string())
+            .collect();
+        assert_eq!(errors[0], "skipped");
+        assert!(errors[1].contains("invalid environment variable name"));
+    }
+
+    #[test]
+    fn unreadable_rootfs_fails_the_whole_sweep() {
+        let missing = Path::new("/nonexistent/rootfs.cpio");
+        let err = sweep(
+            Path::new("kernel"),
+            Some(missing),
+            &[vec![]],
+            &VmConfig::default(),
+            1,
+        )
+        .err()
+        .unwrap();
+        assert!(matches!(err, Error::InitrdIo { .. }));
+    }
+}
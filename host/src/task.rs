@@ -0,0 +1,201 @@
+//! Runs as futures, for async callers.
+//!
+//! Hyperlight runs a guest by blocking the calling thread on the vCPU,
+//! which would stall an async executor. [`VmTask`] moves the run to a
+//! thread of its own and resolves when the guest is done; it works with
+//! any executor, since all it needs is the waker it's polled with.
+//!
+//! Dropping the task before it resolves cancels the run: the guest is
+//! interrupted (or, still booting, never started), the sandbox is torn
+//! down on the run's thread and the result is thrown away. That makes a
+//! `tokio::select!` with a timeout, or a dropped request future, enough
+//! to stop a runaway guest:
+//!
+//! ```no_run
+//! use hyperlight_unikraft::{run_vm_capture_output_async, VmConfig};
+//!
+//! # async fn handler(script: String) -> anyhow::Result<String> {
+//! let run = run_vm_capture_output_async(
+//!     "python-kernel",
+//!     Some(std::fs::read("python.cpio")?),
+//!     vec!["/app.py".into(), script],
+//!     VmConfig::default(),
+//! );
+//! let out = run.await?;
+//! # Ok(out.output)
+//! # }
+//! ```
+
+use anyhow::{anyhow, Result};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use crate::{Error, VmHandle};
+
+/// A run in progress on its own thread; resolves to the run's result.
+/// Dropping it cancels the run.
+#[must_use = "dropping a VmTask cancels the run"]
+pub struct VmTask<T> {
+    slot: Arc<Slot<T>>,
+    cancel: Arc<Cancel>,
+    done: bool,
+}
+
+/// The run's result once it's in, and the waker to call when it is.
+type SlotState<T> = (Option<Result<T, Error>>, Option<Waker>);
+
+struct Slot<T> {
+    state: Mutex<SlotState<T>>,
+}
+
+/// Stops a run from another thread: interrupts the sandbox attached to
+/// it, or keeps one from starting if none is attached yet.
+#[derive(Default)]
+pub(crate) struct Cancel {
+    cancelled: AtomicBool,
+    handle: Mutex<Option<VmHandle>>,
+}
+
+impl Cancel {
+    /// Tie the run's sandbox to this token. Fails if the run was already
+    /// cancelled, so it stops before calling into the guest.
+    pub(crate) fn attach(&self, handle: VmHandle) -> Result<()> {
+        let mut slot = self.handle.lock().unwrap_or_else(|e| e.into_inner());
+        if self.is_cancelled() {
+            return Err(anyhow!("run cancelled"));
+        }
+        *slot = Some(handle);
+        Ok(())
+    }
+
+    fn cancel(&self) {
+        let slot = self.handle.lock().unwrap_or_else(|e| e.into_inner());
+        self.cancelled.store(true, Ordering::SeqCst);
+        if let Some(handle) = slot.as_ref() {
+            handle.interrupt();
+        }
+    }
+
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+/// Run `f` on a new thread named `name`, handing it the task's
+/// cancellation token.
+pub(crate) fn spawn<T, F>(name: &str, f: F) -> VmTask<T>
+where
+    T: Send + 'static,
+    F: FnOnce(Arc<Cancel>) -> Result<T, Error> + Send + 'static,
+{
+    let slot = Arc::new(Slot {
+        state: Mutex::new((None, None)),
+    });
+    let cancel = Arc::new(Cancel::default());
+    let (thread_slot, thread_cancel) = (slot.clone(), cancel.clone());
+    let spawned = std::thread::Builder::new()
+        .name(name.into())
+        .spawn(move || {
+            let result = f(thread_cancel);
+            thread_slot.fill(result);
+        });
+    if let Err(e) = spawned {
+        slot.fill(Err(Error::Other(anyhow!("spawning the run's thread: {e}"))));
+    }
+    VmTask {
+        slot,
+        cancel,
+        done: false,
+    }
+}
+
+impl<T> Slot<T> {
+    fn fill(&self, result: Result<T, Error>) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.0 = Some(result);
+        if let Some(waker) = state.1.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> Future for VmTask<T> {
+    type Output = Result<T, Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.slot.state.lock().unwrap_or_else(|e| e.into_inner());
+        match state.0.take() {
+            Some(result) => {
+                drop(state);
+                self.done = true;
+                Poll::Ready(result)
+            }
+            None => {
+                state.1 = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<T> Drop for VmTask<T> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.cancel.cancel();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::task::Wake;
+    use std::time::Duration;
+
+    fn block_on<F: Future>(f: F) -> F::Output {
+        struct Unpark(std::thread::Thread);
+        impl Wake for Unpark {
+            fn wake(self: Arc<Self>) {
+                self.0.unpark();
+            }
+        }
+        let waker = Waker::from(Arc::new(Unpark(std::thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        let mut f = std::pin::pin!(f);
+        loop {
+            if let Poll::Ready(out) = f.as_mut().poll(&mut cx) {
+                return out;
+            }
+            std::thread::park();
+        }
+    }
+
+    #[test]
+    fn task_resolves_to_the_runs_result() {
+        let task = spawn("hl-test", |_| {
+            std::thread::sleep(Duration::from_millis(20));
+            Ok(7)
+        });
+        assert_eq!(block_on(task).unwrap(), 7);
+        let failed = spawn::<(), _>("hl-test", |_| Err(Error::Other(anyhow!("boom"))));
+        assert_eq!(block_on(failed).unwrap_err().to_string(), "boom");
+    }
+
+    #[test]
+    fn dropping_the_task_cancels_the_run() {
+        let (tx, rx) = mpsc::channel();
+        let task = spawn("hl-test", move |cancel| {
+            while !cancel.is_cancelled() {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            tx.send(()).unwrap();
+            Ok(())
+        });
+        drop(task);
+        rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+}
@@ -0,0 +1,260 @@
+//! Test harness for guest images: boot a kernel, run the app once, and
+//! check its console output and exit.
+//!
+//! ```no_run
+//! use hyperlight_unikraft::testing::KernelTest;
+//!
+//! #[test]
+//! fn hello_prints_greeting() -> anyhow::Result<()> {
+//!     KernelTest::new("build/hello_hyperlight-x86_64")
+//!         .with_initrd("hello.cpio")
+//!         .expect_output_contains("Hello")
+//!         .expect_exit_code(0)
+//!         .run()?;
+//!     Ok(())
+//! }
+//! ```
+
+use anyhow::{anyhow, Context, Result};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use crate::rootfs::MappedInitrd;
+use crate::sink::OutputSink;
+use crate::{Console, VmConfig, VmExit};
+
+/// What a passing [`KernelTest`] observed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KernelTestOutput {
+    /// The application's console output, lossily decoded, one line per
+    /// console line. The boot banner is not included.
+    pub output: String,
+    pub exit: VmExit,
+}
+
+#[derive(Debug, Clone)]
+enum ExpectedExit {
+    Code(u8),
+    Exit(VmExit),
+}
+
+/// A single boot-and-run of a guest image with expectations on the result.
+#[derive(Debug, Clone)]
+pub struct KernelTest {
+    kernel: PathBuf,
+    initrd: Option<PathBuf>,
+    args: Vec<String>,
+    heap_size: Option<u64>,
+    console: Console,
+    contains: Vec<String>,
+    exit: Option<ExpectedExit>,
+}
+
+impl KernelTest {
+    pub fn new<P: Into<PathBuf>>(kernel: P) -> Self {
+        Self {
+            kernel: kernel.into(),
+            initrd: None,
+            args: Vec::new(),
+            heap_size: None,
+            console: Console::default(),
+            contains: Vec::new(),
+            exit: None,
+        }
+    }
+
+    /// The initrd CPIO to boot with.
+    pub fn with_initrd<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.initrd = Some(path.into());
+        self
+    }
+
+    /// Application arguments.
+    pub fn with_args<S, I>(mut self, args: I) -> Self
+    where
+        S: Into<String>,
+        I: IntoIterator<Item = S>,
+    {
+        self.args = args.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Guest heap size in bytes (the builder default otherwise).
+    pub fn with_heap_size(mut self, bytes: u64) -> Self {
+        self.heap_size = Some(bytes);
+        self
+    }
+
+    /// How the kernel prints. By default ([`Console::Auto`]) a kernel
+    /// that prints through `HostPrint` is captured per sandbox, so its
+    /// tests run in parallel once booted; [`Console::HostPrint`] lets
+    /// their boots overlap too.
+    pub fn with_console(mut self, console: Console) -> Self {
+        self.console = console;
+        self
+    }
+
+    /// Require `needle` somewhere in the console output. Repeatable.
+    pub fn expect_output_contains<S: Into<String>>(mut self, needle: S) -> Self {
+        self.contains.push(needle.into());
+        self
+    }
+
+    /// Require the guest to exit with `code`: 0 is a normal halt, any
+    /// other value is the code of a guest abort.
+    pub fn expect_exit_code(mut self, code: u8) -> Self {
+        self.exit = Some(ExpectedExit::Code(code));
+        self
+    }
+
+    /// Require a specific exit, e.g. [`VmExit::OutOfMemory`].
+    pub fn expect_exit(mut self, exit: VmExit) -> Self {
+        self.exit = Some(ExpectedExit::Exit(exit));
+        self
+    }
+
+    /// Boot, run once, and check the expectations. The error lists every
+    /// unmet expectation followed by the captured output.
+    ///
+    /// Runs through the same capture path as
+    /// [`run_vm_capture_output`](crate::run_vm_capture_output), so the
+    /// console is collected the way [`with_console`](Self::with_console)
+    /// says.
+    pub fn run(self) -> Result<KernelTestOutput> {
+        let initrd = self
+            .initrd
+            .as_ref()
+            .map(|p| MappedInitrd::open(p).with_context(|| format!("reading initrd {p:?}")))
+            .transpose()?;
+        let recorded = Arc::new(Mutex::new(Recorded::default()));
+        let mut config = VmConfig::default()
+            .with_console(self.console)
+            .with_sink(Recorder(recorded.clone()));
+        if let Some(heap) = self.heap_size {
+            config = config.with_heap_size(heap);
+        }
+
+        let result =
+            crate::run_vm_capture_output_mapped(&self.kernel, initrd.as_ref(), &self.args, config)
+                .map_err(anyhow::Error::from);
+        let recorded = std::mem::take(&mut *recorded.lock().unwrap_or_else(|e| e.into_inner()));
+        let observed = KernelTestOutput {
+            output: recorded.lines.join("\n"),
+            // A run that failed before the guest ran never reaches `end`.
+            exit: recorded
+                .exit
+                .unwrap_or_else(|| VmExit::from_result(&result)),
+        };
+        self.check(&observed)?;
+        Ok(observed)
+    }
+
+    fn check(&self, observed: &KernelTestOutput) -> Result<()> {
+        let mut failures = Vec::new();
+        for needle in &self.contains {
+            if !observed.output.contains(needle.as_str()) {
+                failures.push(format!("output does not contain {needle:?}"));
+            }
+        }
+        let exit_ok = match &self.exit {
+            None => true,
+            Some(ExpectedExit::Code(0)) => observed.exit.is_halt(),
+            Some(ExpectedExit::Code(code)) => {
+                matches!(observed.exit, VmExit::Abort { code: c, .. } if c == *code)
+            }
+            Some(ExpectedExit::Exit(exit)) => observed.exit == *exit,
+        };
+        if !exit_ok {
+            let wanted = match &self.exit {
+                Some(ExpectedExit::Code(code)) => format!("exit code {code}"),
+                Some(ExpectedExit::Exit(exit)) => exit.to_string(),
+                None => unreachable!(),
+            };
+            failures.push(format!("expected {wanted}, guest {}", observed.exit));
+        }
+        if failures.is_empty() {
+            return Ok(());
+        }
+        Err(anyhow!(
+            "{} failed:\n  {}\n--- captured output ---\n{}",
+            self.kernel.display(),
+            failures.join("\n  "),
+            observed.output
+        ))
+    }
+}
+
+#[derive(Default)]
+struct Recorded {
+    lines: Vec<String>,
+    exit: Option<VmExit>,
+}
+
+/// Collects the lines and exit the capture path reports to its sinks.
+struct Recorder(Arc<Mutex<Recorded>>);
+
+impl OutputSink for Recorder {
+    fn line(&mut self, line: &str) {
+        let mut recorded = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        recorded.lines.push(line.to_string());
+    }
+
+    fn end(&mut self, exit: &VmExit) {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).exit = Some(exit.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn observed(output: &str, exit: VmExit) -> KernelTestOutput {
+        KernelTestOutput {
+            output: output.into(),
+            exit,
+        }
+    }
+
+    #[test]
+    fn exit_code_zero_means_halt_and_others_match_aborts() {
+        let t = KernelTest::new("k").expect_exit_code(0);
+        assert!(t.check(&observed("", VmExit::Halt)).is_ok());
+        assert!(t.check(&observed("", VmExit::OutOfMemory)).is_err());
+
+        let abort = VmExit::Abort {
+            code: 3,
+            message: String::new(),
+        };
+        assert!(KernelTest::new("k")
+            .expect_exit_code(3)
+            .check(&observed("", abort))
+            .is_ok());
+    }
+
+    #[test]
+    fn a_missing_kernel_fails_the_exit_expectation() {
+        let err = KernelTest::new("/no/such/kernel")
+            .with_console(Console::HostPrint)
+            .expect_exit_code(0)
+            .run()
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("expected exit code 0, guest unexpected VM exit"));
+    }
+
+    #[test]
+    fn failures_are_all_reported_with_the_output() {
+        let err = KernelTest::new("hello")
+            .expect_output_contains("Hello")
+            .expect_output_contains("Bye")
+            .expect_exit(VmExit::Halt)
+            .check(&observed("Hello, world", VmExit::StackOverflow))
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            err,
+            "hello failed:\n  output does not contain \"Bye\"\n  \
+             expected halted, guest stack overflow\n--- captured output ---\nHello, world"
+        );
+    }
+}
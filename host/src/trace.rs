@@ -0,0 +1,199 @@
+//! Guest trace frames attached to [`VmOutput`](crate::VmOutput).
+//!
+//! With the `guest-trace` feature, Hyperlight forwards trace records the
+//! guest emits to the host's `tracing` facade under `hyperlight_guest*`
+//! targets. [`VmConfig::with_guest_trace`](crate::VmConfig::with_guest_trace)
+//! collects those events for the duration of a run into
+//! [`VmOutput::trace`](crate::VmOutput::trace), timestamped from the start
+//! of the run, while still passing everything on to the embedder's
+//! subscriber. Without the feature (or with a guest that emits nothing)
+//! the list stays empty.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::subscriber::Interest;
+use tracing::{Dispatch, Event, Metadata, Subscriber};
+
+/// Target prefix of the events Hyperlight re-emits for the guest.
+const GUEST_TARGET_PREFIX: &str = "hyperlight_guest";
+
+/// One trace record from inside the guest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TraceFrame {
+    /// When the host received it, relative to the start of the run.
+    #[cfg_attr(feature = "serde", serde(rename = "at_us", with = "micros"))]
+    pub at: Duration,
+    pub level: String,
+    pub target: String,
+    /// The event's `message` field followed by any others as `key=value`.
+    pub message: String,
+}
+
+#[cfg(feature = "serde")]
+pub(crate) mod micros {
+    use std::time::Duration;
+
+    pub fn serialize<S: serde::Serializer>(d: &Duration, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_u64(d.as_micros() as u64)
+    }
+
+    pub fn deserialize<'de, D: serde::Deserializer<'de>>(d: D) -> Result<Duration, D::Error> {
+        let us = <u64 as serde::Deserialize>::deserialize(d)?;
+        Ok(Duration::from_micros(us))
+    }
+}
+
+fn is_guest(meta: &Metadata<'_>) -> bool {
+    meta.target().starts_with(GUEST_TARGET_PREFIX)
+}
+
+/// Run `f` with a subscriber that records guest events on this thread
+/// and forwards every call to the current default dispatcher. Just runs
+/// `f` when `enabled` is false.
+pub(crate) fn collect<T>(
+    enabled: bool,
+    start: Instant,
+    f: impl FnOnce() -> T,
+) -> (T, Vec<TraceFrame>) {
+    if !enabled {
+        return (f(), Vec::new());
+    }
+    let frames = Arc::new(Mutex::new(Vec::new()));
+    let collector = Collector {
+        inner: tracing::dispatcher::get_default(Dispatch::clone),
+        frames: frames.clone(),
+        start,
+    };
+    let out = tracing::dispatcher::with_default(&Dispatch::new(collector), f);
+    let frames = std::mem::take(&mut *frames.lock().unwrap_or_else(|e| e.into_inner()));
+    (out, frames)
+}
+
+struct Collector {
+    inner: Dispatch,
+    frames: Arc<Mutex<Vec<TraceFrame>>>,
+    start: Instant,
+}
+
+impl Subscriber for Collector {
+    fn register_callsite(&self, meta: &'static Metadata<'static>) -> Interest {
+        let inner = self.inner.register_callsite(meta);
+        if is_guest(meta) {
+            Interest::always()
+        } else if inner.is_never() {
+            Interest::never()
+        } else {
+            Interest::sometimes()
+        }
+    }
+
+    fn enabled(&self, meta: &Metadata<'_>) -> bool {
+        is_guest(meta) || self.inner.enabled(meta)
+    }
+
+    fn new_span(&self, span: &Attributes<'_>) -> Id {
+        self.inner.new_span(span)
+    }
+
+    fn record(&self, span: &Id, values: &Record<'_>) {
+        self.inner.record(span, values)
+    }
+
+    fn record_follows_from(&self, span: &Id, follows: &Id) {
+        self.inner.record_follows_from(span, follows)
+    }
+
+    fn event(&self, event: &Event<'_>) {
+        let meta = event.metadata();
+        if is_guest(meta) {
+            let mut message = Message::default();
+            event.record(&mut message);
+            self.frames
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .push(TraceFrame {
+                    at: self.start.elapsed(),
+                    level: meta.level().to_string(),
+                    target: meta.target().to_string(),
+                    message: message.0,
+                });
+        }
+        if self.inner.enabled(meta) {
+            self.inner.event(event);
+        }
+    }
+
+    fn enter(&self, span: &Id) {
+        self.inner.enter(span)
+    }
+
+    fn exit(&self, span: &Id) {
+        self.inner.exit(span)
+    }
+
+    fn clone_span(&self, id: &Id) -> Id {
+        self.inner.clone_span(id)
+    }
+
+    fn try_close(&self, id: Id) -> bool {
+        self.inner.try_close(id)
+    }
+}
+
+#[derive(Default)]
+struct Message(String);
+
+impl Visit for Message {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if !self.0.is_empty() {
+            self.0.push(' ');
+        }
+        if field.name() == "message" {
+            self.0.push_str(&format!("{value:?}"));
+        } else {
+            self.0.push_str(&format!("{}={value:?}", field.name()));
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.record_debug(field, &format_args!("{value}"));
+        } else {
+            self.record_debug(field, &value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn guest_events_are_collected_and_host_events_are_not() {
+        let ((), frames) = collect(true, Instant::now(), || {
+            tracing::info!(target: "hyperlight_guest::trace", pc = 4096, "entered main");
+            tracing::info!(target: "hyperlight_unikraft", "host side");
+        });
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].level, "INFO");
+        assert_eq!(frames[0].message, "entered main pc=4096");
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn frames_serialize_times_in_microseconds() {
+        let frame = TraceFrame {
+            at: Duration::from_micros(7),
+            level: "INFO".into(),
+            target: "hyperlight_guest::trace".into(),
+            message: "entered main".into(),
+        };
+        let json = serde_json::to_value(&frame).unwrap();
+        assert_eq!(json["at_us"], 7);
+        assert_eq!(serde_json::from_value::<TraceFrame>(json).unwrap(), frame);
+    }
+}
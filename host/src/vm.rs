@@ -0,0 +1,870 @@
+//! One fluent entry point for a run.
+//!
+//! [`Vm::builder`] gathers everything a run needs — kernel, rootfs,
+//! arguments, environment, limits, tools, mounts and how to treat the
+//! console — and checks it in [`build`](VmBuilder::build), so a bad
+//! memory string or a missing kernel fails before anything boots:
+//!
+//! ```no_run
+//! use hyperlight_unikraft::{Capture, Vm};
+//! use std::time::Duration;
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! let out = Vm::builder()
+//!     .kernel("python-kernel")
+//!     .initrd_file("python.cpio")
+//!     .arg("/app.py")
+//!     .env("GREETING", "hello")
+//!     .memory("256Mi")
+//!     .timeout(Duration::from_secs(30))
+//!     .capture(Capture::Both)
+//!     .build()?
+//!     .run()?;
+//! println!("{}", out.output);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Options without a method of their own are reached through
+//! [`config`](VmBuilder::config), which edits the underlying
+//! [`VmConfig`]. The `run_vm*` functions remain for existing callers.
+//!
+//! A [`Vm`] can also be driven step by step. [`create`](Vm::create)
+//! boots it ahead of time, so a server pays for setup before a request
+//! arrives; [`start`](Vm::start) runs the application in the background
+//! and [`wait`](Vm::wait) collects the result. Meanwhile
+//! [`kill`](Vm::kill), or a [`VmHandle`] passed to another thread, stops
+//! the guest, say when the request it was serving goes away:
+//!
+//! ```no_run
+//! # use hyperlight_unikraft::Vm;
+//! # fn main() -> anyhow::Result<()> {
+//! let mut vm = Vm::builder().kernel("python-kernel").arg("/app.py").build()?;
+//! vm.create()?; // boots now
+//! // ... a request arrives
+//! vm.start()?;
+//! let handle = vm.handle().unwrap();
+//! std::thread::spawn(move || handle.kill());
+//! match vm.wait() {
+//!     Ok(out) => print!("{}", out.stdout),
+//!     Err(e) => eprintln!("{e:#}"), // Error::Killed if the kill landed
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! A created VM keeps its sandbox once the run is over. Its
+//! [`snapshot`](Vm::snapshot) is the guest as it stood after boot, before
+//! the application ran; [`restore`](Vm::restore) rewinds to it so the
+//! next [`start`](Vm::start) runs the application again without booting:
+//!
+//! ```no_run
+//! # use hyperlight_unikraft::Vm;
+//! # fn main() -> anyhow::Result<()> {
+//! let mut vm = Vm::builder().kernel("python-kernel").arg("/app.py").build()?;
+//! vm.create()?;
+//! let booted = vm.snapshot()?;
+//! for _ in 0..10 {
+//!     vm.start()?;
+//!     print!("{}", vm.wait()?.stdout);
+//!     vm.restore(&booted)?;
+//! }
+//! booted.save("app.snap")?; // for `run --from-snapshot app.snap`
+//! # Ok(())
+//! # }
+//! ```
+
+use anyhow::{anyhow, bail, Context, Result};
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
+
+use crate::rootfs::{self, MappedInitrd};
+use crate::{
+    parse_memory, BootedRun, Console, Error, ForkSeed, InitrdRef, KernelRef, Preopen, ToolRegistry,
+    VmConfig, VmHandle, VmOutput,
+};
+
+/// What happens to the guest's console output.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum Capture {
+    /// Leave it on the host's stderr; [`VmOutput::output`] stays empty.
+    None,
+    /// Collect it into [`VmOutput`] only.
+    #[default]
+    Output,
+    /// Collect it and forward it live to the host's stderr.
+    Both,
+}
+
+/// A checked run; see [`Vm::builder`]. Either [`run`](Self::run) it in
+/// one go or take it through [`create`](Self::create),
+/// [`start`](Self::start) and [`wait`](Self::wait).
+///
+/// Dropping a started `Vm` kills its guest.
+pub struct Vm {
+    state: State,
+}
+
+/// The inputs of a run, as checked by [`VmBuilder::build`].
+struct Spec {
+    kernel: Kernel,
+    initrd: Option<Initrd>,
+    args: Vec<String>,
+    config: VmConfig,
+    tools: Option<ToolRegistry>,
+    preopens: Vec<Preopen>,
+    /// Start from another VM's snapshot instead of booting.
+    fork: Option<(ForkSeed, Snapshot)>,
+}
+
+enum Kernel {
+    File(PathBuf),
+    Bytes(Cow<'static, [u8]>),
+}
+
+enum Initrd {
+    Mapped(MappedInitrd),
+    Bytes(Cow<'static, [u8]>),
+}
+
+enum State {
+    Built(Box<Spec>),
+    Created(Worker),
+    Running(Worker),
+    /// The run is over; its output, or `None` if it failed.
+    Done(Worker, Option<Box<VmOutput>>),
+    /// Boot failed or the VM's thread is gone; the error went to the
+    /// caller.
+    Failed,
+}
+
+/// The thread a created VM lives on: booted, then running the
+/// application each time it is told to.
+struct Worker {
+    handle: VmHandle,
+    /// What the next run starts from.
+    snapshot: Snapshot,
+    /// What forks take from this VM.
+    seed: ForkSeed,
+    kernel: PathBuf,
+    preopens: Vec<Preopen>,
+    commands: mpsc::Sender<Command>,
+    results: mpsc::Receiver<Result<VmOutput>>,
+}
+
+enum Command {
+    Run,
+    Rewind(Snapshot),
+}
+
+/// Guest memory as it stood after boot, before the application ran;
+/// taken with [`Vm::snapshot`]. Cheap to clone.
+#[derive(Clone)]
+pub struct Snapshot {
+    inner: Arc<hyperlight_host::sandbox::snapshot::Snapshot>,
+}
+
+impl Snapshot {
+    /// Write the snapshot to `path`, for
+    /// [`Sandbox::from_snapshot_file`](crate::Sandbox::from_snapshot_file)
+    /// or the CLI's `--from-snapshot` to start from in another process.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        self.inner
+            .to_file(path)
+            .with_context(|| format!("saving snapshot to {path:?}"))
+    }
+}
+
+impl std::fmt::Debug for Snapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Snapshot").finish_non_exhaustive()
+    }
+}
+
+impl Kernel {
+    fn as_ref(&self) -> KernelRef<'_> {
+        match self {
+            Kernel::File(path) => KernelRef::from(path.as_path()),
+            Kernel::Bytes(image) => KernelRef::from(&image[..]),
+        }
+    }
+
+    /// What the kernel is called; all a fork needs of it.
+    fn path(&self) -> &Path {
+        match self {
+            Kernel::File(path) => path,
+            Kernel::Bytes(_) => Path::new(crate::IN_MEMORY_KERNEL),
+        }
+    }
+}
+
+impl Initrd {
+    fn as_ref(&self) -> InitrdRef<'_> {
+        match self {
+            Initrd::Mapped(m) => InitrdRef::from(m),
+            Initrd::Bytes(b) => InitrdRef::from(&b[..]),
+        }
+    }
+}
+
+impl Spec {
+    fn run(mut self) -> Result<VmOutput> {
+        let result = self.boot().and_then(|mut booted| booted.run(&self.config));
+        self.config.run_post_hooks(&result);
+        result
+    }
+
+    fn boot(&mut self) -> Result<BootedRun> {
+        if let Some((seed, snapshot)) = self.fork.take() {
+            return BootedRun::forked(
+                &seed,
+                snapshot.inner,
+                self.kernel.path(),
+                &self.config,
+                self.tools.take(),
+                &self.preopens,
+            );
+        }
+        crate::boot_for_capture(
+            self.kernel.as_ref(),
+            self.initrd.as_ref().map(Initrd::as_ref),
+            &self.args,
+            &[],
+            &self.config,
+            self.tools.take(),
+            &self.preopens,
+        )
+    }
+}
+
+impl Vm {
+    pub fn builder() -> VmBuilder {
+        VmBuilder::default()
+    }
+
+    /// Boot the kernel, run the application and return what it printed
+    /// along with the run's timings. Post-run hooks see the result.
+    pub fn run(mut self) -> Result<VmOutput> {
+        match std::mem::replace(&mut self.state, State::Failed) {
+            State::Built(spec) => return spec.run(),
+            State::Created(worker) => {
+                self.state = State::Created(worker);
+                self.start()?;
+            }
+            other => self.state = other,
+        }
+        self.wait()?;
+        match std::mem::replace(&mut self.state, State::Failed) {
+            State::Done(_, Some(out)) => Ok(*out),
+            _ => unreachable!("wait leaves a finished run"),
+        }
+    }
+
+    /// Boot the VM now, on a thread of its own, and return once it is
+    /// ready to run the application; [`start`](Self::start) then skips
+    /// straight to the app. Boot failures are returned here.
+    pub fn create(&mut self) -> Result<()> {
+        let mut spec = match std::mem::replace(&mut self.state, State::Failed) {
+            State::Built(spec) => spec,
+            other => {
+                self.state = other;
+                bail!("VM already created");
+            }
+        };
+        let kernel = spec.kernel.path().to_path_buf();
+        let preopens = spec.preopens.clone();
+        let (ready_tx, ready) = mpsc::sync_channel(1);
+        let (commands, commands_rx) = mpsc::channel();
+        let (results_tx, results) = mpsc::channel();
+        std::thread::Builder::new()
+            .name("hl-vm".into())
+            .spawn(move || {
+                let mut booted = match spec.boot() {
+                    Ok(booted) => booted,
+                    Err(e) => {
+                        // Hooks see the failed boot; the caller gets the error.
+                        let failed: Result<VmOutput> = Err(e);
+                        spec.config.run_post_hooks(&failed);
+                        let _ = ready_tx.send(failed.map(|_| unreachable!()));
+                        return;
+                    }
+                };
+                let snapshot = booted
+                    .snapshot()
+                    .map(|inner| Snapshot { inner })
+                    .ok_or_else(|| anyhow!("the booted sandbox has no snapshot"));
+                let _ = ready_tx.send(snapshot.map(|s| (booted.handle(), s, booted.fork_seed())));
+                // Ends when the Vm is dropped.
+                for command in commands_rx {
+                    match command {
+                        Command::Run => {
+                            let result = booted.run(&spec.config);
+                            spec.config.run_post_hooks(&result);
+                            if results_tx.send(result).is_err() {
+                                return;
+                            }
+                        }
+                        Command::Rewind(snapshot) => booted.rewind_to(snapshot.inner),
+                    }
+                }
+            })?;
+        let (handle, snapshot, seed) = ready
+            .recv()
+            .unwrap_or_else(|_| Err(anyhow!("the VM's thread panicked while booting")))?;
+        self.state = State::Created(Worker {
+            handle,
+            snapshot,
+            seed,
+            kernel,
+            preopens,
+            commands,
+            results,
+        });
+        Ok(())
+    }
+
+    /// Run the application in the background, booting first if
+    /// [`create`](Self::create) wasn't called. Returns once it is
+    /// running; [`wait`](Self::wait) for the result.
+    pub fn start(&mut self) -> Result<()> {
+        if let State::Built(_) = self.state {
+            self.create()?;
+        }
+        match std::mem::replace(&mut self.state, State::Failed) {
+            State::Created(worker) => {
+                worker
+                    .commands
+                    .send(Command::Run)
+                    .map_err(|_| anyhow!("the VM's thread exited before starting"))?;
+                self.state = State::Running(worker);
+                Ok(())
+            }
+            other @ State::Done(..) => {
+                self.state = other;
+                bail!("VM already ran; restore() a snapshot to run it again")
+            }
+            other => {
+                self.state = other;
+                bail!("VM already started")
+            }
+        }
+    }
+
+    /// Wait for a [`start`](Self::start)ed run to end and return its
+    /// output, as [`run`](Self::run) would. Once it has succeeded, calling
+    /// again returns the same output.
+    pub fn wait(&mut self) -> Result<&VmOutput> {
+        match std::mem::replace(&mut self.state, State::Failed) {
+            State::Running(worker) => {
+                let Ok(result) = worker.results.recv() else {
+                    bail!("the VM's thread panicked");
+                };
+                match result {
+                    Ok(out) => self.state = State::Done(worker, Some(Box::new(out))),
+                    Err(e) => {
+                        self.state = State::Done(worker, None);
+                        return Err(e);
+                    }
+                }
+            }
+            State::Done(worker, None) => {
+                self.state = State::Done(worker, None);
+                bail!("the run failed");
+            }
+            State::Failed => bail!("the run failed"),
+            other @ State::Done(..) => self.state = other,
+            other => {
+                self.state = other;
+                bail!("VM not started; call start() first");
+            }
+        }
+        Ok(self.output().expect("a finished run has output"))
+    }
+
+    /// Stop the guest. A running app is interrupted; a created VM never
+    /// runs it. [`wait`](Self::wait) then fails with [`Error::Killed`].
+    /// Returns `true` if a running guest was interrupted.
+    pub fn kill(&self) -> bool {
+        match &self.state {
+            State::Created(w) | State::Running(w) => w.handle.kill(),
+            _ => false,
+        }
+    }
+
+    /// A handle on the booted guest for other threads:
+    /// [`VmHandle::kill`] stops it, [`VmHandle::shutdown`] asks first and
+    /// [`VmHandle::stats`] watches it. `None` before
+    /// [`create`](Self::create).
+    pub fn handle(&self) -> Option<VmHandle> {
+        self.worker().map(|w| w.handle.clone())
+    }
+
+    /// The output of a finished run; `None` until [`wait`](Self::wait)
+    /// has returned it.
+    pub fn output(&self) -> Option<&VmOutput> {
+        match &self.state {
+            State::Done(_, out) => out.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// The guest as the next run will find it: booted, with the
+    /// application not yet started. Booting first if
+    /// [`create`](Self::create) wasn't called.
+    pub fn snapshot(&mut self) -> Result<Snapshot> {
+        if let State::Built(_) = self.state {
+            self.create()?;
+        }
+        self.worker()
+            .map(|w| w.snapshot.clone())
+            .ok_or_else(|| anyhow!("the VM failed; no snapshot to take"))
+    }
+
+    /// Make the VM ready to run again, starting from `snapshot`, which
+    /// must have come from this VM's [`snapshot`](Self::snapshot). The
+    /// previous run's output is dropped.
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<()> {
+        match std::mem::replace(&mut self.state, State::Failed) {
+            State::Created(worker) | State::Done(worker, _) => {
+                worker
+                    .commands
+                    .send(Command::Rewind(snapshot.clone()))
+                    .map_err(|_| anyhow!("the VM's thread has exited"))?;
+                self.state = State::Created(Worker {
+                    snapshot: snapshot.clone(),
+                    ..worker
+                });
+                Ok(())
+            }
+            other @ State::Running(_) => {
+                self.state = other;
+                bail!("VM still running; wait() for it first")
+            }
+            other @ State::Built(_) => {
+                self.state = other;
+                bail!("VM not created; nothing to restore")
+            }
+            State::Failed => bail!("the VM failed; nothing to restore"),
+        }
+    }
+
+    /// A new, created VM that starts from this one's current
+    /// [`snapshot`](Self::snapshot) rather than booting, mapping the same
+    /// rootfs copy-on-write; booting this one first if need be. Many
+    /// children can be forked from one parent, each given its own input:
+    ///
+    /// ```no_run
+    /// # use hyperlight_unikraft::Vm;
+    /// # fn main() -> anyhow::Result<()> {
+    /// # let scripts: Vec<String> = vec![];
+    /// let mut parent = Vm::builder()
+    ///     .kernel("python-kernel")
+    ///     .initrd_file("python.cpio")
+    ///     .args(["/usr/bin/python3", "-"]) // the script comes on stdin
+    ///     .build()?;
+    /// for script in scripts {
+    ///     let child = parent.fork(Vm::builder().stdin(script))?;
+    ///     print!("{}", child.run()?.stdout);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// `child` gives the child's run-time options: stdin, tools, timeout,
+    /// capture and [`config`](VmBuilder::config). Whatever was fixed at
+    /// boot — kernel, rootfs, arguments, environment, memory and mounts —
+    /// is the parent's, and setting it on `child` is an error.
+    pub fn fork(&mut self, child: VmBuilder) -> Result<Vm> {
+        let (config, tools) = child.into_fork_options()?;
+        if let State::Built(_) = self.state {
+            self.create()?;
+        }
+        let parent = self
+            .worker()
+            .ok_or_else(|| anyhow!("the VM failed; nothing to fork"))?;
+        let mut vm = Vm {
+            state: State::Built(Box::new(Spec {
+                kernel: Kernel::File(parent.kernel.clone()),
+                initrd: None,
+                args: Vec::new(),
+                config,
+                tools,
+                preopens: parent.preopens.clone(),
+                fork: Some((parent.seed.clone(), parent.snapshot.clone())),
+            })),
+        };
+        vm.create()?;
+        Ok(vm)
+    }
+
+    fn worker(&self) -> Option<&Worker> {
+        match &self.state {
+            State::Created(w) | State::Running(w) | State::Done(w, _) => Some(w),
+            _ => None,
+        }
+    }
+}
+
+impl Drop for Vm {
+    fn drop(&mut self) {
+        if let State::Running(w) = &self.state {
+            w.handle.kill();
+        }
+    }
+}
+
+/// Chainable configuration for a [`Vm`].
+#[derive(Default)]
+pub struct VmBuilder {
+    kernel: Option<Kernel>,
+    initrd: Option<InitrdSource>,
+    args: Vec<String>,
+    memory: Option<String>,
+    capture: Capture,
+    config: VmConfig,
+    tools: Option<ToolRegistry>,
+    preopens: Vec<Preopen>,
+}
+
+enum InitrdSource {
+    File(PathBuf),
+    Bytes(Cow<'static, [u8]>),
+}
+
+impl VmBuilder {
+    /// The Unikraft kernel image. Required.
+    pub fn kernel(mut self, path: impl Into<PathBuf>) -> Self {
+        self.kernel = Some(Kernel::File(path.into()));
+        self
+    }
+
+    /// The kernel image itself, for one embedded with `include_bytes!`
+    /// or downloaded rather than read from disk; see
+    /// [`run_vm_from_bytes`](crate::run_vm_from_bytes).
+    pub fn kernel_bytes(mut self, image: impl Into<Vec<u8>>) -> Self {
+        self.kernel = Some(Kernel::Bytes(Cow::Owned(image.into())));
+        self
+    }
+
+    /// [`kernel_bytes`](Self::kernel_bytes) without the copy, for an
+    /// image compiled into the binary; see [`embed`](crate::embed).
+    pub fn kernel_static(mut self, image: &'static [u8]) -> Self {
+        self.kernel = Some(Kernel::Bytes(Cow::Borrowed(image)));
+        self
+    }
+
+    /// The rootfs CPIO archive, mapped zero-copy into the guest. A
+    /// zstd-compressed archive is decompressed in host memory instead.
+    pub fn initrd_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.initrd = Some(InitrdSource::File(path.into()));
+        self
+    }
+
+    /// An in-memory rootfs CPIO archive.
+    pub fn initrd_bytes(mut self, bytes: Vec<u8>) -> Self {
+        self.initrd = Some(InitrdSource::Bytes(Cow::Owned(bytes)));
+        self
+    }
+
+    /// [`initrd_bytes`](Self::initrd_bytes) without the copy, for an
+    /// archive compiled into the binary.
+    pub fn initrd_static(mut self, bytes: &'static [u8]) -> Self {
+        self.initrd = Some(InitrdSource::Bytes(Cow::Borrowed(bytes)));
+        self
+    }
+
+    /// Append an application argument. Repeatable.
+    pub fn arg(mut self, arg: impl Into<String>) -> Self {
+        self.args.push(arg.into());
+        self
+    }
+
+    /// Append application arguments.
+    pub fn args<S: Into<String>>(mut self, args: impl IntoIterator<Item = S>) -> Self {
+        self.args.extend(args.into_iter().map(Into::into));
+        self
+    }
+
+    /// An environment variable for the application; see
+    /// [`VmConfig::with_env`]. Repeatable.
+    pub fn env(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.config = self.config.with_env(key, value);
+        self
+    }
+
+    /// Guest heap, in [`parse_memory`] syntax: `"256Mi"`, `"1Gi"`,
+    /// `"25%"`. Default 512Mi.
+    pub fn memory(mut self, size: impl Into<String>) -> Self {
+        self.memory = Some(size.into());
+        self
+    }
+
+    /// Guest heap in bytes; [`memory`](Self::memory) with a number.
+    pub fn heap(mut self, bytes: u64) -> Self {
+        self.memory = None;
+        self.config.heap_size = bytes;
+        self
+    }
+
+    /// Guest stack in bytes. Default 8Mi.
+    pub fn stack(mut self, bytes: u64) -> Self {
+        self.config.stack_size = bytes;
+        self
+    }
+
+    /// Interrupt the application if it is still running after `limit`;
+    /// see [`VmConfig::with_timeout`]. The run then fails with
+    /// [`Error::TimedOut`], carrying the output captured so far.
+    pub fn timeout(mut self, limit: Duration) -> Self {
+        self.config = self.config.with_timeout(limit);
+        self
+    }
+
+    /// What to do with the guest's console output (default
+    /// [`Capture::Output`]).
+    pub fn capture(mut self, capture: Capture) -> Self {
+        self.capture = capture;
+        self
+    }
+
+    /// How the guest prints; see [`VmConfig::with_console`].
+    pub fn console(mut self, console: Console) -> Self {
+        self.config = self.config.with_console(console);
+        self
+    }
+
+    /// Collect the console output and copy it live to `writer` rather
+    /// than stderr ([`Capture::Both`]); see [`VmConfig::with_tee_to`].
+    pub fn tee_to(mut self, writer: impl std::io::Write + Send + 'static) -> Self {
+        self.config = self.config.with_tee_to(writer);
+        self.capture(Capture::Both)
+    }
+
+    /// Collect the console output ([`Capture::Output`]) or leave it on
+    /// the host's stderr ([`Capture::None`]).
+    pub fn capture_output(self, on: bool) -> Self {
+        self.capture(if on { Capture::Output } else { Capture::None })
+    }
+
+    /// Bytes for the application's standard input; see
+    /// [`VmConfig::with_stdin`].
+    pub fn stdin(mut self, data: impl Into<Vec<u8>>) -> Self {
+        self.config = self.config.with_stdin(data);
+        self
+    }
+
+    /// Stream the application's standard input from `reader`; see
+    /// [`VmConfig::with_stdin_reader`].
+    pub fn stdin_reader(mut self, reader: impl std::io::Read + Send + 'static) -> Self {
+        self.config = self.config.with_stdin_reader(reader);
+        self
+    }
+
+    /// Register a host function callable from the guest via `__dispatch`.
+    pub fn tool<F>(mut self, name: &str, handler: F) -> Self
+    where
+        F: Fn(serde_json::Value) -> Result<serde_json::Value> + Send + Sync + 'static,
+    {
+        self.tools
+            .get_or_insert_with(ToolRegistry::new)
+            .register(name, handler);
+        self
+    }
+
+    /// Expose a host directory to the guest; see
+    /// [`SandboxBuilder::preopen`](crate::SandboxBuilder::preopen).
+    pub fn preopen(mut self, preopen: Preopen) -> Self {
+        self.preopens.push(preopen);
+        self
+    }
+
+    /// Adjust the underlying [`VmConfig`] for options without a method
+    /// here: `.config(|c| c.with_timezone("UTC0").with_call_stats(true))`.
+    pub fn config(mut self, f: impl FnOnce(VmConfig) -> VmConfig) -> Self {
+        self.config = f(self.config);
+        self
+    }
+
+    /// The options of a [`Vm::fork`] child, which takes everything fixed
+    /// at boot from its parent.
+    fn into_fork_options(self) -> Result<(VmConfig, Option<ToolRegistry>)> {
+        if self.kernel.is_some()
+            || self.initrd.is_some()
+            || !self.args.is_empty()
+            || !self.config.env.is_empty()
+            || self.memory.is_some()
+            || !self.preopens.is_empty()
+        {
+            return Err(Error::config(
+                "a fork starts from its parent's snapshot; kernel, rootfs, arguments, \
+                 environment, memory and mounts come from the parent",
+            )
+            .into());
+        }
+        let mut config = self.config;
+        config.capture_output = self.capture != Capture::None;
+        config.tee_output = self.capture == Capture::Both;
+        Ok((config, self.tools))
+    }
+
+    /// Check the options and open the inputs.
+    pub fn build(self) -> Result<Vm> {
+        let kernel = self
+            .kernel
+            .ok_or_else(|| Error::config("no kernel given; call .kernel(path)"))?;
+        if let Kernel::File(ref path) = kernel {
+            crate::error::check_kernel(path)?;
+        }
+        let mut config = self.config;
+        if let Some(ref memory) = self.memory {
+            config.heap_size = parse_memory(memory).context("memory")?;
+        }
+        config.capture_output = self.capture != Capture::None;
+        config.tee_output = self.capture == Capture::Both;
+        // Fails here rather than after boot.
+        config.validate()?;
+        let initrd = match self.initrd {
+            None => None,
+            Some(InitrdSource::Bytes(bytes)) => Some(Initrd::Bytes(bytes)),
+            Some(InitrdSource::File(path)) => Some(match rootfs::read_if_zstd(&path)? {
+                Some(bytes) => Initrd::Bytes(Cow::Owned(bytes)),
+                None => Initrd::Mapped(MappedInitrd::open(path)?),
+            }),
+        };
+        Ok(Vm {
+            state: State::Built(Box::new(Spec {
+                kernel,
+                initrd,
+                args: self.args,
+                config,
+                tools: self.tools,
+                preopens: self.preopens,
+                fork: None,
+            })),
+        })
+    }
+
+    /// [`build`](Self::build) and [`run`](Vm::run) in one step.
+    pub fn run(self) -> Result<VmOutput> {
+        self.build()?.run()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::workspace::Workspace;
+
+    fn spec(vm: &Vm) -> &Spec {
+        match &vm.state {
+            State::Built(spec) => spec,
+            _ => panic!("not a built VM"),
+        }
+    }
+
+    fn build_err(builder: VmBuilder) -> String {
+        match builder.build() {
+            Ok(_) => panic!("build should fail"),
+            Err(e) => format!("{e:#}"),
+        }
+    }
+
+    #[test]
+    fn build_checks_options_before_booting() {
+        let ws = Workspace::new().unwrap();
+        let kernel = ws.write("kernel", b"\x7fELF").unwrap();
+
+        assert!(build_err(Vm::builder()).contains("no kernel"));
+        assert!(build_err(Vm::builder().kernel(ws.path().join("nope"))).contains("not found"));
+        let bad = Vm::builder().kernel(&kernel).memory("lots");
+        assert!(build_err(bad).starts_with("memory: "));
+        let bad = Vm::builder().kernel(&kernel).env("NOT OK", "x");
+        assert!(build_err(bad).contains("invalid environment variable name"));
+        let missing = Vm::builder()
+            .kernel(&kernel)
+            .initrd_file(ws.path().join("missing.cpio"));
+        assert!(build_err(missing).contains("missing.cpio"));
+
+        let initrd = ws.write("rootfs.cpio", b"070701").unwrap();
+        let vm = Vm::builder()
+            .kernel(&kernel)
+            .initrd_file(&initrd)
+            .args(["/app.py", "-v"])
+            .env("A", "1")
+            .env("A", "2")
+            .heap(1)
+            .memory("64Mi")
+            .stack(1024 * 1024)
+            .timeout(Duration::from_secs(3))
+            .capture(Capture::Both)
+            .build()
+            .unwrap();
+        let vm = spec(&vm);
+        assert_eq!(vm.args, ["/app.py", "-v"]);
+        assert_eq!(vm.config.heap_size, 64 * 1024 * 1024);
+        assert_eq!(vm.config.stack_size, 1024 * 1024);
+        assert_eq!(vm.config.timeout, Some(Duration::from_secs(3)));
+        assert!(vm.config.capture_output && vm.config.tee_output);
+        assert_eq!(vm.config.guest_env().unwrap(), ["A=2"]);
+        assert!(matches!(vm.initrd, Some(Initrd::Mapped(_))));
+
+        let vm = Vm::builder()
+            .kernel(&kernel)
+            .initrd_bytes(b"070701".to_vec())
+            .memory("64Mi")
+            .heap(16 << 20)
+            .capture_output(false)
+            .build()
+            .unwrap();
+        let vm = spec(&vm);
+        assert_eq!(vm.config.heap_size, 16 << 20);
+        // An in-memory kernel has no file to check.
+        let embedded = Vm::builder().kernel_bytes(b"\x7fELF").build().unwrap();
+        assert!(matches!(spec(&embedded).kernel, Kernel::Bytes(_)));
+        assert!(!vm.config.capture_output && !vm.config.tee_output);
+        assert!(matches!(vm.initrd, Some(Initrd::Bytes(_))));
+    }
+
+    #[test]
+    fn lifecycle_steps_check_their_order() {
+        let ws = Workspace::new().unwrap();
+        let kernel = ws.write("kernel", b"\x7fELF").unwrap();
+        let mut vm = Vm::builder().kernel(&kernel).build().unwrap();
+        assert!(vm.handle().is_none() && vm.output().is_none());
+        assert!(!vm.kill());
+        let err = vm.wait().unwrap_err().to_string();
+        assert!(err.contains("not started"), "{err}");
+        // Still usable after the misstep.
+        assert!(matches!(vm.state, State::Built(_)));
+    }
+
+    #[test]
+    fn forks_take_only_run_time_options() {
+        let fork_err = |builder: VmBuilder| match builder.into_fork_options() {
+            Ok(_) => panic!("fork options should be refused"),
+            Err(e) => e.to_string(),
+        };
+        assert!(fork_err(Vm::builder().arg("/other.py")).contains("parent's snapshot"));
+        fork_err(Vm::builder().env("A", "1"));
+        fork_err(Vm::builder().memory("1Gi"));
+
+        let (config, tools) = Vm::builder()
+            .stdin("print(1)")
+            .timeout(Duration::from_secs(1))
+            .capture(Capture::Both)
+            .tool("echo", Ok)
+            .into_fork_options()
+            .unwrap();
+        assert!(config.stdin.is_some() && tools.is_some());
+        assert_eq!(config.timeout, Some(Duration::from_secs(1)));
+        assert!(config.capture_output && config.tee_output);
+    }
+}
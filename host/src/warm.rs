@@ -0,0 +1,152 @@
+//! Pre-warming Python rootfs images.
+//!
+//! A fresh rootfs makes the interpreter compile every module it imports
+//! on every run, since the compiled `.pyc` files land in the guest's
+//! ramfs and vanish with it. [`warm_rootfs`] boots the image once to
+//! compile all of its `.py` sources, optionally runs a warmup script, and
+//! appends what they produced to the archive, so later runs start from
+//! bytecode.
+//!
+//! The guest writes its results through a host directory preopened at
+//! [`WARM_DIR`], so the kernel needs `lib/hostfs`. The `.pyc` files use
+//! unchecked-hash invalidation: the rootfs is immutable and its mtimes
+//! aren't preserved on extraction, so they are always trusted.
+//!
+//! ```no_run
+//! use hyperlight_unikraft::warm::{warm_rootfs, WarmOptions};
+//! use std::path::Path;
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! let rootfs = std::fs::read("python.cpio")?;
+//! let warmed = warm_rootfs(Path::new("python-kernel"), &rootfs, &WarmOptions::default())?;
+//! std::fs::write("python-warm.cpio", &warmed.rootfs)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use anyhow::{Context, Result};
+use std::path::Path;
+
+use crate::workspace::Workspace;
+use crate::{pipeline, rootfs, Preopen, Sandbox};
+
+/// Guest path of the warmup directory.
+pub const WARM_DIR: &str = "/warm";
+
+/// Runs in the guest: compile every listed source into `out/`, mirroring
+/// guest paths, then run the user's script with `WARM_OUT` pointing
+/// there.
+const DRIVER: &str = r#"import importlib.util, os, py_compile, runpy, sys
+out = "/warm/out"
+failed = 0
+with open("/warm/sources") as f:
+    sources = f.read().splitlines()
+for src in sources:
+    dest = out + importlib.util.cache_from_source(src)
+    try:
+        os.makedirs(os.path.dirname(dest), exist_ok=True)
+        py_compile.compile(src, cfile=dest, dfile=src, doraise=True,
+                           invalidation_mode=py_compile.PycInvalidationMode.UNCHECKED_HASH)
+    except Exception as e:
+        failed += 1
+        print("warm: %s: %s" % (src, e), file=sys.stderr)
+if os.path.exists("/warm/script.py"):
+    os.environ["WARM_OUT"] = out
+    runpy.run_path("/warm/script.py", run_name="__main__")
+print("warm: compiled %d of %d sources" % (len(sources) - failed, len(sources)))
+"#;
+
+/// How to warm a rootfs.
+#[derive(Debug, Clone, Default)]
+pub struct WarmOptions {
+    /// Python source run after compilation. Files it writes under
+    /// `$WARM_OUT`, at the guest path they should have (e.g.
+    /// `$WARM_OUT/app/model.bin` for `/app/model.bin`), are added to the
+    /// rootfs too.
+    pub script: Option<Vec<u8>>,
+    /// Guest heap for the warmup boot (default: the builder's).
+    pub heap_size: Option<u64>,
+}
+
+/// A warmed rootfs.
+#[derive(Debug, Clone)]
+pub struct WarmedRootfs {
+    /// The input archive (decompressed) with the new files appended.
+    pub rootfs: Vec<u8>,
+    /// `.py` files found in the input.
+    pub sources: usize,
+    /// Files appended: compiled modules plus whatever the script wrote.
+    pub added: usize,
+}
+
+/// Boot `kernel` (a Python interpreter) on `rootfs` once and return the
+/// rootfs with the compiled bytecode added. `rootfs` may be gzip or zstd
+/// compressed; the result is not.
+pub fn warm_rootfs(kernel: &Path, rootfs: &[u8], options: &WarmOptions) -> Result<WarmedRootfs> {
+    let base = pipeline::decompress(rootfs.to_vec())?;
+    let sources = python_sources(&base)?;
+
+    let ws = Workspace::new()?;
+    ws.write("sources", sources.join("\n"))?;
+    ws.write("warm.py", DRIVER)?;
+    if let Some(ref script) = options.script {
+        ws.write("script.py", script)?;
+    }
+    let out = ws.dir("out")?;
+    // Outside the preopen, so the guest doesn't see its own rootfs twice.
+    let boot = Workspace::new()?;
+    let initrd = boot.write("rootfs.cpio", &base)?;
+
+    let mut builder = Sandbox::builder(kernel)
+        .initrd_file(initrd)
+        .args([format!("{WARM_DIR}/warm.py")])
+        .preopen(Preopen::new(ws.path(), WARM_DIR)?);
+    if let Some(heap) = options.heap_size {
+        builder = builder.heap_size(heap);
+    }
+    let mut sandbox = builder.build().context("booting warmup sandbox")?;
+    sandbox.restore()?;
+    sandbox.call_run().context("running warmup")?;
+    drop(sandbox);
+
+    let files = crate::workspace::collect_files(&out)?;
+    let refs: Vec<(&str, &[u8])> = files
+        .iter()
+        .map(|(p, d)| (p.as_str(), d.as_slice()))
+        .collect();
+    Ok(WarmedRootfs {
+        rootfs: rootfs::append_files(&base, &refs)?,
+        sources: sources.len(),
+        added: files.len(),
+    })
+}
+
+/// Absolute guest paths of the regular `.py` files in `archive`.
+fn python_sources(archive: &[u8]) -> Result<Vec<String>> {
+    let (entries, _) = rootfs::scan(archive)?;
+    Ok(entries
+        .iter()
+        .filter(|e| e.is_file() && e.name.ends_with(".py"))
+        .map(|e| format!("/{}", e.name.trim_start_matches("./")))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sources_are_the_archives_python_files() {
+        let mut cpio = rootfs::CpioWriter::new(Vec::new());
+        cpio.dir("usr", 0o755, 0).unwrap();
+        cpio.file("usr/lib/os.py", 0o644, 0, b"").unwrap();
+        cpio.file("./app.py", 0o644, 0, b"print(1)").unwrap();
+        cpio.file("app.pyc", 0o644, 0, b"").unwrap();
+        cpio.symlink("link.py", "app.py", 0).unwrap();
+        let archive = cpio.finish().unwrap();
+        assert_eq!(
+            python_sources(&archive).unwrap(),
+            ["/usr/lib/os.py", "/app.py"]
+        );
+    }
+}
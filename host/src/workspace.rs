@@ -0,0 +1,230 @@
+//! Per-run scratch directories.
+//!
+//! A [`Workspace`] owns every temporary artifact of a run — modified
+//! rootfs images, extracted files, spilled host regions — in one
+//! directory that is removed when the workspace is dropped.
+
+use anyhow::{anyhow, Context, Result};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Environment variable naming the directory workspaces are created in
+/// (default: the system temp dir).
+pub const ENV_WORKDIR: &str = "HYPERLIGHT_UNIKRAFT_WORKDIR";
+
+/// A uniquely named scratch directory, deleted with its contents on drop
+/// unless [`keep`](Self::keep) is called.
+#[derive(Debug)]
+pub struct Workspace {
+    root: PathBuf,
+    keep: bool,
+    scrub: bool,
+}
+
+impl Workspace {
+    /// Create a workspace under [`ENV_WORKDIR`], or the system temp dir
+    /// if that is unset.
+    pub fn new() -> Result<Self> {
+        let parent = std::env::var_os(ENV_WORKDIR)
+            .map(PathBuf::from)
+            .unwrap_or_else(std::env::temp_dir);
+        Self::new_in(&parent)
+    }
+
+    /// Create a workspace under `parent` (created if missing).
+    pub fn new_in(parent: &Path) -> Result<Self> {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        std::fs::create_dir_all(parent).with_context(|| format!("creating {parent:?}"))?;
+        let root = parent.join(format!(
+            "hl-ws-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        // A leftover from a crashed process with a recycled PID.
+        if root.exists() {
+            std::fs::remove_dir_all(&root)?;
+        }
+        std::fs::create_dir(&root).with_context(|| format!("creating workspace {root:?}"))?;
+        Ok(Self {
+            root,
+            keep: false,
+            scrub: false,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.root
+    }
+
+    /// Path for an artifact called `name` (relative, no `..`). Parent
+    /// directories inside the workspace are created; the file is not.
+    pub fn file(&self, name: &str) -> Result<PathBuf> {
+        let rel = Path::new(name);
+        if name.is_empty()
+            || !rel
+                .components()
+                .all(|c| matches!(c, std::path::Component::Normal(_)))
+        {
+            return Err(anyhow!(
+                "workspace artifact name {name:?} must be a relative path"
+            ));
+        }
+        let path = self.root.join(rel);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        Ok(path)
+    }
+
+    /// Write `data` to the artifact `name` and return its path.
+    pub fn write(&self, name: &str, data: impl AsRef<[u8]>) -> Result<PathBuf> {
+        let path = self.file(name)?;
+        std::fs::write(&path, data).with_context(|| format!("writing {path:?}"))?;
+        Ok(path)
+    }
+
+    /// Create the subdirectory `name` and return its path.
+    pub fn dir(&self, name: &str) -> Result<PathBuf> {
+        let path = self.file(name)?;
+        std::fs::create_dir_all(&path)?;
+        Ok(path)
+    }
+
+    /// Overwrite every file with zeros (and flush it) before the
+    /// directory is removed on drop, so artifacts such as a decrypted
+    /// rootfs don't outlive the run in the page cache or on disk.
+    pub fn scrub_on_drop(mut self) -> Self {
+        self.scrub = true;
+        self
+    }
+
+    /// Keep the directory after drop (for debugging a failed run) and
+    /// return its path.
+    pub fn keep(mut self) -> PathBuf {
+        self.keep = true;
+        self.root.clone()
+    }
+}
+
+impl Drop for Workspace {
+    fn drop(&mut self) {
+        if !self.keep {
+            if self.scrub {
+                scrub_dir(&self.root);
+            }
+            let _ = std::fs::remove_dir_all(&self.root);
+        }
+    }
+}
+
+/// Zero every regular file under `dir`, best effort.
+fn scrub_dir(dir: &Path) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        match entry.file_type() {
+            Ok(t) if t.is_dir() => scrub_dir(&entry.path()),
+            Ok(t) if t.is_file() => {
+                if let Err(e) = zero_file(&entry.path()) {
+                    tracing::warn!("scrubbing {:?}: {e}", entry.path());
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn zero_file(path: &Path) -> std::io::Result<()> {
+    let mut file = std::fs::OpenOptions::new().write(true).open(path)?;
+    let mut left = file.metadata()?.len();
+    let zeros = [0u8; 64 * 1024];
+    while left > 0 {
+        let n = left.min(zeros.len() as u64) as usize;
+        file.write_all(&zeros[..n])?;
+        left -= n as u64;
+    }
+    file.sync_data()
+}
+
+/// Every regular file under `dir` as `(guest path, contents)` — the
+/// path below `dir` with a leading `/` — sorted by path so archives built
+/// from them are deterministic.
+pub(crate) fn collect_files(dir: &Path) -> Result<Vec<(String, Vec<u8>)>> {
+    let mut files = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(d) = pending.pop() {
+        for entry in std::fs::read_dir(&d).with_context(|| format!("reading {d:?}"))? {
+            let entry = entry?;
+            let path = entry.path();
+            let kind = entry.file_type()?;
+            if kind.is_dir() {
+                pending.push(path);
+            } else if kind.is_file() {
+                let rel = path.strip_prefix(dir)?;
+                let guest = rel
+                    .to_str()
+                    .ok_or_else(|| anyhow!("{rel:?} is not UTF-8"))?
+                    .replace(std::path::MAIN_SEPARATOR, "/");
+                files.push((format!("/{guest}"), std::fs::read(&path)?));
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn artifacts_are_removed_on_drop_unless_kept() {
+        let parent = std::env::temp_dir().join(format!("hl-ws-test-{}", std::process::id()));
+        let ws = Workspace::new_in(&parent).unwrap();
+        let script = ws.write("app/main.py", "print(1)").unwrap();
+        assert!(script.starts_with(ws.path()));
+        assert!(ws.file("../escape").is_err());
+        assert!(ws.file("/abs").is_err());
+        let root = ws.path().to_path_buf();
+        drop(ws);
+        assert!(!root.exists());
+
+        let kept = Workspace::new_in(&parent).unwrap().keep();
+        assert!(kept.exists());
+        std::fs::remove_dir_all(&parent).unwrap();
+    }
+
+    #[test]
+    fn scrubbed_workspace_zeroes_files_before_removal() {
+        let parent = std::env::temp_dir().join(format!("hl-ws-scrub-{}", std::process::id()));
+        let ws = Workspace::new_in(&parent).unwrap().scrub_on_drop();
+        let secret = ws.write("nested/initrd", "hunter2").unwrap();
+        // A second link outside the workspace outlives the removal.
+        let witness = parent.join("witness");
+        std::fs::hard_link(&secret, &witness).unwrap();
+        drop(ws);
+        assert_eq!(std::fs::read(&witness).unwrap(), [0; 7]);
+        std::fs::remove_dir_all(&parent).unwrap();
+    }
+
+    #[test]
+    fn outputs_map_to_guest_paths() {
+        let ws = Workspace::new().unwrap();
+        ws.write("out/usr/lib/__pycache__/os.cpython-312.pyc", b"pyc")
+            .unwrap();
+        ws.write("out/app/model.bin", b"weights").unwrap();
+        let files = collect_files(&ws.path().join("out")).unwrap();
+        assert_eq!(
+            files,
+            [
+                ("/app/model.bin".to_string(), b"weights".to_vec()),
+                (
+                    "/usr/lib/__pycache__/os.cpython-312.pyc".to_string(),
+                    b"pyc".to_vec()
+                ),
+            ]
+        );
+    }
+}